@@ -283,6 +283,7 @@ fn compile_shader(kind: GLuint, source: &[u8], api: GLAPI) -> GLuint {
         let preamble = match api {
             GLAPI::GL => b"#version 330\n" as &[u8],
             GLAPI::GLES => b"#version 300 es\n" as &[u8],
+            GLAPI::None => panic!("This example requires OpenGL!"),
         };
 
         let shader = gl::CreateShader(kind);
@@ -8,18 +8,22 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use euclid::Rect;
+use euclid::{Rect, Vector2D};
 use image::RgbaImage;
 
 #[cfg(feature = "enable-winit")]
-use winit::Window;
+use winit::{EventsLoop, Window, WindowBuilder, WindowId};
 
 use crate::{Connection, ConnectionError, GLAPI, GLContextLayerBinding, LayerContainerInfo};
 use crate::{LayerGeometryInfo, LayerId, LayerMap, LayerSurfaceInfo, LayerTreeInfo, Promise};
-use crate::{SurfaceOptions};
+use crate::{Capabilities, ExportToken, LayerEffects, LayerEffectsInfo, ScreenshotError};
+use crate::{LayerScrollInfo, SurfaceOptions, VideoFrame};
 
 // Backend definition
 
+// The methods with default implementations are for optional features. The defaults behave as a
+// backend that doesn't support the feature should, so backends outside this crate only need to
+// implement what they support, and to report it in `capabilities`.
 pub trait Backend: Sized {
     type NativeConnection;
     type GLContext;
@@ -36,6 +40,25 @@ pub trait Backend: Sized {
                               -> Result<Self::GLContext, ()>;
     fn gl_api(&self) -> GLAPI;
 
+    // Capabilities
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            screenshots: false,
+            damage_aware_present: false,
+            transforms: false,
+            native_animations: false,
+            surface_options: SurfaceOptions::empty(),
+            max_texture_size: None,
+            layer_export: false,
+            video_layers: false,
+            layer_effects: false,
+            backdrop_blur: false,
+            blend_modes: false,
+            layer_masks: false,
+            scroll_clipping: false,
+        }
+    }
+
     // Transactions
     fn begin_transaction(&self);
     fn end_transaction(&mut self,
@@ -48,6 +71,10 @@ pub trait Backend: Sized {
     // Layer creation and destruction
     fn add_container_layer(&mut self, new_layer: LayerId);
     fn add_surface_layer(&mut self, new_layer: LayerId);
+    // Without scroll clipping, a scroll layer is an ordinary container.
+    fn add_scroll_layer(&mut self, new_layer: LayerId) {
+        self.add_container_layer(new_layer)
+    }
     fn delete_layer(&mut self, layer: LayerId);
 
     // Layer tree management
@@ -73,6 +100,23 @@ pub trait Backend: Sized {
                          geometry_component: &LayerMap<LayerGeometryInfo>);
     fn unhost_layer(&mut self, layer: LayerId);
 
+    // Cross-process hosting
+    fn export_layer(&mut self,
+                    _: LayerId,
+                    _: &LayerMap<LayerTreeInfo>,
+                    _: &LayerMap<LayerContainerInfo>,
+                    _: &LayerMap<LayerGeometryInfo>)
+                    -> Result<ExportToken, ()> {
+        Err(())
+    }
+    fn import_layer(&mut self, _: LayerId, _: &ExportToken) -> Result<(), ()> {
+        Err(())
+    }
+    // Returns true if an imported layer has a new frame that ending a transaction will display.
+    fn imported_layers_changed(&mut self) -> bool {
+        false
+    }
+
     // Geometry
     fn set_layer_bounds(&mut self,
                         layer: LayerId,
@@ -86,6 +130,41 @@ pub trait Backend: Sized {
                                  layer: LayerId,
                                  surface_component: &LayerMap<LayerSurfaceInfo>);
 
+    // Effects
+    fn set_layer_effects(&mut self,
+                         _: LayerId,
+                         _: &LayerEffects,
+                         _: &LayerMap<LayerTreeInfo>,
+                         _: &LayerMap<LayerGeometryInfo>,
+                         _: &LayerMap<LayerEffectsInfo>) {}
+    fn set_layer_mask(&mut self,
+                      _: LayerId,
+                      _: &LayerMap<LayerTreeInfo>,
+                      _: &LayerMap<LayerGeometryInfo>,
+                      _: &LayerMap<LayerEffectsInfo>) {}
+
+    // Scrolling
+    fn set_layer_content_size(&mut self, _: LayerId, _: &LayerMap<LayerScrollInfo>) {}
+    fn set_layer_content_offset(&mut self,
+                                _: LayerId,
+                                _: &LayerMap<LayerTreeInfo>,
+                                _: &LayerMap<LayerContainerInfo>,
+                                _: &LayerMap<LayerGeometryInfo>,
+                                _: &LayerMap<LayerScrollInfo>) {}
+    // Returns `Err` if the backend can't animate flings in the compositor, in which case the
+    // layer context animates them by setting the content offset every frame. Otherwise, the
+    // backend settles the promise with the final content offset, unless the fling is stopped
+    // first by setting the content offset, starting another fling, or deleting the layer.
+    fn start_fling(&mut self,
+                   _: LayerId,
+                   _: &Vector2D<f32>,
+                   _: &Promise<Vector2D<f32>>,
+                   _: &LayerMap<LayerGeometryInfo>,
+                   _: &LayerMap<LayerScrollInfo>)
+                   -> Result<(), ()> {
+        Err(())
+    }
+
     // OpenGL content binding
     fn bind_layer_to_gl_context(&mut self,
                                 layer: LayerId,
@@ -100,15 +179,51 @@ pub trait Backend: Sized {
                           geometry_component: &LayerMap<LayerGeometryInfo>)
                           -> Result<(), ()>;
 
+    // Video
+    // Without video support, a video layer is an ordinary surface that frames can't be presented
+    // to.
+    fn add_video_layer(&mut self, new_layer: LayerId) {
+        self.add_surface_layer(new_layer)
+    }
+    fn present_video_frame(&mut self,
+                           _: LayerId,
+                           _: &VideoFrame,
+                           _: &LayerMap<LayerTreeInfo>,
+                           _: &LayerMap<LayerGeometryInfo>)
+                           -> Result<(), ()> {
+        Err(())
+    }
+
     // Screenshots
     fn screenshot_hosted_layer(&mut self,
-                               layer: LayerId,
-                               transaction_promise: &Promise<()>,
-                               tree_component: &LayerMap<LayerTreeInfo>,
-                               container_component: &LayerMap<LayerContainerInfo>,
-                               geometry_component: &LayerMap<LayerGeometryInfo>,
-                               surface_component: &LayerMap<LayerSurfaceInfo>)
-                               -> Promise<RgbaImage>;
+                               _: LayerId,
+                               _: &Promise<()>,
+                               _: &LayerMap<LayerTreeInfo>,
+                               _: &LayerMap<LayerContainerInfo>,
+                               _: &LayerMap<LayerGeometryInfo>,
+                               _: &LayerMap<LayerSurfaceInfo>)
+                               -> Promise<RgbaImage, ScreenshotError> {
+        let promise = Promise::new();
+        promise.reject(ScreenshotError::Unsupported);
+        promise
+    }
+
+    // Event dispatch
+    // Returns `Err` if there are no events that could settle a promise.
+    fn pump_events(&mut self) -> Result<(), ()> {
+        Err(())
+    }
+    // Waits for the next frame of the backend's own clock, then moves the animations that it runs
+    // itself, such as flings, to where they should be by then and redraws. Returns `Err` if it
+    // isn't running any.
+    fn run_animation_frame(&mut self,
+                           _: &LayerMap<LayerTreeInfo>,
+                           _: &LayerMap<LayerContainerInfo>,
+                           _: &LayerMap<LayerGeometryInfo>,
+                           _: &LayerMap<LayerSurfaceInfo>)
+                           -> Result<(), ()> {
+        Err(())
+    }
 
     // `winit` integration
     #[cfg(feature = "enable-winit")]
@@ -120,5 +235,27 @@ pub trait Backend: Sized {
                             container_component: &LayerMap<LayerContainerInfo>,
                             geometry_component: &LayerMap<LayerGeometryInfo>)
                             -> Result<(), ()>;
+    #[cfg(feature = "enable-winit")]
+    fn create_window(&mut self, _: WindowBuilder, _: &EventsLoop) -> Result<WindowId, ()> {
+        Err(())
+    }
+    #[cfg(feature = "enable-winit")]
+    fn adopt_window(&mut self, window: Window) -> Result<WindowId, Window> {
+        Err(window)
+    }
+    #[cfg(feature = "enable-winit")]
+    fn window_with_id(&self, _: WindowId) -> Option<&Window> {
+        None
+    }
+    #[cfg(feature = "enable-winit")]
+    fn host_layer_in_window_with_id(&mut self,
+                                    _: LayerId,
+                                    _: WindowId,
+                                    _: &LayerMap<LayerTreeInfo>,
+                                    _: &LayerMap<LayerContainerInfo>,
+                                    _: &LayerMap<LayerGeometryInfo>)
+                                    -> Result<(), ()> {
+        Err(())
+    }
 }
 
@@ -13,15 +13,16 @@
 //! If backend A fails to initialize, then it tries to initialize backend B. Note that more than
 //! two backends can be chained together by making backend A or backend B itself a `Chain`.
 
-use euclid::Rect;
+use euclid::{Rect, Vector2D};
 use image::RgbaImage;
 
 #[cfg(feature = "enable-winit")]
-use winit::Window;
+use winit::{EventsLoop, Window, WindowBuilder, WindowId};
 
 use crate::{Connection, ConnectionError, GLAPI, GLContextLayerBinding, LayerContainerInfo};
 use crate::{LayerGeometryInfo, LayerId, LayerMap, LayerSurfaceInfo, LayerTreeInfo, Promise};
-use crate::{SurfaceOptions};
+use crate::{Capabilities, ExportToken, ScreenshotError, SurfaceOptions, VideoFrame};
+use crate::{LayerEffects, LayerEffectsInfo, LayerScrollInfo};
 
 pub enum Backend<A, B> where A: crate::Backend, B: crate::Backend {
     A(A),
@@ -101,6 +102,15 @@ impl<A, B> crate::Backend for Backend<A, B> where A: crate::Backend, B: crate::B
         }
     }
 
+    // Capabilities
+
+    fn capabilities(&self) -> Capabilities {
+        match *self {
+            Backend::A(ref this) => this.capabilities(),
+            Backend::B(ref this) => this.capabilities(),
+        }
+    }
+
     // Transactions
 
     fn begin_transaction(&self) {
@@ -150,6 +160,13 @@ impl<A, B> crate::Backend for Backend<A, B> where A: crate::Backend, B: crate::B
         }
     }
 
+    fn add_scroll_layer(&mut self, new_layer: LayerId) {
+        match *self {
+            Backend::A(ref mut this) => this.add_scroll_layer(new_layer),
+            Backend::B(ref mut this) => this.add_scroll_layer(new_layer),
+        }
+    }
+
     fn delete_layer(&mut self, layer: LayerId) {
         match *self {
             Backend::A(ref mut this) => this.delete_layer(layer),
@@ -245,6 +262,38 @@ impl<A, B> crate::Backend for Backend<A, B> where A: crate::Backend, B: crate::B
         }
     }
 
+    // Cross-process hosting
+
+    fn export_layer(&mut self,
+                    layer: LayerId,
+                    tree_component: &LayerMap<LayerTreeInfo>,
+                    container_component: &LayerMap<LayerContainerInfo>,
+                    geometry_component: &LayerMap<LayerGeometryInfo>)
+                    -> Result<ExportToken, ()> {
+        match *self {
+            Backend::A(ref mut this) => {
+                this.export_layer(layer, tree_component, container_component, geometry_component)
+            }
+            Backend::B(ref mut this) => {
+                this.export_layer(layer, tree_component, container_component, geometry_component)
+            }
+        }
+    }
+
+    fn import_layer(&mut self, new_layer: LayerId, token: &ExportToken) -> Result<(), ()> {
+        match *self {
+            Backend::A(ref mut this) => this.import_layer(new_layer, token),
+            Backend::B(ref mut this) => this.import_layer(new_layer, token),
+        }
+    }
+
+    fn imported_layers_changed(&mut self) -> bool {
+        match *self {
+            Backend::A(ref mut this) => this.imported_layers_changed(),
+            Backend::B(ref mut this) => this.imported_layers_changed(),
+        }
+    }
+
     // Geometry
 
     fn set_layer_bounds(&mut self,
@@ -282,6 +331,124 @@ impl<A, B> crate::Backend for Backend<A, B> where A: crate::Backend, B: crate::B
         }
     }
 
+    // Effects
+
+    fn set_layer_effects(&mut self,
+                         layer: LayerId,
+                         old_effects: &LayerEffects,
+                         tree_component: &LayerMap<LayerTreeInfo>,
+                         geometry_component: &LayerMap<LayerGeometryInfo>,
+                         effects_component: &LayerMap<LayerEffectsInfo>) {
+        match *self {
+            Backend::A(ref mut this) => {
+                this.set_layer_effects(layer,
+                                       old_effects,
+                                       tree_component,
+                                       geometry_component,
+                                       effects_component)
+            }
+            Backend::B(ref mut this) => {
+                this.set_layer_effects(layer,
+                                       old_effects,
+                                       tree_component,
+                                       geometry_component,
+                                       effects_component)
+            }
+        }
+    }
+
+    fn set_layer_mask(&mut self,
+                      layer: LayerId,
+                      tree_component: &LayerMap<LayerTreeInfo>,
+                      geometry_component: &LayerMap<LayerGeometryInfo>,
+                      effects_component: &LayerMap<LayerEffectsInfo>) {
+        match *self {
+            Backend::A(ref mut this) => {
+                this.set_layer_mask(layer, tree_component, geometry_component, effects_component)
+            }
+            Backend::B(ref mut this) => {
+                this.set_layer_mask(layer, tree_component, geometry_component, effects_component)
+            }
+        }
+    }
+
+    // Scrolling
+
+    fn set_layer_content_size(&mut self,
+                              layer: LayerId,
+                              scroll_component: &LayerMap<LayerScrollInfo>) {
+        match *self {
+            Backend::A(ref mut this) => this.set_layer_content_size(layer, scroll_component),
+            Backend::B(ref mut this) => this.set_layer_content_size(layer, scroll_component),
+        }
+    }
+
+    fn set_layer_content_offset(&mut self,
+                                layer: LayerId,
+                                tree_component: &LayerMap<LayerTreeInfo>,
+                                container_component: &LayerMap<LayerContainerInfo>,
+                                geometry_component: &LayerMap<LayerGeometryInfo>,
+                                scroll_component: &LayerMap<LayerScrollInfo>) {
+        match *self {
+            Backend::A(ref mut this) => {
+                this.set_layer_content_offset(layer,
+                                              tree_component,
+                                              container_component,
+                                              geometry_component,
+                                              scroll_component)
+            }
+            Backend::B(ref mut this) => {
+                this.set_layer_content_offset(layer,
+                                              tree_component,
+                                              container_component,
+                                              geometry_component,
+                                              scroll_component)
+            }
+        }
+    }
+
+    fn start_fling(&mut self,
+                   layer: LayerId,
+                   velocity: &Vector2D<f32>,
+                   promise: &Promise<Vector2D<f32>>,
+                   geometry_component: &LayerMap<LayerGeometryInfo>,
+                   scroll_component: &LayerMap<LayerScrollInfo>)
+                   -> Result<(), ()> {
+        match *self {
+            Backend::A(ref mut this) => {
+                this.start_fling(layer, velocity, promise, geometry_component, scroll_component)
+            }
+            Backend::B(ref mut this) => {
+                this.start_fling(layer, velocity, promise, geometry_component, scroll_component)
+            }
+        }
+    }
+
+    // Video
+
+    fn add_video_layer(&mut self, new_layer: LayerId) {
+        match *self {
+            Backend::A(ref mut this) => this.add_video_layer(new_layer),
+            Backend::B(ref mut this) => this.add_video_layer(new_layer),
+        }
+    }
+
+    fn present_video_frame(&mut self,
+                           layer: LayerId,
+                           frame: &VideoFrame,
+                           tree_component: &LayerMap<LayerTreeInfo>,
+                           geometry_component: &LayerMap<LayerGeometryInfo>)
+                           -> Result<(), ()> {
+        match *self {
+            Backend::A(ref mut this) => {
+                this.present_video_frame(layer, frame, tree_component, geometry_component)
+            }
+            Backend::B(ref mut this) => {
+                this.present_video_frame(layer, frame, tree_component, geometry_component)
+            }
+        }
+    }
+
     // Screenshots
 
     fn screenshot_hosted_layer(&mut self,
@@ -291,7 +458,7 @@ impl<A, B> crate::Backend for Backend<A, B> where A: crate::Backend, B: crate::B
                                container_component: &LayerMap<LayerContainerInfo>,
                                geometry_component: &LayerMap<LayerGeometryInfo>,
                                surface_component: &LayerMap<LayerSurfaceInfo>)
-                               -> Promise<RgbaImage> {
+                               -> Promise<RgbaImage, ScreenshotError> {
         match *self {
             Backend::A(ref mut this) => {
                 this.screenshot_hosted_layer(layer,
@@ -312,6 +479,37 @@ impl<A, B> crate::Backend for Backend<A, B> where A: crate::Backend, B: crate::B
         }
     }
 
+    // Event dispatch
+
+    fn pump_events(&mut self) -> Result<(), ()> {
+        match *self {
+            Backend::A(ref mut this) => this.pump_events(),
+            Backend::B(ref mut this) => this.pump_events(),
+        }
+    }
+
+    fn run_animation_frame(&mut self,
+                           tree_component: &LayerMap<LayerTreeInfo>,
+                           container_component: &LayerMap<LayerContainerInfo>,
+                           geometry_component: &LayerMap<LayerGeometryInfo>,
+                           surface_component: &LayerMap<LayerSurfaceInfo>)
+                           -> Result<(), ()> {
+        match *self {
+            Backend::A(ref mut this) => {
+                this.run_animation_frame(tree_component,
+                                         container_component,
+                                         geometry_component,
+                                         surface_component)
+            }
+            Backend::B(ref mut this) => {
+                this.run_animation_frame(tree_component,
+                                         container_component,
+                                         geometry_component,
+                                         surface_component)
+            }
+        }
+    }
+
     // OpenGL content binding
 
     fn bind_layer_to_gl_context(&mut self,
@@ -385,6 +583,57 @@ impl<A, B> crate::Backend for Backend<A, B> where A: crate::Backend, B: crate::B
             }
         }
     }
+
+    #[cfg(feature = "enable-winit")]
+    fn create_window(&mut self, window_builder: WindowBuilder, event_loop: &EventsLoop)
+                     -> Result<WindowId, ()> {
+        match *self {
+            Backend::A(ref mut this) => this.create_window(window_builder, event_loop),
+            Backend::B(ref mut this) => this.create_window(window_builder, event_loop),
+        }
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn adopt_window(&mut self, window: Window) -> Result<WindowId, Window> {
+        match *self {
+            Backend::A(ref mut this) => this.adopt_window(window),
+            Backend::B(ref mut this) => this.adopt_window(window),
+        }
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn window_with_id(&self, window_id: WindowId) -> Option<&Window> {
+        match *self {
+            Backend::A(ref this) => this.window_with_id(window_id),
+            Backend::B(ref this) => this.window_with_id(window_id),
+        }
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn host_layer_in_window_with_id(&mut self,
+                                    layer: LayerId,
+                                    window_id: WindowId,
+                                    tree_component: &LayerMap<LayerTreeInfo>,
+                                    container_component: &LayerMap<LayerContainerInfo>,
+                                    geometry_component: &LayerMap<LayerGeometryInfo>)
+                                    -> Result<(), ()> {
+        match *self {
+            Backend::A(ref mut this) => {
+                this.host_layer_in_window_with_id(layer,
+                                                  window_id,
+                                                  tree_component,
+                                                  container_component,
+                                                  geometry_component)
+            }
+            Backend::B(ref mut this) => {
+                this.host_layer_in_window_with_id(layer,
+                                                  window_id,
+                                                  tree_component,
+                                                  container_component,
+                                                  geometry_component)
+            }
+        }
+    }
 }
 
 pub enum NativeConnection<A, B> where A: crate::Backend, B: crate::Backend {
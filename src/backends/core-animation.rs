@@ -20,42 +20,57 @@ use core_foundation::base::TCFType;
 use core_foundation::bundle::CFBundle;
 use core_foundation::dictionary::CFDictionary;
 use core_foundation::number::CFNumber;
+use core_foundation::runloop::{CFRunLoop, kCFRunLoopDefaultMode};
 use core_foundation::string::CFString;
 use core_graphics::base::CGFloat;
 use core_graphics::geometry::{CG_ZERO_POINT, CGPoint, CGRect, CGSize};
 use core_graphics::window::{self, CGWindowID, kCGWindowImageBestResolution};
 use core_graphics::window::{kCGWindowImageBoundsIgnoreFraming, kCGWindowListOptionAll};
-use euclid::{Rect, Size2D};
+use euclid::{Rect, Size2D, Vector2D};
 use gl::types::{GLint, GLuint};
 use gl;
 use image::RgbaImage;
 use io_surface::IOSurface;
 use std::ptr;
 use std::sync::Mutex;
+use std::time::Duration;
 
 #[cfg(feature = "enable-winit")]
-use winit::Window;
+use winit::{EventsLoop, Window, WindowBuilder, WindowId};
 #[cfg(feature = "enable-winit")]
 use winit::os::macos::WindowExt;
 
 use crate::{Connection, ConnectionError, GLAPI, GLContextLayerBinding, LayerContainerInfo};
 use crate::{LayerGeometryInfo, LayerId, LayerMap, LayerParent, LayerSurfaceInfo, LayerTreeInfo};
-use crate::{Promise, SurfaceOptions};
+use crate::{Capabilities, ColorFormat, DepthFormat, ExportToken, Promise, ScreenshotError};
+use crate::{LayerEffects, LayerEffectsInfo, LayerScrollInfo, SurfaceOptions, VideoFrame};
 
 #[allow(non_upper_case_globals)]
 const kCGLOGLPVersion_3_2_Core: CGLPixelFormatAttribute = 0x3200;
 
 static OPENGL_FRAMEWORK_IDENTIFIER: &'static str = "com.apple.opengl";
 
+// The prefix of the tokens that name `CAContext`s, which are followed by the context ID.
+static EXPORT_TOKEN_PREFIX: &'static str = "ca:";
+
+#[link(name = "CoreGraphics", kind = "framework")]
+extern "C" {
+    fn CGSMainConnectionID() -> u32;
+}
+
 lazy_static! {
     static ref CREATE_CONTEXT_MUTEX: Mutex<()> = Mutex::new(());
 }
 
 pub struct Backend {
     native_component: LayerMap<NativeInfo>,
+    scroll_component: LayerMap<Vector2D<f32>>,
+    max_texture_size: Option<u32>,
 
     #[cfg(feature = "winit")]
     window: Option<Window>,
+    #[cfg(feature = "enable-winit")]
+    additional_windows: Vec<Window>,
 }
 
 impl crate::Backend for Backend {
@@ -71,13 +86,26 @@ impl crate::Backend for Backend {
 
         Ok(Backend {
             native_component: LayerMap::new(),
+            scroll_component: LayerMap::new(),
+            max_texture_size: None,
 
             window: connection.into_window(),
+            #[cfg(feature = "enable-winit")]
+            additional_windows: vec![],
         })
     }
 
     // TODO(pcwalton): Options.
-    fn create_gl_context(&mut self, _: SurfaceOptions) -> Result<GLContext, ()> {
+    fn create_gl_context(&mut self, options: SurfaceOptions) -> Result<GLContext, ()> {
+        // TODO(pcwalton): Support wide color via `CAOpenGLLayer` pixel formats.
+        match (options.color_format()?, options.depth_format()?) {
+            (ColorFormat::Rgba8, None) | (ColorFormat::Rgba8, Some(DepthFormat::Depth24)) => {}
+            _ => return Err(()),
+        }
+        if options.sample_count()? != 1 {
+            return Err(())
+        }
+
         // Multiple threads can't open a display connection simultaneously, so take a lock here.
         let _lock = CREATE_CONTEXT_MUTEX.lock().unwrap();
         let mut attributes = [kCGLPFAOpenGLProfile, kCGLOGLPVersion_3_2_Core, 0, 0];
@@ -111,6 +139,26 @@ impl crate::Backend for Backend {
         GLAPI::GL
     }
 
+    fn capabilities(&self) -> Capabilities {
+        // TODO(pcwalton): Wide color formats and multisampling. See `create_gl_context()`.
+        Capabilities {
+            screenshots: true,
+            damage_aware_present: false,
+            transforms: true,
+            native_animations: true,
+            surface_options: SurfaceOptions::OPAQUE | SurfaceOptions::DEPTH |
+                SurfaceOptions::STENCIL,
+            max_texture_size: self.max_texture_size,
+            layer_export: true,
+            video_layers: false,
+            layer_effects: false,
+            backdrop_blur: false,
+            blend_modes: false,
+            layer_masks: false,
+            scroll_clipping: true,
+        }
+    }
+
     fn begin_transaction(&self) {
         transaction::begin();
 
@@ -138,8 +186,10 @@ impl crate::Backend for Backend {
 
         self.native_component.add(new_layer, NativeInfo {
             host: nil,
+            remote_context: nil,
             core_animation_layer: layer,
             surface: None,
+            imported: false,
         });
     }
 
@@ -147,8 +197,15 @@ impl crate::Backend for Backend {
         self.add_container_layer(new_layer);
     }
 
+    fn add_scroll_layer(&mut self, new_layer: LayerId) {
+        self.add_container_layer(new_layer);
+        self.native_component[new_layer].core_animation_layer.set_masks_to_bounds(true);
+        self.scroll_component.add(new_layer, Vector2D::zero());
+    }
+
     fn delete_layer(&mut self, layer: LayerId) {
         self.native_component.remove_if_present(layer);
+        self.scroll_component.remove_if_present(layer);
     }
 
     fn insert_before(&mut self,
@@ -207,6 +264,17 @@ impl crate::Backend for Backend {
 
     fn unhost_layer(&mut self, layer: LayerId) {
         let native_component = &mut self.native_component[layer];
+
+        // Exported layers are hosted by a remote context instead of a view.
+        if native_component.remote_context != nil {
+            unsafe {
+                msg_send![native_component.remote_context, setLayer:nil];
+                msg_send![native_component.remote_context, release];
+            }
+            native_component.remote_context = nil;
+            return
+        }
+
         debug_assert_ne!(native_component.host, nil);
 
         unsafe {
@@ -218,6 +286,74 @@ impl crate::Backend for Backend {
         native_component.host = nil;
     }
 
+    // Exported layers are hosted by a `CAContext` that the window server can display in other
+    // processes, which embed it with a `CALayerHost`. This is what Safari and Chrome do to
+    // display their out-of-process content.
+    fn export_layer(&mut self,
+                    layer: LayerId,
+                    tree_component: &LayerMap<LayerTreeInfo>,
+                    container_component: &LayerMap<LayerContainerInfo>,
+                    geometry_component: &LayerMap<LayerGeometryInfo>)
+                    -> Result<ExportToken, ()> {
+        let context_id: u32;
+        {
+            let native_component = &mut self.native_component[layer];
+            debug_assert_eq!(native_component.host, nil);
+            debug_assert_eq!(native_component.remote_context, nil);
+
+            unsafe {
+                let options: id = msg_send![class!(NSDictionary), dictionary];
+                let context: id = msg_send![class!(CAContext),
+                                            contextWithCGSConnection:CGSMainConnectionID()
+                                            options:options];
+                if context == nil {
+                    return Err(())
+                }
+                msg_send![context, retain];
+                msg_send![context, setLayer:native_component.core_animation_layer.id()];
+                context_id = msg_send![context, contextId];
+                native_component.remote_context = context;
+            }
+        }
+
+        self.update_layer_subtree_bounds(layer,
+                                         tree_component,
+                                         container_component,
+                                         geometry_component);
+
+        let token = format!("{}{}", EXPORT_TOKEN_PREFIX, context_id);
+        Ok(ExportToken::from_bytes(token.into_bytes()))
+    }
+
+    fn import_layer(&mut self, new_layer: LayerId, token: &ExportToken) -> Result<(), ()> {
+        let token = std::str::from_utf8(token.as_bytes()).map_err(drop)?;
+        if !token.starts_with(EXPORT_TOKEN_PREFIX) {
+            return Err(())
+        }
+        let context_id: u32 = token[EXPORT_TOKEN_PREFIX.len()..].parse().map_err(drop)?;
+
+        let layer = unsafe {
+            let layer_host: id = msg_send![class!(CALayerHost), new];
+            msg_send![layer_host, setContextId:context_id];
+            CALayer::from_retained_id(layer_host)
+        };
+        layer.set_anchor_point(&CG_ZERO_POINT);
+
+        self.native_component.add(new_layer, NativeInfo {
+            host: nil,
+            remote_context: nil,
+            core_animation_layer: layer,
+            surface: None,
+            imported: true,
+        });
+        Ok(())
+    }
+
+    fn imported_layers_changed(&mut self) -> bool {
+        // The window server updates layer hosts by itself.
+        false
+    }
+
     fn set_layer_bounds(&mut self,
                         layer: LayerId,
                         _: &Rect<f32>,
@@ -238,6 +374,49 @@ impl crate::Backend for Backend {
         core_animation_layer.set_contents_opaque(opaque);
     }
 
+    // TODO(pcwalton): Use `shadowRadius`, `shadowOffset`, a Gaussian blur filter, and
+    // `compositingFilter` for blend modes.
+    fn set_layer_effects(&mut self,
+                         _: LayerId,
+                         _: &LayerEffects,
+                         _: &LayerMap<LayerTreeInfo>,
+                         _: &LayerMap<LayerGeometryInfo>,
+                         _: &LayerMap<LayerEffectsInfo>) {}
+
+    // TODO(pcwalton): Use `CALayer.mask`.
+    fn set_layer_mask(&mut self,
+                      _: LayerId,
+                      _: &LayerMap<LayerTreeInfo>,
+                      _: &LayerMap<LayerGeometryInfo>,
+                      _: &LayerMap<LayerEffectsInfo>) {}
+
+    // Scrolling
+
+    fn set_layer_content_size(&mut self, _: LayerId, _: &LayerMap<LayerScrollInfo>) {}
+
+    // The origin of a Core Animation layer's bounds is the point in its sublayers' coordinate
+    // space that appears at its top left, which is exactly a content offset.
+    fn set_layer_content_offset(&mut self,
+                                layer: LayerId,
+                                tree_component: &LayerMap<LayerTreeInfo>,
+                                _: &LayerMap<LayerContainerInfo>,
+                                geometry_component: &LayerMap<LayerGeometryInfo>,
+                                scroll_component: &LayerMap<LayerScrollInfo>) {
+        self.scroll_component[layer] = scroll_component[layer].content_offset;
+        self.update_layer_bounds(layer, tree_component, geometry_component);
+    }
+
+    fn start_fling(&mut self,
+                   _: LayerId,
+                   _: &Vector2D<f32>,
+                   _: &Promise<Vector2D<f32>>,
+                   _: &LayerMap<LayerGeometryInfo>,
+                   _: &LayerMap<LayerScrollInfo>)
+                   -> Result<(), ()> {
+        // TODO(pcwalton): Animate the sublayer positions with a Core Animation animation.
+        Err(())
+    }
+
     // TODO(pcwalton): Support depth and stencil!
     fn bind_layer_to_gl_context(&mut self,
                                 layer: LayerId,
@@ -246,12 +425,23 @@ impl crate::Backend for Backend {
                                 _: &LayerMap<LayerSurfaceInfo>)
                                 -> Result<GLContextLayerBinding, ()> {
         let native_component = &mut self.native_component[layer];
+        if native_component.imported {
+            // Only the exporting process can draw into an imported layer.
+            return Err(())
+        }
+
         let layer_size = geometry_component[layer].bounds.size.round().to_u32();
         unsafe {
             if CGLSetCurrentContext(context.cgl_context) != kCGLNoError {
                 return Err(())
             }
 
+            if self.max_texture_size.is_none() {
+                let mut max_texture_size = 0;
+                gl::GetIntegerv(gl::MAX_TEXTURE_SIZE, &mut max_texture_size);
+                self.max_texture_size = Some(max_texture_size as u32);
+            }
+
             // FIXME(pcwalton): Verify that GL objects belong to the right context!
             if native_component.surface.is_none() ||
                     native_component.surface.as_ref().unwrap().size != layer_size {
@@ -300,6 +490,23 @@ impl crate::Backend for Backend {
         Ok(())
     }
 
+    // Video
+
+    // TODO(pcwalton): Set YUV `IOSurface`s as the contents of video layers, which Core
+    // Animation can promote to hardware overlays.
+    fn add_video_layer(&mut self, layer: LayerId) {
+        self.add_surface_layer(layer)
+    }
+
+    fn present_video_frame(&mut self,
+                           _: LayerId,
+                           _: &VideoFrame,
+                           _: &LayerMap<LayerTreeInfo>,
+                           _: &LayerMap<LayerGeometryInfo>)
+                           -> Result<(), ()> {
+        Err(())
+    }
+
     // Screenshots
 
     fn screenshot_hosted_layer(&mut self,
@@ -309,7 +516,7 @@ impl crate::Backend for Backend {
                                _: &LayerMap<LayerContainerInfo>,
                                _: &LayerMap<LayerGeometryInfo>,
                                _: &LayerMap<LayerSurfaceInfo>)
-                               -> Promise<RgbaImage> {
+                               -> Promise<RgbaImage, ScreenshotError> {
         let result_promise = Promise::new();
         let result_promise_to_return = result_promise.clone();
 
@@ -338,22 +545,43 @@ impl crate::Backend for Backend {
                                               &CGSize::new(view_frame.size.width,
                                                            view_frame.size.height));
 
-                image = window::create_image(screen_rect,
-                                             kCGWindowListOptionAll,
-                                             window_id,
-                                             kCGWindowImageBoundsIgnoreFraming |
-                                             kCGWindowImageBestResolution).unwrap();
+                image = match window::create_image(screen_rect,
+                                                   kCGWindowListOptionAll,
+                                                   window_id,
+                                                   kCGWindowImageBoundsIgnoreFraming |
+                                                   kCGWindowImageBestResolution) {
+                    Some(image) => image,
+                    None => return result_promise.reject(ScreenshotError::ReadbackFailed),
+                };
             }
 
             let (width, height) = (image.width() as u32, image.height() as u32);
             let mut data = image.data().bytes().to_vec();
             data.chunks_mut(4).for_each(|pixel| pixel.swap(0, 2));
-            result_promise.resolve(RgbaImage::from_vec(width, height, data).unwrap());
+            match RgbaImage::from_vec(width, height, data) {
+                Some(image) => result_promise.resolve(image),
+                None => result_promise.reject(ScreenshotError::ReadbackFailed),
+            }
+        }));
+
+        let rejected_promise = result_promise_to_return.clone();
+        transaction_promise.or_else(Box::new(move |()| {
+            rejected_promise.reject(ScreenshotError::TransactionFailed)
         }));
 
         result_promise_to_return
     }
 
+    // Event dispatch
+
+    fn pump_events(&mut self) -> Result<(), ()> {
+        // Transaction completion blocks are delivered via the main run loop.
+        unsafe {
+            CFRunLoop::run_in_mode(kCFRunLoopDefaultMode, Duration::from_millis(10), true);
+        }
+        Ok(())
+    }
+
     // `winit` integration
 
     #[cfg(feature = "enable-winit")]
@@ -377,6 +605,47 @@ impl crate::Backend for Backend {
             Ok(())
         }
     }
+
+    #[cfg(feature = "enable-winit")]
+    fn create_window(&mut self, window_builder: WindowBuilder, event_loop: &EventsLoop)
+                     -> Result<WindowId, ()> {
+        match window_builder.build(event_loop) {
+            Err(_) => Err(()),
+            Ok(window) => self.adopt_window(window).map_err(|_| ()),
+        }
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn adopt_window(&mut self, window: Window) -> Result<WindowId, Window> {
+        let window_id = window.id();
+        self.additional_windows.push(window);
+        Ok(window_id)
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn window_with_id(&self, window_id: WindowId) -> Option<&Window> {
+        self.window.iter().chain(self.additional_windows.iter()).find(|window| {
+            window.id() == window_id
+        })
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn host_layer_in_window_with_id(&mut self,
+                                    layer: LayerId,
+                                    window_id: WindowId,
+                                    tree_component: &LayerMap<LayerTreeInfo>,
+                                    container_component: &LayerMap<LayerContainerInfo>,
+                                    geometry_component: &LayerMap<LayerGeometryInfo>)
+                                    -> Result<(), ()> {
+        unsafe {
+            self.host_layer(layer,
+                            self.window_with_id(window_id).ok_or(())?.get_nsview() as id,
+                            tree_component,
+                            container_component,
+                            geometry_component);
+            Ok(())
+        }
+    }
 }
 
 impl Backend {
@@ -390,6 +659,8 @@ impl Backend {
             Some(LayerTreeInfo { parent: LayerParent::NativeHost, .. }) => {
                 Some(self.native_component[layer].host)
             }
+            // Masks aren't displayed.
+            Some(LayerTreeInfo { parent: LayerParent::Mask(_), .. }) => None,
         }
     }
 
@@ -409,8 +680,21 @@ impl Backend {
             msg_send![hosting_view, convertRectFromBacking:new_appkit_bounds]
         };
 
+        let content_offset = match self.scroll_component.get(layer) {
+            None => CG_ZERO_POINT,
+            Some(content_offset) => {
+                let content_offset = content_offset.to_f64();
+                let content_offset = NSRect::new(NSPoint::new(content_offset.x, content_offset.y),
+                                                 NSSize::new(0.0, 0.0));
+                let content_offset: NSRect = unsafe {
+                    msg_send![hosting_view, convertRectFromBacking:content_offset]
+                };
+                CGPoint::new(content_offset.origin.x, content_offset.origin.y)
+            }
+        };
+
         let new_core_animation_bounds =
-            CGRect::new(&CG_ZERO_POINT,
+            CGRect::new(&content_offset,
                         &CGSize::new(new_appkit_bounds.size.width, new_appkit_bounds.size.height));
 
         let core_animation_layer = &self.native_component[layer].core_animation_layer;
@@ -479,8 +763,12 @@ impl Drop for GLContext {
 
 struct NativeInfo {
     host: id,
+    // The `CAContext` that hosts this layer, if it's exported.
+    remote_context: id,
     core_animation_layer: CALayer,
     surface: Option<Surface>,
+    // Whether the layer is a `CALayerHost` that displays a layer from another process.
+    imported: bool,
 }
 
 pub type LayerNativeHost = id;
@@ -489,8 +777,10 @@ impl Default for NativeInfo {
     fn default() -> NativeInfo {
         NativeInfo {
             host: nil,
+            remote_context: nil,
             core_animation_layer: CALayer::new(),
             surface: None,
+            imported: false,
         }
     }
 }
@@ -502,6 +792,10 @@ impl Drop for NativeInfo {
                 msg_send![self.host, release];
                 self.host = nil;
             }
+            if self.remote_context != nil {
+                msg_send![self.remote_context, release];
+                self.remote_context = nil;
+            }
         }
     }
 }
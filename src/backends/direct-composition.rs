@@ -8,7 +8,7 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use euclid::Rect;
+use euclid::{Rect, Vector2D};
 use image::{ConvertBuffer, RgbaImage};
 use mozangle::egl::ffi::types::{EGLClientBuffer, EGLConfig, EGLContext, EGLDisplay, EGLSurface};
 use mozangle::egl::ffi::{D3D11_DEVICE_ANGLE, EGLDeviceEXT};
@@ -45,17 +45,22 @@ use winapi::um::wingdi::BITMAPINFOHEADER;
 use winapi::um::winuser::{self, INPUT, KEYBDINPUT, MSG, WNDCLASSEXA};
 
 #[cfg(feature = "enable-winit")]
-use winit::Window;
+use winit::{EventsLoop, Window, WindowBuilder, WindowId};
 #[cfg(all(feature = "enable-winit", target_family = "windows"))]
 use winit::os::windows::WindowExt;
 
 use crate::{Connection, ConnectionError, GLAPI, GLContextLayerBinding, LayerContainerInfo};
 use crate::{LayerGeometryInfo, LayerId, LayerMap, LayerSurfaceInfo, LayerTreeInfo, Promise};
-use crate::{SurfaceOptions};
+use crate::{Capabilities, ColorFormat, DepthFormat, ExportToken, ScreenshotError};
+use crate::{LayerEffects, LayerEffectsInfo, LayerScrollInfo, SurfaceOptions, VideoFrame};
 use self::com::ComPtr;
 
+// How long, in milliseconds, `pump_events` waits for messages while a screenshot is outstanding.
+const SCREENSHOT_POLL_INTERVAL: DWORD = 10;
+
 pub struct Backend {
     native_component: LayerMap<NativeInfo>,
+    scroll_component: LayerMap<Vector2D<f32>>,
 
     d3d_device: ComPtr<ID3D11Device>,
     dcomp_device: ComPtr<IDCompositionDevice>,
@@ -63,11 +68,16 @@ pub struct Backend {
 
     egl_device: EGLDeviceEXT,
     egl_display: EGLDisplay,
+    max_texture_size: Option<u32>,
 
     screenshot_window: Option<HWND>,
+    // Screenshots that the screenshot thread may still settle.
+    pending_screenshots: Vec<Promise<RgbaImage, ScreenshotError>>,
 
     #[cfg(feature = "enable-winit")]
     window: Option<Window>,
+    #[cfg(feature = "enable-winit")]
+    additional_windows: Vec<Window>,
 }
 
 impl crate::Backend for Backend {
@@ -132,6 +142,7 @@ impl crate::Backend for Backend {
 
             Ok(Backend {
                 native_component: LayerMap::new(),
+                scroll_component: LayerMap::new(),
 
                 d3d_device,
                 dcomp_device,
@@ -139,20 +150,39 @@ impl crate::Backend for Backend {
 
                 egl_device,
                 egl_display,
+                max_texture_size: None,
 
                 screenshot_window: None,
+                pending_screenshots: vec![],
 
                 #[cfg(feature = "enable-winit")]
                 window,
+                #[cfg(feature = "enable-winit")]
+                additional_windows: vec![],
             })
         }
     }
 
     fn create_gl_context(&mut self, options: SurfaceOptions) -> Result<GLContext, ()> {
+        // TODO(pcwalton): Support wide color formats via DXGI swap chain formats.
+        match options.color_format()? {
+            ColorFormat::Rgba8 => {}
+            ColorFormat::Srgb8Alpha8 | ColorFormat::Rgb10A2 | ColorFormat::Rgba16F => {
+                return Err(())
+            }
+        }
+        let depth_size = match options.depth_format()? {
+            None => 0,
+            Some(DepthFormat::Depth16) => 16,
+            Some(DepthFormat::Depth24) => 24,
+            Some(DepthFormat::Depth32F) => return Err(()),
+        };
+        let sample_count = options.sample_count()? as i32;
+        let sample_buffers = if sample_count > 1 { 1 } else { 0 };
+
         unsafe {
             // Enumerate the EGL pixel configurations for ANGLE.
             let (mut configs, mut num_configs) = ([ptr::null(); 64], 0);
-            let depth_size = if options.contains(SurfaceOptions::DEPTH) { 16 } else { 0 };
             let stencil_size = if options.contains(SurfaceOptions::STENCIL) { 8 } else { 0 };
             let attributes = [
                 egl::ffi::SURFACE_TYPE as i32,      egl::ffi::WINDOW_BIT as i32,
@@ -163,6 +193,8 @@ impl crate::Backend for Backend {
                 egl::ffi::ALPHA_SIZE as i32,        8,
                 egl::ffi::DEPTH_SIZE as i32,        depth_size,
                 egl::ffi::STENCIL_SIZE as i32,      stencil_size,
+                egl::ffi::SAMPLE_BUFFERS as i32,    sample_buffers,
+                egl::ffi::SAMPLES as i32,           if sample_count > 1 { sample_count } else { 0 },
                 egl::ffi::NONE as i32,              egl::ffi::NONE as i32,
             ];
             let result = egl::ffi::ChooseConfig(self.egl_display,
@@ -230,6 +262,27 @@ impl crate::Backend for Backend {
         GLAPI::GLES
     }
 
+    fn capabilities(&self) -> Capabilities {
+        // TODO(pcwalton): Wide color formats and 32-bit float depth. See `create_gl_context()`.
+        Capabilities {
+            screenshots: true,
+            damage_aware_present: false,
+            transforms: true,
+            native_animations: true,
+            surface_options: SurfaceOptions::OPAQUE | SurfaceOptions::DEPTH |
+                SurfaceOptions::STENCIL | SurfaceOptions::DEPTH16 | SurfaceOptions::MSAA_2X |
+                SurfaceOptions::MSAA_4X | SurfaceOptions::MSAA_8X | SurfaceOptions::MSAA_16X,
+            max_texture_size: self.max_texture_size,
+            layer_export: false,
+            video_layers: false,
+            layer_effects: false,
+            backdrop_blur: false,
+            blend_modes: false,
+            layer_masks: false,
+            scroll_clipping: false,
+        }
+    }
+
     fn begin_transaction(&self) {}
 
     fn end_transaction(&mut self,
@@ -265,8 +318,14 @@ impl crate::Backend for Backend {
         self.add_container_layer(new_layer);
     }
 
+    fn add_scroll_layer(&mut self, new_layer: LayerId) {
+        self.add_container_layer(new_layer);
+        self.scroll_component.add(new_layer, Vector2D::zero());
+    }
+
     fn delete_layer(&mut self, layer: LayerId) {
         self.native_component.remove_if_present(layer);
+        self.scroll_component.remove_if_present(layer);
     }
 
     fn insert_before(&mut self,
@@ -329,26 +388,81 @@ impl crate::Backend for Backend {
         self.native_component[layer].target = None;
     }
 
+    // TODO(pcwalton): DirectComposition can't share visual trees between processes, only
+    // surfaces, via `DCompositionCreateSurfaceHandle()`. Exporting a subtree would mean
+    // flattening it into one shared surface.
+    fn export_layer(&mut self,
+                    _: LayerId,
+                    _: &LayerMap<LayerTreeInfo>,
+                    _: &LayerMap<LayerContainerInfo>,
+                    _: &LayerMap<LayerGeometryInfo>)
+                    -> Result<ExportToken, ()> {
+        Err(())
+    }
+
+    fn import_layer(&mut self, _: LayerId, _: &ExportToken) -> Result<(), ()> {
+        Err(())
+    }
+
+    fn imported_layers_changed(&mut self) -> bool {
+        false
+    }
+
     fn set_layer_bounds(&mut self,
                         layer: LayerId,
                         _: &Rect<f32>,
                         _: &LayerMap<LayerTreeInfo>,
                         _: &LayerMap<LayerContainerInfo>,
                         geometry_component: &LayerMap<LayerGeometryInfo>) {
-        unsafe {
-            let new_bounds = match geometry_component.get(layer) {
-                None => return,
-                Some(geometry_component) => geometry_component.bounds,
-            };
-
-            let visual = &self.native_component[layer].visual;
-            (***visual).SetOffsetX_1(new_bounds.origin.x);
-            (***visual).SetOffsetY_1(new_bounds.origin.y);
-        }
+        self.update_visual_offset(layer, geometry_component)
     }
 
     fn set_layer_surface_options(&mut self, _: LayerId, _: &LayerMap<LayerSurfaceInfo>) {}
 
+    // TODO(pcwalton): Use `IDCompositionShadowEffect`, `IDCompositionGaussianBlurEffect`, and
+    // `IDCompositionBlendEffect`.
+    fn set_layer_effects(&mut self,
+                         _: LayerId,
+                         _: &LayerEffects,
+                         _: &LayerMap<LayerTreeInfo>,
+                         _: &LayerMap<LayerGeometryInfo>,
+                         _: &LayerMap<LayerEffectsInfo>) {}
+
+    // TODO(pcwalton): Render the mask into a surface and use it as the opacity mask of an
+    // `IDCompositionEffectGroup`.
+    fn set_layer_mask(&mut self,
+                      _: LayerId,
+                      _: &LayerMap<LayerTreeInfo>,
+                      _: &LayerMap<LayerGeometryInfo>,
+                      _: &LayerMap<LayerEffectsInfo>) {}
+
+    // Scrolling
+
+    fn set_layer_content_size(&mut self, _: LayerId, _: &LayerMap<LayerScrollInfo>) {}
+
+    // TODO(pcwalton): Clip the children of scroll layers with `SetClip()`. The clip rect is in
+    // the visual's own coordinate space, so it will need to be offset by the content offset too.
+    fn set_layer_content_offset(&mut self,
+                                layer: LayerId,
+                                _: &LayerMap<LayerTreeInfo>,
+                                _: &LayerMap<LayerContainerInfo>,
+                                geometry_component: &LayerMap<LayerGeometryInfo>,
+                                scroll_component: &LayerMap<LayerScrollInfo>) {
+        self.scroll_component[layer] = scroll_component[layer].content_offset;
+        self.update_visual_offset(layer, geometry_component)
+    }
+
+    fn start_fling(&mut self,
+                   _: LayerId,
+                   _: &Vector2D<f32>,
+                   _: &Promise<Vector2D<f32>>,
+                   _: &LayerMap<LayerGeometryInfo>,
+                   _: &LayerMap<LayerScrollInfo>)
+                   -> Result<(), ()> {
+        // TODO(pcwalton): Animate the visual offset with a DirectComposition animation.
+        Err(())
+    }
+
     fn bind_layer_to_gl_context(&mut self,
                                 layer: LayerId,
                                 context: &mut GLContext,
@@ -433,6 +547,12 @@ impl crate::Backend for Backend {
                 return Err(())
             }
 
+            if self.max_texture_size.is_none() {
+                let mut max_texture_size = 0;
+                gl::GetIntegerv(gl::MAX_TEXTURE_SIZE, &mut max_texture_size);
+                self.max_texture_size = Some(max_texture_size as u32);
+            }
+
             Ok(GLContextLayerBinding {
                 layer,
                 framebuffer: 0,
@@ -457,6 +577,23 @@ impl crate::Backend for Backend {
         }
     }
 
+    // Video
+
+    // TODO(pcwalton): Give video layers YUV swap chains and present frames through the D3D11
+    // video processor, so that DirectComposition can promote them to hardware overlays.
+    fn add_video_layer(&mut self, layer: LayerId) {
+        self.add_surface_layer(layer)
+    }
+
+    fn present_video_frame(&mut self,
+                           _: LayerId,
+                           _: &VideoFrame,
+                           _: &LayerMap<LayerTreeInfo>,
+                           _: &LayerMap<LayerGeometryInfo>)
+                           -> Result<(), ()> {
+        Err(())
+    }
+
     // Screenshots
 
     fn screenshot_hosted_layer(&mut self,
@@ -466,15 +603,20 @@ impl crate::Backend for Backend {
                                _: &LayerMap<LayerContainerInfo>,
                                _: &LayerMap<LayerGeometryInfo>,
                                _: &LayerMap<LayerSurfaceInfo>)
-                               -> Promise<RgbaImage> {
+                               -> Promise<RgbaImage, ScreenshotError> {
         self.create_screenshot_window_if_necessary();
 
         let screenshot_window = self.screenshot_window.unwrap();
 
+        let result_promise = Promise::new();
+
         let window: HWND = self.native_component[layer].target.as_ref().unwrap().window;
         let mut window_rect = RECT { left: 0, right: 0, top: 0, bottom: 0, };
         unsafe {
-            assert_ne!(winuser::GetWindowRect(window, &mut window_rect), FALSE);
+            if winuser::GetWindowRect(window, &mut window_rect) == FALSE {
+                result_promise.reject(ScreenshotError::ReadbackFailed);
+                return result_promise
+            }
 
             // The rectangle returned by `GetWindowRect` includes window decorations. Remove them.
             let mut adjusted_rect = RECT { left: 0, right: 0, top: 0, bottom: 0, };
@@ -491,34 +633,52 @@ impl crate::Backend for Backend {
             }
         }
 
-        let result_promise = Promise::new();
         let request = RefCell::new(Some(Box::new(ScreenshotRequest {
             promise: result_promise.clone(),
             window_rect,
         })));
 
+        let rejected_promise = result_promise.clone();
+        transaction_promise.or_else(Box::new(move |()| {
+            rejected_promise.reject(ScreenshotError::TransactionFailed)
+        }));
+
         transaction_promise.then(Box::new(move |()| {
+            let request: Box<ScreenshotRequest> = match request.replace(None) {
+                None => return,
+                Some(request) => request,
+            };
+
             unsafe {
                 // Try to bring the window to the front.
                 if winuser::SetForegroundWindow(window) == FALSE {
                     // We failed to bring the window to the front. Maybe the foreground lock
                     // timeout hasn't expired yet. Let's wait and try again.
                     let mut foreground_lock_timeout = 0;
-                    assert_ne!(winuser::SystemParametersInfoA(
-                                    winuser::SPI_GETFOREGROUNDLOCKTIMEOUT,
-                                    0,
-                                    &mut foreground_lock_timeout as *mut _ as PVOID,
-                                    0),
-                               FALSE);
+                    if winuser::SystemParametersInfoA(
+                            winuser::SPI_GETFOREGROUNDLOCKTIMEOUT,
+                            0,
+                            &mut foreground_lock_timeout as *mut _ as PVOID,
+                            0) == FALSE {
+                        request.promise.reject(ScreenshotError::ReadbackFailed);
+                        return
+                    }
                     thread::sleep(Duration::from_millis(foreground_lock_timeout));
-                    assert_ne!(winuser::SetForegroundWindow(window), FALSE);
+                    if winuser::SetForegroundWindow(window) == FALSE {
+                        request.promise.reject(ScreenshotError::ReadbackFailed);
+                        return
+                    }
                 }
 
                 // Wake up our screenshot thread.
-                let request: Box<ScreenshotRequest> = request.replace(None).unwrap();
-                let request_addr = &*request as *const _ as WPARAM;
-                mem::forget(request);
-                winuser::PostMessageA(screenshot_window, winuser::WM_USER, request_addr, 0);
+                let promise = request.promise.clone();
+                let request_addr = Box::into_raw(request) as WPARAM;
+                if winuser::PostMessageA(screenshot_window, winuser::WM_USER, request_addr, 0) ==
+                        FALSE {
+                    drop(Box::from_raw(request_addr as *mut ScreenshotRequest));
+                    promise.reject(ScreenshotError::ReadbackFailed);
+                    return
+                }
 
                 // Send a Print Screen key to capture the desktop.
                 let mut inputs = [
@@ -540,16 +700,53 @@ impl crate::Backend for Backend {
                     dwExtraInfo: 0,
                 };
 
+                // If the key didn't go through, the screenshot thread will never hear back from
+                // the clipboard, so fail the screenshot now. The thread still owns the request,
+                // but settling its promise twice is harmless.
                 let events_sent = winuser::SendInput(inputs.len() as UINT,
                                                      inputs.as_mut_ptr(),
                                                      mem::size_of::<INPUT>() as _);
-                assert_eq!(events_sent, inputs.len() as UINT);
+                if events_sent != inputs.len() as UINT {
+                    promise.reject(ScreenshotError::ReadbackFailed);
+                }
             }
         }));
 
+        self.pending_screenshots.push(result_promise.clone());
         result_promise
     }
 
+    // Event dispatch
+
+    fn pump_events(&mut self) -> Result<(), ()> {
+        // Screenshots are settled on the screenshot thread, so all we need to do here is dispatch
+        // any messages pending for this thread's windows.
+        unsafe {
+            let mut msg: MSG = mem::zeroed();
+            while winuser::PeekMessageA(&mut msg, ptr::null_mut(), 0, 0, winuser::PM_REMOVE) !=
+                    FALSE {
+                winuser::TranslateMessage(&mut msg);
+                winuser::DispatchMessageA(&mut msg);
+            }
+        }
+
+        // Everything else is settled synchronously, so there's only something to wait for while
+        // a screenshot is outstanding. Sleep until a message arrives or the screenshot thread has
+        // had time to make progress.
+        self.pending_screenshots.retain(|promise| promise.result().is_none());
+        if self.pending_screenshots.is_empty() {
+            return Err(())
+        }
+        unsafe {
+            winuser::MsgWaitForMultipleObjects(0,
+                                               ptr::null(),
+                                               FALSE,
+                                               SCREENSHOT_POLL_INTERVAL,
+                                               winuser::QS_ALLINPUT);
+        }
+        Ok(())
+    }
+
     // `winit` integration
 
     #[cfg(feature = "enable-winit")]
@@ -573,9 +770,70 @@ impl crate::Backend for Backend {
             Ok(())
         }
     }
+
+    #[cfg(feature = "enable-winit")]
+    fn create_window(&mut self, window_builder: WindowBuilder, event_loop: &EventsLoop)
+                     -> Result<WindowId, ()> {
+        match window_builder.build(event_loop) {
+            Err(_) => Err(()),
+            Ok(window) => self.adopt_window(window).map_err(|_| ()),
+        }
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn adopt_window(&mut self, window: Window) -> Result<WindowId, Window> {
+        let window_id = window.id();
+        self.additional_windows.push(window);
+        Ok(window_id)
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn window_with_id(&self, window_id: WindowId) -> Option<&Window> {
+        self.window.iter().chain(self.additional_windows.iter()).find(|window| {
+            window.id() == window_id
+        })
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn host_layer_in_window_with_id(&mut self,
+                                    layer: LayerId,
+                                    window_id: WindowId,
+                                    tree_component: &LayerMap<LayerTreeInfo>,
+                                    container_component: &LayerMap<LayerContainerInfo>,
+                                    geometry_component: &LayerMap<LayerGeometryInfo>)
+                                    -> Result<(), ()> {
+        unsafe {
+            self.host_layer(layer,
+                            self.window_with_id(window_id).ok_or(())?.get_hwnd() as HWND,
+                            tree_component,
+                            container_component,
+                            geometry_component);
+            Ok(())
+        }
+    }
 }
 
 impl Backend {
+    // Scroll layers draw no content of their own, so we can scroll their children by moving the
+    // scroll layer's visual itself.
+    fn update_visual_offset(&mut self,
+                            layer: LayerId,
+                            geometry_component: &LayerMap<LayerGeometryInfo>) {
+        unsafe {
+            let mut new_origin = match geometry_component.get(layer) {
+                None => return,
+                Some(geometry_component) => geometry_component.bounds.origin,
+            };
+            if let Some(content_offset) = self.scroll_component.get(layer) {
+                new_origin -= *content_offset;
+            }
+
+            let visual = &self.native_component[layer].visual;
+            (***visual).SetOffsetX_1(new_origin.x);
+            (***visual).SetOffsetY_1(new_origin.y);
+        }
+    }
+
     fn create_screenshot_window_if_necessary(&mut self) {
         if self.screenshot_window.is_some() {
             return
@@ -642,7 +900,7 @@ struct NativeWindow(HWND);
 unsafe impl Send for NativeWindow {}
 
 struct ScreenshotRequest {
-    promise: Promise<RgbaImage>,
+    promise: Promise<RgbaImage, ScreenshotError>,
     window_rect: RECT,
 }
 
@@ -749,67 +1007,99 @@ unsafe extern "system" fn screenshot_window_proc(window: HWND,
         }
 
         winuser::WM_CLIPBOARDUPDATE => {
-            let promise = winuser::GetWindowLongPtrA(window, winuser::GWLP_USERDATA) as
+            let request = winuser::GetWindowLongPtrA(window, winuser::GWLP_USERDATA) as
                 *mut ScreenshotRequest;
-            if promise.is_null() {
+            if request.is_null() {
                 return winuser::DefWindowProcA(window, msg, wparam, lparam);
             }
 
-            let request: Box<ScreenshotRequest> = mem::transmute(promise);
+            // Keep waiting if this update wasn't our screenshot.
+            let image = match read_screenshot_from_clipboard(&(*request).window_rect) {
+                Ok(None) => return winuser::DefWindowProcA(window, msg, wparam, lparam),
+                Ok(Some(image)) => Ok(image),
+                Err(()) => Err(ScreenshotError::ReadbackFailed),
+            };
+
+            let request: Box<ScreenshotRequest> = Box::from_raw(request);
             winuser::SetWindowLongPtrA(window, winuser::GWLP_USERDATA, 0);
+            match image {
+                Ok(image) => request.promise.resolve(image),
+                Err(error) => request.promise.reject(error),
+            }
+            0
+        }
 
-            assert_ne!(winuser::OpenClipboard(ptr::null_mut()), FALSE);
+        _ => winuser::DefWindowProcA(window, msg, wparam, lparam),
+    }
+}
 
-            // Screenshot data should have no owner. Verify that.
-            //
-            // FIXME(pcwalton): This is still fragile, because other apps can also place ownerless
-            // data on the clipboard, so we might think we have screenshot data when it's actually
-            // some other app placing stuff on the clipboard. But this is better than nothing.
-            let owner = winuser::GetClipboardOwner();
-            if !owner.is_null() {
-                return winuser::DefWindowProcA(window, msg, wparam, lparam);
-            }
+// Returns the part of the screenshot on the clipboard inside the given rect, or `None` if the
+// clipboard holds something other than a screenshot.
+unsafe fn read_screenshot_from_clipboard(rect: &RECT) -> Result<Option<RgbaImage>, ()> {
+    if winuser::OpenClipboard(ptr::null_mut()) == FALSE {
+        return Err(())
+    }
+    let result = read_screenshot_from_open_clipboard(rect);
+    winuser::CloseClipboard();
+    result
+}
 
-            let mut clipboard = winuser::GetClipboardData(winuser::CF_DIB);
-            if clipboard == handleapi::INVALID_HANDLE_VALUE {
-                clipboard = winuser::GetClipboardData(winuser::CF_DIBV5);
-            }
-            if clipboard == handleapi::INVALID_HANDLE_VALUE {
-                return winuser::DefWindowProcA(window, msg, wparam, lparam);
-            }
+unsafe fn read_screenshot_from_open_clipboard(rect: &RECT) -> Result<Option<RgbaImage>, ()> {
+    // Screenshot data should have no owner. Verify that.
+    //
+    // FIXME(pcwalton): This is still fragile, because other apps can also place ownerless data on
+    // the clipboard, so we might think we have screenshot data when it's actually some other app
+    // placing stuff on the clipboard. But this is better than nothing.
+    let owner = winuser::GetClipboardOwner();
+    if !owner.is_null() {
+        return Ok(None)
+    }
 
-            let dib = winbase::GlobalLock(clipboard) as *mut BITMAPINFOHEADER;
-            assert!(!dib.is_null());
-
-            // Bitmap data is bottom-to-top, BGRA. Change to top-to-bottom, RGBA.
-            let src_data = slice::from_raw_parts(dib.offset(1) as *const u32,
-                                                 ((*dib).biSizeImage / 4) as usize);
-            let mut dest_data = Vec::with_capacity(src_data.len() * 4);
-            let screen_width = (*dib).biWidth as usize;
-            let screen_height = (*dib).biHeight as usize;
-            let rect = request.window_rect;
-            for y in (rect.top as usize)..(rect.bottom as usize) {
-                for x in (rect.left as usize)..(rect.right as usize) {
-                    let src_pixel = src_data[(screen_height - y - 1) * screen_width + x];
-                    dest_data.extend_from_slice(&[
-                        ((src_pixel >> 16) & 0xff) as u8,
-                        ((src_pixel >> 8)  & 0xff) as u8,
-                        ((src_pixel >> 0)  & 0xff) as u8,
-                        ((src_pixel >> 24) & 0xff) as u8,
-                    ]);
-                }
-            }
+    let mut clipboard = winuser::GetClipboardData(winuser::CF_DIB);
+    if clipboard == handleapi::INVALID_HANDLE_VALUE {
+        clipboard = winuser::GetClipboardData(winuser::CF_DIBV5);
+    }
+    if clipboard == handleapi::INVALID_HANDLE_VALUE {
+        return Ok(None)
+    }
 
-            winbase::GlobalUnlock(dib as *mut _);
+    let dib = winbase::GlobalLock(clipboard) as *mut BITMAPINFOHEADER;
+    if dib.is_null() {
+        return Err(())
+    }
 
-            let image = RgbaImage::from_vec((rect.right - rect.left) as u32,
-                                            (rect.bottom - rect.top) as u32,
-                                            dest_data).unwrap().convert();
-            request.promise.resolve(image);
-            0
+    // Bitmap data is bottom-to-top, BGRA. Change to top-to-bottom, RGBA.
+    let src_data = slice::from_raw_parts(dib.offset(1) as *const u32,
+                                         ((*dib).biSizeImage / 4) as usize);
+    let screen_width = (*dib).biWidth as usize;
+    let screen_height = (*dib).biHeight as usize;
+    if rect.left < 0 || rect.top < 0 || rect.right < rect.left || rect.bottom < rect.top ||
+            rect.right as usize > screen_width || rect.bottom as usize > screen_height ||
+            src_data.len() < screen_width * screen_height {
+        winbase::GlobalUnlock(dib as *mut _);
+        return Err(())
+    }
+
+    let mut dest_data = Vec::with_capacity(src_data.len() * 4);
+    for y in (rect.top as usize)..(rect.bottom as usize) {
+        for x in (rect.left as usize)..(rect.right as usize) {
+            let src_pixel = src_data[(screen_height - y - 1) * screen_width + x];
+            dest_data.extend_from_slice(&[
+                ((src_pixel >> 16) & 0xff) as u8,
+                ((src_pixel >> 8)  & 0xff) as u8,
+                ((src_pixel >> 0)  & 0xff) as u8,
+                ((src_pixel >> 24) & 0xff) as u8,
+            ]);
         }
+    }
 
-        _ => winuser::DefWindowProcA(window, msg, wparam, lparam),
+    winbase::GlobalUnlock(dib as *mut _);
+
+    match RgbaImage::from_vec((rect.right - rect.left) as u32,
+                              (rect.bottom - rect.top) as u32,
+                              dest_data) {
+        None => Err(()),
+        Some(image) => Ok(Some(image.convert())),
     }
 }
 
@@ -0,0 +1,936 @@
+// planeshift/src/backends/dynamic.rs
+//
+// Copyright © 2018 The Pathfinder Project Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A backend that wraps any other backend behind a trait object.
+//!
+//! `LayerContext<dynamic::Backend>` is a single type no matter which backend is in use, so
+//! libraries can store layer contexts without being generic over the backend. OpenGL contexts
+//! and hosts are type-erased with `Any`. They must be of the types that the wrapped backend
+//! expects; passing anything else is a programming error and panics.
+//!
+//! Given a `Connection::Winit`, this backend chooses which backend to wrap with
+//! `backends::runtime::connect()`.
+
+use euclid::{Rect, Vector2D};
+use image::RgbaImage;
+use std::any::Any;
+
+#[cfg(feature = "enable-winit")]
+use winit::{EventsLoop, Window, WindowBuilder, WindowId};
+
+use crate::{Connection, ConnectionError, GLAPI, GLContextLayerBinding, LayerContainerInfo};
+use crate::{LayerGeometryInfo, LayerId, LayerMap, LayerSurfaceInfo, LayerTreeInfo, Promise};
+use crate::{Capabilities, ExportToken, ScreenshotError, SurfaceOptions, VideoFrame};
+use crate::{LayerEffects, LayerEffectsInfo, LayerScrollInfo};
+
+/// An object-safe version of `Backend`.
+///
+/// Every backend implements this trait. The associated types of `Backend` are replaced with
+/// `Box<dyn Any>`, and there's no constructor.
+pub trait DynBackend {
+    // OpenGL context creation
+    fn create_gl_context(&mut self, surface_options: SurfaceOptions) -> Result<Box<dyn Any>, ()>;
+    unsafe fn wrap_gl_context(&mut self, native_gl_context: Box<dyn Any>)
+                              -> Result<Box<dyn Any>, ()>;
+    fn gl_api(&self) -> GLAPI;
+
+    // Capabilities
+    fn capabilities(&self) -> Capabilities;
+
+    // Transactions
+    fn begin_transaction(&self);
+    fn end_transaction(&mut self,
+                       promise: &Promise<()>,
+                       tree_component: &LayerMap<LayerTreeInfo>,
+                       container_component: &LayerMap<LayerContainerInfo>,
+                       geometry_component: &LayerMap<LayerGeometryInfo>,
+                       surface_component: &LayerMap<LayerSurfaceInfo>);
+
+    // Layer creation and destruction
+    fn add_container_layer(&mut self, new_layer: LayerId);
+    fn add_surface_layer(&mut self, new_layer: LayerId);
+    fn add_scroll_layer(&mut self, new_layer: LayerId);
+    fn delete_layer(&mut self, layer: LayerId);
+
+    // Layer tree management
+    fn insert_before(&mut self,
+                     parent: LayerId,
+                     new_child: LayerId,
+                     reference: Option<LayerId>,
+                     tree_component: &LayerMap<LayerTreeInfo>,
+                     container_component: &LayerMap<LayerContainerInfo>,
+                     geometry_component: &LayerMap<LayerGeometryInfo>);
+    fn remove_from_superlayer(&mut self,
+                              layer: LayerId,
+                              parent: LayerId,
+                              tree_component: &LayerMap<LayerTreeInfo>,
+                              geometry_component: &LayerMap<LayerGeometryInfo>);
+
+    // Native hosting
+    unsafe fn host_layer(&mut self,
+                         layer: LayerId,
+                         host: Box<dyn Any>,
+                         tree_component: &LayerMap<LayerTreeInfo>,
+                         container_component: &LayerMap<LayerContainerInfo>,
+                         geometry_component: &LayerMap<LayerGeometryInfo>);
+    fn unhost_layer(&mut self, layer: LayerId);
+
+    // Cross-process hosting
+    fn export_layer(&mut self,
+                    layer: LayerId,
+                    tree_component: &LayerMap<LayerTreeInfo>,
+                    container_component: &LayerMap<LayerContainerInfo>,
+                    geometry_component: &LayerMap<LayerGeometryInfo>)
+                    -> Result<ExportToken, ()>;
+    fn import_layer(&mut self, new_layer: LayerId, token: &ExportToken) -> Result<(), ()>;
+    fn imported_layers_changed(&mut self) -> bool;
+
+    // Geometry
+    fn set_layer_bounds(&mut self,
+                        layer: LayerId,
+                        old_bounds: &Rect<f32>,
+                        tree_component: &LayerMap<LayerTreeInfo>,
+                        container_component: &LayerMap<LayerContainerInfo>,
+                        geometry_component: &LayerMap<LayerGeometryInfo>);
+
+    // Miscellaneous layer flags
+    fn set_layer_surface_options(&mut self,
+                                 layer: LayerId,
+                                 surface_component: &LayerMap<LayerSurfaceInfo>);
+
+    // Effects
+    fn set_layer_effects(&mut self,
+                         layer: LayerId,
+                         old_effects: &LayerEffects,
+                         tree_component: &LayerMap<LayerTreeInfo>,
+                         geometry_component: &LayerMap<LayerGeometryInfo>,
+                         effects_component: &LayerMap<LayerEffectsInfo>);
+    fn set_layer_mask(&mut self,
+                      layer: LayerId,
+                      tree_component: &LayerMap<LayerTreeInfo>,
+                      geometry_component: &LayerMap<LayerGeometryInfo>,
+                      effects_component: &LayerMap<LayerEffectsInfo>);
+
+    // Scrolling
+    fn set_layer_content_size(&mut self,
+                              layer: LayerId,
+                              scroll_component: &LayerMap<LayerScrollInfo>);
+    fn set_layer_content_offset(&mut self,
+                                layer: LayerId,
+                                tree_component: &LayerMap<LayerTreeInfo>,
+                                container_component: &LayerMap<LayerContainerInfo>,
+                                geometry_component: &LayerMap<LayerGeometryInfo>,
+                                scroll_component: &LayerMap<LayerScrollInfo>);
+    fn start_fling(&mut self,
+                   layer: LayerId,
+                   velocity: &Vector2D<f32>,
+                   promise: &Promise<Vector2D<f32>>,
+                   geometry_component: &LayerMap<LayerGeometryInfo>,
+                   scroll_component: &LayerMap<LayerScrollInfo>)
+                   -> Result<(), ()>;
+
+    // OpenGL content binding
+    fn bind_layer_to_gl_context(&mut self,
+                                layer: LayerId,
+                                context: &mut Box<dyn Any>,
+                                geometry_component: &LayerMap<LayerGeometryInfo>,
+                                surface_component: &LayerMap<LayerSurfaceInfo>)
+                                -> Result<GLContextLayerBinding, ()>;
+    fn present_gl_context(&mut self,
+                          binding: GLContextLayerBinding,
+                          changed_rect: &Rect<f32>,
+                          tree_component: &LayerMap<LayerTreeInfo>,
+                          geometry_component: &LayerMap<LayerGeometryInfo>)
+                          -> Result<(), ()>;
+
+    // Video
+    fn add_video_layer(&mut self, new_layer: LayerId);
+    fn present_video_frame(&mut self,
+                           layer: LayerId,
+                           frame: &VideoFrame,
+                           tree_component: &LayerMap<LayerTreeInfo>,
+                           geometry_component: &LayerMap<LayerGeometryInfo>)
+                           -> Result<(), ()>;
+
+    // Screenshots
+    fn screenshot_hosted_layer(&mut self,
+                               layer: LayerId,
+                               transaction_promise: &Promise<()>,
+                               tree_component: &LayerMap<LayerTreeInfo>,
+                               container_component: &LayerMap<LayerContainerInfo>,
+                               geometry_component: &LayerMap<LayerGeometryInfo>,
+                               surface_component: &LayerMap<LayerSurfaceInfo>)
+                               -> Promise<RgbaImage, ScreenshotError>;
+
+    // Event dispatch
+    fn pump_events(&mut self) -> Result<(), ()>;
+    fn run_animation_frame(&mut self,
+                           tree_component: &LayerMap<LayerTreeInfo>,
+                           container_component: &LayerMap<LayerContainerInfo>,
+                           geometry_component: &LayerMap<LayerGeometryInfo>,
+                           surface_component: &LayerMap<LayerSurfaceInfo>)
+                           -> Result<(), ()>;
+
+    // `winit` integration
+    #[cfg(feature = "enable-winit")]
+    fn window(&self) -> Option<&Window>;
+    #[cfg(feature = "enable-winit")]
+    fn host_layer_in_window(&mut self,
+                            layer: LayerId,
+                            tree_component: &LayerMap<LayerTreeInfo>,
+                            container_component: &LayerMap<LayerContainerInfo>,
+                            geometry_component: &LayerMap<LayerGeometryInfo>)
+                            -> Result<(), ()>;
+    #[cfg(feature = "enable-winit")]
+    fn create_window(&mut self, window_builder: WindowBuilder, event_loop: &EventsLoop)
+                     -> Result<WindowId, ()>;
+    #[cfg(feature = "enable-winit")]
+    fn adopt_window(&mut self, window: Window) -> Result<WindowId, Window>;
+    #[cfg(feature = "enable-winit")]
+    fn window_with_id(&self, window_id: WindowId) -> Option<&Window>;
+    #[cfg(feature = "enable-winit")]
+    fn host_layer_in_window_with_id(&mut self,
+                                    layer: LayerId,
+                                    window_id: WindowId,
+                                    tree_component: &LayerMap<LayerTreeInfo>,
+                                    container_component: &LayerMap<LayerContainerInfo>,
+                                    geometry_component: &LayerMap<LayerGeometryInfo>)
+                                    -> Result<(), ()>;
+}
+
+impl<B> DynBackend for B where B: crate::Backend,
+                               B::GLContext: 'static,
+                               B::NativeGLContext: 'static,
+                               B::Host: 'static {
+    // OpenGL context creation
+
+    fn create_gl_context(&mut self, surface_options: SurfaceOptions) -> Result<Box<dyn Any>, ()> {
+        match crate::Backend::create_gl_context(self, surface_options) {
+            Ok(context) => Ok(Box::new(context)),
+            Err(()) => Err(()),
+        }
+    }
+
+    unsafe fn wrap_gl_context(&mut self, native_gl_context: Box<dyn Any>)
+                              -> Result<Box<dyn Any>, ()> {
+        let native_gl_context = native_gl_context.downcast::<B::NativeGLContext>()
+                                                 .expect("wrap_gl_context(): Wrong context type");
+        match crate::Backend::wrap_gl_context(self, *native_gl_context) {
+            Ok(context) => Ok(Box::new(context)),
+            Err(()) => Err(()),
+        }
+    }
+
+    fn gl_api(&self) -> GLAPI {
+        crate::Backend::gl_api(self)
+    }
+
+    // Capabilities
+
+    fn capabilities(&self) -> Capabilities {
+        crate::Backend::capabilities(self)
+    }
+
+    // Transactions
+
+    fn begin_transaction(&self) {
+        crate::Backend::begin_transaction(self)
+    }
+
+    fn end_transaction(&mut self,
+                       promise: &Promise<()>,
+                       tree_component: &LayerMap<LayerTreeInfo>,
+                       container_component: &LayerMap<LayerContainerInfo>,
+                       geometry_component: &LayerMap<LayerGeometryInfo>,
+                       surface_component: &LayerMap<LayerSurfaceInfo>) {
+        crate::Backend::end_transaction(self,
+                                        promise,
+                                        tree_component,
+                                        container_component,
+                                        geometry_component,
+                                        surface_component)
+    }
+
+    // Layer creation and destruction
+
+    fn add_container_layer(&mut self, new_layer: LayerId) {
+        crate::Backend::add_container_layer(self, new_layer)
+    }
+
+    fn add_surface_layer(&mut self, new_layer: LayerId) {
+        crate::Backend::add_surface_layer(self, new_layer)
+    }
+
+    fn add_scroll_layer(&mut self, new_layer: LayerId) {
+        crate::Backend::add_scroll_layer(self, new_layer)
+    }
+
+    fn delete_layer(&mut self, layer: LayerId) {
+        crate::Backend::delete_layer(self, layer)
+    }
+
+    // Layer tree management
+
+    fn insert_before(&mut self,
+                     parent: LayerId,
+                     new_child: LayerId,
+                     reference: Option<LayerId>,
+                     tree_component: &LayerMap<LayerTreeInfo>,
+                     container_component: &LayerMap<LayerContainerInfo>,
+                     geometry_component: &LayerMap<LayerGeometryInfo>) {
+        crate::Backend::insert_before(self,
+                                      parent,
+                                      new_child,
+                                      reference,
+                                      tree_component,
+                                      container_component,
+                                      geometry_component)
+    }
+
+    fn remove_from_superlayer(&mut self,
+                              layer: LayerId,
+                              parent: LayerId,
+                              tree_component: &LayerMap<LayerTreeInfo>,
+                              geometry_component: &LayerMap<LayerGeometryInfo>) {
+        crate::Backend::remove_from_superlayer(self,
+                                               layer,
+                                               parent,
+                                               tree_component,
+                                               geometry_component)
+    }
+
+    // Native hosting
+
+    unsafe fn host_layer(&mut self,
+                         layer: LayerId,
+                         host: Box<dyn Any>,
+                         tree_component: &LayerMap<LayerTreeInfo>,
+                         container_component: &LayerMap<LayerContainerInfo>,
+                         geometry_component: &LayerMap<LayerGeometryInfo>) {
+        let host = host.downcast::<B::Host>().expect("host_layer(): Wrong host type");
+        crate::Backend::host_layer(self,
+                                   layer,
+                                   *host,
+                                   tree_component,
+                                   container_component,
+                                   geometry_component)
+    }
+
+    fn unhost_layer(&mut self, layer: LayerId) {
+        crate::Backend::unhost_layer(self, layer)
+    }
+
+    // Cross-process hosting
+
+    fn export_layer(&mut self,
+                    layer: LayerId,
+                    tree_component: &LayerMap<LayerTreeInfo>,
+                    container_component: &LayerMap<LayerContainerInfo>,
+                    geometry_component: &LayerMap<LayerGeometryInfo>)
+                    -> Result<ExportToken, ()> {
+        crate::Backend::export_layer(self,
+                                     layer,
+                                     tree_component,
+                                     container_component,
+                                     geometry_component)
+    }
+
+    fn import_layer(&mut self, new_layer: LayerId, token: &ExportToken) -> Result<(), ()> {
+        crate::Backend::import_layer(self, new_layer, token)
+    }
+
+    fn imported_layers_changed(&mut self) -> bool {
+        crate::Backend::imported_layers_changed(self)
+    }
+
+    // Geometry
+
+    fn set_layer_bounds(&mut self,
+                        layer: LayerId,
+                        old_bounds: &Rect<f32>,
+                        tree_component: &LayerMap<LayerTreeInfo>,
+                        container_component: &LayerMap<LayerContainerInfo>,
+                        geometry_component: &LayerMap<LayerGeometryInfo>) {
+        crate::Backend::set_layer_bounds(self,
+                                         layer,
+                                         old_bounds,
+                                         tree_component,
+                                         container_component,
+                                         geometry_component)
+    }
+
+    // Miscellaneous layer flags
+
+    fn set_layer_surface_options(&mut self,
+                                 layer: LayerId,
+                                 surface_component: &LayerMap<LayerSurfaceInfo>) {
+        crate::Backend::set_layer_surface_options(self, layer, surface_component)
+    }
+
+    // Effects
+
+    fn set_layer_effects(&mut self,
+                         layer: LayerId,
+                         old_effects: &LayerEffects,
+                         tree_component: &LayerMap<LayerTreeInfo>,
+                         geometry_component: &LayerMap<LayerGeometryInfo>,
+                         effects_component: &LayerMap<LayerEffectsInfo>) {
+        crate::Backend::set_layer_effects(self,
+                                          layer,
+                                          old_effects,
+                                          tree_component,
+                                          geometry_component,
+                                          effects_component)
+    }
+
+    fn set_layer_mask(&mut self,
+                      layer: LayerId,
+                      tree_component: &LayerMap<LayerTreeInfo>,
+                      geometry_component: &LayerMap<LayerGeometryInfo>,
+                      effects_component: &LayerMap<LayerEffectsInfo>) {
+        crate::Backend::set_layer_mask(self,
+                                       layer,
+                                       tree_component,
+                                       geometry_component,
+                                       effects_component)
+    }
+
+    // Scrolling
+
+    fn set_layer_content_size(&mut self,
+                              layer: LayerId,
+                              scroll_component: &LayerMap<LayerScrollInfo>) {
+        crate::Backend::set_layer_content_size(self, layer, scroll_component)
+    }
+
+    fn set_layer_content_offset(&mut self,
+                                layer: LayerId,
+                                tree_component: &LayerMap<LayerTreeInfo>,
+                                container_component: &LayerMap<LayerContainerInfo>,
+                                geometry_component: &LayerMap<LayerGeometryInfo>,
+                                scroll_component: &LayerMap<LayerScrollInfo>) {
+        crate::Backend::set_layer_content_offset(self,
+                                                 layer,
+                                                 tree_component,
+                                                 container_component,
+                                                 geometry_component,
+                                                 scroll_component)
+    }
+
+    fn start_fling(&mut self,
+                   layer: LayerId,
+                   velocity: &Vector2D<f32>,
+                   promise: &Promise<Vector2D<f32>>,
+                   geometry_component: &LayerMap<LayerGeometryInfo>,
+                   scroll_component: &LayerMap<LayerScrollInfo>)
+                   -> Result<(), ()> {
+        crate::Backend::start_fling(self,
+                                    layer,
+                                    velocity,
+                                    promise,
+                                    geometry_component,
+                                    scroll_component)
+    }
+
+    // OpenGL content binding
+
+    fn bind_layer_to_gl_context(&mut self,
+                                layer: LayerId,
+                                context: &mut Box<dyn Any>,
+                                geometry_component: &LayerMap<LayerGeometryInfo>,
+                                surface_component: &LayerMap<LayerSurfaceInfo>)
+                                -> Result<GLContextLayerBinding, ()> {
+        let context = context.downcast_mut::<B::GLContext>()
+                             .expect("bind_layer_to_gl_context(): Wrong context type");
+        crate::Backend::bind_layer_to_gl_context(self,
+                                                 layer,
+                                                 context,
+                                                 geometry_component,
+                                                 surface_component)
+    }
+
+    fn present_gl_context(&mut self,
+                          binding: GLContextLayerBinding,
+                          changed_rect: &Rect<f32>,
+                          tree_component: &LayerMap<LayerTreeInfo>,
+                          geometry_component: &LayerMap<LayerGeometryInfo>)
+                          -> Result<(), ()> {
+        crate::Backend::present_gl_context(self,
+                                           binding,
+                                           changed_rect,
+                                           tree_component,
+                                           geometry_component)
+    }
+
+    // Video
+
+    fn add_video_layer(&mut self, new_layer: LayerId) {
+        crate::Backend::add_video_layer(self, new_layer)
+    }
+
+    fn present_video_frame(&mut self,
+                           layer: LayerId,
+                           frame: &VideoFrame,
+                           tree_component: &LayerMap<LayerTreeInfo>,
+                           geometry_component: &LayerMap<LayerGeometryInfo>)
+                           -> Result<(), ()> {
+        crate::Backend::present_video_frame(self,
+                                            layer,
+                                            frame,
+                                            tree_component,
+                                            geometry_component)
+    }
+
+    // Screenshots
+
+    fn screenshot_hosted_layer(&mut self,
+                               layer: LayerId,
+                               transaction_promise: &Promise<()>,
+                               tree_component: &LayerMap<LayerTreeInfo>,
+                               container_component: &LayerMap<LayerContainerInfo>,
+                               geometry_component: &LayerMap<LayerGeometryInfo>,
+                               surface_component: &LayerMap<LayerSurfaceInfo>)
+                               -> Promise<RgbaImage, ScreenshotError> {
+        crate::Backend::screenshot_hosted_layer(self,
+                                                layer,
+                                                transaction_promise,
+                                                tree_component,
+                                                container_component,
+                                                geometry_component,
+                                                surface_component)
+    }
+
+    // Event dispatch
+
+    fn pump_events(&mut self) -> Result<(), ()> {
+        crate::Backend::pump_events(self)
+    }
+
+    fn run_animation_frame(&mut self,
+                           tree_component: &LayerMap<LayerTreeInfo>,
+                           container_component: &LayerMap<LayerContainerInfo>,
+                           geometry_component: &LayerMap<LayerGeometryInfo>,
+                           surface_component: &LayerMap<LayerSurfaceInfo>)
+                           -> Result<(), ()> {
+        crate::Backend::run_animation_frame(self,
+                                            tree_component,
+                                            container_component,
+                                            geometry_component,
+                                            surface_component)
+    }
+
+    // `winit` integration
+
+    #[cfg(feature = "enable-winit")]
+    fn window(&self) -> Option<&Window> {
+        crate::Backend::window(self)
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn host_layer_in_window(&mut self,
+                            layer: LayerId,
+                            tree_component: &LayerMap<LayerTreeInfo>,
+                            container_component: &LayerMap<LayerContainerInfo>,
+                            geometry_component: &LayerMap<LayerGeometryInfo>)
+                            -> Result<(), ()> {
+        crate::Backend::host_layer_in_window(self,
+                                             layer,
+                                             tree_component,
+                                             container_component,
+                                             geometry_component)
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn create_window(&mut self, window_builder: WindowBuilder, event_loop: &EventsLoop)
+                     -> Result<WindowId, ()> {
+        crate::Backend::create_window(self, window_builder, event_loop)
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn adopt_window(&mut self, window: Window) -> Result<WindowId, Window> {
+        crate::Backend::adopt_window(self, window)
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn window_with_id(&self, window_id: WindowId) -> Option<&Window> {
+        crate::Backend::window_with_id(self, window_id)
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn host_layer_in_window_with_id(&mut self,
+                                    layer: LayerId,
+                                    window_id: WindowId,
+                                    tree_component: &LayerMap<LayerTreeInfo>,
+                                    container_component: &LayerMap<LayerContainerInfo>,
+                                    geometry_component: &LayerMap<LayerGeometryInfo>)
+                                    -> Result<(), ()> {
+        crate::Backend::host_layer_in_window_with_id(self,
+                                                     layer,
+                                                     window_id,
+                                                     tree_component,
+                                                     container_component,
+                                                     geometry_component)
+    }
+}
+
+/// A backend that forwards to any other backend, chosen at runtime.
+pub struct Backend(Box<dyn DynBackend>);
+
+impl Backend {
+    /// Wraps a backend that has already been connected.
+    #[inline]
+    pub fn from_backend<B>(backend: B) -> Backend where B: 'static + DynBackend {
+        Backend(Box::new(backend))
+    }
+
+    /// Connects a backend of the given type and wraps it.
+    pub fn connect<B>(connection: Connection<B::NativeConnection>)
+                      -> Result<Backend, ConnectionError>
+                      where B: 'static + crate::Backend + DynBackend {
+        Ok(Backend::from_backend(B::new(connection)?))
+    }
+}
+
+/// A type-erased OpenGL context, created by the wrapped backend.
+pub struct GLContext(pub Box<dyn Any>);
+
+impl crate::Backend for Backend {
+    type NativeConnection = Backend;
+    type GLContext = GLContext;
+    type NativeGLContext = Box<dyn Any>;
+    type Host = Box<dyn Any>;
+
+    // Constructor
+
+    fn new(connection: Connection<Backend>) -> Result<Backend, ConnectionError> {
+        match connection {
+            Connection::Native(backend) => Ok(backend),
+            #[cfg(feature = "enable-winit")]
+            Connection::Winit(window_builder, event_loop) => {
+                crate::backends::runtime::connect(window_builder, event_loop)
+            }
+        }
+    }
+
+    // OpenGL context creation
+
+    fn create_gl_context(&mut self, surface_options: SurfaceOptions) -> Result<GLContext, ()> {
+        Ok(GLContext(self.0.create_gl_context(surface_options)?))
+    }
+
+    unsafe fn wrap_gl_context(&mut self, native_gl_context: Box<dyn Any>) -> Result<GLContext, ()> {
+        Ok(GLContext(self.0.wrap_gl_context(native_gl_context)?))
+    }
+
+    fn gl_api(&self) -> GLAPI {
+        self.0.gl_api()
+    }
+
+    // Capabilities
+
+    fn capabilities(&self) -> Capabilities {
+        self.0.capabilities()
+    }
+
+    // Transactions
+
+    fn begin_transaction(&self) {
+        self.0.begin_transaction()
+    }
+
+    fn end_transaction(&mut self,
+                       promise: &Promise<()>,
+                       tree_component: &LayerMap<LayerTreeInfo>,
+                       container_component: &LayerMap<LayerContainerInfo>,
+                       geometry_component: &LayerMap<LayerGeometryInfo>,
+                       surface_component: &LayerMap<LayerSurfaceInfo>) {
+        self.0.end_transaction(promise,
+                               tree_component,
+                               container_component,
+                               geometry_component,
+                               surface_component)
+    }
+
+    // Layer creation and destruction
+
+    fn add_container_layer(&mut self, new_layer: LayerId) {
+        self.0.add_container_layer(new_layer)
+    }
+
+    fn add_surface_layer(&mut self, new_layer: LayerId) {
+        self.0.add_surface_layer(new_layer)
+    }
+
+    fn add_scroll_layer(&mut self, new_layer: LayerId) {
+        self.0.add_scroll_layer(new_layer)
+    }
+
+    fn delete_layer(&mut self, layer: LayerId) {
+        self.0.delete_layer(layer)
+    }
+
+    // Layer tree management
+
+    fn insert_before(&mut self,
+                     parent: LayerId,
+                     new_child: LayerId,
+                     reference: Option<LayerId>,
+                     tree_component: &LayerMap<LayerTreeInfo>,
+                     container_component: &LayerMap<LayerContainerInfo>,
+                     geometry_component: &LayerMap<LayerGeometryInfo>) {
+        self.0.insert_before(parent,
+                             new_child,
+                             reference,
+                             tree_component,
+                             container_component,
+                             geometry_component)
+    }
+
+    fn remove_from_superlayer(&mut self,
+                              layer: LayerId,
+                              parent: LayerId,
+                              tree_component: &LayerMap<LayerTreeInfo>,
+                              geometry_component: &LayerMap<LayerGeometryInfo>) {
+        self.0.remove_from_superlayer(layer, parent, tree_component, geometry_component)
+    }
+
+    // Native hosting
+
+    unsafe fn host_layer(&mut self,
+                         layer: LayerId,
+                         host: Box<dyn Any>,
+                         tree_component: &LayerMap<LayerTreeInfo>,
+                         container_component: &LayerMap<LayerContainerInfo>,
+                         geometry_component: &LayerMap<LayerGeometryInfo>) {
+        self.0.host_layer(layer, host, tree_component, container_component, geometry_component)
+    }
+
+    fn unhost_layer(&mut self, layer: LayerId) {
+        self.0.unhost_layer(layer)
+    }
+
+    // Cross-process hosting
+
+    fn export_layer(&mut self,
+                    layer: LayerId,
+                    tree_component: &LayerMap<LayerTreeInfo>,
+                    container_component: &LayerMap<LayerContainerInfo>,
+                    geometry_component: &LayerMap<LayerGeometryInfo>)
+                    -> Result<ExportToken, ()> {
+        self.0.export_layer(layer, tree_component, container_component, geometry_component)
+    }
+
+    fn import_layer(&mut self, new_layer: LayerId, token: &ExportToken) -> Result<(), ()> {
+        self.0.import_layer(new_layer, token)
+    }
+
+    fn imported_layers_changed(&mut self) -> bool {
+        self.0.imported_layers_changed()
+    }
+
+    // Geometry
+
+    fn set_layer_bounds(&mut self,
+                        layer: LayerId,
+                        old_bounds: &Rect<f32>,
+                        tree_component: &LayerMap<LayerTreeInfo>,
+                        container_component: &LayerMap<LayerContainerInfo>,
+                        geometry_component: &LayerMap<LayerGeometryInfo>) {
+        self.0.set_layer_bounds(layer,
+                                old_bounds,
+                                tree_component,
+                                container_component,
+                                geometry_component)
+    }
+
+    // Miscellaneous layer flags
+
+    fn set_layer_surface_options(&mut self,
+                                 layer: LayerId,
+                                 surface_component: &LayerMap<LayerSurfaceInfo>) {
+        self.0.set_layer_surface_options(layer, surface_component)
+    }
+
+    // Effects
+
+    fn set_layer_effects(&mut self,
+                         layer: LayerId,
+                         old_effects: &LayerEffects,
+                         tree_component: &LayerMap<LayerTreeInfo>,
+                         geometry_component: &LayerMap<LayerGeometryInfo>,
+                         effects_component: &LayerMap<LayerEffectsInfo>) {
+        self.0.set_layer_effects(layer,
+                                 old_effects,
+                                 tree_component,
+                                 geometry_component,
+                                 effects_component)
+    }
+
+    fn set_layer_mask(&mut self,
+                      layer: LayerId,
+                      tree_component: &LayerMap<LayerTreeInfo>,
+                      geometry_component: &LayerMap<LayerGeometryInfo>,
+                      effects_component: &LayerMap<LayerEffectsInfo>) {
+        self.0.set_layer_mask(layer, tree_component, geometry_component, effects_component)
+    }
+
+    // Scrolling
+
+    fn set_layer_content_size(&mut self,
+                              layer: LayerId,
+                              scroll_component: &LayerMap<LayerScrollInfo>) {
+        self.0.set_layer_content_size(layer, scroll_component)
+    }
+
+    fn set_layer_content_offset(&mut self,
+                                layer: LayerId,
+                                tree_component: &LayerMap<LayerTreeInfo>,
+                                container_component: &LayerMap<LayerContainerInfo>,
+                                geometry_component: &LayerMap<LayerGeometryInfo>,
+                                scroll_component: &LayerMap<LayerScrollInfo>) {
+        self.0.set_layer_content_offset(layer,
+                                        tree_component,
+                                        container_component,
+                                        geometry_component,
+                                        scroll_component)
+    }
+
+    fn start_fling(&mut self,
+                   layer: LayerId,
+                   velocity: &Vector2D<f32>,
+                   promise: &Promise<Vector2D<f32>>,
+                   geometry_component: &LayerMap<LayerGeometryInfo>,
+                   scroll_component: &LayerMap<LayerScrollInfo>)
+                   -> Result<(), ()> {
+        self.0.start_fling(layer, velocity, promise, geometry_component, scroll_component)
+    }
+
+    // OpenGL content binding
+
+    fn bind_layer_to_gl_context(&mut self,
+                                layer: LayerId,
+                                context: &mut GLContext,
+                                geometry_component: &LayerMap<LayerGeometryInfo>,
+                                surface_component: &LayerMap<LayerSurfaceInfo>)
+                                -> Result<GLContextLayerBinding, ()> {
+        self.0.bind_layer_to_gl_context(layer,
+                                        &mut context.0,
+                                        geometry_component,
+                                        surface_component)
+    }
+
+    fn present_gl_context(&mut self,
+                          binding: GLContextLayerBinding,
+                          changed_rect: &Rect<f32>,
+                          tree_component: &LayerMap<LayerTreeInfo>,
+                          geometry_component: &LayerMap<LayerGeometryInfo>)
+                          -> Result<(), ()> {
+        self.0.present_gl_context(binding, changed_rect, tree_component, geometry_component)
+    }
+
+    // Video
+
+    fn add_video_layer(&mut self, new_layer: LayerId) {
+        self.0.add_video_layer(new_layer)
+    }
+
+    fn present_video_frame(&mut self,
+                           layer: LayerId,
+                           frame: &VideoFrame,
+                           tree_component: &LayerMap<LayerTreeInfo>,
+                           geometry_component: &LayerMap<LayerGeometryInfo>)
+                           -> Result<(), ()> {
+        self.0.present_video_frame(layer, frame, tree_component, geometry_component)
+    }
+
+    // Screenshots
+
+    fn screenshot_hosted_layer(&mut self,
+                               layer: LayerId,
+                               transaction_promise: &Promise<()>,
+                               tree_component: &LayerMap<LayerTreeInfo>,
+                               container_component: &LayerMap<LayerContainerInfo>,
+                               geometry_component: &LayerMap<LayerGeometryInfo>,
+                               surface_component: &LayerMap<LayerSurfaceInfo>)
+                               -> Promise<RgbaImage, ScreenshotError> {
+        self.0.screenshot_hosted_layer(layer,
+                                       transaction_promise,
+                                       tree_component,
+                                       container_component,
+                                       geometry_component,
+                                       surface_component)
+    }
+
+    // Event dispatch
+
+    fn pump_events(&mut self) -> Result<(), ()> {
+        self.0.pump_events()
+    }
+
+    fn run_animation_frame(&mut self,
+                           tree_component: &LayerMap<LayerTreeInfo>,
+                           container_component: &LayerMap<LayerContainerInfo>,
+                           geometry_component: &LayerMap<LayerGeometryInfo>,
+                           surface_component: &LayerMap<LayerSurfaceInfo>)
+                           -> Result<(), ()> {
+        self.0.run_animation_frame(tree_component,
+                                   container_component,
+                                   geometry_component,
+                                   surface_component)
+    }
+
+    // `winit` integration
+
+    #[cfg(feature = "enable-winit")]
+    fn window(&self) -> Option<&Window> {
+        self.0.window()
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn host_layer_in_window(&mut self,
+                            layer: LayerId,
+                            tree_component: &LayerMap<LayerTreeInfo>,
+                            container_component: &LayerMap<LayerContainerInfo>,
+                            geometry_component: &LayerMap<LayerGeometryInfo>)
+                            -> Result<(), ()> {
+        self.0.host_layer_in_window(layer, tree_component, container_component, geometry_component)
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn create_window(&mut self, window_builder: WindowBuilder, event_loop: &EventsLoop)
+                     -> Result<WindowId, ()> {
+        self.0.create_window(window_builder, event_loop)
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn adopt_window(&mut self, window: Window) -> Result<WindowId, Window> {
+        self.0.adopt_window(window)
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn window_with_id(&self, window_id: WindowId) -> Option<&Window> {
+        self.0.window_with_id(window_id)
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn host_layer_in_window_with_id(&mut self,
+                                    layer: LayerId,
+                                    window_id: WindowId,
+                                    tree_component: &LayerMap<LayerTreeInfo>,
+                                    container_component: &LayerMap<LayerContainerInfo>,
+                                    geometry_component: &LayerMap<LayerGeometryInfo>)
+                                    -> Result<(), ()> {
+        self.0.host_layer_in_window_with_id(layer,
+                                            window_id,
+                                            tree_component,
+                                            container_component,
+                                            geometry_component)
+    }
+}
@@ -9,54 +9,86 @@
 // except according to those terms.
 
 //! A fallback backend that renders the layers ourselves using OpenGL.
-
-use euclid::{Point2D, Rect, Size2D};
-use gl::types::{GLchar, GLint, GLuint, GLvoid};
+//!
+//! Exported layer trees are rendered into offscreen framebuffers and read back into
+//! shared-memory files after each transaction; importing processes upload the images into
+//! textures whenever they end a transaction, which `LayerContext::update_imported_layers()` does
+//! when there are any.
+
+use euclid::{Point2D, Rect, Size2D, Vector2D};
+use gl::types::{GLchar, GLenum, GLint, GLuint, GLvoid};
 use gl;
 use image::RgbaImage;
+use std::mem;
 use std::os::raw::c_void;
 use std::ptr;
 
 #[cfg(feature = "enable-glutin")]
 use glutin::{Api, ContextBuilder, GlContext, GlProfile, GlRequest, GlWindow};
 #[cfg(feature = "enable-winit")]
-use winit::{EventsLoop, Window, WindowBuilder};
+use winit::{EventsLoop, Window, WindowBuilder, WindowId};
 
 use crate::{Connection, ConnectionError, GLAPI, GLContextLayerBinding, LayerContainerInfo};
 use crate::{LayerGeometryInfo, LayerId, LayerMap, LayerParent, LayerSurfaceInfo, LayerTreeInfo};
-use crate::{Promise, SurfaceOptions};
+use crate::{Capabilities, ColorFormat, DepthFormat, ExportToken, Promise, ScreenshotError};
+use crate::{BlendMode, DropShadow, LayerEffects, LayerEffectsInfo, SurfaceOptions, VideoFrame};
+use crate::{LayerScrollInfo, VideoPlane, YUVFormat};
+use crate::effects;
+use crate::fling::{self, Fling, FlingAnimator};
+use crate::export::{SharedImageReader, SharedImageWriter};
+use crate::video::{self, Conversion};
 
 // FIXME(pcwalton): Clean up GL resources in destructor.
 pub struct Backend {
     native_component: LayerMap<LayerNativeInfo>,
+    effects_component: LayerMap<LayerEffects>,
+    mask_component: LayerMap<LayerId>,
+    scroll_component: LayerMap<Vector2D<f32>>,
 
     connection: Box<dyn GLInterface>,
-    hosted_layer: Option<LayerId>,
-    dirty_rect: Option<Rect<f32>>,
+    windows: Vec<Box<dyn GLInterface>>,
+    hosted_roots: Vec<HostedRoot>,
+    pending_screenshots: Vec<PendingScreenshot>,
+    flings: FlingAnimator,
 
     vertex_shader: GLuint,
     fragment_shader: GLuint,
     program: GLuint,
+    attribute_position: GLint,
+    attribute_tex_coord: GLint,
     uniform_scale: GLint,
     uniform_translation: GLint,
     uniform_depth: GLint,
     uniform_texture: GLint,
-    vertex_array: GLuint,
+    uniform_encode_srgb: GLint,
+    uniform_opaque: GLint,
     vertex_buffer: GLuint,
+    video_program: VideoProgram,
+    blur_program: BlurProgram,
+    blend_program: BlendProgram,
+
+    max_texture_size: u32,
+    max_samples: u32,
 }
 
 impl crate::Backend for Backend {
     type NativeConnection = Box<dyn GLInterface>;
     type GLContext = ();
     type NativeGLContext = ();
-    type Host = ();
+    type Host = Box<dyn GLInterface>;
 
     // Constructor
     fn new(connection: Connection<Box<dyn GLInterface>>) -> Result<Self, ConnectionError> {
-        let connection = match connection {
+        let connection: Box<dyn GLInterface> = match connection {
             #[cfg(feature = "enable-winit")]
             Connection::Winit(window_builder, event_loop) => {
-                Box::new(Interface::new(window_builder, event_loop))
+                match Interface::new(window_builder, event_loop) {
+                    Ok(interface) => Box::new(interface),
+                    Err(()) => {
+                        let reason = "failed to create an OpenGL window";
+                        return Err(ConnectionError::with_reason(reason))
+                    }
+                }
             }
             Connection::Native(connection) => connection,
         };
@@ -69,11 +101,10 @@ impl crate::Backend for Backend {
         let (vertex_shader, fragment_shader, program);
         let (attribute_position, attribute_tex_coord);
         let (uniform_scale, uniform_translation, uniform_depth, uniform_texture);
-        let (mut vertex_array, mut vertex_buffer) = (0, 0);
+        let (uniform_encode_srgb, uniform_opaque);
+        let mut vertex_buffer = 0;
+        let (mut max_texture_size, mut max_samples) = (0, 0);
         unsafe {
-            gl::GenVertexArrays(1, &mut vertex_array);
-            gl::BindVertexArray(vertex_array);
-
             vertex_shader = create_shader(gl::VERTEX_SHADER, VERTEX_SHADER_SOURCE);
             fragment_shader = create_shader(gl::FRAGMENT_SHADER, FRAGMENT_SHADER_SOURCE);
             program = gl::CreateProgram();
@@ -92,6 +123,10 @@ impl crate::Backend for Backend {
             uniform_depth = gl::GetUniformLocation(program, b"uDepth\0".as_ptr() as *const GLchar);
             uniform_texture = gl::GetUniformLocation(program,
                                                      b"uTexture\0".as_ptr() as *const GLchar);
+            uniform_encode_srgb =
+                gl::GetUniformLocation(program, b"uEncodeSRGB\0".as_ptr() as *const GLchar);
+            uniform_opaque = gl::GetUniformLocation(program,
+                                                    b"uOpaque\0".as_ptr() as *const GLchar);
 
             gl::GenBuffers(1, &mut vertex_buffer);
             gl::BindBuffer(gl::ARRAY_BUFFER, vertex_buffer);
@@ -100,38 +135,51 @@ impl crate::Backend for Backend {
                            VERTEX_BUFFER_DATA.as_ptr() as *const GLvoid,
                            gl::STATIC_DRAW);
 
-            gl::VertexAttribPointer(attribute_tex_coord as GLuint,
-                                    2,
-                                    gl::BYTE,
-                                    gl::FALSE,
-                                    4,
-                                    2 as *const GLvoid);
-            gl::VertexAttribPointer(attribute_position as GLuint,
-                                    2,
-                                    gl::BYTE,
-                                    gl::FALSE,
-                                    4,
-                                    0 as *const GLvoid);
-            gl::EnableVertexAttribArray(attribute_tex_coord as GLuint);
-            gl::EnableVertexAttribArray(attribute_position as GLuint);
+            gl::GetIntegerv(gl::MAX_TEXTURE_SIZE, &mut max_texture_size);
+            gl::GetIntegerv(gl::MAX_SAMPLES, &mut max_samples);
+        }
+
+        let video_program = VideoProgram::new(vertex_shader,
+                                               attribute_position,
+                                               attribute_tex_coord);
+        let blur_program = BlurProgram::new(vertex_shader, attribute_position, attribute_tex_coord);
+        let blend_program = BlendProgram::new(vertex_shader,
+                                              attribute_position,
+                                              attribute_tex_coord);
+        unsafe {
+            gl::UseProgram(program);
         }
 
         Ok(Backend {
             native_component: LayerMap::new(),
+            effects_component: LayerMap::new(),
+            mask_component: LayerMap::new(),
+            scroll_component: LayerMap::new(),
 
             connection,
-            hosted_layer: None,
-            dirty_rect: None,
+            windows: vec![],
+            hosted_roots: vec![],
+            pending_screenshots: vec![],
+            flings: FlingAnimator::new(),
 
             vertex_shader,
             fragment_shader,
             program,
+            attribute_position,
+            attribute_tex_coord,
             uniform_scale,
             uniform_translation,
             uniform_depth,
             uniform_texture,
-            vertex_array,
+            uniform_encode_srgb,
+            uniform_opaque,
             vertex_buffer,
+            video_program,
+            blur_program,
+            blend_program,
+
+            max_texture_size: max_texture_size as u32,
+            max_samples: max_samples as u32,
         })
     }
 
@@ -148,6 +196,37 @@ impl crate::Backend for Backend {
         self.connection.gl_api()
     }
 
+    // Capabilities
+
+    fn capabilities(&self) -> Capabilities {
+        // Layer framebuffers support every format, but multisampling is limited by the driver.
+        let mut surface_options = SurfaceOptions::all();
+        for &(option, sample_count) in &[(SurfaceOptions::MSAA_2X, 2),
+                                         (SurfaceOptions::MSAA_4X, 4),
+                                         (SurfaceOptions::MSAA_8X, 8),
+                                         (SurfaceOptions::MSAA_16X, 16)] {
+            if sample_count > self.max_samples {
+                surface_options.remove(option);
+            }
+        }
+
+        Capabilities {
+            screenshots: true,
+            damage_aware_present: true,
+            transforms: false,
+            native_animations: true,
+            surface_options,
+            max_texture_size: Some(self.max_texture_size),
+            layer_export: true,
+            video_layers: true,
+            layer_effects: true,
+            backdrop_blur: true,
+            blend_modes: true,
+            layer_masks: true,
+            scroll_clipping: true,
+        }
+    }
+
     // Transactions
 
     fn begin_transaction(&self) {
@@ -160,67 +239,25 @@ impl crate::Backend for Backend {
                        container_component: &LayerMap<LayerContainerInfo>,
                        geometry_component: &LayerMap<LayerGeometryInfo>,
                        surface_component: &LayerMap<LayerSurfaceInfo>) {
-        match (self.dirty_rect, self.hosted_layer) {
-            (Some(dirty_rect), Some(hosted_layer)) => {
-                self.connection.prepare_to_draw();
-
-                // TODO(pcwalton)
-                let default_framebuffer = self.connection.default_framebuffer();
-                let default_framebuffer_size = self.connection.default_framebuffer_size();
-
-                unsafe {
-                    gl::BindVertexArray(self.vertex_array);
-                    gl::UseProgram(self.program);
-                    gl::BindFramebuffer(gl::FRAMEBUFFER, default_framebuffer);
-                    gl::Viewport(0,
-                                0,
-                                default_framebuffer_size.width as GLint,
-                                default_framebuffer_size.height as GLint);
-
-                    gl::ClearDepth(1.0);
-                    gl::ClearStencil(0);
-                    gl::Clear(gl::DEPTH_BUFFER_BIT | gl::STENCIL_BUFFER_BIT);
-
-                    gl::DepthFunc(gl::LEQUAL);
-                    gl::Enable(gl::DEPTH_TEST);
-                    gl::Disable(gl::BLEND);
-
-                    let mut depth = 0.0;
-                    self.render_opaque_layer_subtree(hosted_layer,
-                                                    &Point2D::zero(),
-                                                    &mut depth,
-                                                    tree_component,
-                                                    container_component,
-                                                    geometry_component,
-                                                    surface_component);
-
-                    gl::Disable(gl::DEPTH_TEST);
-                    gl::BlendEquation(gl::FUNC_ADD);
-                    gl::BlendFunc(gl::ONE, gl::ONE_MINUS_SRC_ALPHA);
-                    gl::Enable(gl::BLEND);
-
-                    self.render_transparent_layer_subtree(hosted_layer,
-                                                          &Point2D::zero(),
-                                                          &mut depth,
-                                                          tree_component,
-                                                          container_component,
-                                                          geometry_component,
-                                                          surface_component);
+        self.update_imported_layers(tree_component, geometry_component);
+        self.advance_flings(tree_component, geometry_component);
+
+        self.render_hosted_roots(tree_component,
+                                 container_component,
+                                 geometry_component,
+                                 surface_component);
+
+        // Screenshots of layers that went unrendered (for example, because they were unhosted
+        // before the transaction ended) can never be fulfilled.
+        for screenshot in self.pending_screenshots.drain(..) {
+            screenshot.promise.reject(ScreenshotError::ReadbackFailed);
+        }
 
-                    gl::Disable(gl::SCISSOR_TEST);
-                    gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
-                }
+        // Flings that came to rest have now been drawn at their final offsets.
+        self.flings.settle();
 
-                self.dirty_rect = None;
-                promise.resolve(());
-                self.connection.present(&dirty_rect);
-            }
-            (Some(_), None) => {
-                self.dirty_rect = None;
-                promise.resolve(());
-            }
-            (None, _) => promise.resolve(()),
-        }
+        self.connection.make_current();
+        promise.resolve(());
     }
 
     // Layer creation and destruction
@@ -230,23 +267,33 @@ impl crate::Backend for Backend {
     fn add_surface_layer(&mut self, layer: LayerId) {
         self.native_component.add(layer, LayerNativeInfo {
             framebuffer: None,
+            import: None,
+            video: None,
         });
     }
 
+    fn add_scroll_layer(&mut self, layer: LayerId) {
+        self.scroll_component.add(layer, Vector2D::zero());
+    }
+
     fn delete_layer(&mut self, layer: LayerId) {
         if let Some(native_component) = self.native_component.get_mut(layer) {
-            if let Some(ref mut framebuffer) = native_component.framebuffer {
-                unsafe {
-                    gl::DeleteFramebuffers(1, &mut framebuffer.framebuffer);
-                    if let Some(mut renderbuffer) = framebuffer.depth_stencil_renderbuffer {
-                        gl::DeleteRenderbuffers(1, &mut renderbuffer);
-                    }
-                    gl::DeleteTextures(1, &mut framebuffer.color_texture);
-                }
+            if let Some(mut framebuffer) = native_component.framebuffer.take() {
+                framebuffer.destroy();
+            }
+            if let Some(mut import) = native_component.import.take() {
+                import.destroy();
+            }
+            if let Some(mut video) = native_component.video.take() {
+                video.destroy();
             }
         }
 
         self.native_component.remove_if_present(layer);
+        self.effects_component.remove_if_present(layer);
+        self.mask_component.remove_if_present(layer);
+        self.scroll_component.remove_if_present(layer);
+        self.flings.stop(layer);
     }
 
     // Layer tree management
@@ -267,25 +314,85 @@ impl crate::Backend for Backend {
                               parent: LayerId,
                               tree_component: &LayerMap<LayerTreeInfo>,
                               geometry_component: &LayerMap<LayerGeometryInfo>) {
-        let rect = &geometry_component[old_child].bounds;
-        self.invalidate_layer(parent, rect, tree_component, geometry_component);
+        let rect = self.painted_rect(old_child, &geometry_component[old_child].bounds);
+        self.invalidate_layer_content(parent, &rect, tree_component, geometry_component);
     }
 
     // Native hosting
 
     unsafe fn host_layer(&mut self,
                          layer: LayerId,
-                         _: Self::Host,
-                         _: &LayerMap<LayerTreeInfo>,
+                         target: Box<dyn GLInterface>,
+                         tree_component: &LayerMap<LayerTreeInfo>,
                          _: &LayerMap<LayerContainerInfo>,
-                         _: &LayerMap<LayerGeometryInfo>) {
-        debug_assert!(self.hosted_layer.is_none());
-        self.hosted_layer = Some(layer);
+                         geometry_component: &LayerMap<LayerGeometryInfo>) {
+        self.add_hosted_root(layer, HostTarget::Native(target), tree_component, geometry_component);
     }
 
     fn unhost_layer(&mut self, layer: LayerId) {
-        debug_assert_eq!(self.hosted_layer, Some(layer));
-        self.hosted_layer = None;
+        let hosted_root_index = self.hosted_root_index(layer)
+                                    .expect("unhost_layer(): Layer not hosted!");
+        let mut hosted_root = self.hosted_roots.remove(hosted_root_index);
+
+        self.target(&hosted_root.target).make_current();
+        unsafe {
+            gl::DeleteVertexArrays(1, &mut hosted_root.vertex_array);
+        }
+        hosted_root.destroy_effect_targets();
+        if let HostTarget::Export(ref mut export) = hosted_root.target {
+            export.destroy();
+        }
+        self.connection.make_current();
+    }
+
+    // Cross-process hosting
+
+    fn export_layer(&mut self,
+                    layer: LayerId,
+                    tree_component: &LayerMap<LayerTreeInfo>,
+                    _: &LayerMap<LayerContainerInfo>,
+                    geometry_component: &LayerMap<LayerGeometryInfo>)
+                    -> Result<ExportToken, ()> {
+        let shared_image = SharedImageWriter::new().map_err(drop)?;
+        let token = shared_image.token()?;
+
+        let size = match geometry_component.get(layer) {
+            None => Size2D::zero(),
+            Some(geometry_info) => geometry_info.bounds.round_out().size.to_u32(),
+        };
+        let target = HostTarget::Export(ExportTarget {
+            framebuffer: None,
+            size,
+            gl_api: self.connection.gl_api(),
+            shared_image,
+        });
+
+        self.add_hosted_root(layer, target, tree_component, geometry_component);
+        Ok(token)
+    }
+
+    fn import_layer(&mut self, new_layer: LayerId, token: &ExportToken) -> Result<(), ()> {
+        let shared_image = SharedImageReader::open(token)?;
+        self.native_component.add(new_layer, LayerNativeInfo {
+            framebuffer: None,
+            import: Some(LayerImport {
+                shared_image,
+                texture: None,
+            }),
+            video: None,
+        });
+        Ok(())
+    }
+
+    fn imported_layers_changed(&mut self) -> bool {
+        self.native_component.0.iter_mut().any(|native_component| {
+            match *native_component {
+                Some(LayerNativeInfo { import: Some(ref mut import), .. }) => {
+                    import.shared_image.has_changed()
+                }
+                _ => false,
+            }
+        })
     }
 
     // Geometry
@@ -299,8 +406,16 @@ impl crate::Backend for Backend {
         if let Some(tree_info) = tree_component.get(layer) {
             match tree_info.parent {
                 LayerParent::Layer(parent_layer) => {
-                    self.invalidate_layer(parent_layer,
-                                          old_bounds,
+                    let old_rect = self.painted_rect(layer, old_bounds);
+                    self.invalidate_layer_content(parent_layer,
+                                                  &old_rect,
+                                                  tree_component,
+                                                  geometry_component)
+                }
+                LayerParent::Mask(masked_layer) => {
+                    let old_rect = self.painted_rect(layer, old_bounds);
+                    self.invalidate_layer(masked_layer,
+                                          &old_rect,
                                           tree_component,
                                           geometry_component)
                 }
@@ -310,19 +425,21 @@ impl crate::Backend for Backend {
 
         let new_size = geometry_component[layer].bounds.size;
 
+        // Resize the framebuffer of an exported root the next time it's drawn.
+        if let Some(hosted_root_index) = self.hosted_root_index(layer) {
+            let hosted_root = &mut self.hosted_roots[hosted_root_index];
+            if let HostTarget::Export(ref mut export) = hosted_root.target {
+                export.size = geometry_component[layer].bounds.round_out().size.to_u32();
+            }
+        }
+
         if let Some(native_component) = self.native_component.get_mut(layer) {
-            if native_component.framebuffer.is_some() {
-                let LayerFramebuffer {
-                    mut framebuffer,
-                    size,
-                    ..
-                } = native_component.framebuffer.as_ref().unwrap();
-                if *size != new_size.round().to_u32() {
-                    unsafe {
-                        gl::DeleteFramebuffers(1, &mut framebuffer);
-                    }
-                    native_component.framebuffer = None;
-                }
+            let size_changed = match native_component.framebuffer {
+                Some(ref framebuffer) => framebuffer.size != new_size.round().to_u32(),
+                None => false,
+            };
+            if size_changed {
+                native_component.framebuffer.take().unwrap().destroy();
             }
         }
 
@@ -334,7 +451,92 @@ impl crate::Backend for Backend {
 
     // Miscellaneous layer flags
 
-    fn set_layer_surface_options(&mut self, _: LayerId, _: &LayerMap<LayerSurfaceInfo>) {}
+    fn set_layer_surface_options(&mut self,
+                                 layer: LayerId,
+                                 surface_component: &LayerMap<LayerSurfaceInfo>) {
+        // Reallocate the framebuffer the next time the layer is bound if its format changed.
+        let native_component = &mut self.native_component[layer];
+        let new_surface_options = surface_component[layer].options;
+        let options_changed = match native_component.framebuffer {
+            Some(ref framebuffer) => framebuffer.surface_options != new_surface_options,
+            None => false,
+        };
+        if options_changed {
+            native_component.framebuffer.take().unwrap().destroy();
+        }
+    }
+
+    // Effects
+
+    fn set_layer_effects(&mut self,
+                         layer: LayerId,
+                         old_effects: &LayerEffects,
+                         tree_component: &LayerMap<LayerTreeInfo>,
+                         geometry_component: &LayerMap<LayerGeometryInfo>,
+                         effects_component: &LayerMap<LayerEffectsInfo>) {
+        *self.effects_component.get_mut_default(layer) = effects_component[layer].effects;
+
+        // Invalidation spreads the rect by the new effects, so this covers both.
+        let bounds = geometry_component.get(layer).map_or(Rect::zero(), |info| info.bounds);
+        let rect = effects::painted_rect(old_effects, &Rect::new(Point2D::zero(), bounds.size));
+        self.invalidate_layer(layer, &rect, tree_component, geometry_component);
+    }
+
+    fn set_layer_mask(&mut self,
+                      layer: LayerId,
+                      tree_component: &LayerMap<LayerTreeInfo>,
+                      geometry_component: &LayerMap<LayerGeometryInfo>,
+                      effects_component: &LayerMap<LayerEffectsInfo>) {
+        // Masked layers are drawn through offscreen framebuffers like layers with effects, so
+        // make sure that they have some.
+        self.effects_component.get_mut_default(layer);
+        self.mask_component.remove_if_present(layer);
+        if let Some(mask) = effects_component[layer].mask {
+            self.mask_component.add(layer, mask);
+        }
+
+        let bounds = geometry_component.get(layer).map_or(Rect::zero(), |info| info.bounds);
+        let rect = Rect::new(Point2D::zero(), bounds.size);
+        self.invalidate_layer(layer, &rect, tree_component, geometry_component);
+    }
+
+    // Scrolling
+
+    fn set_layer_content_size(&mut self, _: LayerId, _: &LayerMap<LayerScrollInfo>) {}
+
+    fn set_layer_content_offset(&mut self,
+                                layer: LayerId,
+                                tree_component: &LayerMap<LayerTreeInfo>,
+                                _: &LayerMap<LayerContainerInfo>,
+                                geometry_component: &LayerMap<LayerGeometryInfo>,
+                                scroll_component: &LayerMap<LayerScrollInfo>) {
+        // Scrolling only changes where the children are drawn, so nothing needs to be redrawn
+        // into their framebuffers.
+        self.flings.stop(layer);
+        self.scroll_component[layer] = scroll_component[layer].content_offset;
+
+        let bounds = geometry_component.get(layer).map_or(Rect::zero(), |info| info.bounds);
+        let rect = Rect::new(Point2D::zero(), bounds.size);
+        self.invalidate_layer(layer, &rect, tree_component, geometry_component);
+    }
+
+    fn start_fling(&mut self,
+                   layer: LayerId,
+                   velocity: &Vector2D<f32>,
+                   promise: &Promise<Vector2D<f32>>,
+                   geometry_component: &LayerMap<LayerGeometryInfo>,
+                   scroll_component: &LayerMap<LayerScrollInfo>)
+                   -> Result<(), ()> {
+        // The fling advances on our own frame clock in `run_animation_frame`, as well as whenever
+        // a transaction ends.
+        let bounds_size = geometry_component.get(layer).map_or(Size2D::zero(), |info| {
+            info.bounds.size
+        });
+        let max_offset = fling::max_offset(&bounds_size, &scroll_component[layer].content_size);
+        let fling = Fling::new(&self.scroll_component[layer], velocity, &max_offset);
+        self.flings.start(layer, fling, promise);
+        Ok(())
+    }
 
     // OpenGL content binding
 
@@ -345,65 +547,16 @@ impl crate::Backend for Backend {
                                 surface_component: &LayerMap<LayerSurfaceInfo>)
                                 -> Result<GLContextLayerBinding, ()> {
         let native_component = &mut self.native_component[layer];
+        if native_component.import.is_some() || native_component.video.is_some() {
+            // Only the exporting process can draw into an imported layer, and video layers are
+            // updated with `present_video_frame()`.
+            return Err(())
+        }
 
         if native_component.framebuffer.is_none() {
-            let mut framebuffer = LayerFramebuffer {
-                color_texture: 0,
-                depth_stencil_renderbuffer: None,
-                framebuffer: 0,
-                size: geometry_component[layer].bounds.round_out().size.to_u32(),
-                surface_options: surface_component[layer].options,
-            };
-
-            unsafe {
-                // Create color texture.
-                gl::GenTextures(1, &mut framebuffer.color_texture);
-                gl::ActiveTexture(gl::TEXTURE0);
-                gl::BindTexture(gl::TEXTURE_2D, framebuffer.color_texture);
-                gl::TexImage2D(gl::TEXTURE_2D,
-                               0,
-                               gl::RGBA as GLint,
-                               framebuffer.size.width as GLint,
-                               framebuffer.size.height as GLint,
-                               0,
-                               gl::RGBA,
-                               gl::UNSIGNED_BYTE,
-                               ptr::null());
-                gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as GLint);
-                gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as GLint);
-                gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as GLint);
-                gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as GLint);
-
-                // Create depth/stencil renderbuffer, if necessary.
-                if framebuffer.surface_options
-                              .intersects(SurfaceOptions::DEPTH | SurfaceOptions::STENCIL) {
-                    let mut renderbuffer = 0;
-                    gl::GenRenderbuffers(1, &mut renderbuffer);
-                    gl::BindRenderbuffer(gl::RENDERBUFFER, renderbuffer);
-                    gl::RenderbufferStorage(gl::RENDERBUFFER,
-                                            gl::DEPTH24_STENCIL8,
-                                            framebuffer.size.width as GLint,
-                                            framebuffer.size.height as GLint);
-                    framebuffer.depth_stencil_renderbuffer = Some(renderbuffer);
-                }
-
-                // Create FBO.
-                gl::GenFramebuffers(1, &mut framebuffer.framebuffer);
-                gl::BindFramebuffer(gl::FRAMEBUFFER, framebuffer.framebuffer);
-                gl::FramebufferTexture2D(gl::FRAMEBUFFER,
-                                         gl::COLOR_ATTACHMENT0,
-                                         gl::TEXTURE_2D,
-                                         framebuffer.color_texture,
-                                         0);
-                if let Some(renderbuffer) = framebuffer.depth_stencil_renderbuffer {
-                    gl::FramebufferRenderbuffer(gl::FRAMEBUFFER,
-                                                gl::DEPTH_STENCIL_ATTACHMENT,
-                                                gl::RENDERBUFFER,
-                                                renderbuffer);
-                }
-            }
-
-            native_component.framebuffer = Some(framebuffer);
+            let size = geometry_component[layer].bounds.round_out().size.to_u32();
+            let surface_options = surface_component[layer].options;
+            native_component.framebuffer = Some(LayerFramebuffer::new(&size, surface_options)?);
         }
 
         let framebuffer = native_component.framebuffer.as_ref().unwrap().framebuffer;
@@ -424,6 +577,10 @@ impl crate::Backend for Backend {
                           tree_component: &LayerMap<LayerTreeInfo>,
                           geometry_component: &LayerMap<LayerGeometryInfo>)
                           -> Result<(), ()> {
+        if let Some(ref framebuffer) = self.native_component[binding.layer].framebuffer {
+            framebuffer.resolve();
+        }
+
         unsafe {
             gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
         }
@@ -433,69 +590,108 @@ impl crate::Backend for Backend {
         Ok(())
     }
 
+    // Video
+
+    fn add_video_layer(&mut self, layer: LayerId) {
+        self.native_component.add(layer, LayerNativeInfo {
+            framebuffer: None,
+            import: None,
+            video: Some(LayerVideo {
+                upload_textures: vec![],
+                frame: None,
+            }),
+        });
+    }
+
+    fn present_video_frame(&mut self,
+                           layer: LayerId,
+                           frame: &VideoFrame,
+                           tree_component: &LayerMap<LayerTreeInfo>,
+                           geometry_component: &LayerMap<LayerGeometryInfo>)
+                           -> Result<(), ()> {
+        {
+            let video = self.native_component[layer].video.as_mut().ok_or(())?;
+            let mut textures = vec![];
+            for (plane_index, plane) in frame.planes.iter().enumerate() {
+                match *plane {
+                    VideoPlane::Texture(texture) => textures.push(texture),
+                    VideoPlane::Memory { data, stride } => {
+                        textures.push(video.upload_plane(frame, plane_index, data, stride));
+                    }
+                }
+            }
+
+            video.frame = Some(LayerVideoFrame {
+                format: frame.format,
+                conversion: Conversion::new(frame.color_matrix, frame.color_range),
+                textures,
+            });
+        }
+
+        if let Some(geometry_info) = geometry_component.get(layer) {
+            let rect = Rect::new(Point2D::zero(), geometry_info.bounds.size);
+            self.invalidate_layer(layer, &rect, tree_component, geometry_component);
+        }
+        Ok(())
+    }
+
     // Screenshots
 
     fn screenshot_hosted_layer(&mut self,
                                root_layer: LayerId,
-                               render_promise: &Promise<()>,
+                               _: &Promise<()>,
                                tree_component: &LayerMap<LayerTreeInfo>,
                                _: &LayerMap<LayerContainerInfo>,
                                geometry_component: &LayerMap<LayerGeometryInfo>,
                                _: &LayerMap<LayerSurfaceInfo>)
-                               -> Promise<RgbaImage> {
+                               -> Promise<RgbaImage, ScreenshotError> {
         let promise = Promise::new();
 
-        let mut bounds = Rect::new(Point2D::zero(), geometry_component[root_layer].bounds.size);
-        let mut layer = root_layer;
-        loop {
-            bounds.origin += geometry_component[layer].bounds.origin.to_vector();
-            match tree_component.get(layer) {
-                Some(LayerTreeInfo { parent: LayerParent::Layer(parent), .. }) => layer = *parent,
-                Some(_) | None => break,
-            }
-        }
+        // Make sure the root gets rendered this transaction, so that there's something to read
+        // back.
+        let bounds = geometry_component[root_layer].bounds;
+        self.invalidate_layer(root_layer,
+                              &Rect::new(Point2D::zero(), bounds.size),
+                              tree_component,
+                              geometry_component);
 
-        let screenshot_info = ScreenshotInfo {
-            framebuffer: self.connection.default_framebuffer(),
+        self.pending_screenshots.push(PendingScreenshot {
+            root_layer,
             bounds: bounds.round().to_u32(),
             promise: promise.clone(),
-        };
-
-        render_promise.then(Box::new(move |()| {
-            unsafe {
-                gl::BindFramebuffer(gl::FRAMEBUFFER, screenshot_info.framebuffer);
-                let bounds = screenshot_info.bounds;
-                let (width, height) = (bounds.size.width as usize, bounds.size.height as usize);
-                let mut pixels = vec![0; width * height * 4];
-                gl::ReadPixels(bounds.origin.x as GLint,
-                               bounds.origin.y as GLint,
-                               bounds.size.width as GLint,
-                               bounds.size.height as GLint,
-                               gl::RGBA,
-                               gl::UNSIGNED_BYTE,
-                               pixels.as_mut_ptr() as *mut _);
+        });
 
-                // Flip vertically.
-                for y0 in 0..(height / 2) {
-                    let (start0, start1) = (y0 * width * 4, (height - y0 - 1) * width * 4);
-                    for offset in 0..(width * 4) {
-                        pixels.swap(start0 + offset, start1 + offset);
-                    }
-                }
+        promise
+    }
 
-                screenshot_info.promise.resolve(RgbaImage::from_vec(bounds.size.width,
-                                                                    bounds.size.height,
-                                                                    pixels).unwrap());
-            }
-        }));
+    // Event dispatch
 
-        return promise;
+    fn pump_events(&mut self) -> Result<(), ()> {
+        // Our promises are settled synchronously, from `end_transaction` or
+        // `run_animation_frame`, so there are no events that could settle one.
+        Err(())
+    }
 
-        struct ScreenshotInfo {
-            framebuffer: GLuint,
-            bounds: Rect<u32>,
-            promise: Promise<RgbaImage>,
+    fn run_animation_frame(&mut self,
+                           tree_component: &LayerMap<LayerTreeInfo>,
+                           container_component: &LayerMap<LayerContainerInfo>,
+                           geometry_component: &LayerMap<LayerGeometryInfo>,
+                           surface_component: &LayerMap<LayerSurfaceInfo>)
+                           -> Result<(), ()> {
+        if self.flings.is_empty() {
+            return Err(())
         }
+
+        self.flings.wait_for_frame();
+        self.advance_flings(tree_component, geometry_component);
+        self.render_hosted_roots(tree_component,
+                                 container_component,
+                                 geometry_component,
+                                 surface_component);
+        self.flings.settle();
+
+        self.connection.make_current();
+        Ok(())
     }
 
     // `winit` integration
@@ -509,13 +705,57 @@ impl crate::Backend for Backend {
     fn host_layer_in_window(&mut self,
                             layer: LayerId,
                             tree_component: &LayerMap<LayerTreeInfo>,
-                            container_component: &LayerMap<LayerContainerInfo>,
+                            _: &LayerMap<LayerContainerInfo>,
                             geometry_component: &LayerMap<LayerGeometryInfo>)
                             -> Result<(), ()> {
-        unsafe {
-            self.host_layer(layer, (), tree_component, container_component, geometry_component);
-            Ok(())
-        }
+        self.add_hosted_root(layer, HostTarget::Connection, tree_component, geometry_component);
+        Ok(())
+    }
+
+    #[cfg(all(feature = "enable-winit", feature = "enable-glutin"))]
+    fn create_window(&mut self, window_builder: WindowBuilder, event_loop: &EventsLoop)
+                     -> Result<WindowId, ()> {
+        let window = self.connection.create_shared_window(window_builder, event_loop)?;
+        let window_id = window.window().ok_or(())?.id();
+        self.windows.push(window);
+        Ok(window_id)
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn adopt_window(&mut self, window: Window) -> Result<WindowId, Window> {
+        // We have no way to attach an OpenGL context to a window that someone else created, so
+        // this always fails. `create_window` is the supported way to get more windows here.
+        Err(window)
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn window_with_id(&self, window_id: WindowId) -> Option<&Window> {
+        self.connection.window().into_iter().chain(self.windows.iter().filter_map(|window| {
+            window.window()
+        })).find(|window| window.id() == window_id)
+    }
+
+    #[cfg(all(feature = "enable-winit", feature = "enable-glutin"))]
+    fn host_layer_in_window_with_id(&mut self,
+                                    layer: LayerId,
+                                    window_id: WindowId,
+                                    tree_component: &LayerMap<LayerTreeInfo>,
+                                    _: &LayerMap<LayerContainerInfo>,
+                                    geometry_component: &LayerMap<LayerGeometryInfo>)
+                                    -> Result<(), ()> {
+        let target = if self.connection.window().map(|window| window.id()) == Some(window_id) {
+            HostTarget::Connection
+        } else {
+            match self.windows.iter().position(|window| {
+                window.window().map(|window| window.id()) == Some(window_id)
+            }) {
+                None => return Err(()),
+                Some(window_index) => HostTarget::Window(window_index),
+            }
+        };
+
+        self.add_hosted_root(layer, target, tree_component, geometry_component);
+        Ok(())
     }
 
     #[cfg(all(feature = "enable-winit", not(feature = "enable-glutin")))]
@@ -533,19 +773,140 @@ impl crate::Backend for Backend {
                             -> Result<(), ()> {
         Err(())
     }
+
+    #[cfg(all(feature = "enable-winit", not(feature = "enable-glutin")))]
+    fn create_window(&mut self, _: WindowBuilder, _: &EventsLoop) -> Result<WindowId, ()> {
+        Err(())
+    }
+
+    #[cfg(all(feature = "enable-winit", not(feature = "enable-glutin")))]
+    fn host_layer_in_window_with_id(&mut self,
+                                    _: LayerId,
+                                    _: WindowId,
+                                    _: &LayerMap<LayerTreeInfo>,
+                                    _: &LayerMap<LayerContainerInfo>,
+                                    _: &LayerMap<LayerGeometryInfo>)
+                                    -> Result<(), ()> {
+        Err(())
+    }
 }
 
 impl Backend {
+    // Renders and presents each hosted root that has changed since it was last presented.
+    fn render_hosted_roots(&mut self,
+                           tree_component: &LayerMap<LayerTreeInfo>,
+                           container_component: &LayerMap<LayerContainerInfo>,
+                           geometry_component: &LayerMap<LayerGeometryInfo>,
+                           surface_component: &LayerMap<LayerSurfaceInfo>) {
+        for hosted_root_index in 0..self.hosted_roots.len() {
+            let mut dirty_rect = match self.hosted_roots[hosted_root_index].dirty_rect.take() {
+                None => continue,
+                Some(dirty_rect) => dirty_rect,
+            };
+
+            let hosted_layer = self.hosted_roots[hosted_root_index].layer;
+            if let Some(geometry_info) = geometry_component.get(hosted_layer) {
+                // The dirty rect is relative to the hosted root itself.
+                self.add_backdrop_damage(hosted_layer,
+                                         &-geometry_info.bounds.origin.to_vector(),
+                                         &mut dirty_rect,
+                                         tree_component,
+                                         container_component,
+                                         geometry_component);
+            }
+            let vertex_array = self.hosted_roots[hosted_root_index].vertex_array;
+
+            // Exported roots are drawn at the origin of their own framebuffer.
+            let mut origin = Point2D::zero();
+            if let HostTarget::Export(_) = self.hosted_roots[hosted_root_index].target {
+                if let Some(geometry_info) = geometry_component.get(hosted_layer) {
+                    origin = Point2D::zero() - geometry_info.bounds.origin.to_vector();
+                }
+            }
+
+            self.target(&self.hosted_roots[hosted_root_index].target).make_current();
+
+            let (default_framebuffer, default_framebuffer_size);
+            {
+                let target = self.target_mut(hosted_root_index);
+                target.prepare_to_draw();
+
+                default_framebuffer = target.default_framebuffer();
+                default_framebuffer_size = target.default_framebuffer_size();
+            }
+
+            self.prepare_effect_targets(hosted_root_index,
+                                        tree_component,
+                                        container_component,
+                                        geometry_component);
+
+            unsafe {
+                gl::BindVertexArray(vertex_array);
+                gl::UseProgram(self.program);
+                gl::BindFramebuffer(gl::FRAMEBUFFER, default_framebuffer);
+                gl::Viewport(0,
+                             0,
+                             default_framebuffer_size.width as GLint,
+                             default_framebuffer_size.height as GLint);
+
+                let framebuffer_size = default_framebuffer_size.to_f32();
+                self.render_layers(&[hosted_layer],
+                                   &origin,
+                                   &framebuffer_size,
+                                   &Rect::new(Point2D::zero(), framebuffer_size),
+                                   &self.hosted_roots[hosted_root_index].effect_targets,
+                                   tree_component,
+                                   container_component,
+                                   geometry_component,
+                                   surface_component);
+
+                gl::Disable(gl::SCISSOR_TEST);
+                gl::Disable(gl::DEPTH_TEST);
+                gl::DepthMask(gl::TRUE);
+            }
+
+            // Read back any screenshots of this root before the buffers are swapped.
+            self.take_screenshots(hosted_layer, default_framebuffer, &default_framebuffer_size);
+
+            unsafe {
+                gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+            }
+
+            self.target_mut(hosted_root_index).present(&dirty_rect);
+        }
+    }
+
+    // Moves each layer that's being flung to where it should be by now.
+    fn advance_flings(&mut self,
+                      tree_component: &LayerMap<LayerTreeInfo>,
+                      geometry_component: &LayerMap<LayerGeometryInfo>) {
+        for (layer, offset) in self.flings.advance() {
+            self.scroll_component[layer] = offset;
+
+            let bounds = geometry_component.get(layer).map_or(Rect::zero(), |info| info.bounds);
+            let rect = Rect::new(Point2D::zero(), bounds.size);
+            self.invalidate_layer(layer, &rect, tree_component, geometry_component);
+        }
+    }
+
     fn invalidate_layer(&mut self,
                         layer: LayerId,
                         dirty_rect: &Rect<f32>,
                         tree_component: &LayerMap<LayerTreeInfo>,
                         geometry_component: &LayerMap<LayerGeometryInfo>) {
+        // Effects spread changes beyond the rect that changed.
+        let dirty_rect = &self.painted_rect(layer, dirty_rect);
+
         if let Some(tree_info) = tree_component.get(layer) {
             match tree_info.parent {
                 LayerParent::NativeHost => {
-                    match self.dirty_rect {
-                        None => self.dirty_rect = Some(*dirty_rect),
+                    let hosted_root_index = match self.hosted_root_index(layer) {
+                        None => return,
+                        Some(hosted_root_index) => hosted_root_index,
+                    };
+                    let hosted_root = &mut self.hosted_roots[hosted_root_index];
+                    match hosted_root.dirty_rect {
+                        None => hosted_root.dirty_rect = Some(*dirty_rect),
                         Some(ref mut dirty_rect_ref) => {
                             *dirty_rect_ref = dirty_rect.union(dirty_rect_ref)
                         }
@@ -554,151 +915,1839 @@ impl Backend {
                 LayerParent::Layer(parent) => {
                     let parent_origin = geometry_component[layer].bounds.origin.to_vector();
                     let dirty_rect = dirty_rect.translate(&parent_origin);
-                    self.invalidate_layer(parent, &dirty_rect, tree_component, geometry_component)
+                    self.invalidate_layer_content(parent,
+                                                  &dirty_rect,
+                                                  tree_component,
+                                                  geometry_component)
+                }
+                // Masks are positioned relative to the layers they mask, but they aren't part of
+                // their content, so they aren't scrolled.
+                LayerParent::Mask(masked_layer) => {
+                    let masked_origin = geometry_component[layer].bounds.origin.to_vector();
+                    let dirty_rect = dirty_rect.translate(&masked_origin);
+                    self.invalidate_layer(masked_layer,
+                                          &dirty_rect,
+                                          tree_component,
+                                          geometry_component)
                 }
             }
         }
     }
 
-    fn render_opaque_layer_subtree(&self,
-                                   layer: LayerId,
-                                   origin: &Point2D<f32>,
-                                   next_depth_value: &mut f32,
-                                   tree_component: &LayerMap<LayerTreeInfo>,
-                                   container_component: &LayerMap<LayerContainerInfo>,
-                                   geometry_component: &LayerMap<LayerGeometryInfo>,
-                                   surface_component: &LayerMap<LayerSurfaceInfo>) {
-        let bounds = geometry_component[layer].bounds;
-
-        // If this is a container layer, don't render anything; just recurse.
-        if let Some(container_info) = container_component.get(layer) {
-            let new_origin = *origin + bounds.origin.to_vector();
-            let mut maybe_kid = container_info.first_child;
-            while let Some(kid) = maybe_kid {
-                self.render_opaque_layer_subtree(kid,
-                                                 &new_origin,
-                                                 next_depth_value,
-                                                 tree_component,
-                                                 container_component,
-                                                 geometry_component,
-                                                 surface_component);
-                maybe_kid = tree_component[kid].next_sibling;
-            }
-            return
+    // Invalidates a rect in the coordinate system that the children of a container layer are
+    // positioned in, which scroll layers shift by their content offset and clip to their bounds.
+    fn invalidate_layer_content(&mut self,
+                                layer: LayerId,
+                                dirty_rect: &Rect<f32>,
+                                tree_component: &LayerMap<LayerTreeInfo>,
+                                geometry_component: &LayerMap<LayerGeometryInfo>) {
+        let mut dirty_rect = *dirty_rect;
+        if let Some(content_offset) = self.scroll_component.get(layer) {
+            let bounds = geometry_component.get(layer).map_or(Rect::zero(), |info| info.bounds);
+            let clip_rect = Rect::new(Point2D::zero(), bounds.size);
+            dirty_rect = match dirty_rect.translate(&-*content_offset).intersection(&clip_rect) {
+                None => return,
+                Some(dirty_rect) => dirty_rect,
+            };
         }
+        self.invalidate_layer(layer, &dirty_rect, tree_component, geometry_component)
+    }
 
-        // Assign a depth value.
-        let depth = *next_depth_value;
-        *next_depth_value += DEPTH_QUANTUM;
+    // Returns where the top left corner of the content of a container layer is drawn, given where
+    // that of its parent is.
+    fn content_origin(&self, layer: LayerId, origin: &Point2D<f32>, bounds: &Rect<f32>)
+                      -> Point2D<f32> {
+        let content_offset = self.scroll_component.get(layer).cloned().unwrap_or(Vector2D::zero());
+        *origin + bounds.origin.to_vector() - content_offset
+    }
+
+    // Returns the part of a clip rect that a layer leaves its children to draw in. Scroll layers
+    // clip their children to their bounds; other layers don't clip them.
+    fn content_clip_rect(&self,
+                         layer: LayerId,
+                         origin: &Point2D<f32>,
+                         bounds: &Rect<f32>,
+                         clip_rect: &Rect<f32>)
+                         -> Rect<f32> {
+        if !self.scroll_component.has(layer) {
+            return *clip_rect
+        }
+        bounds.translate(&origin.to_vector()).intersection(clip_rect).unwrap_or(Rect::zero())
+    }
+
+    // Returns the effects of the layer, if it has any. Masked layers always have effects, even
+    // if they're empty.
+    fn layer_effects(&self, layer: LayerId) -> Option<&LayerEffects> {
+        self.effects_component.get(layer).filter(|effects| {
+            !effects.is_empty() || self.mask_component.has(layer)
+        })
+    }
+
+    // Returns the area, in the layer's coordinate system, that a rect of its content paints
+    // once its effects are applied.
+    fn painted_rect(&self, layer: LayerId, rect: &Rect<f32>) -> Rect<f32> {
+        match self.layer_effects(layer) {
+            Some(effects) => effects::painted_rect(effects, rect),
+            None => *rect,
+        }
+    }
+
+    // Renders sibling layer trees into the bound framebuffer, later ones above earlier ones, and
+    // clipped to a rect of it.
+    //
+    // NB: This leaves the depth test, blending, and the scissor test enabled, as they are for
+    // transparent layers.
+    unsafe fn render_layers(&self,
+                            layers: &[LayerId],
+                            origin: &Point2D<f32>,
+                            framebuffer_size: &Size2D<f32>,
+                            clip_rect: &Rect<f32>,
+                            effect_targets: &LayerMap<EffectTarget>,
+                            tree_component: &LayerMap<LayerTreeInfo>,
+                            container_component: &LayerMap<LayerContainerInfo>,
+                            geometry_component: &LayerMap<LayerGeometryInfo>,
+                            surface_component: &LayerMap<LayerSurfaceInfo>) {
+        // Later siblings are stacked above earlier ones, and they get greater depth values.
+        gl::Disable(gl::SCISSOR_TEST);
+        gl::ClearDepth(0.0);
+        gl::ClearStencil(0);
+        gl::Clear(gl::DEPTH_BUFFER_BIT | gl::STENCIL_BUFFER_BIT);
+
+        gl::DepthFunc(gl::GEQUAL);
+        gl::DepthMask(gl::TRUE);
+        gl::Enable(gl::DEPTH_TEST);
+        gl::Disable(gl::BLEND);
+
+        // A backdrop blur reads what's beneath it, so opaque layers above the first one can't be
+        // drawn early. They're drawn in order with the transparent layers instead.
+        let mut depth = 0.0;
+        let mut opaque_depth_limit = None;
+        for &layer in layers {
+            opaque_depth_limit = self.first_backdrop_depth(layer,
+                                                           &mut depth,
+                                                           tree_component,
+                                                           container_component);
+            if opaque_depth_limit.is_some() {
+                break
+            }
+        }
+        let opaque_depth_limit = opaque_depth_limit.unwrap_or(std::f32::INFINITY);
+
+        depth = 0.0;
+        for &layer in layers {
+            self.render_opaque_layer_subtree(layer,
+                                             origin,
+                                             framebuffer_size,
+                                             clip_rect,
+                                             &mut depth,
+                                             opaque_depth_limit,
+                                             tree_component,
+                                             container_component,
+                                             geometry_component,
+                                             surface_component);
+        }
+
+        // Draw transparent layers back to front, hiding the parts of them that opaque layers
+        // cover.
+        gl::DepthMask(gl::FALSE);
+        gl::BlendEquation(gl::FUNC_ADD);
+        gl::BlendFunc(gl::ONE, gl::ONE_MINUS_SRC_ALPHA);
+        gl::Enable(gl::BLEND);
+
+        depth = 0.0;
+        for &layer in layers {
+            self.render_transparent_layer_subtree(layer,
+                                                  origin,
+                                                  framebuffer_size,
+                                                  clip_rect,
+                                                  &mut depth,
+                                                  opaque_depth_limit,
+                                                  effect_targets,
+                                                  tree_component,
+                                                  container_component,
+                                                  geometry_component,
+                                                  surface_component);
+        }
+    }
+
+    // Returns the depth value of the first layer, in drawing order, that blurs its backdrop,
+    // assigning depth values the same way that the rendering passes do.
+    fn first_backdrop_depth(&self,
+                            layer: LayerId,
+                            next_depth_value: &mut f32,
+                            tree_component: &LayerMap<LayerTreeInfo>,
+                            container_component: &LayerMap<LayerContainerInfo>)
+                            -> Option<f32> {
+        if let Some(effects) = self.layer_effects(layer) {
+            let depth = *next_depth_value;
+            *next_depth_value += DEPTH_QUANTUM;
+            return if effects.backdrop_blur_radius > 0.0 { Some(depth) } else { None }
+        }
+
+        if let Some(container_info) = container_component.get(layer) {
+            let mut maybe_kid = container_info.first_child;
+            while let Some(kid) = maybe_kid {
+                let depth = self.first_backdrop_depth(kid,
+                                                      next_depth_value,
+                                                      tree_component,
+                                                      container_component);
+                if depth.is_some() {
+                    return depth
+                }
+                maybe_kid = tree_component[kid].next_sibling;
+            }
+            return None
+        }
+
+        *next_depth_value += DEPTH_QUANTUM;
+        None
+    }
+
+    // Backdrop blurs spread changes beneath a layer across its bounds, so this extends the dirty
+    // rect of a hosted root to cover the layers whose backdrops it reaches.
+    //
+    // `offset` maps the coordinate system of the layer's parent to that of the hosted root.
+    fn add_backdrop_damage(&self,
+                           layer: LayerId,
+                           offset: &Vector2D<f32>,
+                           dirty_rect: &mut Rect<f32>,
+                           tree_component: &LayerMap<LayerTreeInfo>,
+                           container_component: &LayerMap<LayerContainerInfo>,
+                           geometry_component: &LayerMap<LayerGeometryInfo>) {
+        let bounds = match geometry_component.get(layer) {
+            None => return,
+            Some(geometry_info) => geometry_info.bounds,
+        };
+
+        if let Some(effects) = self.layer_effects(layer) {
+            if effects.backdrop_blur_radius > 0.0 {
+                let extent = effects::blur_extent(effects.backdrop_blur_radius) as f32;
+                let rect = bounds.translate(offset);
+                if rect.inflate(extent, extent).intersects(dirty_rect) {
+                    *dirty_rect = dirty_rect.union(&rect);
+                }
+            }
+        }
+
+        if let Some(container_info) = container_component.get(layer) {
+            let offset = self.content_origin(layer, &offset.to_point(), &bounds).to_vector();
+            let mut maybe_kid = container_info.first_child;
+            while let Some(kid) = maybe_kid {
+                self.add_backdrop_damage(kid,
+                                         &offset,
+                                         dirty_rect,
+                                         tree_component,
+                                         container_component,
+                                         geometry_component);
+                maybe_kid = tree_component[kid].next_sibling;
+            }
+        }
+    }
+
+    fn render_opaque_layer_subtree(&self,
+                                   layer: LayerId,
+                                   origin: &Point2D<f32>,
+                                   framebuffer_size: &Size2D<f32>,
+                                   clip_rect: &Rect<f32>,
+                                   next_depth_value: &mut f32,
+                                   opaque_depth_limit: f32,
+                                   tree_component: &LayerMap<LayerTreeInfo>,
+                                   container_component: &LayerMap<LayerContainerInfo>,
+                                   geometry_component: &LayerMap<LayerGeometryInfo>,
+                                   surface_component: &LayerMap<LayerSurfaceInfo>) {
+        // Layers with effects are drawn as a whole, in the transparent pass.
+        if self.layer_effects(layer).is_some() {
+            *next_depth_value += DEPTH_QUANTUM;
+            return
+        }
+
+        let bounds = geometry_component[layer].bounds;
+
+        // If this is a container layer, don't render anything; just recurse.
+        if let Some(container_info) = container_component.get(layer) {
+            let new_origin = self.content_origin(layer, origin, &bounds);
+            let new_clip_rect = self.content_clip_rect(layer, origin, &bounds, clip_rect);
+            let mut maybe_kid = container_info.first_child;
+            while let Some(kid) = maybe_kid {
+                self.render_opaque_layer_subtree(kid,
+                                                 &new_origin,
+                                                 framebuffer_size,
+                                                 &new_clip_rect,
+                                                 next_depth_value,
+                                                 opaque_depth_limit,
+                                                 tree_component,
+                                                 container_component,
+                                                 geometry_component,
+                                                 surface_component);
+                maybe_kid = tree_component[kid].next_sibling;
+            }
+            return
+        }
+
+        // Assign a depth value.
+        let depth = *next_depth_value;
+        *next_depth_value += DEPTH_QUANTUM;
+
+        // Only consider the layers of the appropriate opacity.
+        if depth >= opaque_depth_limit ||
+                !surface_component[layer].options.contains(SurfaceOptions::OPAQUE) {
+            return
+        }
+
+        self.render_layer(layer, origin, framebuffer_size, clip_rect, depth, geometry_component);
+    }
+
+    fn render_transparent_layer_subtree(&self,
+                                        layer: LayerId,
+                                        origin: &Point2D<f32>,
+                                        framebuffer_size: &Size2D<f32>,
+                                        clip_rect: &Rect<f32>,
+                                        next_depth_value: &mut f32,
+                                        opaque_depth_limit: f32,
+                                        effect_targets: &LayerMap<EffectTarget>,
+                                        tree_component: &LayerMap<LayerTreeInfo>,
+                                        container_component: &LayerMap<LayerContainerInfo>,
+                                        geometry_component: &LayerMap<LayerGeometryInfo>,
+                                        surface_component: &LayerMap<LayerSurfaceInfo>) {
+        if let Some(effects) = self.layer_effects(layer) {
+            let depth = *next_depth_value;
+            *next_depth_value += DEPTH_QUANTUM;
+            self.render_layer_with_effects(layer,
+                                           effects,
+                                           origin,
+                                           framebuffer_size,
+                                           clip_rect,
+                                           depth,
+                                           effect_targets,
+                                           tree_component,
+                                           container_component,
+                                           geometry_component,
+                                           surface_component);
+            return
+        }
+
+        let bounds = geometry_component[layer].bounds;
+
+        // If this is a container layer, don't render anything; just recurse.
+        if let Some(container_info) = container_component.get(layer) {
+            let new_origin = self.content_origin(layer, origin, &bounds);
+            let new_clip_rect = self.content_clip_rect(layer, origin, &bounds, clip_rect);
+            let mut maybe_kid = container_info.first_child;
+            while let Some(kid) = maybe_kid {
+                self.render_transparent_layer_subtree(kid,
+                                                      &new_origin,
+                                                      framebuffer_size,
+                                                      &new_clip_rect,
+                                                      next_depth_value,
+                                                      opaque_depth_limit,
+                                                      effect_targets,
+                                                      tree_component,
+                                                      container_component,
+                                                      geometry_component,
+                                                      surface_component);
+                maybe_kid = tree_component[kid].next_sibling;
+            }
+            return
+        }
+
+        // Assign the same depth value that the opaque pass did.
+        let depth = *next_depth_value;
+        *next_depth_value += DEPTH_QUANTUM;
+
+        // Only consider the layers of the appropriate opacity.
+        if depth < opaque_depth_limit &&
+                surface_component[layer].options.contains(SurfaceOptions::OPAQUE) {
+            return
+        }
+
+        self.render_layer(layer, origin, framebuffer_size, clip_rect, depth, geometry_component);
+    }
+
+    fn render_layer(&self,
+                    layer: LayerId,
+                    origin: &Point2D<f32>,
+                    framebuffer_size: &Size2D<f32>,
+                    clip_rect: &Rect<f32>,
+                    depth: f32,
+                    geometry_component: &LayerMap<LayerGeometryInfo>) {
+        unsafe {
+            set_clip_rect(clip_rect, framebuffer_size);
+        }
+
+        let (color_texture, surface_options) = match self.native_component[layer] {
+            LayerNativeInfo { framebuffer: Some(ref framebuffer), .. } => {
+                (framebuffer.color_texture, framebuffer.surface_options)
+            }
+            LayerNativeInfo { import: Some(LayerImport { texture: Some(texture), .. }), .. } => {
+                (texture, SurfaceOptions::empty())
+            }
+            LayerNativeInfo { video: Some(LayerVideo { frame: Some(ref frame), .. }), .. } => {
+                self.render_video_layer(layer,
+                                        frame,
+                                        origin,
+                                        framebuffer_size,
+                                        depth,
+                                        geometry_component);
+                return
+            }
+            _ => return,
+        };
+
+        // Sampling from an sRGB texture decodes to linear values, so encode them again.
+        let encode_srgb = surface_options.color_format() == Ok(ColorFormat::Srgb8Alpha8);
+
+        let bounds = geometry_component[layer].bounds;
+
+        unsafe {
+            // Set uniforms.
+            set_layer_transform(self.uniform_scale,
+                                self.uniform_translation,
+                                self.uniform_depth,
+                                &bounds,
+                                origin,
+                                framebuffer_size,
+                                depth);
+
+            // Bind texture.
+            gl::ActiveTexture(gl::TEXTURE0);
+            gl::BindTexture(gl::TEXTURE_2D, color_texture);
+            gl::Uniform1i(self.uniform_texture, 0);
+            gl::Uniform1i(self.uniform_encode_srgb, encode_srgb as GLint);
+            gl::Uniform1i(self.uniform_opaque,
+                          surface_options.contains(SurfaceOptions::OPAQUE) as GLint);
+
+            // Draw the layer.
+            gl::DrawArrays(gl::TRIANGLE_STRIP, 0, 4);
+        }
+    }
+
+    // Renders the layer and its descendants into an offscreen framebuffer, padded to make room
+    // for the effects, and then composites the blurred backdrop, the shadow, and the content.
+    fn render_layer_with_effects(&self,
+                                 layer: LayerId,
+                                 effects: &LayerEffects,
+                                 origin: &Point2D<f32>,
+                                 framebuffer_size: &Size2D<f32>,
+                                 clip_rect: &Rect<f32>,
+                                 depth: f32,
+                                 effect_targets: &LayerMap<EffectTarget>,
+                                 tree_component: &LayerMap<LayerTreeInfo>,
+                                 container_component: &LayerMap<LayerContainerInfo>,
+                                 geometry_component: &LayerMap<LayerGeometryInfo>,
+                                 surface_component: &LayerMap<LayerSurfaceInfo>) {
+        let target = match effect_targets.get(layer) {
+            None => return,
+            Some(target) => target,
+        };
+
+        let bounds = geometry_component[layer].bounds;
+        let content_rect = effects::content_rect(effects, &bounds);
+        let content_size = target.content.size.to_f32();
+        let full_rect = Rect::new(Point2D::zero(), content_size);
+
+        unsafe {
+            let mut outer_framebuffer = 0;
+            let mut outer_viewport = [0; 4];
+            gl::GetIntegerv(gl::DRAW_FRAMEBUFFER_BINDING, &mut outer_framebuffer);
+            gl::GetIntegerv(gl::VIEWPORT, outer_viewport.as_mut_ptr());
+
+            let bind_offscreen = |framebuffer: &LayerFramebuffer| {
+                gl::BindFramebuffer(gl::FRAMEBUFFER, framebuffer.framebuffer);
+                gl::Viewport(0,
+                             0,
+                             framebuffer.size.width as GLint,
+                             framebuffer.size.height as GLint);
+                gl::Disable(gl::DEPTH_TEST);
+                gl::Disable(gl::BLEND);
+                gl::Disable(gl::SCISSOR_TEST);
+            };
+            let bind_outer = || {
+                gl::BindFramebuffer(gl::FRAMEBUFFER, outer_framebuffer as GLuint);
+                gl::Viewport(outer_viewport[0],
+                             outer_viewport[1],
+                             outer_viewport[2],
+                             outer_viewport[3]);
+                gl::Enable(gl::DEPTH_TEST);
+                gl::Enable(gl::BLEND);
+                set_clip_rect(clip_rect, framebuffer_size);
+            };
+
+            // Each blur is separable: blur horizontally into the scratch framebuffer, and then
+            // vertically from there into the destination.
+            let horizontal = Vector2D::new(1.0 / content_size.width, 0.0);
+            let vertical = Vector2D::new(0.0, 1.0 / content_size.height);
+
+            // Copies what's beneath the padded content rect out of the outer framebuffer.
+            let framebuffer_height = framebuffer_size.height as GLint;
+            let copy_backdrop = |backdrop: &LayerFramebuffer| {
+                let framebuffer_rect = Rect::new(Point2D::zero(), framebuffer_size.to_i32());
+                let source_rect = content_rect.translate(&origin.to_vector()).round().to_i32();
+
+                bind_offscreen(backdrop);
+                gl::ClearColor(0.0, 0.0, 0.0, 0.0);
+                gl::Clear(gl::COLOR_BUFFER_BIT);
+                if let Some(clipped_rect) = source_rect.intersection(&framebuffer_rect) {
+                    // Flip both rects to OpenGL's bottom-left origin.
+                    let dest_rect = clipped_rect.translate(&-source_rect.origin.to_vector());
+                    let backdrop_height = backdrop.size.height as GLint;
+                    gl::BindFramebuffer(gl::READ_FRAMEBUFFER, outer_framebuffer as GLuint);
+                    gl::BlitFramebuffer(clipped_rect.min_x(),
+                                        framebuffer_height - clipped_rect.max_y(),
+                                        clipped_rect.max_x(),
+                                        framebuffer_height - clipped_rect.min_y(),
+                                        dest_rect.min_x(),
+                                        backdrop_height - dest_rect.max_y(),
+                                        dest_rect.max_x(),
+                                        backdrop_height - dest_rect.min_y(),
+                                        gl::COLOR_BUFFER_BIT,
+                                        gl::NEAREST);
+                }
+            };
+
+            // Everything beneath this layer has already been drawn, so copy it out of the outer
+            // framebuffer, as far as the blur reaches, and blur it back in within the bounds.
+            if effects.backdrop_blur_radius > 0.0 {
+                let backdrop = target.backdrop.as_ref().unwrap();
+                copy_backdrop(backdrop);
+
+                let kernel = effects::gaussian_kernel(effects.backdrop_blur_radius);
+                bind_offscreen(&target.scratch);
+                self.draw_blurred(backdrop.color_texture,
+                                  &horizontal,
+                                  &kernel,
+                                  None,
+                                  &full_rect,
+                                  &Point2D::zero(),
+                                  &content_size,
+                                  0.0);
+
+                // The blurred backdrop replaces what was there, so don't blend it.
+                bind_outer();
+                let backdrop_clip_rect = bounds.translate(&origin.to_vector())
+                                               .intersection(clip_rect)
+                                               .unwrap_or(Rect::zero());
+                set_clip_rect(&backdrop_clip_rect, framebuffer_size);
+                gl::Disable(gl::BLEND);
+                self.draw_blurred(target.scratch.color_texture,
+                                  &vertical,
+                                  &kernel,
+                                  None,
+                                  &content_rect,
+                                  origin,
+                                  framebuffer_size,
+                                  depth);
+                gl::Enable(gl::BLEND);
+            }
+
+            // Render the content with the top left corner of the padded rect at the origin.
+            bind_offscreen(&target.content);
+            gl::ClearColor(0.0, 0.0, 0.0, 0.0);
+            gl::Clear(gl::COLOR_BUFFER_BIT);
+            let content_origin = (-content_rect.origin.to_vector()).to_point();
+            match container_component.get(layer) {
+                None => {
+                    self.render_layer(layer,
+                                      &content_origin,
+                                      &content_size,
+                                      &full_rect,
+                                      0.0,
+                                      geometry_component)
+                }
+                Some(container_info) => {
+                    let mut kids = vec![];
+                    let mut maybe_kid = container_info.first_child;
+                    while let Some(kid) = maybe_kid {
+                        kids.push(kid);
+                        maybe_kid = tree_component[kid].next_sibling;
+                    }
+                    self.render_layers(&kids,
+                                       &self.content_origin(layer, &content_origin, &bounds),
+                                       &content_size,
+                                       &self.content_clip_rect(layer,
+                                                               &content_origin,
+                                                               &bounds,
+                                                               &full_rect),
+                                       effect_targets,
+                                       tree_component,
+                                       container_component,
+                                       geometry_component,
+                                       surface_component);
+                }
+            }
+
+            // Render the mask tree where it sits relative to the layer, and multiply the content
+            // by its alpha, before the shadow and blur are derived from the content.
+            if let (Some(&mask), Some(mask_framebuffer)) = (self.mask_component.get(layer),
+                                                            target.mask.as_ref()) {
+                bind_offscreen(mask_framebuffer);
+                gl::ClearColor(0.0, 0.0, 0.0, 0.0);
+                gl::Clear(gl::COLOR_BUFFER_BIT);
+                self.render_layers(&[mask],
+                                   &(content_origin + bounds.origin.to_vector()),
+                                   &content_size,
+                                   &full_rect,
+                                   effect_targets,
+                                   tree_component,
+                                   container_component,
+                                   geometry_component,
+                                   surface_component);
+
+                bind_offscreen(&target.content);
+                gl::BlendFunc(gl::ZERO, gl::SRC_ALPHA);
+                gl::Enable(gl::BLEND);
+                self.draw_blurred(mask_framebuffer.color_texture,
+                                  &vertical,
+                                  &[1.0],
+                                  None,
+                                  &full_rect,
+                                  &Point2D::zero(),
+                                  &content_size,
+                                  0.0);
+                gl::BlendFunc(gl::ONE, gl::ONE_MINUS_SRC_ALPHA);
+            }
+
+            if let Some(ref shadow) = effects.shadow {
+                let kernel = effects::gaussian_kernel(shadow.radius);
+                bind_offscreen(&target.scratch);
+                self.draw_blurred(target.content.color_texture,
+                                  &horizontal,
+                                  &kernel,
+                                  None,
+                                  &full_rect,
+                                  &Point2D::zero(),
+                                  &content_size,
+                                  0.0);
+                bind_outer();
+                self.draw_blurred(target.scratch.color_texture,
+                                  &vertical,
+                                  &kernel,
+                                  Some(shadow),
+                                  &content_rect.translate(&shadow.offset),
+                                  origin,
+                                  framebuffer_size,
+                                  depth);
+            }
+
+            // Blur the content in place, so that it can be blended like unblurred content.
+            if effects.blur_radius > 0.0 {
+                let kernel = effects::gaussian_kernel(effects.blur_radius);
+                bind_offscreen(&target.scratch);
+                self.draw_blurred(target.content.color_texture,
+                                  &horizontal,
+                                  &kernel,
+                                  None,
+                                  &full_rect,
+                                  &Point2D::zero(),
+                                  &content_size,
+                                  0.0);
+                bind_offscreen(&target.content);
+                self.draw_blurred(target.scratch.color_texture,
+                                  &vertical,
+                                  &kernel,
+                                  None,
+                                  &full_rect,
+                                  &Point2D::zero(),
+                                  &content_size,
+                                  0.0);
+            }
+
+            match blend_factors(effects.blend_mode) {
+                Some((src_rgb, dest_rgb, src_alpha, dest_alpha)) => {
+                    // A one-tap kernel copies the content as it is.
+                    bind_outer();
+                    gl::BlendFuncSeparate(src_rgb, dest_rgb, src_alpha, dest_alpha);
+                    self.draw_blurred(target.content.color_texture,
+                                      &vertical,
+                                      &[1.0],
+                                      None,
+                                      &content_rect,
+                                      origin,
+                                      framebuffer_size,
+                                      depth);
+                    gl::BlendFunc(gl::ONE, gl::ONE_MINUS_SRC_ALPHA);
+                }
+                None => {
+                    // Blending can't express this mode, so read what's beneath the content and
+                    // replace it with the blended result.
+                    let backdrop = target.backdrop.as_ref().unwrap();
+                    copy_backdrop(backdrop);
+                    bind_outer();
+                    gl::Disable(gl::BLEND);
+                    self.draw_blended(target.content.color_texture,
+                                      backdrop.color_texture,
+                                      effects.blend_mode,
+                                      &content_rect,
+                                      origin,
+                                      framebuffer_size,
+                                      depth);
+                    gl::Enable(gl::BLEND);
+                }
+            }
+        }
+    }
+
+    // Draws a texture into a rect of the bound framebuffer, combined with a copy of what's
+    // beneath the rect using a blend mode.
+    unsafe fn draw_blended(&self,
+                           texture: GLuint,
+                           backdrop_texture: GLuint,
+                           blend_mode: BlendMode,
+                           rect: &Rect<f32>,
+                           origin: &Point2D<f32>,
+                           framebuffer_size: &Size2D<f32>,
+                           depth: f32) {
+        let program = &self.blend_program;
+        gl::UseProgram(program.program);
+        set_layer_transform(program.uniform_scale,
+                            program.uniform_translation,
+                            program.uniform_depth,
+                            rect,
+                            origin,
+                            framebuffer_size,
+                            depth);
+
+        gl::ActiveTexture(gl::TEXTURE0);
+        gl::BindTexture(gl::TEXTURE_2D, texture);
+        gl::Uniform1i(program.uniform_source, 0);
+        gl::ActiveTexture(gl::TEXTURE1);
+        gl::BindTexture(gl::TEXTURE_2D, backdrop_texture);
+        gl::Uniform1i(program.uniform_backdrop, 1);
+        gl::Uniform1i(program.uniform_mode, blend_mode_index(blend_mode));
+
+        gl::DrawArrays(gl::TRIANGLE_STRIP, 0, 4);
+        gl::ActiveTexture(gl::TEXTURE0);
+        gl::UseProgram(self.program);
+    }
+
+    // Draws a texture, blurred along one axis, into a rect of the bound framebuffer. If a shadow
+    // is given, draws its color with the blurred alpha of the texture instead.
+    unsafe fn draw_blurred(&self,
+                           texture: GLuint,
+                           texel_step: &Vector2D<f32>,
+                           kernel: &[f32],
+                           shadow: Option<&DropShadow>,
+                           rect: &Rect<f32>,
+                           origin: &Point2D<f32>,
+                           framebuffer_size: &Size2D<f32>,
+                           depth: f32) {
+        let program = &self.blur_program;
+        gl::UseProgram(program.program);
+        set_layer_transform(program.uniform_scale,
+                            program.uniform_translation,
+                            program.uniform_depth,
+                            rect,
+                            origin,
+                            framebuffer_size,
+                            depth);
+
+        gl::ActiveTexture(gl::TEXTURE0);
+        gl::BindTexture(gl::TEXTURE_2D, texture);
+        gl::Uniform1i(program.uniform_texture, 0);
+        gl::Uniform2f(program.uniform_texel_step, texel_step.x, texel_step.y);
+        gl::Uniform1fv(program.uniform_weights, kernel.len() as GLint, kernel.as_ptr());
+        gl::Uniform1i(program.uniform_tap_count, kernel.len() as GLint);
+
+        match shadow {
+            None => gl::Uniform1i(program.uniform_shadow, gl::FALSE as GLint),
+            Some(shadow) => {
+                // The shader expects a premultiplied color.
+                let alpha = shadow.opacity.max(0.0).min(1.0) * shadow.color[3] as f32 / 255.0;
+                gl::Uniform1i(program.uniform_shadow, gl::TRUE as GLint);
+                gl::Uniform4f(program.uniform_shadow_color,
+                              shadow.color[0] as f32 / 255.0 * alpha,
+                              shadow.color[1] as f32 / 255.0 * alpha,
+                              shadow.color[2] as f32 / 255.0 * alpha,
+                              alpha);
+            }
+        }
+
+        gl::DrawArrays(gl::TRIANGLE_STRIP, 0, 4);
+        gl::UseProgram(self.program);
+    }
+
+    // Allocates offscreen framebuffers for the layers with effects in a hosted root's tree, and
+    // frees those of layers that have left it.
+    //
+    // NB: The context of the hosted root's target must be current, because framebuffer objects
+    // aren't shared between contexts.
+    fn prepare_effect_targets(&mut self,
+                              hosted_root_index: usize,
+                              tree_component: &LayerMap<LayerTreeInfo>,
+                              container_component: &LayerMap<LayerContainerInfo>,
+                              geometry_component: &LayerMap<LayerGeometryInfo>) {
+        let mut old_targets = mem::replace(&mut self.hosted_roots[hosted_root_index].effect_targets,
+                                           LayerMap::new());
+        let mut new_targets = LayerMap::new();
+
+        let mut layers_to_visit = vec![self.hosted_roots[hosted_root_index].layer];
+        while let Some(layer) = layers_to_visit.pop() {
+            if let Some(effects) = self.layer_effects(layer) {
+                let bounds = geometry_component[layer].bounds;
+                let size = effects::content_rect(effects, &bounds).size.to_u32();
+                // Empty framebuffers are incomplete, so always allocate at least one pixel.
+                let size = Size2D::new(size.width.max(1), size.height.max(1));
+                let backdrop = effects.backdrop_blur_radius > 0.0 ||
+                    blend_factors(effects.blend_mode).is_none();
+                let mask = self.mask_component.get(layer).cloned();
+
+                let mut target = None;
+                if old_targets.has(layer) {
+                    let mut old_target = old_targets.take(layer);
+                    if old_target.content.size == size &&
+                            old_target.backdrop.is_some() == backdrop &&
+                            old_target.mask.is_some() == mask.is_some() {
+                        target = Some(old_target);
+                    } else {
+                        old_target.destroy();
+                    }
+                }
+                if target.is_none() {
+                    target = EffectTarget::new(&size, backdrop, mask.is_some()).ok();
+                }
+                match target {
+                    Some(target) => new_targets.add(layer, target),
+                    None => warn!("Failed to allocate a framebuffer for effects on {:?}", layer),
+                }
+
+                // The mask tree may have effects of its own.
+                if let Some(mask) = mask {
+                    layers_to_visit.push(mask);
+                }
+            }
+
+            if let Some(container_info) = container_component.get(layer) {
+                let mut maybe_kid = container_info.first_child;
+                while let Some(kid) = maybe_kid {
+                    layers_to_visit.push(kid);
+                    maybe_kid = tree_component[kid].next_sibling;
+                }
+            }
+        }
+
+        for old_target in old_targets.0.iter_mut().filter_map(Option::as_mut) {
+            old_target.destroy();
+        }
+        self.hosted_roots[hosted_root_index].effect_targets = new_targets;
+    }
+
+    // Video layers are drawn with a program of their own, which converts YUV to RGB.
+    fn render_video_layer(&self,
+                          layer: LayerId,
+                          frame: &LayerVideoFrame,
+                          origin: &Point2D<f32>,
+                          framebuffer_size: &Size2D<f32>,
+                          depth: f32,
+                          geometry_component: &LayerMap<LayerGeometryInfo>) {
+        let program = &self.video_program;
+        let bounds = geometry_component[layer].bounds;
+
+        unsafe {
+            gl::UseProgram(program.program);
+            set_layer_transform(program.uniform_scale,
+                                program.uniform_translation,
+                                program.uniform_depth,
+                                &bounds,
+                                origin,
+                                framebuffer_size,
+                                depth);
+            gl::UniformMatrix3fv(program.uniform_color_matrix,
+                                 1,
+                                 gl::FALSE,
+                                 frame.conversion.column_major_matrix().as_ptr());
+            let offset = frame.conversion.offset;
+            gl::Uniform3f(program.uniform_color_offset, offset[0], offset[1], offset[2]);
+            gl::Uniform1i(program.uniform_interleaved_chroma,
+                          (frame.format == YUVFormat::NV12) as GLint);
+
+            // NV12 frames have no V plane, so bind the UV plane in its place.
+            let plane_uniforms = [program.uniform_y_texture,
+                                  program.uniform_u_texture,
+                                  program.uniform_v_texture];
+            for (plane_index, &uniform) in plane_uniforms.iter().enumerate() {
+                let texture = frame.textures[plane_index.min(frame.textures.len() - 1)];
+                gl::ActiveTexture(gl::TEXTURE0 + plane_index as GLuint);
+                gl::BindTexture(gl::TEXTURE_2D, texture);
+                gl::Uniform1i(uniform, plane_index as GLint);
+            }
+
+            gl::DrawArrays(gl::TRIANGLE_STRIP, 0, 4);
+
+            gl::ActiveTexture(gl::TEXTURE0);
+            gl::UseProgram(self.program);
+        }
+    }
+
+    fn add_hosted_root(&mut self,
+                       layer: LayerId,
+                       target: HostTarget,
+                       tree_component: &LayerMap<LayerTreeInfo>,
+                       geometry_component: &LayerMap<LayerGeometryInfo>) {
+        debug_assert!(self.hosted_root_index(layer).is_none());
+        debug_assert!(match target {
+            HostTarget::Native(_) | HostTarget::Export(_) => true,
+            HostTarget::Connection | HostTarget::Window(_) => {
+                self.hosted_roots.iter().all(|hosted_root| hosted_root.target != target)
+            }
+        });
+
+        // Vertex arrays aren't shared between contexts, so each target needs its own.
+        self.target(&target).make_current();
+        let vertex_array = self.create_vertex_array();
+        self.connection.make_current();
+
+        self.hosted_roots.push(HostedRoot {
+            layer,
+            target,
+            dirty_rect: None,
+            vertex_array,
+            effect_targets: LayerMap::new(),
+        });
+
+        if let Some(geometry_info) = geometry_component.get(layer) {
+            self.invalidate_layer(layer,
+                                  &Rect::new(Point2D::zero(), geometry_info.bounds.size),
+                                  tree_component,
+                                  geometry_component);
+        }
+    }
+
+    fn hosted_root_index(&self, layer: LayerId) -> Option<usize> {
+        self.hosted_roots.iter().position(|hosted_root| hosted_root.layer == layer)
+    }
+
+    // Returns the interface whose context the target renders in.
+    fn target<'a>(&'a self, target: &'a HostTarget) -> &'a dyn GLInterface {
+        match *target {
+            HostTarget::Connection | HostTarget::Export(_) => &*self.connection,
+            HostTarget::Window(window_index) => &*self.windows[window_index],
+            HostTarget::Native(ref target) => &**target,
+        }
+    }
+
+    // Returns the interface that the target renders into. For exported roots, this is an
+    // offscreen framebuffer in the connection's context.
+    fn target_mut(&mut self, hosted_root_index: usize) -> &mut dyn GLInterface {
+        match self.hosted_roots[hosted_root_index].target {
+            HostTarget::Connection => &mut *self.connection,
+            HostTarget::Window(window_index) => &mut *self.windows[window_index],
+            HostTarget::Native(ref mut target) => &mut **target,
+            HostTarget::Export(ref mut target) => target,
+        }
+    }
+
+    // Uploads any new images that exporting processes have written since the last transaction.
+    fn update_imported_layers(&mut self,
+                              tree_component: &LayerMap<LayerTreeInfo>,
+                              geometry_component: &LayerMap<LayerGeometryInfo>) {
+        let mut changed_layers = vec![];
+        for (layer_index, native_component) in self.native_component.0.iter_mut().enumerate() {
+            let import = match *native_component {
+                Some(LayerNativeInfo { import: Some(ref mut import), .. }) => import,
+                _ => continue,
+            };
+            match import.shared_image.read_if_changed() {
+                Ok(None) => {}
+                Ok(Some(image)) => {
+                    import.upload(image);
+                    changed_layers.push(LayerId(layer_index as u32));
+                }
+                Err(error) => warn!("Failed to read imported layer {}: {}", layer_index, error),
+            }
+        }
+
+        for layer in changed_layers {
+            if let Some(geometry_info) = geometry_component.get(layer) {
+                let rect = Rect::new(Point2D::zero(), geometry_info.bounds.size);
+                self.invalidate_layer(layer, &rect, tree_component, geometry_component);
+            }
+        }
+    }
+
+    // NB: The context of the target that `root_layer` is hosted in must be current.
+    fn take_screenshots(&mut self,
+                        root_layer: LayerId,
+                        framebuffer: GLuint,
+                        framebuffer_size: &Size2D<u32>) {
+        let mut screenshot_index = 0;
+        while screenshot_index < self.pending_screenshots.len() {
+            if self.pending_screenshots[screenshot_index].root_layer != root_layer {
+                screenshot_index += 1;
+                continue
+            }
+
+            let screenshot = self.pending_screenshots.remove(screenshot_index);
+            unsafe {
+                gl::BindFramebuffer(gl::FRAMEBUFFER, framebuffer);
+                let bounds = screenshot.bounds;
+                let (width, height) = (bounds.size.width as usize, bounds.size.height as usize);
+                let mut pixels = vec![0; width * height * 4];
+                gl::ReadPixels(bounds.origin.x as GLint,
+                               framebuffer_size.height as GLint - bounds.max_y() as GLint,
+                               bounds.size.width as GLint,
+                               bounds.size.height as GLint,
+                               gl::RGBA,
+                               gl::UNSIGNED_BYTE,
+                               pixels.as_mut_ptr() as *mut _);
+
+                flip_vertically(&mut pixels, width, height);
+
+                match RgbaImage::from_vec(bounds.size.width, bounds.size.height, pixels) {
+                    Some(image) => screenshot.promise.resolve(image),
+                    None => screenshot.promise.reject(ScreenshotError::ReadbackFailed),
+                }
+            }
+        }
+    }
+
+    // NB: The context that the vertex array is to belong to must be current.
+    fn create_vertex_array(&self) -> GLuint {
+        let mut vertex_array = 0;
+        unsafe {
+            gl::GenVertexArrays(1, &mut vertex_array);
+            gl::BindVertexArray(vertex_array);
+            gl::BindBuffer(gl::ARRAY_BUFFER, self.vertex_buffer);
+
+            gl::VertexAttribPointer(self.attribute_tex_coord as GLuint,
+                                    2,
+                                    gl::BYTE,
+                                    gl::FALSE,
+                                    4,
+                                    2 as *const GLvoid);
+            gl::VertexAttribPointer(self.attribute_position as GLuint,
+                                    2,
+                                    gl::BYTE,
+                                    gl::FALSE,
+                                    4,
+                                    0 as *const GLvoid);
+            gl::EnableVertexAttribArray(self.attribute_tex_coord as GLuint);
+            gl::EnableVertexAttribArray(self.attribute_position as GLuint);
+        }
+        vertex_array
+    }
+}
+
+impl Drop for Backend {
+    fn drop(&mut self) {
+        unsafe {
+            for hosted_root_index in 0..self.hosted_roots.len() {
+                let mut vertex_array = self.hosted_roots[hosted_root_index].vertex_array;
+                self.target(&self.hosted_roots[hosted_root_index].target).make_current();
+                gl::DeleteVertexArrays(1, &mut vertex_array);
+                self.hosted_roots[hosted_root_index].destroy_effect_targets();
+                if let HostTarget::Export(ref mut export) =
+                        self.hosted_roots[hosted_root_index].target {
+                    export.destroy();
+                }
+            }
+
+            self.connection.make_current();
+
+            self.video_program.destroy();
+            self.blur_program.destroy();
+            self.blend_program.destroy();
+            gl::DeleteBuffers(1, &mut self.vertex_buffer);
+            gl::DeleteProgram(self.program);
+            gl::DeleteShader(self.fragment_shader);
+            gl::DeleteShader(self.vertex_shader);
+        }
+    }
+}
+
+struct HostedRoot {
+    layer: LayerId,
+    target: HostTarget,
+    dirty_rect: Option<Rect<f32>>,
+    vertex_array: GLuint,
+    // Offscreen framebuffers for the layers with effects in this root's tree. These belong to
+    // the context of the target, like the vertex array.
+    effect_targets: LayerMap<EffectTarget>,
+}
+
+impl HostedRoot {
+    // NB: The context of the target must be current.
+    fn destroy_effect_targets(&mut self) {
+        for effect_target in self.effect_targets.0.iter_mut().filter_map(Option::as_mut) {
+            effect_target.destroy();
+        }
+        self.effect_targets = LayerMap::new();
+    }
+}
+
+// The framebuffers that a layer with effects is rendered through. All of them are the size of
+// the layer's padded content rect.
+struct EffectTarget {
+    // The layer and its descendants, padded to make room for the effects.
+    content: LayerFramebuffer,
+    // Holds the result of the first pass of each separable blur.
+    scratch: LayerFramebuffer,
+    // What was beneath the layer, if it blurs its backdrop or has a blend mode that reads it.
+    backdrop: Option<LayerFramebuffer>,
+    // The mask tree, if the layer has a mask.
+    mask: Option<LayerFramebuffer>,
+}
+
+impl EffectTarget {
+    fn new(size: &Size2D<u32>, backdrop: bool, mask: bool) -> Result<EffectTarget, ()> {
+        // The content and mask framebuffers need depth buffers, because layer trees are rendered
+        // into them in two passes, like the layers of a hosted root.
+        let mut content = LayerFramebuffer::new(size, SurfaceOptions::DEPTH)?;
+        let mut scratch = match LayerFramebuffer::new(size, SurfaceOptions::empty()) {
+            Ok(scratch) => scratch,
+            Err(()) => {
+                content.destroy();
+                return Err(())
+            }
+        };
+        let mut target_backdrop = None;
+        if backdrop {
+            match LayerFramebuffer::new(size, SurfaceOptions::empty()) {
+                Ok(backdrop) => target_backdrop = Some(backdrop),
+                Err(()) => {
+                    content.destroy();
+                    scratch.destroy();
+                    return Err(())
+                }
+            }
+        }
+        let mut target_mask = None;
+        if mask {
+            match LayerFramebuffer::new(size, SurfaceOptions::DEPTH) {
+                Ok(mask) => target_mask = Some(mask),
+                Err(()) => {
+                    content.destroy();
+                    scratch.destroy();
+                    if let Some(ref mut backdrop) = target_backdrop {
+                        backdrop.destroy();
+                    }
+                    return Err(())
+                }
+            }
+        }
+        Ok(EffectTarget { content, scratch, backdrop: target_backdrop, mask: target_mask })
+    }
+
+    fn destroy(&mut self) {
+        self.content.destroy();
+        self.scratch.destroy();
+        if let Some(ref mut backdrop) = self.backdrop {
+            backdrop.destroy();
+        }
+        if let Some(ref mut mask) = self.mask {
+            mask.destroy();
+        }
+    }
+}
+
+// Where a hosted root is displayed.
+enum HostTarget {
+    // The window that the backend was created with.
+    Connection,
+    // One of the windows created with `create_window`, by index.
+    Window(usize),
+    // A target supplied to `host_layer`.
+    Native(Box<dyn GLInterface>),
+    // An offscreen framebuffer that is copied to another process after each transaction.
+    Export(ExportTarget),
+}
+
+impl PartialEq for HostTarget {
+    fn eq(&self, other: &HostTarget) -> bool {
+        match (self, other) {
+            (&HostTarget::Connection, &HostTarget::Connection) => true,
+            (&HostTarget::Window(this), &HostTarget::Window(other)) => this == other,
+            _ => false,
+        }
+    }
+}
+
+struct PendingScreenshot {
+    root_layer: LayerId,
+    bounds: Rect<u32>,
+    promise: Promise<RgbaImage, ScreenshotError>,
+}
+
+struct LayerNativeInfo {
+    framebuffer: Option<LayerFramebuffer>,
+    // Where the contents come from, if the layer was imported from another process.
+    import: Option<LayerImport>,
+    video: Option<LayerVideo>,
+}
+
+struct LayerVideo {
+    // The textures that planes in memory are uploaded to, allocated as they're needed.
+    upload_textures: Vec<GLuint>,
+    // The most recently presented frame.
+    frame: Option<LayerVideoFrame>,
+}
+
+struct LayerVideoFrame {
+    format: YUVFormat,
+    conversion: Conversion,
+    // One per plane. These are either our upload textures or the client's own textures.
+    textures: Vec<GLuint>,
+}
+
+impl LayerVideo {
+    // Uploads a plane into the texture that we keep for it, and returns that texture.
+    fn upload_plane(&mut self, frame: &VideoFrame, plane_index: usize, data: &[u8], stride: usize)
+                    -> GLuint {
+        let (size, bytes_per_sample) = video::plane_geometry(frame.format,
+                                                             &frame.size,
+                                                             plane_index);
+        let (internal_format, format) = match bytes_per_sample {
+            2 => (gl::RG8, gl::RG),
+            _ => (gl::R8, gl::RED),
+        };
+
+        unsafe {
+            while self.upload_textures.len() <= plane_index {
+                let mut texture = 0;
+                gl::GenTextures(1, &mut texture);
+                gl::BindTexture(gl::TEXTURE_2D, texture);
+                gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as GLint);
+                gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as GLint);
+                gl::TexParameteri(gl::TEXTURE_2D,
+                                  gl::TEXTURE_WRAP_S,
+                                  gl::CLAMP_TO_EDGE as GLint);
+                gl::TexParameteri(gl::TEXTURE_2D,
+                                  gl::TEXTURE_WRAP_T,
+                                  gl::CLAMP_TO_EDGE as GLint);
+                self.upload_textures.push(texture);
+            }
+
+            let texture = self.upload_textures[plane_index];
+            gl::ActiveTexture(gl::TEXTURE0);
+            gl::BindTexture(gl::TEXTURE_2D, texture);
+            gl::PixelStorei(gl::UNPACK_ALIGNMENT, 1);
+            gl::PixelStorei(gl::UNPACK_ROW_LENGTH, (stride / bytes_per_sample) as GLint);
+            gl::TexImage2D(gl::TEXTURE_2D,
+                           0,
+                           internal_format as GLint,
+                           size.width as GLint,
+                           size.height as GLint,
+                           0,
+                           format,
+                           gl::UNSIGNED_BYTE,
+                           data.as_ptr() as *const GLvoid);
+            gl::PixelStorei(gl::UNPACK_ROW_LENGTH, 0);
+            gl::PixelStorei(gl::UNPACK_ALIGNMENT, 4);
+            texture
+        }
+    }
+
+    fn destroy(&mut self) {
+        unsafe {
+            for mut texture in self.upload_textures.drain(..) {
+                gl::DeleteTextures(1, &mut texture);
+            }
+        }
+        self.frame = None;
+    }
+}
+
+// The program that draws video layers, converting YUV to RGB as it samples the planes.
+struct VideoProgram {
+    fragment_shader: GLuint,
+    program: GLuint,
+    uniform_scale: GLint,
+    uniform_translation: GLint,
+    uniform_depth: GLint,
+    uniform_y_texture: GLint,
+    uniform_u_texture: GLint,
+    uniform_v_texture: GLint,
+    uniform_interleaved_chroma: GLint,
+    uniform_color_matrix: GLint,
+    uniform_color_offset: GLint,
+}
+
+impl VideoProgram {
+    // Shares the vertex shader and vertex attribute locations of the main program, so that the
+    // same vertex arrays work with both.
+    fn new(vertex_shader: GLuint, attribute_position: GLint, attribute_tex_coord: GLint)
+           -> VideoProgram {
+        unsafe {
+            let fragment_shader = create_shader(gl::FRAGMENT_SHADER,
+                                                VIDEO_FRAGMENT_SHADER_SOURCE);
+            let program = gl::CreateProgram();
+            gl::AttachShader(program, vertex_shader);
+            gl::AttachShader(program, fragment_shader);
+            gl::BindAttribLocation(program,
+                                   attribute_position as GLuint,
+                                   b"aPosition\0".as_ptr() as *const GLchar);
+            gl::BindAttribLocation(program,
+                                   attribute_tex_coord as GLuint,
+                                   b"aTexCoord\0".as_ptr() as *const GLchar);
+            gl::LinkProgram(program);
+
+            let uniform = |name: &[u8]| gl::GetUniformLocation(program, name.as_ptr() as *const _);
+            VideoProgram {
+                fragment_shader,
+                program,
+                uniform_scale: uniform(b"uScale\0"),
+                uniform_translation: uniform(b"uTranslation\0"),
+                uniform_depth: uniform(b"uDepth\0"),
+                uniform_y_texture: uniform(b"uYTexture\0"),
+                uniform_u_texture: uniform(b"uUTexture\0"),
+                uniform_v_texture: uniform(b"uVTexture\0"),
+                uniform_interleaved_chroma: uniform(b"uInterleavedChroma\0"),
+                uniform_color_matrix: uniform(b"uColorMatrix\0"),
+                uniform_color_offset: uniform(b"uColorOffset\0"),
+            }
+        }
+    }
+
+    fn destroy(&mut self) {
+        unsafe {
+            gl::DeleteProgram(self.program);
+            gl::DeleteShader(self.fragment_shader);
+        }
+    }
+}
+
+// The program that draws one pass of a separable Gaussian blur, optionally filling the blurred
+// alpha with a shadow color.
+struct BlurProgram {
+    fragment_shader: GLuint,
+    program: GLuint,
+    uniform_scale: GLint,
+    uniform_translation: GLint,
+    uniform_depth: GLint,
+    uniform_texture: GLint,
+    uniform_texel_step: GLint,
+    uniform_weights: GLint,
+    uniform_tap_count: GLint,
+    uniform_shadow: GLint,
+    uniform_shadow_color: GLint,
+}
 
-        // Only consider the layers of the appropriate opacity.
-        if !surface_component[layer].options.contains(SurfaceOptions::OPAQUE) {
-            return
+impl BlurProgram {
+    // Like the video program, this shares the vertex shader and attribute locations of the main
+    // program.
+    fn new(vertex_shader: GLuint, attribute_position: GLint, attribute_tex_coord: GLint)
+           -> BlurProgram {
+        unsafe {
+            let fragment_shader = create_shader(gl::FRAGMENT_SHADER,
+                                                BLUR_FRAGMENT_SHADER_SOURCE);
+            let program = gl::CreateProgram();
+            gl::AttachShader(program, vertex_shader);
+            gl::AttachShader(program, fragment_shader);
+            gl::BindAttribLocation(program,
+                                   attribute_position as GLuint,
+                                   b"aPosition\0".as_ptr() as *const GLchar);
+            gl::BindAttribLocation(program,
+                                   attribute_tex_coord as GLuint,
+                                   b"aTexCoord\0".as_ptr() as *const GLchar);
+            gl::LinkProgram(program);
+
+            let uniform = |name: &[u8]| gl::GetUniformLocation(program, name.as_ptr() as *const _);
+            BlurProgram {
+                fragment_shader,
+                program,
+                uniform_scale: uniform(b"uScale\0"),
+                uniform_translation: uniform(b"uTranslation\0"),
+                uniform_depth: uniform(b"uDepth\0"),
+                uniform_texture: uniform(b"uTexture\0"),
+                uniform_texel_step: uniform(b"uTexelStep\0"),
+                uniform_weights: uniform(b"uWeights\0"),
+                uniform_tap_count: uniform(b"uTapCount\0"),
+                uniform_shadow: uniform(b"uShadow\0"),
+                uniform_shadow_color: uniform(b"uShadowColor\0"),
+            }
         }
+    }
 
-        self.render_layer(layer, origin, depth, geometry_component);
+    fn destroy(&mut self) {
+        unsafe {
+            gl::DeleteProgram(self.program);
+            gl::DeleteShader(self.fragment_shader);
+        }
     }
+}
 
-    fn render_transparent_layer_subtree(&self,
-                                        layer: LayerId,
-                                        origin: &Point2D<f32>,
-                                        next_depth_value: &mut f32,
-                                        tree_component: &LayerMap<LayerTreeInfo>,
-                                        container_component: &LayerMap<LayerContainerInfo>,
-                                        geometry_component: &LayerMap<LayerGeometryInfo>,
-                                        surface_component: &LayerMap<LayerSurfaceInfo>) {
-        let bounds = geometry_component[layer].bounds;
+// The program that composites a layer with a blend mode that blending can't express, reading
+// from a copy of what's beneath it.
+struct BlendProgram {
+    fragment_shader: GLuint,
+    program: GLuint,
+    uniform_scale: GLint,
+    uniform_translation: GLint,
+    uniform_depth: GLint,
+    uniform_source: GLint,
+    uniform_backdrop: GLint,
+    uniform_mode: GLint,
+}
 
-        // If this is a container layer, don't render anything; just recurse.
-        if let Some(container_info) = container_component.get(layer) {
-            let new_origin = *origin + bounds.origin.to_vector();
-            let mut maybe_kid = container_info.last_child;
-            while let Some(kid) = maybe_kid {
-                self.render_transparent_layer_subtree(kid,
-                                                      &new_origin,
-                                                      next_depth_value,
-                                                      tree_component,
-                                                      container_component,
-                                                      geometry_component,
-                                                      surface_component);
-                maybe_kid = tree_component[kid].prev_sibling;
+impl BlendProgram {
+    // Like the video program, this shares the vertex shader and attribute locations of the main
+    // program.
+    fn new(vertex_shader: GLuint, attribute_position: GLint, attribute_tex_coord: GLint)
+           -> BlendProgram {
+        unsafe {
+            let fragment_shader = create_shader(gl::FRAGMENT_SHADER,
+                                                BLEND_FRAGMENT_SHADER_SOURCE);
+            let program = gl::CreateProgram();
+            gl::AttachShader(program, vertex_shader);
+            gl::AttachShader(program, fragment_shader);
+            gl::BindAttribLocation(program,
+                                   attribute_position as GLuint,
+                                   b"aPosition\0".as_ptr() as *const GLchar);
+            gl::BindAttribLocation(program,
+                                   attribute_tex_coord as GLuint,
+                                   b"aTexCoord\0".as_ptr() as *const GLchar);
+            gl::LinkProgram(program);
+
+            let uniform = |name: &[u8]| gl::GetUniformLocation(program, name.as_ptr() as *const _);
+            BlendProgram {
+                fragment_shader,
+                program,
+                uniform_scale: uniform(b"uScale\0"),
+                uniform_translation: uniform(b"uTranslation\0"),
+                uniform_depth: uniform(b"uDepth\0"),
+                uniform_source: uniform(b"uSource\0"),
+                uniform_backdrop: uniform(b"uBackdrop\0"),
+                uniform_mode: uniform(b"uMode\0"),
             }
-            return
         }
+    }
 
-        // Assign a depth value.
-        *next_depth_value -= DEPTH_QUANTUM;
-        let depth = *next_depth_value;
-
-        // Only consider the layers of the appropriate opacity.
-        if surface_component[layer].options.contains(SurfaceOptions::OPAQUE) {
-            return
+    fn destroy(&mut self) {
+        unsafe {
+            gl::DeleteProgram(self.program);
+            gl::DeleteShader(self.fragment_shader);
         }
-
-        self.render_layer(layer, origin, depth, geometry_component);
     }
+}
 
-    fn render_layer(&self,
-                    layer: LayerId,
-                    origin: &Point2D<f32>,
-                    depth: f32,
-                    geometry_component: &LayerMap<LayerGeometryInfo>) {
-        let color_texture = match self.native_component[layer].framebuffer {
-            Some(ref framebuffer) => framebuffer.color_texture,
-            None => return,
-        };
+struct LayerImport {
+    shared_image: SharedImageReader,
+    // Created when the first image arrives.
+    texture: Option<GLuint>,
+}
 
-        let bounds = geometry_component[layer].bounds;
-        let framebuffer_size = self.connection.default_framebuffer_size().to_f32();
+impl LayerImport {
+    fn upload(&mut self, image: RgbaImage) {
+        let (width, height) = image.dimensions();
+        let mut pixels = image.into_raw();
 
-        unsafe {
-            // Set uniforms.
-            gl::Uniform1f(self.uniform_depth, depth);
-            gl::UniformMatrix2fv(self.uniform_scale, 1, gl::FALSE, [
-                2.0 * bounds.size.width / framebuffer_size.width, 0.0,
-                0.0, 2.0 * bounds.size.height / framebuffer_size.height,
-            ].as_ptr());
-            gl::Uniform2f(self.uniform_translation,
-                          2.0 * (origin.x + bounds.origin.x) / framebuffer_size.width - 1.0,
-                          2.0 * (origin.y + bounds.origin.y) / framebuffer_size.height - 1.0);
+        // The image is stored top row first, but OpenGL textures start at the bottom.
+        flip_vertically(&mut pixels, width as usize, height as usize);
 
-            // Bind texture.
+        unsafe {
             gl::ActiveTexture(gl::TEXTURE0);
-            gl::BindTexture(gl::TEXTURE_2D, color_texture);
-            gl::Uniform1i(self.uniform_texture, 0);
+            match self.texture {
+                Some(texture) => gl::BindTexture(gl::TEXTURE_2D, texture),
+                None => {
+                    let mut texture = 0;
+                    gl::GenTextures(1, &mut texture);
+                    gl::BindTexture(gl::TEXTURE_2D, texture);
+                    gl::TexParameteri(gl::TEXTURE_2D,
+                                      gl::TEXTURE_MIN_FILTER,
+                                      gl::LINEAR as GLint);
+                    gl::TexParameteri(gl::TEXTURE_2D,
+                                      gl::TEXTURE_MAG_FILTER,
+                                      gl::LINEAR as GLint);
+                    gl::TexParameteri(gl::TEXTURE_2D,
+                                      gl::TEXTURE_WRAP_S,
+                                      gl::CLAMP_TO_EDGE as GLint);
+                    gl::TexParameteri(gl::TEXTURE_2D,
+                                      gl::TEXTURE_WRAP_T,
+                                      gl::CLAMP_TO_EDGE as GLint);
+                    self.texture = Some(texture);
+                }
+            }
+            gl::TexImage2D(gl::TEXTURE_2D,
+                           0,
+                           gl::RGBA8 as GLint,
+                           width as GLint,
+                           height as GLint,
+                           0,
+                           gl::RGBA,
+                           gl::UNSIGNED_BYTE,
+                           pixels.as_ptr() as *const GLvoid);
+        }
+    }
 
-            // Draw the layer.
-            gl::DrawArrays(gl::TRIANGLE_STRIP, 0, 4);
+    fn destroy(&mut self) {
+        if let Some(mut texture) = self.texture.take() {
+            unsafe {
+                gl::DeleteTextures(1, &mut texture);
+            }
         }
     }
 }
 
-impl Drop for Backend {
-    fn drop(&mut self) {
-        unsafe {
-            self.connection.make_current();
+// Renders an exported layer tree offscreen, in the connection's context, and copies the result
+// into shared memory on present.
+struct ExportTarget {
+    // Allocated on first draw, and reallocated whenever the size changes.
+    framebuffer: Option<LayerFramebuffer>,
+    size: Size2D<u32>,
+    gl_api: GLAPI,
+    shared_image: SharedImageWriter,
+}
 
-            gl::DeleteBuffers(1, &mut self.vertex_buffer);
-            gl::DeleteVertexArrays(1, &mut self.vertex_array);
-            gl::DeleteProgram(self.program);
-            gl::DeleteShader(self.fragment_shader);
-            gl::DeleteShader(self.vertex_shader);
+impl ExportTarget {
+    fn destroy(&mut self) {
+        if let Some(mut framebuffer) = self.framebuffer.take() {
+            framebuffer.destroy();
         }
     }
 }
 
-struct LayerNativeInfo {
-    framebuffer: Option<LayerFramebuffer>,
+impl GLInterface for ExportTarget {
+    fn gl_api(&self) -> GLAPI {
+        self.gl_api
+    }
+
+    fn get_proc_address(&self, _: &str) -> Option<*const c_void> {
+        None
+    }
+
+    // The backend makes its connection current before drawing to us.
+    fn make_current(&self) {}
+
+    fn prepare_to_draw(&mut self) {
+        // Empty framebuffers are incomplete, so always allocate at least one pixel.
+        let size = Size2D::new(self.size.width.max(1), self.size.height.max(1));
+        if self.framebuffer.as_ref().map(|framebuffer| framebuffer.size) != Some(size) {
+            self.destroy();
+            let surface_options = SurfaceOptions::DEPTH | SurfaceOptions::STENCIL;
+            self.framebuffer = LayerFramebuffer::new(&size, surface_options).ok();
+        }
+
+        // Unlike a window, nothing else clears the framebuffer between frames.
+        if let Some(ref framebuffer) = self.framebuffer {
+            unsafe {
+                gl::BindFramebuffer(gl::FRAMEBUFFER, framebuffer.framebuffer);
+                gl::ClearColor(0.0, 0.0, 0.0, 0.0);
+                gl::Clear(gl::COLOR_BUFFER_BIT);
+            }
+        }
+    }
+
+    fn present(&mut self, _: &Rect<f32>) {
+        // FIXME(pcwalton): Read back and write only the invalid rect.
+        let framebuffer = match self.framebuffer {
+            None => {
+                warn!("Failed to allocate a framebuffer for an exported layer");
+                return
+            }
+            Some(ref framebuffer) => framebuffer.framebuffer,
+        };
+
+        let (width, height) = (self.size.width as usize, self.size.height as usize);
+        let mut pixels = vec![0; width * height * 4];
+        unsafe {
+            gl::BindFramebuffer(gl::FRAMEBUFFER, framebuffer);
+            gl::ReadPixels(0,
+                           0,
+                           width as GLint,
+                           height as GLint,
+                           gl::RGBA,
+                           gl::UNSIGNED_BYTE,
+                           pixels.as_mut_ptr() as *mut _);
+            gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+        }
+        flip_vertically(&mut pixels, width, height);
+
+        let image = RgbaImage::from_raw(self.size.width, self.size.height, pixels).unwrap();
+        if let Err(error) = self.shared_image.write(&image) {
+            warn!("Failed to update an exported layer: {}", error);
+        }
+    }
+
+    fn default_framebuffer(&self) -> GLuint {
+        self.framebuffer.as_ref().map_or(0, |framebuffer| framebuffer.framebuffer)
+    }
+
+    fn default_framebuffer_size(&self) -> Size2D<u32> {
+        self.framebuffer.as_ref().map_or(Size2D::zero(), |framebuffer| framebuffer.size)
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn window(&self) -> Option<&Window> {
+        None
+    }
 }
 
 struct LayerFramebuffer {
     color_texture: GLuint,
     depth_stencil_renderbuffer: Option<GLuint>,
+    // The framebuffer that the client renders into. If the layer is multisampled, this is the
+    // multisample framebuffer; otherwise, the color texture is attached to it directly.
     framebuffer: GLuint,
+    multisample: Option<LayerMultisampleBuffers>,
     size: Size2D<u32>,
     surface_options: SurfaceOptions,
 }
 
+// A multisampled color buffer, plus the framebuffer that it's resolved into on present.
+struct LayerMultisampleBuffers {
+    color_renderbuffer: GLuint,
+    resolve_framebuffer: GLuint,
+}
+
+impl LayerFramebuffer {
+    // Returns `Err` if the surface options don't describe a format that the driver can render to.
+    fn new(size: &Size2D<u32>, surface_options: SurfaceOptions) -> Result<LayerFramebuffer, ()> {
+        let (internal_format, format, component_type) = match surface_options.color_format()? {
+            ColorFormat::Rgba8 => (gl::RGBA8, gl::RGBA, gl::UNSIGNED_BYTE),
+            ColorFormat::Srgb8Alpha8 => (gl::SRGB8_ALPHA8, gl::RGBA, gl::UNSIGNED_BYTE),
+            ColorFormat::Rgb10A2 => (gl::RGB10_A2, gl::RGBA, gl::UNSIGNED_INT_2_10_10_10_REV),
+            ColorFormat::Rgba16F => (gl::RGBA16F, gl::RGBA, gl::HALF_FLOAT),
+        };
+
+        let has_stencil = surface_options.contains(SurfaceOptions::STENCIL);
+        let depth_stencil_format = match (surface_options.depth_format()?, has_stencil) {
+            (None, false) => None,
+            (None, true) | (Some(DepthFormat::Depth24), _) => {
+                Some((gl::DEPTH24_STENCIL8, gl::DEPTH_STENCIL_ATTACHMENT))
+            }
+            (Some(DepthFormat::Depth32F), _) => {
+                Some((gl::DEPTH32F_STENCIL8, gl::DEPTH_STENCIL_ATTACHMENT))
+            }
+            (Some(DepthFormat::Depth16), false) => {
+                Some((gl::DEPTH_COMPONENT16, gl::DEPTH_ATTACHMENT))
+            }
+            // There's no packed format with a 16-bit depth buffer and a stencil buffer.
+            (Some(DepthFormat::Depth16), true) => return Err(()),
+        };
+
+        let sample_count = surface_options.sample_count()?;
+
+        let mut framebuffer = LayerFramebuffer {
+            color_texture: 0,
+            depth_stencil_renderbuffer: None,
+            framebuffer: 0,
+            multisample: None,
+            size: *size,
+            surface_options,
+        };
+
+        unsafe {
+            // Create color texture.
+            gl::GenTextures(1, &mut framebuffer.color_texture);
+            gl::ActiveTexture(gl::TEXTURE0);
+            gl::BindTexture(gl::TEXTURE_2D, framebuffer.color_texture);
+            gl::TexImage2D(gl::TEXTURE_2D,
+                           0,
+                           internal_format as GLint,
+                           size.width as GLint,
+                           size.height as GLint,
+                           0,
+                           format,
+                           component_type,
+                           ptr::null());
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as GLint);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as GLint);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as GLint);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as GLint);
+
+            // Create depth/stencil renderbuffer, if necessary. This has to have the same number of
+            // samples as the color buffer that the client renders into.
+            if let Some((depth_stencil_format, _)) = depth_stencil_format {
+                let mut renderbuffer = 0;
+                gl::GenRenderbuffers(1, &mut renderbuffer);
+                gl::BindRenderbuffer(gl::RENDERBUFFER, renderbuffer);
+                gl::RenderbufferStorageMultisample(gl::RENDERBUFFER,
+                                                   multisample_count(sample_count),
+                                                   depth_stencil_format,
+                                                   size.width as GLint,
+                                                   size.height as GLint);
+                framebuffer.depth_stencil_renderbuffer = Some(renderbuffer);
+            }
+
+            // If multisampling, create a multisampled color renderbuffer to render into, and
+            // attach the color texture to a separate framebuffer to resolve into.
+            if sample_count > 1 {
+                let mut multisample = LayerMultisampleBuffers {
+                    color_renderbuffer: 0,
+                    resolve_framebuffer: 0,
+                };
+                gl::GenRenderbuffers(1, &mut multisample.color_renderbuffer);
+                gl::BindRenderbuffer(gl::RENDERBUFFER, multisample.color_renderbuffer);
+                gl::RenderbufferStorageMultisample(gl::RENDERBUFFER,
+                                                   sample_count as GLint,
+                                                   internal_format,
+                                                   size.width as GLint,
+                                                   size.height as GLint);
+
+                gl::GenFramebuffers(1, &mut multisample.resolve_framebuffer);
+                gl::BindFramebuffer(gl::FRAMEBUFFER, multisample.resolve_framebuffer);
+                gl::FramebufferTexture2D(gl::FRAMEBUFFER,
+                                         gl::COLOR_ATTACHMENT0,
+                                         gl::TEXTURE_2D,
+                                         framebuffer.color_texture,
+                                         0);
+                let status = gl::CheckFramebufferStatus(gl::FRAMEBUFFER);
+                framebuffer.multisample = Some(multisample);
+                if status != gl::FRAMEBUFFER_COMPLETE {
+                    gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+                    framebuffer.destroy();
+                    return Err(())
+                }
+            }
+
+            // Create FBO.
+            gl::GenFramebuffers(1, &mut framebuffer.framebuffer);
+            gl::BindFramebuffer(gl::FRAMEBUFFER, framebuffer.framebuffer);
+            match framebuffer.multisample {
+                None => {
+                    gl::FramebufferTexture2D(gl::FRAMEBUFFER,
+                                             gl::COLOR_ATTACHMENT0,
+                                             gl::TEXTURE_2D,
+                                             framebuffer.color_texture,
+                                             0);
+                }
+                Some(ref multisample) => {
+                    gl::FramebufferRenderbuffer(gl::FRAMEBUFFER,
+                                                gl::COLOR_ATTACHMENT0,
+                                                gl::RENDERBUFFER,
+                                                multisample.color_renderbuffer);
+                }
+            }
+            if let (Some(renderbuffer), Some((_, attachment))) =
+                    (framebuffer.depth_stencil_renderbuffer, depth_stencil_format) {
+                gl::FramebufferRenderbuffer(gl::FRAMEBUFFER,
+                                            attachment,
+                                            gl::RENDERBUFFER,
+                                            renderbuffer);
+            }
+
+            // Make sure the driver actually supports rendering to this format.
+            let status = gl::CheckFramebufferStatus(gl::FRAMEBUFFER);
+            gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+            if status != gl::FRAMEBUFFER_COMPLETE {
+                framebuffer.destroy();
+                return Err(())
+            }
+        }
+
+        Ok(framebuffer)
+    }
+
+    // Resolves the multisampled color buffer, if any, into the color texture.
+    fn resolve(&self) {
+        let multisample = match self.multisample {
+            None => return,
+            Some(ref multisample) => multisample,
+        };
+
+        unsafe {
+            gl::BindFramebuffer(gl::READ_FRAMEBUFFER, self.framebuffer);
+            gl::BindFramebuffer(gl::DRAW_FRAMEBUFFER, multisample.resolve_framebuffer);
+            gl::BlitFramebuffer(0, 0,
+                                self.size.width as GLint, self.size.height as GLint,
+                                0, 0,
+                                self.size.width as GLint, self.size.height as GLint,
+                                gl::COLOR_BUFFER_BIT,
+                                gl::NEAREST);
+        }
+    }
+
+    fn destroy(&mut self) {
+        unsafe {
+            gl::DeleteFramebuffers(1, &mut self.framebuffer);
+            if let Some(mut renderbuffer) = self.depth_stencil_renderbuffer.take() {
+                gl::DeleteRenderbuffers(1, &mut renderbuffer);
+            }
+            if let Some(mut multisample) = self.multisample.take() {
+                gl::DeleteFramebuffers(1, &mut multisample.resolve_framebuffer);
+                gl::DeleteRenderbuffers(1, &mut multisample.color_renderbuffer);
+            }
+            gl::DeleteTextures(1, &mut self.color_texture);
+        }
+    }
+}
+
+// Sets the uniforms that position a layer in the framebuffer, which the main program and the
+// video program share.
+// Returns the blend factors, for RGB and then alpha, that composite premultiplied content with
+// the given blend mode, or `None` if blending can't express it.
+fn blend_factors(blend_mode: BlendMode) -> Option<(GLenum, GLenum, GLenum, GLenum)> {
+    match blend_mode {
+        BlendMode::Normal => {
+            Some((gl::ONE, gl::ONE_MINUS_SRC_ALPHA, gl::ONE, gl::ONE_MINUS_SRC_ALPHA))
+        }
+        BlendMode::Screen => {
+            Some((gl::ONE, gl::ONE_MINUS_SRC_COLOR, gl::ONE, gl::ONE_MINUS_SRC_ALPHA))
+        }
+        BlendMode::Plus => Some((gl::ONE, gl::ONE, gl::ONE, gl::ONE)),
+        BlendMode::Multiply | BlendMode::Overlay | BlendMode::Darken | BlendMode::Lighten |
+        BlendMode::ColorDodge | BlendMode::ColorBurn | BlendMode::HardLight |
+        BlendMode::SoftLight | BlendMode::Difference | BlendMode::Exclusion => None,
+    }
+}
+
+// The value of `uMode` in the blend shader.
+fn blend_mode_index(blend_mode: BlendMode) -> GLint {
+    match blend_mode {
+        BlendMode::Multiply => 0,
+        BlendMode::Overlay => 1,
+        BlendMode::Darken => 2,
+        BlendMode::Lighten => 3,
+        BlendMode::ColorDodge => 4,
+        BlendMode::ColorBurn => 5,
+        BlendMode::HardLight => 6,
+        BlendMode::SoftLight => 7,
+        BlendMode::Difference => 8,
+        BlendMode::Exclusion => 9,
+        // These are done with blending.
+        BlendMode::Normal | BlendMode::Screen | BlendMode::Plus => -1,
+    }
+}
+
+// Restricts drawing to a rect of the bound framebuffer, in the coordinate system that layers are
+// drawn in, rounded out to whole pixels.
+unsafe fn set_clip_rect(clip_rect: &Rect<f32>, framebuffer_size: &Size2D<f32>) {
+    let clip_rect = clip_rect.round_out().to_i32();
+    gl::Enable(gl::SCISSOR_TEST);
+    gl::Scissor(clip_rect.min_x(),
+                framebuffer_size.height as GLint - clip_rect.max_y(),
+                clip_rect.size.width,
+                clip_rect.size.height);
+}
+
+unsafe fn set_layer_transform(uniform_scale: GLint,
+                              uniform_translation: GLint,
+                              uniform_depth: GLint,
+                              bounds: &Rect<f32>,
+                              origin: &Point2D<f32>,
+                              framebuffer_size: &Size2D<f32>,
+                              depth: f32) {
+    gl::Uniform1f(uniform_depth, depth);
+    gl::UniformMatrix2fv(uniform_scale, 1, gl::FALSE, [
+        2.0 * bounds.size.width / framebuffer_size.width, 0.0,
+        0.0, 2.0 * bounds.size.height / framebuffer_size.height,
+    ].as_ptr());
+    // Layer coordinates have their origin at the top left, but OpenGL's are at the bottom left.
+    let bottom = origin.y + bounds.max_y();
+    gl::Uniform2f(uniform_translation,
+                  2.0 * (origin.x + bounds.origin.x) / framebuffer_size.width - 1.0,
+                  1.0 - 2.0 * bottom / framebuffer_size.height);
+}
+
+// Swaps the rows of a tightly-packed RGBA image, converting between OpenGL's bottom-up row order
+// and the top-down order that images use.
+fn flip_vertically(pixels: &mut [u8], width: usize, height: usize) {
+    for y0 in 0..(height / 2) {
+        let (start0, start1) = (y0 * width * 4, (height - y0 - 1) * width * 4);
+        for offset in 0..(width * 4) {
+            pixels.swap(start0 + offset, start1 + offset);
+        }
+    }
+}
+
+// `glRenderbufferStorageMultisample()` takes zero, not one, to mean "not multisampled".
+fn multisample_count(sample_count: u32) -> GLint {
+    if sample_count > 1 { sample_count as GLint } else { 0 }
+}
+
+/// A target that the GL backend can render a hosted layer tree into.
+///
+/// The backend is created with one such interface, which it renders into when a layer is hosted
+/// with `host_layer_in_window`. Additional layer trees can be hosted with `host_layer`, each in a
+/// target of its own. All of the targets must share OpenGL objects with the backend's connection.
 pub trait GLInterface {
     fn gl_api(&self) -> GLAPI;
 
@@ -712,6 +2761,13 @@ pub trait GLInterface {
 
     #[cfg(feature = "enable-winit")]
     fn window(&self) -> Option<&Window>;
+
+    /// Creates a new window with an OpenGL context that shares objects with this one.
+    #[cfg(feature = "enable-winit")]
+    fn create_shared_window(&self, _: WindowBuilder, _: &EventsLoop)
+                            -> Result<Box<dyn GLInterface>, ()> {
+        Err(())
+    }
 }
 
 struct Interface {
@@ -719,11 +2775,12 @@ struct Interface {
 }
 
 impl Interface {
-    fn new(window_builder: WindowBuilder, events_loop: &EventsLoop) -> Interface {
+    fn new(window_builder: WindowBuilder, events_loop: &EventsLoop) -> Result<Interface, ()> {
         let context = ContextBuilder::new().with_gl(GlRequest::Specific(Api::OpenGl, (3, 3)))
                                            .with_gl_profile(GlProfile::Core);
-        Interface {
-            gl_window: GlWindow::new(window_builder, context, events_loop).unwrap(),
+        match GlWindow::new(window_builder, context, events_loop) {
+            Ok(gl_window) => Ok(Interface { gl_window }),
+            Err(_) => Err(()),
         }
     }
 }
@@ -772,6 +2829,18 @@ impl GLInterface for Interface {
     fn window(&self) -> Option<&Window> {
         Some(self.gl_window.window())
     }
+
+    #[cfg(feature = "enable-winit")]
+    fn create_shared_window(&self, window_builder: WindowBuilder, events_loop: &EventsLoop)
+                            -> Result<Box<dyn GLInterface>, ()> {
+        let context = ContextBuilder::new().with_gl(GlRequest::Specific(Api::OpenGl, (3, 3)))
+                                           .with_gl_profile(GlProfile::Core)
+                                           .with_shared_lists(self.gl_window.context());
+        match GlWindow::new(window_builder, context, events_loop) {
+            Ok(gl_window) => Ok(Box::new(Interface { gl_window })),
+            Err(_) => Err(()),
+        }
+    }
 }
 
 unsafe fn create_shader(kind: GLuint, source: &[u8]) -> GLuint {
@@ -831,12 +2900,162 @@ static FRAGMENT_SHADER_SOURCE: &'static [u8] = b"\
     #version 330
 
     uniform sampler2D uTexture;
+    uniform bool uEncodeSRGB;
+    uniform bool uOpaque;
+
+    in vec2 vTexCoord;
+
+    out vec4 oFragColor;
+
+    vec3 linearToSRGB(vec3 color) {
+        vec3 low = color * 12.92;
+        vec3 high = 1.055 * pow(color, vec3(1.0 / 2.4)) - 0.055;
+        return mix(low, high, step(vec3(0.0031308), color));
+    }
+
+    void main() {
+        vec4 color = texture(uTexture, vTexCoord);
+        if (uOpaque)
+            color.a = 1.0;
+        if (uEncodeSRGB)
+            color.rgb = linearToSRGB(color.rgb);
+        oFragColor = color;
+    }
+";
+
+static VIDEO_FRAGMENT_SHADER_SOURCE: &'static [u8] = b"\
+    #version 330
+
+    uniform sampler2D uYTexture;
+    uniform sampler2D uUTexture;
+    uniform sampler2D uVTexture;
+    uniform bool uInterleavedChroma;
+    uniform mat3 uColorMatrix;
+    uniform vec3 uColorOffset;
+
+    in vec2 vTexCoord;
+
+    out vec4 oFragColor;
+
+    void main() {
+        // Video planes are stored top row first.
+        vec2 texCoord = vec2(vTexCoord.x, 1.0 - vTexCoord.y);
+
+        vec3 yuv;
+        yuv.x = texture(uYTexture, texCoord).r;
+        if (uInterleavedChroma)
+            yuv.yz = texture(uUTexture, texCoord).rg;
+        else
+            yuv.yz = vec2(texture(uUTexture, texCoord).r, texture(uVTexture, texCoord).r);
+
+        oFragColor = vec4(clamp(uColorMatrix * (yuv - uColorOffset), 0.0, 1.0), 1.0);
+    }
+";
+
+// The kernel is sized to `effects::MAX_BLUR_EXTENT`, plus the center tap.
+static BLUR_FRAGMENT_SHADER_SOURCE: &'static [u8] = b"\
+    #version 330
+
+    uniform sampler2D uTexture;
+    uniform vec2 uTexelStep;
+    uniform float uWeights[65];
+    uniform int uTapCount;
+    uniform bool uShadow;
+    uniform vec4 uShadowColor;
+
+    in vec2 vTexCoord;
+
+    out vec4 oFragColor;
+
+    void main() {
+        vec4 sum = texture(uTexture, vTexCoord) * uWeights[0];
+        for (int i = 1; i < uTapCount; i++) {
+            vec2 offset = uTexelStep * float(i);
+            sum += (texture(uTexture, vTexCoord - offset) +
+                    texture(uTexture, vTexCoord + offset)) * uWeights[i];
+        }
+        oFragColor = uShadow ? uShadowColor * sum.a : sum;
+    }
+";
+
+// Implements the separable blend modes of the W3C Compositing and Blending specification, like
+// `effects::blend()`. `uMode` is from `blend_mode_index()`.
+static BLEND_FRAGMENT_SHADER_SOURCE: &'static [u8] = b"\
+    #version 330
+
+    uniform sampler2D uSource;
+    uniform sampler2D uBackdrop;
+    uniform int uMode;
 
     in vec2 vTexCoord;
 
     out vec4 oFragColor;
 
+    float multiply(float backdrop, float source) {
+        return backdrop * source;
+    }
+
+    float screen(float backdrop, float source) {
+        return backdrop + source - backdrop * source;
+    }
+
+    float hardLight(float backdrop, float source) {
+        if (source <= 0.5)
+            return multiply(backdrop, 2.0 * source);
+        return screen(backdrop, 2.0 * source - 1.0);
+    }
+
+    float blendChannel(float backdrop, float source) {
+        switch (uMode) {
+        case 0:
+            return multiply(backdrop, source);
+        case 1:
+            return hardLight(source, backdrop);
+        case 2:
+            return min(backdrop, source);
+        case 3:
+            return max(backdrop, source);
+        case 4:
+            if (backdrop <= 0.0)
+                return 0.0;
+            if (source >= 1.0)
+                return 1.0;
+            return min(1.0, backdrop / (1.0 - source));
+        case 5:
+            if (backdrop >= 1.0)
+                return 1.0;
+            if (source <= 0.0)
+                return 0.0;
+            return 1.0 - min(1.0, (1.0 - backdrop) / source);
+        case 6:
+            return hardLight(backdrop, source);
+        case 7: {
+            if (source <= 0.5)
+                return backdrop - (1.0 - 2.0 * source) * backdrop * (1.0 - backdrop);
+            float d = backdrop <= 0.25 ? ((16.0 * backdrop - 12.0) * backdrop + 4.0) * backdrop :
+                sqrt(backdrop);
+            return backdrop + (2.0 * source - 1.0) * (d - backdrop);
+        }
+        case 8:
+            return abs(backdrop - source);
+        default:
+            return backdrop + source - 2.0 * backdrop * source;
+        }
+    }
+
     void main() {
-        oFragColor = texture(uTexture, vTexCoord);
+        vec4 src = texture(uSource, vTexCoord);
+        vec4 dest = texture(uBackdrop, vTexCoord);
+
+        // Blend the unpremultiplied colors where the layer and its backdrop overlap.
+        vec3 source = src.a > 0.0 ? src.rgb / src.a : vec3(0.0);
+        vec3 backdrop = dest.a > 0.0 ? dest.rgb / dest.a : vec3(0.0);
+        vec3 mixed = vec3(blendChannel(backdrop.r, source.r),
+                          blendChannel(backdrop.g, source.g),
+                          blendChannel(backdrop.b, source.b));
+
+        oFragColor.rgb = src.rgb * (1.0 - dest.a) + dest.rgb * (1.0 - src.a) +
+            src.a * dest.a * mixed;
+        oFragColor.a = src.a + dest.a * (1.0 - src.a);
     }
 ";
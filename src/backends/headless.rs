@@ -0,0 +1,370 @@
+// planeshift/src/backends/headless.rs
+//
+// Copyright © 2018 The Pathfinder Project Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A backend that renders offscreen using EGL, with no window system.
+//!
+//! This is the `gl` backend's compositor, rendering into framebuffer objects supplied by
+//! `HeadlessInterface` instead of windows. It needs neither a GPU nor a display server, so it runs
+//! in CI on Mesa's `llvmpipe` software rasterizer. Screenshots work as usual.
+//!
+//! To use it, create a `HeadlessInterface` and pass it as a `Connection::Native` to
+//! `LayerContext::with_backend_connection()`. `host_layer_in_window()` hosts a layer tree in the
+//! connection's framebuffer; to host more layer trees, pass interfaces created with
+//! `HeadlessInterface::create_shared()` to `host_layer()`.
+
+use euclid::{Rect, Size2D};
+use gl::types::{GLint, GLuint};
+use gl;
+use std::ffi::{CStr, CString};
+use std::mem;
+use std::os::raw::c_void;
+use std::ptr;
+use std::rc::Rc;
+use std::sync::Mutex;
+
+#[cfg(feature = "enable-winit")]
+use winit::Window;
+
+use crate::backends::gl::GLInterface;
+use crate::egl::types::{EGLConfig, EGLContext, EGLDisplay, EGLSurface, EGLenum, EGLint};
+use crate::egl;
+use crate::GLAPI;
+
+pub use crate::backends::gl::Backend;
+
+// EGL hands out the same display every time, and `eglInitialize()` doesn't count references, so
+// we count the displays that are alive ourselves and only terminate it once they're all gone.
+lazy_static! {
+    static ref DISPLAY_COUNT: Mutex<usize> = Mutex::new(0);
+}
+
+// From `EGL_EXT_platform_base`.
+type GetPlatformDisplayEXTFn = unsafe extern "C" fn(EGLenum, *mut c_void, *const EGLint)
+                                                    -> EGLDisplay;
+
+// From `EGL_MESA_platform_surfaceless`.
+const EGL_PLATFORM_SURFACELESS_MESA: EGLenum = 0x31dd;
+
+// From `EGL_KHR_create_context`.
+const EGL_CONTEXT_MAJOR_VERSION_KHR: EGLint = 0x3098;
+const EGL_CONTEXT_MINOR_VERSION_KHR: EGLint = 0x30fb;
+const EGL_CONTEXT_OPENGL_PROFILE_MASK_KHR: EGLint = 0x30fd;
+const EGL_CONTEXT_OPENGL_CORE_PROFILE_BIT_KHR: EGLint = 0x1;
+
+/// An offscreen OpenGL rendering target for the `gl` backend.
+pub struct HeadlessInterface {
+    display: Rc<HeadlessDisplay>,
+    egl_config: EGLConfig,
+    egl_context: EGLContext,
+    // If the driver supports `EGL_KHR_surfaceless_context`, this is `NO_SURFACE`. Otherwise, it's
+    // a dummy pbuffer, since we always render into our own framebuffer object.
+    egl_surface: EGLSurface,
+
+    framebuffer: GLuint,
+    color_renderbuffer: GLuint,
+    depth_stencil_renderbuffer: GLuint,
+    size: Size2D<u32>,
+}
+
+impl HeadlessInterface {
+    /// Creates a new offscreen target of the given size, in device pixels.
+    ///
+    /// Returns `Err` if no EGL implementation is available or it can't create an OpenGL 3.3 core
+    /// profile context.
+    pub fn new(size: &Size2D<u32>) -> Result<HeadlessInterface, ()> {
+        unsafe {
+            let display = Rc::new(HeadlessDisplay::new()?);
+            let egl_config = choose_config(display.egl_display)?;
+            HeadlessInterface::with_display(display, egl_config, egl::NO_CONTEXT, size)
+        }
+    }
+
+    /// Creates a new offscreen target that shares OpenGL objects with this one.
+    ///
+    /// Use this to create targets for `host_layer()`.
+    pub fn create_shared(&self, size: &Size2D<u32>) -> Result<HeadlessInterface, ()> {
+        let interface = unsafe {
+            HeadlessInterface::with_display(self.display.clone(),
+                                            self.egl_config,
+                                            self.egl_context,
+                                            size)
+        };
+
+        // The backend expects its connection's context to stay current.
+        self.make_current();
+        interface
+    }
+
+    unsafe fn with_display(display: Rc<HeadlessDisplay>,
+                           egl_config: EGLConfig,
+                           share_context: EGLContext,
+                           size: &Size2D<u32>)
+                           -> Result<HeadlessInterface, ()> {
+        let egl_display = display.egl_display;
+
+        // Create an OpenGL 3.3 core profile context, which is what the `gl` backend's shaders
+        // require.
+        let attributes = [
+            EGL_CONTEXT_MAJOR_VERSION_KHR,          3,
+            EGL_CONTEXT_MINOR_VERSION_KHR,          3,
+            EGL_CONTEXT_OPENGL_PROFILE_MASK_KHR,    EGL_CONTEXT_OPENGL_CORE_PROFILE_BIT_KHR,
+            egl::NONE as i32,                       egl::NONE as i32,
+        ];
+        let egl_context = egl::CreateContext(egl_display,
+                                             egl_config,
+                                             share_context,
+                                             attributes.as_ptr());
+        if egl_context == egl::NO_CONTEXT {
+            return Err(())
+        }
+
+        let egl_surface = if display.surfaceless {
+            egl::NO_SURFACE
+        } else {
+            let attributes = [
+                egl::WIDTH as i32,  1,
+                egl::HEIGHT as i32, 1,
+                egl::NONE as i32,   egl::NONE as i32,
+            ];
+            let egl_surface = egl::CreatePbufferSurface(egl_display,
+                                                        egl_config,
+                                                        attributes.as_ptr());
+            if egl_surface == egl::NO_SURFACE {
+                egl::DestroyContext(egl_display, egl_context);
+                return Err(())
+            }
+            egl_surface
+        };
+
+        let mut interface = HeadlessInterface {
+            display,
+            egl_config,
+            egl_context,
+            egl_surface,
+            framebuffer: 0,
+            color_renderbuffer: 0,
+            depth_stencil_renderbuffer: 0,
+            size: *size,
+        };
+
+        if egl::MakeCurrent(egl_display, egl_surface, egl_surface, egl_context) != egl::TRUE {
+            return Err(())
+        }
+
+        // We need GL symbols to create our framebuffer, before the backend has loaded them.
+        gl::load_with(|symbol| interface.get_proc_address(symbol).unwrap_or(ptr::null()));
+
+        // Create the framebuffer that the backend renders into. It needs a depth buffer, since
+        // the backend uses the depth test to composite opaque layers.
+        gl::GenRenderbuffers(1, &mut interface.color_renderbuffer);
+        gl::BindRenderbuffer(gl::RENDERBUFFER, interface.color_renderbuffer);
+        gl::RenderbufferStorage(gl::RENDERBUFFER,
+                                gl::RGBA8,
+                                size.width as GLint,
+                                size.height as GLint);
+        gl::GenRenderbuffers(1, &mut interface.depth_stencil_renderbuffer);
+        gl::BindRenderbuffer(gl::RENDERBUFFER, interface.depth_stencil_renderbuffer);
+        gl::RenderbufferStorage(gl::RENDERBUFFER,
+                                gl::DEPTH24_STENCIL8,
+                                size.width as GLint,
+                                size.height as GLint);
+
+        gl::GenFramebuffers(1, &mut interface.framebuffer);
+        gl::BindFramebuffer(gl::FRAMEBUFFER, interface.framebuffer);
+        gl::FramebufferRenderbuffer(gl::FRAMEBUFFER,
+                                    gl::COLOR_ATTACHMENT0,
+                                    gl::RENDERBUFFER,
+                                    interface.color_renderbuffer);
+        gl::FramebufferRenderbuffer(gl::FRAMEBUFFER,
+                                    gl::DEPTH_STENCIL_ATTACHMENT,
+                                    gl::RENDERBUFFER,
+                                    interface.depth_stencil_renderbuffer);
+        let status = gl::CheckFramebufferStatus(gl::FRAMEBUFFER);
+        gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+        if status != gl::FRAMEBUFFER_COMPLETE {
+            return Err(())
+        }
+
+        Ok(interface)
+    }
+}
+
+impl GLInterface for HeadlessInterface {
+    fn gl_api(&self) -> GLAPI {
+        GLAPI::GL
+    }
+
+    fn get_proc_address(&self, symbol: &str) -> Option<*const c_void> {
+        let symbol = CString::new(symbol.as_bytes()).unwrap();
+        unsafe {
+            let address = egl::GetProcAddress(symbol.as_ptr()) as *const _ as *const c_void;
+            if address.is_null() {
+                None
+            } else {
+                Some(address)
+            }
+        }
+    }
+
+    fn make_current(&self) {
+        unsafe {
+            let result = egl::MakeCurrent(self.display.egl_display,
+                                          self.egl_surface,
+                                          self.egl_surface,
+                                          self.egl_context);
+            assert_eq!(result, egl::TRUE);
+        }
+    }
+
+    fn prepare_to_draw(&mut self) {
+        // There's nothing underneath the root layer to show through, so start from transparent
+        // black like a freshly-mapped window would.
+        unsafe {
+            gl::BindFramebuffer(gl::FRAMEBUFFER, self.framebuffer);
+            gl::ClearColor(0.0, 0.0, 0.0, 0.0);
+            gl::Clear(gl::COLOR_BUFFER_BIT);
+        }
+    }
+
+    fn present(&mut self, _: &Rect<f32>) {
+        // Nobody is looking at the framebuffer, but make sure rendering actually completes so
+        // that timing in tests resembles a real backend.
+        unsafe {
+            gl::Finish();
+        }
+    }
+
+    fn default_framebuffer(&self) -> GLuint {
+        self.framebuffer
+    }
+
+    fn default_framebuffer_size(&self) -> Size2D<u32> {
+        self.size
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn window(&self) -> Option<&Window> {
+        None
+    }
+}
+
+impl Drop for HeadlessInterface {
+    fn drop(&mut self) {
+        unsafe {
+            let egl_display = self.display.egl_display;
+            if egl::MakeCurrent(egl_display,
+                                self.egl_surface,
+                                self.egl_surface,
+                                self.egl_context) == egl::TRUE {
+                gl::DeleteFramebuffers(1, &mut self.framebuffer);
+                gl::DeleteRenderbuffers(1, &mut self.depth_stencil_renderbuffer);
+                gl::DeleteRenderbuffers(1, &mut self.color_renderbuffer);
+            }
+
+            egl::MakeCurrent(egl_display, egl::NO_SURFACE, egl::NO_SURFACE, egl::NO_CONTEXT);
+            if self.egl_surface != egl::NO_SURFACE {
+                egl::DestroySurface(egl_display, self.egl_surface);
+            }
+            egl::DestroyContext(egl_display, self.egl_context);
+        }
+    }
+}
+
+// The EGL display, shared among all the interfaces created from the same `HeadlessInterface`.
+struct HeadlessDisplay {
+    egl_display: EGLDisplay,
+    surfaceless: bool,
+}
+
+impl HeadlessDisplay {
+    unsafe fn new() -> Result<HeadlessDisplay, ()> {
+        // Prefer Mesa's surfaceless platform, which needs no display server at all. Otherwise,
+        // fall back to the default display, which works with headless drivers like NVIDIA's.
+        let mut egl_display = egl::NO_DISPLAY;
+        if has_extension(egl::NO_DISPLAY, "EGL_MESA_platform_surfaceless") {
+            let symbol = CString::new("eglGetPlatformDisplayEXT").unwrap();
+            let get_platform_display =
+                egl::GetProcAddress(symbol.as_ptr()) as *const _ as *const c_void;
+            if !get_platform_display.is_null() {
+                let get_platform_display: GetPlatformDisplayEXTFn =
+                    mem::transmute(get_platform_display);
+                egl_display = get_platform_display(EGL_PLATFORM_SURFACELESS_MESA,
+                                                   ptr::null_mut(),
+                                                   ptr::null());
+            }
+        }
+        if egl_display == egl::NO_DISPLAY {
+            egl_display = egl::GetDisplay(egl::DEFAULT_DISPLAY as *mut _);
+        }
+        if egl_display == egl::NO_DISPLAY {
+            return Err(())
+        }
+
+        let mut display_count = DISPLAY_COUNT.lock().unwrap();
+        if egl::Initialize(egl_display, ptr::null_mut(), ptr::null_mut()) != egl::TRUE {
+            return Err(())
+        }
+        if egl::BindAPI(egl::OPENGL_API) != egl::TRUE {
+            if *display_count == 0 {
+                egl::Terminate(egl_display);
+            }
+            return Err(())
+        }
+        *display_count += 1;
+
+        let surfaceless = has_extension(egl_display, "EGL_KHR_surfaceless_context");
+        Ok(HeadlessDisplay { egl_display, surfaceless })
+    }
+}
+
+impl Drop for HeadlessDisplay {
+    fn drop(&mut self) {
+        let mut display_count = DISPLAY_COUNT.lock().unwrap();
+        *display_count -= 1;
+        if *display_count == 0 {
+            unsafe {
+                egl::Terminate(self.egl_display);
+            }
+        }
+    }
+}
+
+unsafe fn choose_config(egl_display: EGLDisplay) -> Result<EGLConfig, ()> {
+    let (mut config, mut num_configs) = (ptr::null(), 0);
+    let attributes = [
+        egl::SURFACE_TYPE as i32,       egl::PBUFFER_BIT as i32,
+        egl::RENDERABLE_TYPE as i32,    egl::OPENGL_BIT as i32,
+        egl::RED_SIZE as i32,           8,
+        egl::GREEN_SIZE as i32,         8,
+        egl::BLUE_SIZE as i32,          8,
+        egl::ALPHA_SIZE as i32,         8,
+        egl::NONE as i32,               egl::NONE as i32,
+    ];
+    let result = egl::ChooseConfig(egl_display,
+                                   attributes.as_ptr(),
+                                   &mut config,
+                                   1,
+                                   &mut num_configs);
+    if result != egl::TRUE || num_configs == 0 {
+        return Err(())
+    }
+    Ok(config)
+}
+
+// Passing `NO_DISPLAY` queries client extensions, per `EGL_EXT_client_extensions`.
+unsafe fn has_extension(egl_display: EGLDisplay, name: &str) -> bool {
+    let extensions = egl::QueryString(egl_display, egl::EXTENSIONS as EGLint);
+    if extensions.is_null() {
+        // The driver doesn't support client extensions, so it can't support the extension.
+        return false
+    }
+    let extensions = CStr::from_ptr(extensions).to_string_lossy();
+    extensions.split_whitespace().any(|extension| extension == name)
+}
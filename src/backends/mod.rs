@@ -27,3 +27,11 @@ pub mod wayland;
 // Special backends
 pub mod gl;
 pub mod alternate;
+pub mod dynamic;
+#[cfg(target_os = "linux")]
+pub mod headless;
+pub mod recording;
+#[cfg(unix)]
+pub mod remote;
+pub mod runtime;
+pub mod software;
@@ -0,0 +1,579 @@
+// planeshift/src/backends/recording.rs
+//
+// Copyright © 2018 The Pathfinder Project Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A mock backend that records every call made to it, for unit testing.
+//!
+//! This backend has no graphics side effects. Pass an `EventLog` as a `Connection::Native` to
+//! `LayerContext::with_backend_connection()`, keep a clone of it, and inspect the calls that the
+//! `LayerContext` made with `EventLog::events()` or `EventLog::take()`. To test error handling,
+//! `EventLog::set_failing()` makes every call that can fail do so.
+
+use euclid::{Rect, Size2D, Vector2D};
+use image::RgbaImage;
+use std::cell::{Cell, RefCell};
+use std::mem;
+use std::rc::Rc;
+
+#[cfg(feature = "enable-winit")]
+use winit::{EventsLoop, Window, WindowBuilder, WindowId};
+
+use crate::{Connection, ConnectionError, GLAPI, GLContextLayerBinding, LayerContainerInfo};
+use crate::{LayerGeometryInfo, LayerId, LayerMap, LayerSurfaceInfo, LayerTreeInfo, Promise};
+use crate::{Capabilities, ExportToken, ScreenshotError, SurfaceOptions, VideoFrame, YUVFormat};
+use crate::{LayerEffects, LayerEffectsInfo, LayerScrollInfo};
+
+/// A call made to the recording backend.
+///
+/// Where a trait method receives the new state of a layer through the components, the event
+/// records that state instead, so that tests don't have to reconstruct it.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Event {
+    CreateGLContext(SurfaceOptions),
+    WrapGLContext,
+    GLAPI,
+    Capabilities,
+    BeginTransaction,
+    EndTransaction,
+    AddContainerLayer(LayerId),
+    AddSurfaceLayer(LayerId),
+    AddScrollLayer(LayerId),
+    DeleteLayer(LayerId),
+    /// The parent, the new child, and the reference layer.
+    InsertBefore(LayerId, LayerId, Option<LayerId>),
+    /// The old child and its former parent.
+    RemoveFromSuperlayer(LayerId, LayerId),
+    HostLayer(LayerId),
+    UnhostLayer(LayerId),
+    ExportLayer(LayerId),
+    /// The new layer and the token that it was imported from.
+    ImportLayer(LayerId, ExportToken),
+    ImportedLayersChanged,
+    /// The layer, its old bounds, and its new bounds.
+    SetLayerBounds(LayerId, Rect<f32>, Rect<f32>),
+    SetLayerSurfaceOptions(LayerId, SurfaceOptions),
+    /// The layer and its new effects.
+    SetLayerEffects(LayerId, LayerEffects),
+    /// The layer and its new mask.
+    SetLayerMask(LayerId, Option<LayerId>),
+    /// The layer and its new content size.
+    SetLayerContentSize(LayerId, Size2D<f32>),
+    /// The layer and its new content offset.
+    SetLayerContentOffset(LayerId, Vector2D<f32>),
+    /// The layer and the velocity that it was flung at.
+    StartFling(LayerId, Vector2D<f32>),
+    BindLayerToGLContext(LayerId),
+    /// The layer and the rect that changed.
+    PresentGLContext(LayerId, Rect<f32>),
+    AddVideoLayer(LayerId),
+    /// The layer, and the format and size of the frame.
+    PresentVideoFrame(LayerId, YUVFormat, Size2D<u32>),
+    ScreenshotHostedLayer(LayerId),
+    PumpEvents,
+    Window,
+    HostLayerInWindow(LayerId),
+    CreateWindow,
+    AdoptWindow,
+    WindowWithId,
+    HostLayerInWindowWithId(LayerId),
+}
+
+/// A shared list of the calls that a recording backend has received.
+#[derive(Clone)]
+pub struct EventLog {
+    events: Rc<RefCell<Vec<Event>>>,
+    failing: Rc<Cell<bool>>,
+}
+
+impl EventLog {
+    #[inline]
+    pub fn new() -> EventLog {
+        EventLog {
+            events: Rc::new(RefCell::new(vec![])),
+            failing: Rc::new(Cell::new(false)),
+        }
+    }
+
+    /// Returns a copy of all the events recorded so far.
+    pub fn events(&self) -> Vec<Event> {
+        self.events.borrow().clone()
+    }
+
+    /// Returns all the events recorded so far and clears the log.
+    pub fn take(&self) -> Vec<Event> {
+        mem::replace(&mut *self.events.borrow_mut(), vec![])
+    }
+
+    /// Sets whether the calls that can fail, such as `export_layer` and `create_gl_context`, do
+    /// so. They're recorded either way.
+    pub fn set_failing(&self, failing: bool) {
+        self.failing.set(failing)
+    }
+
+    fn push(&self, event: Event) {
+        self.events.borrow_mut().push(event)
+    }
+
+    // Returns the result of a call that can fail.
+    fn result(&self) -> Result<(), ()> {
+        if self.failing.get() {
+            Err(())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+pub struct Backend {
+    log: EventLog,
+
+    #[cfg(feature = "enable-winit")]
+    windows: Vec<Window>,
+}
+
+impl crate::Backend for Backend {
+    type NativeConnection = EventLog;
+    type GLContext = ();
+    type NativeGLContext = ();
+    type Host = ();
+
+    // Constructor
+
+    fn new(connection: Connection<EventLog>) -> Result<Backend, ConnectionError> {
+        let log = match connection {
+            Connection::Native(log) => log,
+            // Don't open a window; nobody would be able to see the log anyway.
+            #[cfg(feature = "enable-winit")]
+            Connection::Winit(..) => EventLog::new(),
+        };
+
+        Ok(Backend {
+            log,
+
+            #[cfg(feature = "enable-winit")]
+            windows: vec![],
+        })
+    }
+
+    // OpenGL context creation
+
+    fn create_gl_context(&mut self, options: SurfaceOptions) -> Result<(), ()> {
+        self.log.push(Event::CreateGLContext(options));
+        self.log.result()
+    }
+
+    unsafe fn wrap_gl_context(&mut self, _: ()) -> Result<(), ()> {
+        self.log.push(Event::WrapGLContext);
+        self.log.result()
+    }
+
+    fn gl_api(&self) -> GLAPI {
+        self.log.push(Event::GLAPI);
+        GLAPI::None
+    }
+
+    // Capabilities
+
+    fn capabilities(&self) -> Capabilities {
+        self.log.push(Event::Capabilities);
+
+        // Accept everything so that tests can exercise every code path in the layer context.
+        Capabilities {
+            screenshots: false,
+            damage_aware_present: false,
+            transforms: false,
+            native_animations: false,
+            surface_options: SurfaceOptions::all(),
+            max_texture_size: None,
+            layer_export: true,
+            video_layers: true,
+            layer_effects: true,
+            backdrop_blur: true,
+            blend_modes: true,
+            layer_masks: true,
+            scroll_clipping: true,
+        }
+    }
+
+    // Transactions
+
+    fn begin_transaction(&self) {
+        self.log.push(Event::BeginTransaction);
+    }
+
+    fn end_transaction(&mut self,
+                       promise: &Promise<()>,
+                       _: &LayerMap<LayerTreeInfo>,
+                       _: &LayerMap<LayerContainerInfo>,
+                       _: &LayerMap<LayerGeometryInfo>,
+                       _: &LayerMap<LayerSurfaceInfo>) {
+        self.log.push(Event::EndTransaction);
+        promise.resolve(());
+    }
+
+    // Layer creation and destruction
+
+    fn add_container_layer(&mut self, layer: LayerId) {
+        self.log.push(Event::AddContainerLayer(layer));
+    }
+
+    fn add_surface_layer(&mut self, layer: LayerId) {
+        self.log.push(Event::AddSurfaceLayer(layer));
+    }
+
+    fn add_scroll_layer(&mut self, layer: LayerId) {
+        self.log.push(Event::AddScrollLayer(layer));
+    }
+
+    fn delete_layer(&mut self, layer: LayerId) {
+        self.log.push(Event::DeleteLayer(layer));
+    }
+
+    // Layer tree management
+
+    fn insert_before(&mut self,
+                     parent: LayerId,
+                     new_child: LayerId,
+                     reference: Option<LayerId>,
+                     _: &LayerMap<LayerTreeInfo>,
+                     _: &LayerMap<LayerContainerInfo>,
+                     _: &LayerMap<LayerGeometryInfo>) {
+        self.log.push(Event::InsertBefore(parent, new_child, reference));
+    }
+
+    fn remove_from_superlayer(&mut self,
+                              layer: LayerId,
+                              parent: LayerId,
+                              _: &LayerMap<LayerTreeInfo>,
+                              _: &LayerMap<LayerGeometryInfo>) {
+        self.log.push(Event::RemoveFromSuperlayer(layer, parent));
+    }
+
+    // Native hosting
+
+    unsafe fn host_layer(&mut self,
+                         layer: LayerId,
+                         _: (),
+                         _: &LayerMap<LayerTreeInfo>,
+                         _: &LayerMap<LayerContainerInfo>,
+                         _: &LayerMap<LayerGeometryInfo>) {
+        self.log.push(Event::HostLayer(layer));
+    }
+
+    fn unhost_layer(&mut self, layer: LayerId) {
+        self.log.push(Event::UnhostLayer(layer));
+    }
+
+    // Cross-process hosting
+
+    fn export_layer(&mut self,
+                    layer: LayerId,
+                    _: &LayerMap<LayerTreeInfo>,
+                    _: &LayerMap<LayerContainerInfo>,
+                    _: &LayerMap<LayerGeometryInfo>)
+                    -> Result<ExportToken, ()> {
+        self.log.push(Event::ExportLayer(layer));
+        self.log.result()?;
+        Ok(ExportToken::from_bytes(format!("recording:{}", layer.0).into_bytes()))
+    }
+
+    fn import_layer(&mut self, new_layer: LayerId, token: &ExportToken) -> Result<(), ()> {
+        self.log.push(Event::ImportLayer(new_layer, token.clone()));
+        self.log.result()
+    }
+
+    fn imported_layers_changed(&mut self) -> bool {
+        // Nothing is ever exported to us, so nothing changes.
+        self.log.push(Event::ImportedLayersChanged);
+        false
+    }
+
+    // Geometry
+
+    fn set_layer_bounds(&mut self,
+                        layer: LayerId,
+                        old_bounds: &Rect<f32>,
+                        _: &LayerMap<LayerTreeInfo>,
+                        _: &LayerMap<LayerContainerInfo>,
+                        geometry_component: &LayerMap<LayerGeometryInfo>) {
+        let new_bounds = geometry_component[layer].bounds;
+        self.log.push(Event::SetLayerBounds(layer, *old_bounds, new_bounds));
+    }
+
+    // Miscellaneous layer flags
+
+    fn set_layer_surface_options(&mut self,
+                                 layer: LayerId,
+                                 surface_component: &LayerMap<LayerSurfaceInfo>) {
+        let options = surface_component[layer].options;
+        self.log.push(Event::SetLayerSurfaceOptions(layer, options));
+    }
+
+    // Effects
+
+    fn set_layer_effects(&mut self,
+                         layer: LayerId,
+                         _: &LayerEffects,
+                         _: &LayerMap<LayerTreeInfo>,
+                         _: &LayerMap<LayerGeometryInfo>,
+                         effects_component: &LayerMap<LayerEffectsInfo>) {
+        let effects = effects_component[layer].effects;
+        self.log.push(Event::SetLayerEffects(layer, effects));
+    }
+
+    fn set_layer_mask(&mut self,
+                      layer: LayerId,
+                      _: &LayerMap<LayerTreeInfo>,
+                      _: &LayerMap<LayerGeometryInfo>,
+                      effects_component: &LayerMap<LayerEffectsInfo>) {
+        self.log.push(Event::SetLayerMask(layer, effects_component[layer].mask));
+    }
+
+    // Scrolling
+
+    fn set_layer_content_size(&mut self,
+                              layer: LayerId,
+                              scroll_component: &LayerMap<LayerScrollInfo>) {
+        let content_size = scroll_component[layer].content_size;
+        self.log.push(Event::SetLayerContentSize(layer, content_size));
+    }
+
+    fn set_layer_content_offset(&mut self,
+                                layer: LayerId,
+                                _: &LayerMap<LayerTreeInfo>,
+                                _: &LayerMap<LayerContainerInfo>,
+                                _: &LayerMap<LayerGeometryInfo>,
+                                scroll_component: &LayerMap<LayerScrollInfo>) {
+        let content_offset = scroll_component[layer].content_offset;
+        self.log.push(Event::SetLayerContentOffset(layer, content_offset));
+    }
+
+    fn start_fling(&mut self,
+                   layer: LayerId,
+                   velocity: &Vector2D<f32>,
+                   _: &Promise<Vector2D<f32>>,
+                   _: &LayerMap<LayerGeometryInfo>,
+                   _: &LayerMap<LayerScrollInfo>)
+                   -> Result<(), ()> {
+        // Let the layer context animate the fling, so that each step shows up in the log.
+        self.log.push(Event::StartFling(layer, *velocity));
+        Err(())
+    }
+
+    // OpenGL content binding
+
+    fn bind_layer_to_gl_context(&mut self,
+                                layer: LayerId,
+                                _: &mut (),
+                                _: &LayerMap<LayerGeometryInfo>,
+                                _: &LayerMap<LayerSurfaceInfo>)
+                                -> Result<GLContextLayerBinding, ()> {
+        self.log.push(Event::BindLayerToGLContext(layer));
+        self.log.result()?;
+        Ok(GLContextLayerBinding {
+            layer,
+            framebuffer: 0,
+        })
+    }
+
+    fn present_gl_context(&mut self,
+                          binding: GLContextLayerBinding,
+                          changed_rect: &Rect<f32>,
+                          _: &LayerMap<LayerTreeInfo>,
+                          _: &LayerMap<LayerGeometryInfo>)
+                          -> Result<(), ()> {
+        self.log.push(Event::PresentGLContext(binding.layer, *changed_rect));
+        self.log.result()
+    }
+
+    // Video
+
+    fn add_video_layer(&mut self, layer: LayerId) {
+        self.log.push(Event::AddVideoLayer(layer));
+    }
+
+    fn present_video_frame(&mut self,
+                           layer: LayerId,
+                           frame: &VideoFrame,
+                           _: &LayerMap<LayerTreeInfo>,
+                           _: &LayerMap<LayerGeometryInfo>)
+                           -> Result<(), ()> {
+        self.log.push(Event::PresentVideoFrame(layer, frame.format, frame.size));
+        self.log.result()
+    }
+
+    // Screenshots
+
+    fn screenshot_hosted_layer(&mut self,
+                               layer: LayerId,
+                               _: &Promise<()>,
+                               _: &LayerMap<LayerTreeInfo>,
+                               _: &LayerMap<LayerContainerInfo>,
+                               _: &LayerMap<LayerGeometryInfo>,
+                               _: &LayerMap<LayerSurfaceInfo>)
+                               -> Promise<RgbaImage, ScreenshotError> {
+        self.log.push(Event::ScreenshotHostedLayer(layer));
+
+        // There are no pixels to capture.
+        let promise = Promise::new();
+        promise.reject(ScreenshotError::Unsupported);
+        promise
+    }
+
+    // Event dispatch
+
+    fn pump_events(&mut self) -> Result<(), ()> {
+        // All of our promises are settled synchronously, so there's nothing to wait for.
+        self.log.push(Event::PumpEvents);
+        Err(())
+    }
+
+    // `winit` integration
+
+    #[cfg(feature = "enable-winit")]
+    fn window(&self) -> Option<&Window> {
+        self.log.push(Event::Window);
+        self.windows.get(0)
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn host_layer_in_window(&mut self,
+                            layer: LayerId,
+                            _: &LayerMap<LayerTreeInfo>,
+                            _: &LayerMap<LayerContainerInfo>,
+                            _: &LayerMap<LayerGeometryInfo>)
+                            -> Result<(), ()> {
+        self.log.push(Event::HostLayerInWindow(layer));
+        self.log.result()
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn create_window(&mut self, _: WindowBuilder, _: &EventsLoop) -> Result<WindowId, ()> {
+        // Creating a window would be a side effect, so fail instead. Use `adopt_window()` to test
+        // code that needs one.
+        self.log.push(Event::CreateWindow);
+        Err(())
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn adopt_window(&mut self, window: Window) -> Result<WindowId, Window> {
+        self.log.push(Event::AdoptWindow);
+        let window_id = window.id();
+        self.windows.push(window);
+        Ok(window_id)
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn window_with_id(&self, window_id: WindowId) -> Option<&Window> {
+        self.log.push(Event::WindowWithId);
+        self.windows.iter().find(|window| window.id() == window_id)
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn host_layer_in_window_with_id(&mut self,
+                                    layer: LayerId,
+                                    window_id: WindowId,
+                                    _: &LayerMap<LayerTreeInfo>,
+                                    _: &LayerMap<LayerContainerInfo>,
+                                    _: &LayerMap<LayerGeometryInfo>)
+                                    -> Result<(), ()> {
+        self.log.push(Event::HostLayerInWindowWithId(layer));
+        if !self.windows.iter().any(|window| window.id() == window_id) {
+            return Err(())
+        }
+        self.log.result()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Connection, LayerContext, LayerParent, SurfaceOptions};
+    use super::{Backend, Event, EventLog};
+
+    fn context() -> (LayerContext<Backend>, EventLog) {
+        let log = EventLog::new();
+        let context = LayerContext::with_backend_connection(Connection::Native(log.clone()));
+        (context.unwrap(), log)
+    }
+
+    #[test]
+    fn insert_and_remove_children() {
+        let (mut context, log) = context();
+        context.begin_transaction();
+        let root = context.add_container_layer();
+        let first = context.add_surface_layer();
+        let second = context.add_surface_layer();
+        unsafe {
+            context.host_layer((), root);
+        }
+        context.append_child(root, second);
+        context.insert_before(root, first, Some(second));
+        context.remove_from_parent(second);
+        context.remove_from_parent(root);
+        context.delete_layer(second);
+        context.end_transaction();
+
+        assert_eq!(log.take(), vec![
+            Event::BeginTransaction,
+            Event::AddContainerLayer(root),
+            Event::AddSurfaceLayer(first),
+            Event::AddSurfaceLayer(second),
+            Event::HostLayer(root),
+            Event::InsertBefore(root, second, None),
+            Event::InsertBefore(root, first, Some(second)),
+            Event::RemoveFromSuperlayer(second, root),
+            Event::UnhostLayer(root),
+            Event::DeleteLayer(second),
+            Event::EndTransaction,
+        ]);
+        assert_eq!(context.parent_of(first), Some(&LayerParent::Layer(root)));
+        assert_eq!(context.parent_of(second), None);
+        assert_eq!(context.parent_of(root), None);
+    }
+
+    #[test]
+    fn record_failing_calls() {
+        let (mut context, log) = context();
+        context.begin_transaction();
+        let layer = context.add_surface_layer();
+        context.end_transaction();
+        log.take();
+
+        log.set_failing(true);
+        assert!(context.create_gl_context(SurfaceOptions::empty()).is_err());
+        log.set_failing(false);
+        let mut gl_context = context.create_gl_context(SurfaceOptions::empty()).unwrap();
+        log.set_failing(true);
+        context.begin_transaction();
+        assert!(context.bind_layer_to_gl_context(layer, &mut gl_context).is_err());
+        context.end_transaction();
+
+        assert_eq!(log.take(), vec![
+            Event::CreateGLContext(SurfaceOptions::empty()),
+            Event::CreateGLContext(SurfaceOptions::empty()),
+            Event::BeginTransaction,
+            Event::BindLayerToGLContext(layer),
+            Event::EndTransaction,
+        ]);
+    }
+
+    #[test]
+    fn record_queries() {
+        let (context, log) = context();
+        context.gl_api();
+        context.capabilities();
+        #[cfg(feature = "enable-winit")]
+        {
+            assert!(context.window().is_none());
+            assert_eq!(log.take(), vec![Event::GLAPI, Event::Capabilities, Event::Window]);
+        }
+        #[cfg(not(feature = "enable-winit"))]
+        assert_eq!(log.take(), vec![Event::GLAPI, Event::Capabilities]);
+    }
+}
@@ -0,0 +1,673 @@
+// planeshift/src/backends/remote.rs
+//
+// Copyright © 2018 The Pathfinder Project Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A backend that forwards layer operations to a compositor in another process.
+//!
+//! The layer tree is replayed by a `planeshift::server::Server` into a real `LayerContext` on
+//! the other end of a Unix socket, so a crash in this process doesn't take down the windows that
+//! display its layers. Pass a connected `UnixStream` as a `Connection::Native`, or set
+//! `PLANESHIFT_SOCKET` to the path of the server's socket and pass a `Connection::Winit`.
+//! Connecting waits for the server to accept the client, at which point the server reports what
+//! it can do for the client; `capabilities()` returns that.
+//!
+//! Like the software backend, surface layers are drawn on the CPU, via `GLContext::pixels_mut()`
+//! between `bind_layer_to_gl_context()` and `present_gl_context()`. Each surface is backed by a
+//! file whose descriptor is passed to the server, which reads the pixels from it on present.
+//! Surface pixels are premultiplied RGBA, top row first.
+
+use euclid::{Point2D, Rect, Size2D, Vector2D};
+use image::RgbaImage;
+use std::cell::{Cell, RefCell, RefMut};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::rc::Rc;
+
+#[cfg(feature = "enable-winit")]
+use std::env;
+
+#[cfg(feature = "enable-winit")]
+use winit::{EventsLoop, Window, WindowBuilder, WindowId};
+
+use crate::protocol::{self, Receiver, Reply, Request};
+use crate::{Connection, ConnectionError, GLAPI, GLContextLayerBinding, LayerContainerInfo};
+use crate::{LayerGeometryInfo, LayerId, LayerMap, LayerSurfaceInfo, LayerTreeInfo, Promise};
+use crate::{Capabilities, ExportToken, ScreenshotError, SurfaceOptions, VideoFrame};
+use crate::{LayerEffects, LayerEffectsInfo, LayerScrollInfo};
+use crate::video;
+
+/// The environment variable that holds the path of the server's socket, used when connecting
+/// with a `Connection::Winit`.
+pub const SOCKET_ENV_VAR: &'static str = "PLANESHIFT_SOCKET";
+
+/// The host that `host_layer_in_window()` uses: the server's main window.
+pub const MAIN_WINDOW_HOST: HostId = HostId(0);
+
+pub struct Backend {
+    native_component: LayerMap<LayerNativeInfo>,
+
+    stream: UnixStream,
+    receiver: Receiver,
+    // What the server told us that we can do when it accepted us.
+    capabilities: Capabilities,
+    pending_replies: VecDeque<PendingReply>,
+    // Flings that the server is animating for us.
+    flings: Vec<PendingFling>,
+    next_fling_id: u32,
+    // Set once the server has gone away, after which every request is dropped.
+    disconnected: Cell<bool>,
+}
+
+/// Identifies a place in the server's user interface where a root layer can be hosted.
+///
+/// What each host means is up to the server's `Delegate`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct HostId(pub u32);
+
+impl crate::Backend for Backend {
+    type NativeConnection = UnixStream;
+    type GLContext = GLContext;
+    type NativeGLContext = ();
+    type Host = HostId;
+
+    // Constructor
+
+    fn new(connection: Connection<UnixStream>) -> Result<Backend, ConnectionError> {
+        let stream = match connection {
+            Connection::Native(stream) => stream,
+            // The server owns the windows, so don't open one.
+            #[cfg(feature = "enable-winit")]
+            Connection::Winit(..) => {
+                let path = match env::var_os(SOCKET_ENV_VAR) {
+                    None => {
+                        return Err(ConnectionError::with_reason("PLANESHIFT_SOCKET isn't set"))
+                    }
+                    Some(path) => path,
+                };
+                match UnixStream::connect(path) {
+                    Ok(stream) => stream,
+                    Err(_) => {
+                        return Err(ConnectionError::with_reason("failed to connect to the server"))
+                    }
+                }
+            }
+        };
+
+        let mut receiver = Receiver::new(protocol::MAX_REPLY_SIZE, 0);
+        let capabilities = match receive_capabilities(&stream, &mut receiver) {
+            Ok(capabilities) => capabilities,
+            Err(error) => {
+                warn!("The compositor server didn't accept the connection: {}", error);
+                return Err(ConnectionError::with_reason("the server refused the connection"))
+            }
+        };
+
+        Ok(Backend {
+            native_component: LayerMap::new(),
+
+            stream,
+            receiver,
+            capabilities,
+            pending_replies: VecDeque::new(),
+            flings: vec![],
+            next_fling_id: 0,
+            disconnected: Cell::new(false),
+        })
+    }
+
+    // OpenGL context creation
+
+    fn create_gl_context(&mut self, options: SurfaceOptions) -> Result<GLContext, ()> {
+        // Surfaces are plain 8-bit RGBA pixels, like those of the software backend.
+        if !(options - SurfaceOptions::OPAQUE).is_empty() {
+            return Err(())
+        }
+
+        Ok(GLContext {
+            surface: None,
+        })
+    }
+
+    unsafe fn wrap_gl_context(&mut self, _: ()) -> Result<GLContext, ()> {
+        Ok(GLContext {
+            surface: None,
+        })
+    }
+
+    fn gl_api(&self) -> GLAPI {
+        GLAPI::None
+    }
+
+    // Capabilities
+
+    fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    // Transactions
+
+    fn begin_transaction(&self) {
+        self.send(&Request::BeginTransaction);
+    }
+
+    fn end_transaction(&mut self,
+                       promise: &Promise<()>,
+                       _: &LayerMap<LayerTreeInfo>,
+                       _: &LayerMap<LayerContainerInfo>,
+                       _: &LayerMap<LayerGeometryInfo>,
+                       _: &LayerMap<LayerSurfaceInfo>) {
+        if !self.send(&Request::EndTransaction) {
+            promise.reject(());
+            return
+        }
+        self.pending_replies.push_back(PendingReply::Transaction(promise.clone()));
+    }
+
+    // Layer creation and destruction
+
+    fn add_container_layer(&mut self, layer: LayerId) {
+        self.send(&Request::AddContainerLayer(layer));
+    }
+
+    fn add_surface_layer(&mut self, layer: LayerId) {
+        self.native_component.add(layer, LayerNativeInfo { buffer: None });
+        self.send(&Request::AddSurfaceLayer(layer));
+    }
+
+    fn add_scroll_layer(&mut self, layer: LayerId) {
+        self.send(&Request::AddScrollLayer(layer));
+    }
+
+    fn delete_layer(&mut self, layer: LayerId) {
+        self.native_component.remove_if_present(layer);
+        self.flings.retain(|fling| fling.layer != layer);
+        self.send(&Request::DeleteLayer(layer));
+    }
+
+    // Layer tree management
+
+    fn insert_before(&mut self,
+                     parent: LayerId,
+                     new_child: LayerId,
+                     reference: Option<LayerId>,
+                     _: &LayerMap<LayerTreeInfo>,
+                     _: &LayerMap<LayerContainerInfo>,
+                     _: &LayerMap<LayerGeometryInfo>) {
+        self.send(&Request::InsertBefore(parent, new_child, reference));
+    }
+
+    fn remove_from_superlayer(&mut self,
+                              layer: LayerId,
+                              _: LayerId,
+                              _: &LayerMap<LayerTreeInfo>,
+                              _: &LayerMap<LayerGeometryInfo>) {
+        self.send(&Request::RemoveFromParent(layer));
+    }
+
+    // Native hosting
+
+    unsafe fn host_layer(&mut self,
+                         layer: LayerId,
+                         host: HostId,
+                         _: &LayerMap<LayerTreeInfo>,
+                         _: &LayerMap<LayerContainerInfo>,
+                         _: &LayerMap<LayerGeometryInfo>) {
+        self.send(&Request::HostLayer(layer, host.0));
+    }
+
+    fn unhost_layer(&mut self, layer: LayerId) {
+        self.send(&Request::RemoveFromParent(layer));
+    }
+
+    // Cross-process hosting
+
+    // TODO(pcwalton): Let clients of the same server share layers by having the server hand out
+    // tokens that name layers in its own context.
+    fn export_layer(&mut self,
+                    _: LayerId,
+                    _: &LayerMap<LayerTreeInfo>,
+                    _: &LayerMap<LayerContainerInfo>,
+                    _: &LayerMap<LayerGeometryInfo>)
+                    -> Result<ExportToken, ()> {
+        Err(())
+    }
+
+    fn import_layer(&mut self, _: LayerId, _: &ExportToken) -> Result<(), ()> {
+        Err(())
+    }
+
+    fn imported_layers_changed(&mut self) -> bool {
+        false
+    }
+
+    // Geometry
+
+    fn set_layer_bounds(&mut self,
+                        layer: LayerId,
+                        _: &Rect<f32>,
+                        _: &LayerMap<LayerTreeInfo>,
+                        _: &LayerMap<LayerContainerInfo>,
+                        geometry_component: &LayerMap<LayerGeometryInfo>) {
+        self.send(&Request::SetLayerBounds(layer, geometry_component[layer].bounds));
+    }
+
+    // Miscellaneous layer flags
+
+    fn set_layer_surface_options(&mut self,
+                                 layer: LayerId,
+                                 surface_component: &LayerMap<LayerSurfaceInfo>) {
+        self.send(&Request::SetLayerSurfaceOptions(layer, surface_component[layer].options));
+    }
+
+    // Effects
+
+    fn set_layer_effects(&mut self,
+                         layer: LayerId,
+                         _: &LayerEffects,
+                         _: &LayerMap<LayerTreeInfo>,
+                         _: &LayerMap<LayerGeometryInfo>,
+                         effects_component: &LayerMap<LayerEffectsInfo>) {
+        self.send(&Request::SetLayerEffects(layer, effects_component[layer].effects));
+    }
+
+    fn set_layer_mask(&mut self,
+                      layer: LayerId,
+                      _: &LayerMap<LayerTreeInfo>,
+                      _: &LayerMap<LayerGeometryInfo>,
+                      effects_component: &LayerMap<LayerEffectsInfo>) {
+        self.send(&Request::SetLayerMask(layer, effects_component[layer].mask));
+    }
+
+    // Scrolling
+
+    fn set_layer_content_size(&mut self,
+                              layer: LayerId,
+                              scroll_component: &LayerMap<LayerScrollInfo>) {
+        self.send(&Request::SetLayerContentSize(layer, scroll_component[layer].content_size));
+    }
+
+    fn set_layer_content_offset(&mut self,
+                                layer: LayerId,
+                                _: &LayerMap<LayerTreeInfo>,
+                                _: &LayerMap<LayerContainerInfo>,
+                                _: &LayerMap<LayerGeometryInfo>,
+                                scroll_component: &LayerMap<LayerScrollInfo>) {
+        // The server stops its fling of the layer, too.
+        self.flings.retain(|fling| fling.layer != layer);
+        let content_offset = scroll_component[layer].content_offset;
+        self.send(&Request::SetLayerContentOffset(layer, content_offset));
+    }
+
+    fn start_fling(&mut self,
+                   layer: LayerId,
+                   velocity: &Vector2D<f32>,
+                   promise: &Promise<Vector2D<f32>>,
+                   _: &LayerMap<LayerGeometryInfo>,
+                   _: &LayerMap<LayerScrollInfo>)
+                   -> Result<(), ()> {
+        // The server animates the fling, so the layer keeps moving smoothly while we're busy.
+        let id = self.next_fling_id;
+        self.next_fling_id = self.next_fling_id.wrapping_add(1);
+        if !self.send(&Request::StartFling(id, layer, *velocity)) {
+            return Err(())
+        }
+        self.flings.retain(|fling| fling.layer != layer);
+        self.flings.push(PendingFling { id, layer, promise: promise.clone() });
+        Ok(())
+    }
+
+    // OpenGL content binding
+
+    fn bind_layer_to_gl_context(&mut self,
+                                layer: LayerId,
+                                context: &mut GLContext,
+                                geometry_component: &LayerMap<LayerGeometryInfo>,
+                                _: &LayerMap<LayerSurfaceInfo>)
+                                -> Result<GLContextLayerBinding, ()> {
+        self.ensure_buffer(layer, geometry_component)?;
+
+        context.surface = self.native_component[layer].buffer
+                                                       .as_ref()
+                                                       .map(|buffer| buffer.pixels.clone());
+
+        Ok(GLContextLayerBinding {
+            layer,
+            framebuffer: 0,
+        })
+    }
+
+    fn present_gl_context(&mut self,
+                          binding: GLContextLayerBinding,
+                          changed_rect: &Rect<f32>,
+                          _: &LayerMap<LayerTreeInfo>,
+                          _: &LayerMap<LayerGeometryInfo>)
+                          -> Result<(), ()> {
+        // FIXME(pcwalton): Map the buffer so that clients can draw into it directly, and only
+        // copy the rows that changed.
+        {
+            let buffer = self.native_component[binding.layer].buffer.as_ref().ok_or(())?;
+            buffer.file.write_all_at(&**buffer.pixels.borrow(), 0).map_err(drop)?;
+        }
+
+        if !self.send(&Request::PresentBuffer(binding.layer, *changed_rect)) {
+            return Err(())
+        }
+        Ok(())
+    }
+
+    // Video
+
+    // FIXME(pcwalton): Send the planes to the server as they are, so that its backend can convert
+    // them on the GPU.
+    fn add_video_layer(&mut self, layer: LayerId) {
+        self.add_surface_layer(layer);
+        self.send(&Request::SetLayerSurfaceOptions(layer, SurfaceOptions::OPAQUE));
+    }
+
+    fn present_video_frame(&mut self,
+                           layer: LayerId,
+                           frame: &VideoFrame,
+                           _: &LayerMap<LayerTreeInfo>,
+                           geometry_component: &LayerMap<LayerGeometryInfo>)
+                           -> Result<(), ()> {
+        self.ensure_buffer(layer, geometry_component)?;
+
+        let size = {
+            let buffer = self.native_component[layer].buffer.as_ref().ok_or(())?;
+            let mut pixels = buffer.pixels.borrow_mut();
+            video::convert_to_rgba(frame, &mut pixels)?;
+            buffer.file.write_all_at(&**pixels, 0).map_err(drop)?;
+            pixels.dimensions()
+        };
+
+        let rect = Rect::new(Point2D::zero(), Size2D::new(size.0 as f32, size.1 as f32));
+        if !self.send(&Request::PresentBuffer(layer, rect)) {
+            return Err(())
+        }
+        Ok(())
+    }
+
+    // Screenshots
+
+    fn screenshot_hosted_layer(&mut self,
+                               layer: LayerId,
+                               _: &Promise<()>,
+                               _: &LayerMap<LayerTreeInfo>,
+                               _: &LayerMap<LayerContainerInfo>,
+                               _: &LayerMap<LayerGeometryInfo>,
+                               _: &LayerMap<LayerSurfaceInfo>)
+                               -> Promise<RgbaImage, ScreenshotError> {
+        let promise = Promise::new();
+        if !self.send(&Request::ScreenshotHostedLayer(layer)) {
+            promise.reject(ScreenshotError::TransactionFailed);
+            return promise
+        }
+        self.pending_replies.push_back(PendingReply::Screenshot(promise.clone()));
+        promise
+    }
+
+    // Event dispatch
+
+    fn pump_events(&mut self) -> Result<(), ()> {
+        if self.disconnected.get() {
+            let error = io::Error::new(io::ErrorKind::BrokenPipe, "server hung up");
+            self.disconnect(&error);
+        }
+
+        // Only block if the server owes us a reply or will tell us when a fling ends; otherwise,
+        // we'd wait forever.
+        if self.pending_replies.is_empty() && self.flings.is_empty() {
+            return Err(())
+        }
+
+        loop {
+            match self.receiver.next_message() {
+                Ok(None) => {}
+                Ok(Some((payload, _))) => {
+                    match Reply::decode(&payload) {
+                        Ok(reply) => self.dispatch_reply(reply),
+                        Err(()) => {
+                            let error = io::Error::new(io::ErrorKind::InvalidData,
+                                                       "malformed reply");
+                            self.disconnect(&error);
+                        }
+                    }
+                    return Ok(())
+                }
+                Err(error) => {
+                    self.disconnect(&error);
+                    return Ok(())
+                }
+            }
+
+            match self.receiver.receive(&self.stream) {
+                Ok(0) => {
+                    let error = io::Error::new(io::ErrorKind::UnexpectedEof, "server hung up");
+                    self.disconnect(&error);
+                    return Ok(())
+                }
+                Ok(_) => {}
+                Err(error) => {
+                    self.disconnect(&error);
+                    return Ok(())
+                }
+            }
+        }
+    }
+
+    // `winit` integration
+
+    #[cfg(feature = "enable-winit")]
+    fn window(&self) -> Option<&Window> {
+        None
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn host_layer_in_window(&mut self,
+                            layer: LayerId,
+                            tree_component: &LayerMap<LayerTreeInfo>,
+                            container_component: &LayerMap<LayerContainerInfo>,
+                            geometry_component: &LayerMap<LayerGeometryInfo>)
+                            -> Result<(), ()> {
+        unsafe {
+            crate::Backend::host_layer(self,
+                                       layer,
+                                       MAIN_WINDOW_HOST,
+                                       tree_component,
+                                       container_component,
+                                       geometry_component);
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn create_window(&mut self, _: WindowBuilder, _: &EventsLoop) -> Result<WindowId, ()> {
+        Err(())
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn adopt_window(&mut self, window: Window) -> Result<WindowId, Window> {
+        Err(window)
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn window_with_id(&self, _: WindowId) -> Option<&Window> {
+        None
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn host_layer_in_window_with_id(&mut self,
+                                    _: LayerId,
+                                    _: WindowId,
+                                    _: &LayerMap<LayerTreeInfo>,
+                                    _: &LayerMap<LayerContainerInfo>,
+                                    _: &LayerMap<LayerGeometryInfo>)
+                                    -> Result<(), ()> {
+        Err(())
+    }
+}
+
+impl Backend {
+    // Allocates a new buffer and hands it to the server if the layer changed size.
+    fn ensure_buffer(&mut self,
+                     layer: LayerId,
+                     geometry_component: &LayerMap<LayerGeometryInfo>)
+                     -> Result<(), ()> {
+        let size = geometry_component[layer].bounds.round_out().size.to_u32();
+
+        let needs_buffer = match self.native_component[layer].buffer {
+            None => true,
+            Some(ref buffer) => buffer.pixels.borrow().dimensions() != (size.width, size.height),
+        };
+        if needs_buffer {
+            // FIXME(pcwalton): Use `memfd_create()` on Linux so that this is guaranteed to be
+            // backed by memory rather than a disk.
+            let file = tempfile::tempfile().map_err(drop)?;
+            file.set_len(size.width as u64 * size.height as u64 * 4).map_err(drop)?;
+            if !self.send_with_fd(&Request::AttachBuffer(layer, size), Some(&file)) {
+                return Err(())
+            }
+            self.native_component[layer].buffer = Some(LayerBuffer {
+                pixels: Rc::new(RefCell::new(RgbaImage::new(size.width, size.height))),
+                file,
+            });
+        }
+        Ok(())
+    }
+
+    // Sends a request to the server. Returns false if the server has gone away.
+    fn send(&self, request: &Request) -> bool {
+        self.send_with_fd(request, None)
+    }
+
+    fn send_with_fd(&self, request: &Request, file: Option<&File>) -> bool {
+        if self.disconnected.get() {
+            return false
+        }
+        let fd = file.map(|file| file.as_raw_fd());
+        match protocol::send(&self.stream, &request.encode(), fd) {
+            Ok(()) => true,
+            Err(error) => {
+                // Any replies that we're waiting for are failed the next time events are pumped.
+                warn!("Lost the connection to the compositor server: {}", error);
+                self.disconnected.set(true);
+                false
+            }
+        }
+    }
+
+    fn dispatch_reply(&mut self, reply: Reply) {
+        // Flings end in their own time, rather than in the order of the requests. If the fling
+        // was stopped, nobody is waiting for it anymore.
+        if let Reply::FlingEnded(id, result) = reply {
+            if let Some(fling_index) = self.flings.iter().position(|fling| fling.id == id) {
+                let fling = self.flings.remove(fling_index);
+                match result {
+                    Ok(offset) => fling.promise.resolve(offset),
+                    Err(()) => fling.promise.reject(()),
+                }
+            }
+            return
+        }
+
+        match (self.pending_replies.pop_front(), reply) {
+            (Some(PendingReply::Transaction(promise)), Reply::TransactionCommitted) => {
+                promise.resolve(())
+            }
+            (Some(PendingReply::Screenshot(promise)), Reply::Screenshot(Ok(image))) => {
+                promise.resolve(image)
+            }
+            (Some(PendingReply::Screenshot(promise)), Reply::Screenshot(Err(error))) => {
+                promise.reject(error)
+            }
+            (pending_reply, _) => {
+                if let Some(pending_reply) = pending_reply {
+                    self.pending_replies.push_front(pending_reply);
+                }
+                let error = io::Error::new(io::ErrorKind::InvalidData, "unexpected reply");
+                self.disconnect(&error);
+            }
+        }
+    }
+
+    // Gives up on the server, failing everything that's waiting on it.
+    fn disconnect(&mut self, error: &io::Error) {
+        if !self.disconnected.get() {
+            warn!("Lost the connection to the compositor server: {}", error);
+            self.disconnected.set(true);
+        }
+
+        for pending_reply in self.pending_replies.drain(..) {
+            match pending_reply {
+                PendingReply::Transaction(promise) => promise.reject(()),
+                PendingReply::Screenshot(promise) => {
+                    promise.reject(ScreenshotError::TransactionFailed)
+                }
+            }
+        }
+        for fling in self.flings.drain(..) {
+            fling.promise.reject(());
+        }
+    }
+}
+
+pub struct GLContext {
+    surface: Option<Rc<RefCell<RgbaImage>>>,
+}
+
+impl GLContext {
+    /// Returns the pixels of the layer most recently bound to this context, or `None` if no layer
+    /// has been bound.
+    ///
+    /// The pixels are premultiplied RGBA, top row first.
+    pub fn pixels_mut(&self) -> Option<RefMut<RgbaImage>> {
+        self.surface.as_ref().map(|surface| surface.borrow_mut())
+    }
+}
+
+struct LayerNativeInfo {
+    buffer: Option<LayerBuffer>,
+}
+
+struct LayerBuffer {
+    pixels: Rc<RefCell<RgbaImage>>,
+    // Shared with the server.
+    file: File,
+}
+
+enum PendingReply {
+    Transaction(Promise<()>),
+    Screenshot(Promise<RgbaImage, ScreenshotError>),
+}
+
+struct PendingFling {
+    id: u32,
+    layer: LayerId,
+    promise: Promise<Vector2D<f32>>,
+}
+
+// Waits for the capabilities that the server sends first thing when it accepts a client. The
+// server closes the connection instead if it refuses the client.
+fn receive_capabilities(stream: &UnixStream, receiver: &mut Receiver) -> io::Result<Capabilities> {
+    loop {
+        if let Some((payload, _)) = receiver.next_message()? {
+            return match Reply::decode(&payload) {
+                Ok(Reply::Capabilities(capabilities)) => Ok(capabilities),
+                _ => Err(io::Error::new(io::ErrorKind::InvalidData, "expected capabilities")),
+            }
+        }
+        if receiver.receive(stream)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "server hung up"))
+        }
+    }
+}
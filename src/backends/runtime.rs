@@ -0,0 +1,172 @@
+// planeshift/src/backends/runtime.rs
+//
+// Copyright © 2018 The Pathfinder Project Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Chooses a backend at runtime.
+//!
+//! The candidates are tried in order until one initializes successfully; the reason that each
+//! failed candidate didn't is logged. By default, the native backend is tried first, then the
+//! OpenGL backend, then the software backend. The `PLANESHIFT_BACKEND` environment variable
+//! overrides this order with a comma-separated list of backend names, for example `gl` or
+//! `gl,software`.
+//!
+//! The chosen backend is wrapped in a `dynamic::Backend`. To create a layer context with it, pass
+//! the result of `connect()` to `LayerContext::with_backend()`, or simply pass a
+//! `Connection::Winit` to `LayerContext::<dynamic::Backend>::with_backend_connection()`, which
+//! calls `connect()`.
+
+use std::env;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+#[cfg(feature = "enable-winit")]
+use winit::{EventsLoop, WindowBuilder};
+
+#[cfg(feature = "enable-winit")]
+use crate::backends::{dynamic, gl, software};
+#[cfg(all(feature = "enable-winit",
+          any(target_os = "macos", target_family = "windows", target_os = "linux")))]
+use crate::backends::default;
+#[cfg(feature = "enable-winit")]
+use crate::{Connection, ConnectionError};
+
+/// The environment variable that overrides the order in which backends are tried.
+pub const BACKEND_ENV_VAR: &'static str = "PLANESHIFT_BACKEND";
+
+/// The order in which backends are tried if the environment doesn't say otherwise.
+pub const DEFAULT_ORDER: [BackendKind; 3] = [
+    BackendKind::Native,
+    BackendKind::GL,
+    BackendKind::Software,
+];
+
+/// The backends that can be chosen at runtime.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BackendKind {
+    /// The native backend for this platform: Core Animation on macOS, DirectComposition on
+    /// Windows, and Wayland on Linux. Other platforms have none, so it always fails to
+    /// initialize there.
+    Native,
+    /// The backend that composites layers itself with OpenGL.
+    GL,
+    /// The backend that composites layers on the CPU.
+    Software,
+}
+
+impl BackendKind {
+    /// Returns the name of this backend, as used in `PLANESHIFT_BACKEND`.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            BackendKind::Native => "native",
+            BackendKind::GL => "gl",
+            BackendKind::Software => "software",
+        }
+    }
+}
+
+impl Display for BackendKind {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        self.name().fmt(formatter)
+    }
+}
+
+impl FromStr for BackendKind {
+    type Err = ();
+
+    fn from_str(name: &str) -> Result<BackendKind, ()> {
+        match &*name.trim().to_lowercase() {
+            "native" => Ok(BackendKind::Native),
+            "gl" => Ok(BackendKind::GL),
+            "software" => Ok(BackendKind::Software),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Returns the order in which backends should be tried, taking `PLANESHIFT_BACKEND` into
+/// account.
+///
+/// Unknown backend names in the environment variable are logged and skipped. If it names no
+/// known backends at all, the default order is used.
+pub fn backend_order() -> Vec<BackendKind> {
+    let value = match env::var(BACKEND_ENV_VAR) {
+        Ok(value) => value,
+        Err(_) => return DEFAULT_ORDER.to_vec(),
+    };
+
+    let order: Vec<_> = value.split(',').filter_map(|name| {
+        match name.parse() {
+            Ok(kind) => Some(kind),
+            Err(()) => {
+                warn!("{}: unknown backend `{}`", BACKEND_ENV_VAR, name);
+                None
+            }
+        }
+    }).collect();
+
+    if order.is_empty() {
+        warn!("{} names no known backends; using the default order", BACKEND_ENV_VAR);
+        return DEFAULT_ORDER.to_vec()
+    }
+    order
+}
+
+/// Creates a window and connects the first backend that initializes successfully, trying them
+/// in the order returned by `backend_order()`.
+#[cfg(feature = "enable-winit")]
+pub fn connect(window_builder: WindowBuilder, event_loop: &EventsLoop)
+               -> Result<dynamic::Backend, ConnectionError> {
+    connect_in_order(&backend_order(), window_builder, event_loop)
+}
+
+/// Creates a window and connects the first of the given backends that initializes successfully.
+///
+/// This ignores `PLANESHIFT_BACKEND`.
+#[cfg(feature = "enable-winit")]
+pub fn connect_in_order(order: &[BackendKind],
+                        window_builder: WindowBuilder,
+                        event_loop: &EventsLoop)
+                        -> Result<dynamic::Backend, ConnectionError> {
+    for &kind in order {
+        let window_builder = window_builder.clone();
+        let result = match kind {
+            #[cfg(any(target_os = "macos", target_family = "windows", target_os = "linux"))]
+            BackendKind::Native => {
+                dynamic::Backend::connect::<default::Backend>(Connection::Winit(window_builder,
+                                                                                event_loop))
+            }
+            #[cfg(not(any(target_os = "macos", target_family = "windows", target_os = "linux")))]
+            BackendKind::Native => {
+                Err(ConnectionError::with_reason("there's no native backend for this platform"))
+            }
+            BackendKind::GL => {
+                dynamic::Backend::connect::<gl::Backend>(Connection::Winit(window_builder,
+                                                                           event_loop))
+            }
+            BackendKind::Software => {
+                dynamic::Backend::connect::<software::Backend>(Connection::Winit(window_builder,
+                                                                                 event_loop))
+            }
+        };
+
+        match result {
+            Ok(backend) => {
+                info!("Using the {} backend", kind);
+                return Ok(backend)
+            }
+            Err(err) => {
+                warn!("The {} backend failed to initialize: {}",
+                      kind,
+                      err.reason().unwrap_or("unknown error"));
+            }
+        }
+    }
+
+    Err(ConnectionError::with_reason("no backend could be initialized"))
+}
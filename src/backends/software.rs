@@ -0,0 +1,939 @@
+// planeshift/src/backends/software.rs
+//
+// Copyright © 2018 The Pathfinder Project Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A backend that composites layers on the CPU, with no OpenGL at all.
+//!
+//! Surface layers are backed by CPU-side images, which clients draw into via
+//! `GLContext::pixels_mut()` between `bind_layer_to_gl_context()` and `present_gl_context()`.
+//! Hosted layer trees are composited into images of their own, and screenshots are exact copies
+//! of those images. This makes this backend the reference that the GPU backends are checked
+//! against, as well as a last-resort fallback.
+//!
+//! Layer coordinates have their origin at the top left, and surface images are stored top row
+//! first. Surface pixels are premultiplied RGBA, and are composited with the "over" operator.
+//!
+//! Exported layer trees are composited like hosted ones, and each composited image is copied into
+//! a shared-memory file that importing processes read from. Importers pick up new images whenever
+//! they end a transaction, which `LayerContext::update_imported_layers()` does when there are any.
+//!
+//! Flings run on a frame clock of our own, so scroll layers keep moving while the application
+//! waits on the fling's promise, without it having to end any transactions.
+
+use euclid::{Point2D, Rect, Size2D, Vector2D};
+use image::{Rgba, RgbaImage};
+use std::cell::{RefCell, RefMut};
+use std::rc::Rc;
+
+#[cfg(feature = "enable-winit")]
+use winit::{EventsLoop, Window, WindowBuilder, WindowId};
+
+use crate::{Connection, ConnectionError, GLAPI, GLContextLayerBinding, LayerContainerInfo};
+use crate::{LayerGeometryInfo, LayerId, LayerMap, LayerParent, LayerSurfaceInfo, LayerTreeInfo};
+use crate::{Capabilities, ExportToken, LayerEffects, LayerEffectsInfo, Promise, ScreenshotError};
+use crate::{BlendMode, LayerScrollInfo, SurfaceOptions, VideoFrame};
+use crate::effects;
+use crate::export::{SharedImageReader, SharedImageWriter};
+use crate::fling::{self, Fling, FlingAnimator};
+use crate::video;
+
+pub struct Backend {
+    native_component: LayerMap<LayerNativeInfo>,
+    effects_component: LayerMap<LayerEffects>,
+    mask_component: LayerMap<LayerId>,
+    scroll_component: LayerMap<Vector2D<f32>>,
+
+    // The size of the framebuffer that `host_layer_in_window()` hosts layers in.
+    connection_size: Size2D<u32>,
+    hosted_roots: Vec<HostedRoot>,
+    pending_screenshots: Vec<PendingScreenshot>,
+    flings: FlingAnimator,
+
+    #[cfg(feature = "enable-winit")]
+    window: Option<Window>,
+}
+
+impl crate::Backend for Backend {
+    type NativeConnection = Size2D<u32>;
+    type GLContext = GLContext;
+    type NativeGLContext = ();
+    type Host = Size2D<u32>;
+
+    // Constructor
+
+    fn new(connection: Connection<Size2D<u32>>) -> Result<Backend, ConnectionError> {
+        #[cfg(feature = "enable-winit")]
+        let mut window = None;
+
+        let connection_size = match connection {
+            Connection::Native(size) => size,
+            #[cfg(feature = "enable-winit")]
+            Connection::Winit(..) => {
+                // FIXME(pcwalton): We have no way to get our pixels onto the screen, so the window
+                // stays blank. Screenshots still work, though.
+                let new_window = match connection.into_window() {
+                    None => return Err(ConnectionError::with_reason("failed to create window")),
+                    Some(new_window) => new_window,
+                };
+                let size = match new_window.get_inner_size() {
+                    None => return Err(ConnectionError::with_reason("window was closed")),
+                    Some(size) => size.to_physical(new_window.get_hidpi_factor()),
+                };
+                window = Some(new_window);
+                Size2D::new(size.width.round() as u32, size.height.round() as u32)
+            }
+        };
+
+        Ok(Backend {
+            native_component: LayerMap::new(),
+            effects_component: LayerMap::new(),
+            mask_component: LayerMap::new(),
+            scroll_component: LayerMap::new(),
+
+            connection_size,
+            hosted_roots: vec![],
+            pending_screenshots: vec![],
+            flings: FlingAnimator::new(),
+
+            #[cfg(feature = "enable-winit")]
+            window,
+        })
+    }
+
+    // OpenGL context creation
+
+    fn create_gl_context(&mut self, options: SurfaceOptions) -> Result<GLContext, ()> {
+        // We have no depth or stencil buffers, no multisampling, and no formats besides 8-bit
+        // RGBA.
+        if !(options - SurfaceOptions::OPAQUE).is_empty() {
+            return Err(())
+        }
+
+        Ok(GLContext {
+            surface: None,
+        })
+    }
+
+    unsafe fn wrap_gl_context(&mut self, _: ()) -> Result<GLContext, ()> {
+        Ok(GLContext {
+            surface: None,
+        })
+    }
+
+    fn gl_api(&self) -> GLAPI {
+        GLAPI::None
+    }
+
+    // Capabilities
+
+    fn capabilities(&self) -> Capabilities {
+        // Presenting recomposites the whole hosted root, regardless of the changed rect.
+        Capabilities {
+            screenshots: true,
+            damage_aware_present: false,
+            transforms: false,
+            native_animations: true,
+            surface_options: SurfaceOptions::OPAQUE,
+            max_texture_size: None,
+            layer_export: true,
+            video_layers: true,
+            layer_effects: true,
+            backdrop_blur: true,
+            blend_modes: true,
+            layer_masks: true,
+            scroll_clipping: true,
+        }
+    }
+
+    // Transactions
+
+    fn begin_transaction(&self) {}
+
+    fn end_transaction(&mut self,
+                       promise: &Promise<()>,
+                       tree_component: &LayerMap<LayerTreeInfo>,
+                       container_component: &LayerMap<LayerContainerInfo>,
+                       geometry_component: &LayerMap<LayerGeometryInfo>,
+                       surface_component: &LayerMap<LayerSurfaceInfo>) {
+        self.update_imported_layers(tree_component);
+
+        self.advance_flings(tree_component);
+        self.render_hosted_roots(tree_component,
+                                 container_component,
+                                 geometry_component,
+                                 surface_component);
+
+        for screenshot in self.pending_screenshots.drain(..) {
+            let hosted_root = match self.hosted_roots.iter().find(|hosted_root| {
+                hosted_root.layer == screenshot.root_layer
+            }) {
+                None => {
+                    // The layer was unhosted before the transaction ended.
+                    screenshot.promise.reject(ScreenshotError::ReadbackFailed);
+                    continue
+                }
+                Some(hosted_root) => hosted_root,
+            };
+
+            let bounds = screenshot.bounds;
+            let mut image = RgbaImage::new(bounds.size.width as u32, bounds.size.height as u32);
+            for (x, y, pixel) in image.enumerate_pixels_mut() {
+                let (src_x, src_y) = (bounds.origin.x + x as i32, bounds.origin.y + y as i32);
+                if src_x >= 0 && src_y >= 0 &&
+                        (src_x as u32) < hosted_root.framebuffer.width() &&
+                        (src_y as u32) < hosted_root.framebuffer.height() {
+                    *pixel = *hosted_root.framebuffer.get_pixel(src_x as u32, src_y as u32);
+                }
+            }
+            screenshot.promise.resolve(image);
+        }
+
+        // Flings that came to rest have now been drawn at their final offsets.
+        self.flings.settle();
+
+        promise.resolve(());
+    }
+
+    // Layer creation and destruction
+
+    fn add_container_layer(&mut self, _: LayerId) {}
+
+    fn add_surface_layer(&mut self, layer: LayerId) {
+        self.native_component.add(layer, LayerNativeInfo {
+            surface: None,
+            import: None,
+        });
+    }
+
+    fn add_scroll_layer(&mut self, layer: LayerId) {
+        self.scroll_component.add(layer, Vector2D::zero());
+    }
+
+    fn delete_layer(&mut self, layer: LayerId) {
+        self.native_component.remove_if_present(layer);
+        self.effects_component.remove_if_present(layer);
+        self.mask_component.remove_if_present(layer);
+        self.scroll_component.remove_if_present(layer);
+        self.flings.stop(layer);
+    }
+
+    // Layer tree management
+
+    fn insert_before(&mut self,
+                     _: LayerId,
+                     new_child: LayerId,
+                     _: Option<LayerId>,
+                     tree_component: &LayerMap<LayerTreeInfo>,
+                     _: &LayerMap<LayerContainerInfo>,
+                     _: &LayerMap<LayerGeometryInfo>) {
+        self.invalidate_layer(new_child, tree_component);
+    }
+
+    fn remove_from_superlayer(&mut self,
+                              _: LayerId,
+                              parent: LayerId,
+                              tree_component: &LayerMap<LayerTreeInfo>,
+                              _: &LayerMap<LayerGeometryInfo>) {
+        self.invalidate_layer(parent, tree_component);
+    }
+
+    // Native hosting
+
+    unsafe fn host_layer(&mut self,
+                         layer: LayerId,
+                         size: Size2D<u32>,
+                         _: &LayerMap<LayerTreeInfo>,
+                         _: &LayerMap<LayerContainerInfo>,
+                         _: &LayerMap<LayerGeometryInfo>) {
+        self.add_hosted_root(layer, &size, None);
+    }
+
+    fn unhost_layer(&mut self, layer: LayerId) {
+        let hosted_root_index = self.hosted_roots
+                                    .iter()
+                                    .position(|hosted_root| hosted_root.layer == layer)
+                                    .expect("unhost_layer(): Layer not hosted!");
+        self.hosted_roots.remove(hosted_root_index);
+    }
+
+    // Cross-process hosting
+
+    fn export_layer(&mut self,
+                    layer: LayerId,
+                    _: &LayerMap<LayerTreeInfo>,
+                    _: &LayerMap<LayerContainerInfo>,
+                    geometry_component: &LayerMap<LayerGeometryInfo>)
+                    -> Result<ExportToken, ()> {
+        let export = SharedImageWriter::new().map_err(drop)?;
+        let token = export.token()?;
+
+        let (width, height) = surface_size(&layer_bounds(layer, geometry_component));
+        self.add_hosted_root(layer, &Size2D::new(width, height), Some(export));
+        Ok(token)
+    }
+
+    fn import_layer(&mut self, new_layer: LayerId, token: &ExportToken) -> Result<(), ()> {
+        let import = SharedImageReader::open(token)?;
+        self.native_component.add(new_layer, LayerNativeInfo {
+            surface: None,
+            import: Some(import),
+        });
+        Ok(())
+    }
+
+    fn imported_layers_changed(&mut self) -> bool {
+        self.native_component.0.iter_mut().any(|native_component| {
+            match *native_component {
+                Some(LayerNativeInfo { import: Some(ref mut import), .. }) => import.has_changed(),
+                _ => false,
+            }
+        })
+    }
+
+    // Geometry
+
+    fn set_layer_bounds(&mut self,
+                        layer: LayerId,
+                        _: &Rect<f32>,
+                        tree_component: &LayerMap<LayerTreeInfo>,
+                        _: &LayerMap<LayerContainerInfo>,
+                        geometry_component: &LayerMap<LayerGeometryInfo>) {
+        // Reallocate the surface the next time the layer is bound if its size changed. Imported
+        // surfaces keep the size that the exporter gave them.
+        let new_size = surface_size(&geometry_component[layer].bounds);
+        if let Some(native_component) = self.native_component.get_mut(layer) {
+            let size_changed = match native_component.surface {
+                _ if native_component.import.is_some() => false,
+                Some(ref surface) => surface.borrow().dimensions() != new_size,
+                None => false,
+            };
+            if size_changed {
+                native_component.surface = None;
+            }
+        }
+
+        self.invalidate_layer(layer, tree_component);
+    }
+
+    // Miscellaneous layer flags
+
+    fn set_layer_surface_options(&mut self, _: LayerId, _: &LayerMap<LayerSurfaceInfo>) {}
+
+    // Effects
+
+    fn set_layer_effects(&mut self,
+                         layer: LayerId,
+                         _: &LayerEffects,
+                         tree_component: &LayerMap<LayerTreeInfo>,
+                         _: &LayerMap<LayerGeometryInfo>,
+                         effects_component: &LayerMap<LayerEffectsInfo>) {
+        *self.effects_component.get_mut_default(layer) = effects_component[layer].effects;
+        self.invalidate_layer(layer, tree_component);
+    }
+
+    fn set_layer_mask(&mut self,
+                      layer: LayerId,
+                      tree_component: &LayerMap<LayerTreeInfo>,
+                      _: &LayerMap<LayerGeometryInfo>,
+                      effects_component: &LayerMap<LayerEffectsInfo>) {
+        // Masked layers are rendered into images of their own like layers with effects, so make
+        // sure that they have some.
+        self.effects_component.get_mut_default(layer);
+        self.mask_component.remove_if_present(layer);
+        if let Some(mask) = effects_component[layer].mask {
+            self.mask_component.add(layer, mask);
+        }
+        self.invalidate_layer(layer, tree_component);
+    }
+
+    // Scrolling
+
+    fn set_layer_content_size(&mut self, _: LayerId, _: &LayerMap<LayerScrollInfo>) {}
+
+    fn set_layer_content_offset(&mut self,
+                                layer: LayerId,
+                                tree_component: &LayerMap<LayerTreeInfo>,
+                                _: &LayerMap<LayerContainerInfo>,
+                                _: &LayerMap<LayerGeometryInfo>,
+                                scroll_component: &LayerMap<LayerScrollInfo>) {
+        self.flings.stop(layer);
+        self.scroll_component[layer] = scroll_component[layer].content_offset;
+        self.invalidate_layer(layer, tree_component);
+    }
+
+    fn start_fling(&mut self,
+                   layer: LayerId,
+                   velocity: &Vector2D<f32>,
+                   promise: &Promise<Vector2D<f32>>,
+                   geometry_component: &LayerMap<LayerGeometryInfo>,
+                   scroll_component: &LayerMap<LayerScrollInfo>)
+                   -> Result<(), ()> {
+        let bounds_size = layer_bounds(layer, geometry_component).size;
+        let max_offset = fling::max_offset(&bounds_size, &scroll_component[layer].content_size);
+        let fling = Fling::new(&self.scroll_component[layer], velocity, &max_offset);
+        self.flings.start(layer, fling, promise);
+        Ok(())
+    }
+
+    // OpenGL content binding
+
+    fn bind_layer_to_gl_context(&mut self,
+                                layer: LayerId,
+                                context: &mut GLContext,
+                                geometry_component: &LayerMap<LayerGeometryInfo>,
+                                _: &LayerMap<LayerSurfaceInfo>)
+                                -> Result<GLContextLayerBinding, ()> {
+        let native_component = &mut self.native_component[layer];
+        if native_component.import.is_some() {
+            // Only the exporting process can draw into an imported layer.
+            return Err(())
+        }
+        if native_component.surface.is_none() {
+            let (width, height) = surface_size(&geometry_component[layer].bounds);
+            native_component.surface = Some(Rc::new(RefCell::new(RgbaImage::new(width, height))));
+        }
+
+        context.surface = native_component.surface.clone();
+
+        Ok(GLContextLayerBinding {
+            layer,
+            framebuffer: 0,
+        })
+    }
+
+    fn present_gl_context(&mut self,
+                          binding: GLContextLayerBinding,
+                          _: &Rect<f32>,
+                          tree_component: &LayerMap<LayerTreeInfo>,
+                          _: &LayerMap<LayerGeometryInfo>)
+                          -> Result<(), ()> {
+        self.invalidate_layer(binding.layer, tree_component);
+        Ok(())
+    }
+
+    // Video
+
+    fn add_video_layer(&mut self, layer: LayerId) {
+        self.add_surface_layer(layer);
+    }
+
+    // Frames are converted to RGB as they're presented, at the size of the layer.
+    fn present_video_frame(&mut self,
+                           layer: LayerId,
+                           frame: &VideoFrame,
+                           tree_component: &LayerMap<LayerTreeInfo>,
+                           geometry_component: &LayerMap<LayerGeometryInfo>)
+                           -> Result<(), ()> {
+        let (width, height) = surface_size(&layer_bounds(layer, geometry_component));
+        let mut image = RgbaImage::new(width, height);
+        video::convert_to_rgba(frame, &mut image)?;
+
+        self.native_component[layer].surface = Some(Rc::new(RefCell::new(image)));
+        self.invalidate_layer(layer, tree_component);
+        Ok(())
+    }
+
+    // Screenshots
+
+    fn screenshot_hosted_layer(&mut self,
+                               root_layer: LayerId,
+                               _: &Promise<()>,
+                               tree_component: &LayerMap<LayerTreeInfo>,
+                               _: &LayerMap<LayerContainerInfo>,
+                               geometry_component: &LayerMap<LayerGeometryInfo>,
+                               _: &LayerMap<LayerSurfaceInfo>)
+                               -> Promise<RgbaImage, ScreenshotError> {
+        let promise = Promise::new();
+
+        // Make sure the root gets rendered this transaction, in case it was never rendered.
+        self.invalidate_layer(root_layer, tree_component);
+
+        let bounds = geometry_component[root_layer].bounds.round();
+        self.pending_screenshots.push(PendingScreenshot {
+            root_layer,
+            bounds: bounds.to_i32(),
+            promise: promise.clone(),
+        });
+
+        promise
+    }
+
+    // Event dispatch
+
+    fn pump_events(&mut self) -> Result<(), ()> {
+        // Our promises are settled synchronously, from `end_transaction` or
+        // `run_animation_frame`, so there are no events that could settle one.
+        Err(())
+    }
+
+    fn run_animation_frame(&mut self,
+                           tree_component: &LayerMap<LayerTreeInfo>,
+                           container_component: &LayerMap<LayerContainerInfo>,
+                           geometry_component: &LayerMap<LayerGeometryInfo>,
+                           surface_component: &LayerMap<LayerSurfaceInfo>)
+                           -> Result<(), ()> {
+        if self.flings.is_empty() {
+            return Err(())
+        }
+
+        self.flings.wait_for_frame();
+        self.advance_flings(tree_component);
+        self.render_hosted_roots(tree_component,
+                                 container_component,
+                                 geometry_component,
+                                 surface_component);
+        self.flings.settle();
+        Ok(())
+    }
+
+    // `winit` integration
+
+    #[cfg(feature = "enable-winit")]
+    fn window(&self) -> Option<&Window> {
+        self.window.as_ref()
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn host_layer_in_window(&mut self,
+                            layer: LayerId,
+                            _: &LayerMap<LayerTreeInfo>,
+                            _: &LayerMap<LayerContainerInfo>,
+                            _: &LayerMap<LayerGeometryInfo>)
+                            -> Result<(), ()> {
+        let size = self.connection_size;
+        self.add_hosted_root(layer, &size, None);
+        Ok(())
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn create_window(&mut self, _: WindowBuilder, _: &EventsLoop) -> Result<WindowId, ()> {
+        // We can't display anything in additional windows either, so don't pretend to.
+        Err(())
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn adopt_window(&mut self, window: Window) -> Result<WindowId, Window> {
+        Err(window)
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn window_with_id(&self, window_id: WindowId) -> Option<&Window> {
+        self.window.as_ref().filter(|window| window.id() == window_id)
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn host_layer_in_window_with_id(&mut self,
+                                    layer: LayerId,
+                                    window_id: WindowId,
+                                    tree_component: &LayerMap<LayerTreeInfo>,
+                                    container_component: &LayerMap<LayerContainerInfo>,
+                                    geometry_component: &LayerMap<LayerGeometryInfo>)
+                                    -> Result<(), ()> {
+        if self.window_with_id(window_id).is_none() {
+            return Err(())
+        }
+        crate::Backend::host_layer_in_window(self,
+                                             layer,
+                                             tree_component,
+                                             container_component,
+                                             geometry_component)
+    }
+}
+
+impl Backend {
+    // Composites each hosted root that has changed since it was last composited.
+    fn render_hosted_roots(&mut self,
+                           tree_component: &LayerMap<LayerTreeInfo>,
+                           container_component: &LayerMap<LayerContainerInfo>,
+                           geometry_component: &LayerMap<LayerGeometryInfo>,
+                           surface_component: &LayerMap<LayerSurfaceInfo>) {
+        for hosted_root_index in 0..self.hosted_roots.len() {
+            if !self.hosted_roots[hosted_root_index].dirty {
+                continue
+            }
+
+            // Exported roots are as big as the layer itself, and are drawn at its origin.
+            let hosted_layer = self.hosted_roots[hosted_root_index].layer;
+            let mut origin = Point2D::zero();
+            if self.hosted_roots[hosted_root_index].export.is_some() {
+                let bounds = layer_bounds(hosted_layer, geometry_component);
+                let (width, height) = surface_size(&bounds);
+                self.hosted_roots[hosted_root_index].size = Size2D::new(width, height);
+                origin = Point2D::zero() - bounds.origin.to_vector();
+            }
+
+            let mut framebuffer = RgbaImage::new(self.hosted_roots[hosted_root_index].size.width,
+                                                 self.hosted_roots[hosted_root_index].size.height);
+            self.render_layer_subtree(hosted_layer,
+                                      &origin,
+                                      &mut framebuffer,
+                                      tree_component,
+                                      container_component,
+                                      geometry_component,
+                                      surface_component);
+
+            let hosted_root = &mut self.hosted_roots[hosted_root_index];
+            if let Some(ref mut export) = hosted_root.export {
+                if let Err(error) = export.write(&framebuffer) {
+                    warn!("Failed to update exported layer {:?}: {}", hosted_layer, error);
+                }
+            }
+            hosted_root.framebuffer = framebuffer;
+            hosted_root.dirty = false;
+        }
+    }
+
+    // Moves each layer that's being flung to where it should be by now.
+    fn advance_flings(&mut self, tree_component: &LayerMap<LayerTreeInfo>) {
+        for (layer, offset) in self.flings.advance() {
+            self.scroll_component[layer] = offset;
+            self.invalidate_layer(layer, tree_component);
+        }
+    }
+
+    fn add_hosted_root(&mut self,
+                       layer: LayerId,
+                       size: &Size2D<u32>,
+                       export: Option<SharedImageWriter>) {
+        debug_assert!(self.hosted_roots.iter().all(|hosted_root| hosted_root.layer != layer));
+        self.hosted_roots.push(HostedRoot {
+            layer,
+            size: *size,
+            framebuffer: RgbaImage::new(size.width, size.height),
+            dirty: true,
+            export,
+        });
+    }
+
+    // Picks up any new images that exporting processes have written since the last transaction.
+    fn update_imported_layers(&mut self, tree_component: &LayerMap<LayerTreeInfo>) {
+        let mut changed_layers = vec![];
+        for (layer_index, native_component) in self.native_component.0.iter_mut().enumerate() {
+            let native_component = match *native_component {
+                Some(ref mut native_component) => native_component,
+                None => continue,
+            };
+            let import = match native_component.import {
+                Some(ref mut import) => import,
+                None => continue,
+            };
+            match import.read_if_changed() {
+                Ok(None) => {}
+                Ok(Some(image)) => {
+                    native_component.surface = Some(Rc::new(RefCell::new(image)));
+                    changed_layers.push(LayerId(layer_index as u32));
+                }
+                Err(error) => warn!("Failed to read imported layer {}: {}", layer_index, error),
+            }
+        }
+
+        for layer in changed_layers {
+            self.invalidate_layer(layer, tree_component);
+        }
+    }
+
+    // We always recomposite the entire hosted layer tree, so we only need to track which roots
+    // are dirty, not which parts of them.
+    fn invalidate_layer(&mut self, layer: LayerId, tree_component: &LayerMap<LayerTreeInfo>) {
+        let mut layer = layer;
+        while let Some(tree_info) = tree_component.get(layer) {
+            match tree_info.parent {
+                LayerParent::Layer(parent) | LayerParent::Mask(parent) => layer = parent,
+                LayerParent::NativeHost => {
+                    if let Some(hosted_root) = self.hosted_roots.iter_mut().find(|hosted_root| {
+                        hosted_root.layer == layer
+                    }) {
+                        hosted_root.dirty = true;
+                    }
+                    return
+                }
+            }
+        }
+    }
+
+    // Renders back to front, in tree order.
+    fn render_layer_subtree(&self,
+                            layer: LayerId,
+                            origin: &Point2D<f32>,
+                            framebuffer: &mut RgbaImage,
+                            tree_component: &LayerMap<LayerTreeInfo>,
+                            container_component: &LayerMap<LayerContainerInfo>,
+                            geometry_component: &LayerMap<LayerGeometryInfo>,
+                            surface_component: &LayerMap<LayerSurfaceInfo>) {
+        match self.effects_component.get(layer) {
+            Some(effects) if !effects.is_empty() || self.mask_component.has(layer) => {
+                self.render_layer_with_effects(layer,
+                                               effects,
+                                               origin,
+                                               framebuffer,
+                                               tree_component,
+                                               container_component,
+                                               geometry_component,
+                                               surface_component)
+            }
+            _ => {
+                self.render_layer_contents(layer,
+                                           origin,
+                                           framebuffer,
+                                           tree_component,
+                                           container_component,
+                                           geometry_component,
+                                           surface_component)
+            }
+        }
+    }
+
+    // Renders the layer and its descendants into an image of their own, padded to make room for
+    // the effects, and then composites the blurred backdrop, the shadow, and the content.
+    fn render_layer_with_effects(&self,
+                                 layer: LayerId,
+                                 effects: &LayerEffects,
+                                 origin: &Point2D<f32>,
+                                 framebuffer: &mut RgbaImage,
+                                 tree_component: &LayerMap<LayerTreeInfo>,
+                                 container_component: &LayerMap<LayerContainerInfo>,
+                                 geometry_component: &LayerMap<LayerGeometryInfo>,
+                                 surface_component: &LayerMap<LayerSurfaceInfo>) {
+        let bounds = geometry_component[layer].bounds;
+        let content_rect = effects::content_rect(effects, &bounds);
+
+        let (width, height) = surface_size(&content_rect);
+        let content_origin = (-content_rect.origin.to_vector()).to_point();
+        let mut content = RgbaImage::new(width, height);
+        self.render_layer_contents(layer,
+                                   &content_origin,
+                                   &mut content,
+                                   tree_component,
+                                   container_component,
+                                   geometry_component,
+                                   surface_component);
+
+        // The mask is positioned relative to the layer, and the shadow and blur are derived from
+        // the masked content.
+        if let Some(&mask) = self.mask_component.get(layer) {
+            let mut mask_image = RgbaImage::new(width, height);
+            self.render_layer_subtree(mask,
+                                      &(content_origin + bounds.origin.to_vector()),
+                                      &mut mask_image,
+                                      tree_component,
+                                      container_component,
+                                      geometry_component,
+                                      surface_component);
+            effects::mask(&mut content, &mask_image);
+        }
+
+        // Everything beneath this layer has already been composited, so the backdrop is the
+        // framebuffer itself.
+        if effects.backdrop_blur_radius > 0.0 {
+            let backdrop_rect = bounds.translate(&origin.to_vector()).round().to_i32();
+            effects::blur_backdrop(framebuffer, &backdrop_rect, effects.backdrop_blur_radius);
+        }
+
+        let dest_origin = *origin + content_rect.origin.to_vector();
+        if let Some(ref shadow) = effects.shadow {
+            let shadow_origin = dest_origin + shadow.offset;
+            composite_image(&effects::shadow(&content, shadow), &shadow_origin, false, framebuffer);
+        }
+        if effects.blur_radius > 0.0 {
+            content = effects::blur(&content, effects.blur_radius);
+        }
+        match effects.blend_mode {
+            BlendMode::Normal => composite_image(&content, &dest_origin, false, framebuffer),
+            blend_mode => blend_image(&content, &dest_origin, blend_mode, framebuffer),
+        }
+    }
+
+    fn render_layer_contents(&self,
+                             layer: LayerId,
+                             origin: &Point2D<f32>,
+                             framebuffer: &mut RgbaImage,
+                             tree_component: &LayerMap<LayerTreeInfo>,
+                             container_component: &LayerMap<LayerContainerInfo>,
+                             geometry_component: &LayerMap<LayerGeometryInfo>,
+                             surface_component: &LayerMap<LayerSurfaceInfo>) {
+        let bounds = geometry_component[layer].bounds;
+
+        // If this is a container layer, don't render anything; just recurse.
+        if let Some(container_info) = container_component.get(layer) {
+            let new_origin = *origin + bounds.origin.to_vector();
+            let content_offset = match self.scroll_component.get(layer) {
+                None => {
+                    self.render_children(container_info,
+                                         &new_origin,
+                                         framebuffer,
+                                         tree_component,
+                                         container_component,
+                                         geometry_component,
+                                         surface_component);
+                    return
+                }
+                Some(content_offset) => *content_offset,
+            };
+
+            // Scroll layers clip their children, so render them into a copy of the part of the
+            // framebuffer that the layer covers, and then copy that back.
+            let framebuffer_rect = Rect::new(Point2D::zero(),
+                                             Size2D::new(framebuffer.width() as i32,
+                                                         framebuffer.height() as i32));
+            let clip_rect = Rect::new(new_origin, bounds.size).round().to_i32();
+            let clip_rect = match clip_rect.intersection(&framebuffer_rect) {
+                None => return,
+                Some(clip_rect) => clip_rect,
+            };
+            let (left, top) = (clip_rect.origin.x as u32, clip_rect.origin.y as u32);
+            let mut clipped = RgbaImage::from_fn(clip_rect.size.width as u32,
+                                                 clip_rect.size.height as u32,
+                                                 |x, y| *framebuffer.get_pixel(left + x, top + y));
+            let content_origin = new_origin - content_offset -
+                clip_rect.origin.to_f32().to_vector();
+            self.render_children(container_info,
+                                 &content_origin,
+                                 &mut clipped,
+                                 tree_component,
+                                 container_component,
+                                 geometry_component,
+                                 surface_component);
+            for (x, y, pixel) in clipped.enumerate_pixels() {
+                framebuffer.put_pixel(left + x, top + y, *pixel);
+            }
+            return
+        }
+
+        let surface = match self.native_component[layer].surface {
+            None => return,
+            Some(ref surface) => surface.borrow(),
+        };
+
+        let opaque = surface_component[layer].options.contains(SurfaceOptions::OPAQUE);
+        composite_image(&surface, &(*origin + bounds.origin.to_vector()), opaque, framebuffer);
+    }
+
+    // Renders the children of a container layer, with the top left corner of its content at the
+    // given origin.
+    fn render_children(&self,
+                       container_info: &LayerContainerInfo,
+                       origin: &Point2D<f32>,
+                       framebuffer: &mut RgbaImage,
+                       tree_component: &LayerMap<LayerTreeInfo>,
+                       container_component: &LayerMap<LayerContainerInfo>,
+                       geometry_component: &LayerMap<LayerGeometryInfo>,
+                       surface_component: &LayerMap<LayerSurfaceInfo>) {
+        let mut maybe_kid = container_info.first_child;
+        while let Some(kid) = maybe_kid {
+            self.render_layer_subtree(kid,
+                                      origin,
+                                      framebuffer,
+                                      tree_component,
+                                      container_component,
+                                      geometry_component,
+                                      surface_component);
+            maybe_kid = tree_component[kid].next_sibling;
+        }
+    }
+}
+
+/// A binding between a client and the CPU-side surface of a layer.
+///
+/// Since this backend doesn't use OpenGL, clients draw into `pixels_mut()` instead of the
+/// framebuffer named by the `GLContextLayerBinding`.
+pub struct GLContext {
+    surface: Option<Rc<RefCell<RgbaImage>>>,
+}
+
+impl GLContext {
+    /// Returns the pixels of the layer most recently bound to this context, or `None` if no layer
+    /// has been bound.
+    ///
+    /// The pixels are premultiplied RGBA, top row first.
+    pub fn pixels_mut(&self) -> Option<RefMut<RgbaImage>> {
+        self.surface.as_ref().map(|surface| surface.borrow_mut())
+    }
+}
+
+struct LayerNativeInfo {
+    surface: Option<Rc<RefCell<RgbaImage>>>,
+    // Where the surface comes from, if the layer was imported from another process.
+    import: Option<SharedImageReader>,
+}
+
+struct HostedRoot {
+    layer: LayerId,
+    size: Size2D<u32>,
+    framebuffer: RgbaImage,
+    dirty: bool,
+    // Where the framebuffer is copied to after each transaction, if the root was exported.
+    export: Option<SharedImageWriter>,
+}
+
+struct PendingScreenshot {
+    root_layer: LayerId,
+    bounds: Rect<i32>,
+    promise: Promise<RgbaImage, ScreenshotError>,
+}
+
+fn layer_bounds(layer: LayerId, geometry_component: &LayerMap<LayerGeometryInfo>) -> Rect<f32> {
+    geometry_component.get(layer).map_or(Rect::zero(), |geometry_info| geometry_info.bounds)
+}
+
+fn surface_size(bounds: &Rect<f32>) -> (u32, u32) {
+    let size = bounds.round_out().size.to_u32();
+    (size.width, size.height)
+}
+
+// Draws an image into the framebuffer with its top left corner at the given point.
+fn composite_image(image: &RgbaImage,
+                   origin: &Point2D<f32>,
+                   opaque: bool,
+                   framebuffer: &mut RgbaImage) {
+    let dest_origin = origin.round().to_i32();
+    for (x, y, src) in image.enumerate_pixels() {
+        let (dest_x, dest_y) = (dest_origin.x + x as i32, dest_origin.y + y as i32);
+        if dest_x < 0 || dest_y < 0 || dest_x as u32 >= framebuffer.width() ||
+                dest_y as u32 >= framebuffer.height() {
+            continue
+        }
+
+        let dest = framebuffer.get_pixel_mut(dest_x as u32, dest_y as u32);
+        if opaque {
+            *dest = Rgba { data: [src[0], src[1], src[2], 255] };
+        } else {
+            *dest = composite_over(src, dest);
+        }
+    }
+}
+
+// Like `composite_image`, but combines the image with the framebuffer using a blend mode.
+fn blend_image(image: &RgbaImage,
+               origin: &Point2D<f32>,
+               blend_mode: BlendMode,
+               framebuffer: &mut RgbaImage) {
+    let dest_origin = origin.round().to_i32();
+    for (x, y, src) in image.enumerate_pixels() {
+        let (dest_x, dest_y) = (dest_origin.x + x as i32, dest_origin.y + y as i32);
+        if dest_x < 0 || dest_y < 0 || dest_x as u32 >= framebuffer.width() ||
+                dest_y as u32 >= framebuffer.height() {
+            continue
+        }
+
+        let dest = framebuffer.get_pixel_mut(dest_x as u32, dest_y as u32);
+        *dest = effects::blend(src, dest, blend_mode);
+    }
+}
+
+// Premultiplied source-over, rounding the same way `glBlendFunc(GL_ONE,
+// GL_ONE_MINUS_SRC_ALPHA)` does.
+fn composite_over(src: &Rgba<u8>, dest: &Rgba<u8>) -> Rgba<u8> {
+    let inv_src_alpha = 255 - src[3] as u32;
+    let mut result = [0; 4];
+    for channel in 0..4 {
+        let value = src[channel] as u32 + (dest[channel] as u32 * inv_src_alpha + 127) / 255;
+        result[channel] = value.min(255) as u8;
+    }
+    Rgba { data: result }
+}
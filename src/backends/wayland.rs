@@ -12,10 +12,10 @@
 
 use dbus::Connection as DbusConnection;
 use dbus::{BusType, Message};
-use euclid::{Rect, Size2D};
+use euclid::{Rect, Size2D, Vector2D};
 use image::{self, RgbaImage};
 use std::collections::{HashMap, HashSet};
-use std::ffi::CString;
+use std::ffi::{CStr, CString};
 use std::fs::File;
 use std::io::Write;
 use std::mem;
@@ -49,18 +49,30 @@ use wayland_client::{Display, EventQueue, GlobalEvent, GlobalManager, Proxy};
 use wayland_sys::client::{WAYLAND_CLIENT_HANDLE, wl_display, wl_proxy};
 
 #[cfg(feature = "enable-winit")]
-use winit::Window;
+use winit::{EventsLoop, Window, WindowBuilder, WindowId};
 #[cfg(feature = "enable-winit")]
 use winit::os::unix::WindowExt;
 
-use crate::egl::types::{EGLContext, EGLDisplay, EGLSurface, EGLint};
+use crate::egl::types::{EGLConfig, EGLContext, EGLDisplay, EGLSurface, EGLint};
 use crate::egl;
 use crate::{Connection, ConnectionError, GLAPI, GLContextLayerBinding, LayerContainerInfo};
 use crate::{LayerGeometryInfo, LayerId, LayerParent, LayerSurfaceInfo, LayerTreeInfo, LayerMap};
-use crate::{Promise, SurfaceOptions};
+use crate::{Capabilities, ColorFormat, DepthFormat, ExportToken, Promise, ScreenshotError};
+use crate::{LayerEffects, LayerEffectsInfo, LayerScrollInfo, SurfaceOptions, VideoFrame};
+
+// From `EGL_EXT_pixel_format_float`.
+const EGL_COLOR_COMPONENT_TYPE_EXT: EGLint = 0x3339;
+const EGL_COLOR_COMPONENT_TYPE_FIXED_EXT: EGLint = 0x333a;
+const EGL_COLOR_COMPONENT_TYPE_FLOAT_EXT: EGLint = 0x333b;
+
+// From `EGL_KHR_gl_colorspace`.
+const EGL_GL_COLORSPACE_KHR: EGLint = 0x309d;
+const EGL_GL_COLORSPACE_SRGB_KHR: EGLint = 0x3089;
+const EGL_GL_COLORSPACE_LINEAR_KHR: EGLint = 0x308a;
 
 pub struct Backend {
     native_component: LayerMap<NativeInfo>,
+    scroll_component: LayerMap<Vector2D<f32>>,
 
     dirty_layers: HashSet<LayerId>,
 
@@ -72,6 +84,7 @@ pub struct Backend {
     globals: GlobalManager,
     display: Display,
     event_queue: EventQueue,
+    disconnected: bool,
     #[allow(dead_code)]
     compositor: Proxy<WlCompositor>,
     #[allow(dead_code)]
@@ -84,8 +97,12 @@ pub struct Backend {
     zero_buffer: Proxy<WlBuffer>,
 
     egl_display: EGLDisplay,
+    surface_options: SurfaceOptions,
+    max_texture_size: Option<u32>,
 
     window: Option<Window>,
+    #[cfg(feature = "enable-winit")]
+    additional_windows: Vec<Window>,
 }
 
 impl crate::Backend for Backend {
@@ -103,7 +120,7 @@ impl crate::Backend for Backend {
             #[cfg(feature = "enable-winit")]
             Connection::Winit(window_builder, event_queue) => {
                 let window = match window_builder.build(event_queue) {
-                    Err(_) => return Err(ConnectionError::new()),
+                    Err(_) => return Err(ConnectionError::with_reason("failed to create window")),
                     Ok(window) => window,
                 };
                 match window.get_wayland_display() {
@@ -117,7 +134,9 @@ impl crate::Backend for Backend {
                             }, Some(window))
                         }
                     }
-                    None => return Err(ConnectionError::new())
+                    None => {
+                        return Err(ConnectionError::with_reason("not running under Wayland"))
+                    }
                 }
             }
         };
@@ -164,7 +183,7 @@ impl crate::Backend for Backend {
                                    .unwrap()
                                    .implement(|_, _| ());
 
-        let egl_display;
+        let (egl_display, surface_options, max_texture_size);
         unsafe {
             egl::BindAPI(egl::OPENGL_API);
 
@@ -177,10 +196,16 @@ impl crate::Backend for Backend {
                 let symbol = CString::new(symbol.as_bytes()).unwrap();
                 egl::GetProcAddress(symbol.as_ptr()) as *const _ as *const c_void
             });
+
+            // Find out what the driver can actually allocate, so that `capabilities()` doesn't
+            // promise more than `create_gl_context()` can deliver.
+            surface_options = supported_surface_options(egl_display);
+            max_texture_size = query_max_texture_size(egl_display);
         }
 
         Ok(Backend {
             native_component: LayerMap::new(),
+            scroll_component: LayerMap::new(),
 
             dirty_layers: HashSet::new(),
 
@@ -190,6 +215,7 @@ impl crate::Backend for Backend {
             globals,
             display: connection.display,
             event_queue: connection.event_queue,
+            disconnected: false,
             compositor,
             subcompositor,
             shm,
@@ -198,30 +224,64 @@ impl crate::Backend for Backend {
             zero_buffer,
 
             egl_display,
+            surface_options,
+            max_texture_size,
 
             window,
+            #[cfg(feature = "enable-winit")]
+            additional_windows: vec![],
         })
     }
 
     // OpenGL context creation
 
     fn create_gl_context(&mut self, options: SurfaceOptions) -> Result<GLContext, ()> {
+        if !self.surface_options.contains(options) {
+            return Err(())
+        }
+
+        let (color_sizes, float_color) = match options.color_format()? {
+            ColorFormat::Rgba8 | ColorFormat::Srgb8Alpha8 => ([8, 8, 8, 8], false),
+            ColorFormat::Rgb10A2 => ([10, 10, 10, 2], false),
+            ColorFormat::Rgba16F => ([16, 16, 16, 16], true),
+        };
+        let depth_size = match options.depth_format()? {
+            None => 0,
+            Some(DepthFormat::Depth16) => 16,
+            Some(DepthFormat::Depth24) => 24,
+            Some(DepthFormat::Depth32F) => 32,
+        };
+        let stencil_size = if options.contains(SurfaceOptions::STENCIL) { 8 } else { 0 };
+        let sample_count = options.sample_count()? as i32;
+        let sample_buffers = if sample_count > 1 { 1 } else { 0 };
+        let component_type = if float_color {
+            EGL_COLOR_COMPONENT_TYPE_FLOAT_EXT
+        } else {
+            EGL_COLOR_COMPONENT_TYPE_FIXED_EXT
+        };
+
         unsafe {
             // Enumerate the EGL pixel configurations.
             let (mut configs, mut num_configs) = ([ptr::null(); 64], 0);
-            let depth_size = if options.contains(SurfaceOptions::DEPTH) { 16 } else { 0 };
-            let stencil_size = if options.contains(SurfaceOptions::STENCIL) { 8 } else { 0 };
-            let attributes = [
+            let mut attributes = vec![
                 egl::SURFACE_TYPE as i32,       egl::WINDOW_BIT as i32,
                 egl::RENDERABLE_TYPE as i32,    egl::OPENGL_BIT as i32,
-                egl::RED_SIZE as i32,           8,
-                egl::GREEN_SIZE as i32,         8,
-                egl::BLUE_SIZE as i32,          8,
-                egl::ALPHA_SIZE as i32,         8,
+                egl::RED_SIZE as i32,           color_sizes[0],
+                egl::GREEN_SIZE as i32,         color_sizes[1],
+                egl::BLUE_SIZE as i32,          color_sizes[2],
+                egl::ALPHA_SIZE as i32,         color_sizes[3],
                 egl::DEPTH_SIZE as i32,         depth_size,
                 egl::STENCIL_SIZE as i32,       stencil_size,
-                egl::NONE as i32,               egl::NONE as i32,
+                egl::SAMPLE_BUFFERS as i32,     sample_buffers,
+                egl::SAMPLES as i32,            if sample_count > 1 { sample_count } else { 0 },
             ];
+            // Drivers without `EGL_EXT_pixel_format_float` reject the attribute outright, so only
+            // pass it if we need it.
+            if float_color {
+                attributes.extend_from_slice(&[EGL_COLOR_COMPONENT_TYPE_EXT, component_type]);
+            }
+            attributes.extend_from_slice(&[egl::NONE as i32, egl::NONE as i32]);
+
             let result = egl::ChooseConfig(self.egl_display,
                                            attributes.as_ptr(),
                                            configs.as_mut_ptr(),
@@ -233,9 +293,27 @@ impl crate::Backend for Backend {
 
             // Choose an EGL pixel configuration.
             //
-            // FIXME(pcwalton): Do a better job of making sure we get the right context via
-            // `eglGetConfigAttrib()`.
-            let config = configs[0];
+            // `eglChooseConfig()` treats sizes as minimums and sorts deeper configurations first,
+            // so look for one that matches exactly.
+            let egl_display = self.egl_display;
+            let config_attribute = |config, attribute| {
+                let mut value = 0;
+                egl::GetConfigAttrib(egl_display, config, attribute as i32, &mut value);
+                value
+            };
+            let config = match configs[0..(num_configs as usize)].iter().cloned().find(|&config| {
+                config_attribute(config, egl::RED_SIZE) == color_sizes[0] &&
+                    config_attribute(config, egl::GREEN_SIZE) == color_sizes[1] &&
+                    config_attribute(config, egl::BLUE_SIZE) == color_sizes[2] &&
+                    config_attribute(config, egl::ALPHA_SIZE) == color_sizes[3] &&
+                    config_attribute(config, egl::DEPTH_SIZE) == depth_size &&
+                    config_attribute(config, egl::STENCIL_SIZE) == stencil_size &&
+                    config_attribute(config, egl::SAMPLE_BUFFERS) == sample_buffers &&
+                    (sample_buffers == 0 || config_attribute(config, egl::SAMPLES) == sample_count)
+            }) {
+                None => return Err(()),
+                Some(config) => config,
+            };
 
             // Create an EGL context.
             let attributes = [
@@ -264,6 +342,25 @@ impl crate::Backend for Backend {
         GLAPI::GLES
     }
 
+    fn capabilities(&self) -> Capabilities {
+        // Subsurfaces can only be positioned, and we always swap the entire surface.
+        Capabilities {
+            screenshots: false,
+            damage_aware_present: false,
+            transforms: false,
+            native_animations: false,
+            surface_options: self.surface_options,
+            max_texture_size: self.max_texture_size,
+            layer_export: false,
+            video_layers: false,
+            layer_effects: false,
+            backdrop_blur: false,
+            blend_modes: false,
+            layer_masks: false,
+            scroll_clipping: false,
+        }
+    }
+
     fn begin_transaction(&self) {}
 
     fn end_transaction(&mut self,
@@ -287,8 +384,10 @@ impl crate::Backend for Backend {
             surface.commit();
         }
 
-        self.display.flush().unwrap();
-        self.event_queue.dispatch().unwrap();
+        if self.flush_and_dispatch(true).is_err() {
+            promise.reject(());
+            return
+        }
 
         // FIXME(pcwalton): Is this right?
         promise.resolve(());
@@ -330,8 +429,14 @@ impl crate::Backend for Backend {
         self.add_layer(new_layer);
     }
 
+    fn add_scroll_layer(&mut self, new_layer: LayerId) {
+        self.add_container_layer(new_layer);
+        self.scroll_component.add(new_layer, Vector2D::zero());
+    }
+
     fn delete_layer(&mut self, layer: LayerId) {
         self.native_component.remove_if_present(layer);
+        self.scroll_component.remove_if_present(layer);
         self.dirty_layers.insert(layer);
     }
 
@@ -339,9 +444,9 @@ impl crate::Backend for Backend {
                      parent: LayerId,
                      new_child: LayerId,
                      reference: Option<LayerId>,
-                     _: &LayerMap<LayerTreeInfo>,
+                     tree_component: &LayerMap<LayerTreeInfo>,
                      _: &LayerMap<LayerContainerInfo>,
-                     _: &LayerMap<LayerGeometryInfo>) {
+                     geometry_component: &LayerMap<LayerGeometryInfo>) {
         let subsurface = self.subcompositor
                              .get_subsurface(&self.native_component[new_child].surface,
                                              &self.native_component[parent].surface)
@@ -354,6 +459,7 @@ impl crate::Backend for Backend {
         }
 
         self.native_component[new_child].subsurface = Some(subsurface);
+        self.update_subsurface_position(new_child, tree_component, geometry_component);
 
         self.dirty_layers.insert(parent);
         self.dirty_layers.insert(new_child);
@@ -406,17 +512,35 @@ impl crate::Backend for Backend {
         }
     }
 
+    // TODO(pcwalton): Wayland surfaces belong to a single client connection, so sharing a
+    // subtree would mean acting as a nested compositor: listening on a socket of our own, and
+    // attaching the buffers that the child process commits there to our subsurfaces.
+    fn export_layer(&mut self,
+                    _: LayerId,
+                    _: &LayerMap<LayerTreeInfo>,
+                    _: &LayerMap<LayerContainerInfo>,
+                    _: &LayerMap<LayerGeometryInfo>)
+                    -> Result<ExportToken, ()> {
+        Err(())
+    }
+
+    fn import_layer(&mut self, _: LayerId, _: &ExportToken) -> Result<(), ()> {
+        Err(())
+    }
+
+    fn imported_layers_changed(&mut self) -> bool {
+        false
+    }
+
     fn set_layer_bounds(&mut self,
                         layer: LayerId,
                         _: &Rect<f32>,
-                        _: &LayerMap<LayerTreeInfo>,
+                        tree_component: &LayerMap<LayerTreeInfo>,
                         _: &LayerMap<LayerContainerInfo>,
                         geometry_component: &LayerMap<LayerGeometryInfo>) {
         let bounds = geometry_component[layer].bounds.round().to_i32();
 
-        if let Some(ref subsurface) = self.native_component[layer].subsurface {
-            subsurface.set_position(bounds.origin.x, bounds.origin.y);
-        }
+        self.update_subsurface_position(layer, tree_component, geometry_component);
 
         let native_component = &mut self.native_component[layer];
         if native_component.egl_window_size.to_i32() != bounds.size {
@@ -435,12 +559,69 @@ impl crate::Backend for Backend {
         self.dirty_layers.insert(layer);
     }
 
+    // TODO(pcwalton): Render layers with effects into buffers of their own, as the GL backend
+    // does.
+    fn set_layer_effects(&mut self,
+                         _: LayerId,
+                         _: &LayerEffects,
+                         _: &LayerMap<LayerTreeInfo>,
+                         _: &LayerMap<LayerGeometryInfo>,
+                         _: &LayerMap<LayerEffectsInfo>) {}
+
+    // TODO(pcwalton): Render masked layers into buffers of their own, too.
+    fn set_layer_mask(&mut self,
+                      _: LayerId,
+                      _: &LayerMap<LayerTreeInfo>,
+                      _: &LayerMap<LayerGeometryInfo>,
+                      _: &LayerMap<LayerEffectsInfo>) {}
+
+    // Scrolling
+
+    fn set_layer_content_size(&mut self, _: LayerId, _: &LayerMap<LayerScrollInfo>) {}
+
+    // TODO(pcwalton): Clip the children of scroll layers to their bounds. This will require
+    // cropping each child's buffer with `wp_viewporter`.
+    fn set_layer_content_offset(&mut self,
+                                layer: LayerId,
+                                tree_component: &LayerMap<LayerTreeInfo>,
+                                container_component: &LayerMap<LayerContainerInfo>,
+                                geometry_component: &LayerMap<LayerGeometryInfo>,
+                                scroll_component: &LayerMap<LayerScrollInfo>) {
+        self.scroll_component[layer] = scroll_component[layer].content_offset;
+
+        // Subsurface positions are double-buffered state of the parent, so committing the scroll
+        // layer moves all of its children at once.
+        let mut maybe_kid = container_component[layer].first_child;
+        while let Some(kid) = maybe_kid {
+            self.update_subsurface_position(kid, tree_component, geometry_component);
+            maybe_kid = tree_component[kid].next_sibling;
+        }
+
+        self.dirty_layers.insert(layer);
+    }
+
+    fn start_fling(&mut self,
+                   _: LayerId,
+                   _: &Vector2D<f32>,
+                   _: &Promise<Vector2D<f32>>,
+                   _: &LayerMap<LayerGeometryInfo>,
+                   _: &LayerMap<LayerScrollInfo>)
+                   -> Result<(), ()> {
+        // The compositor can't move subsurfaces on its own, so the layer context animates flings.
+        Err(())
+    }
+
     fn bind_layer_to_gl_context(&mut self,
                                 layer: LayerId,
                                 context: &mut Self::GLContext,
                                 _: &LayerMap<LayerGeometryInfo>,
-                                _: &LayerMap<LayerSurfaceInfo>)
+                                surface_component: &LayerMap<LayerSurfaceInfo>)
                                 -> Result<GLContextLayerBinding, ()> {
+        let srgb = match surface_component[layer].options.color_format()? {
+            ColorFormat::Srgb8Alpha8 => true,
+            ColorFormat::Rgba8 | ColorFormat::Rgb10A2 | ColorFormat::Rgba16F => false,
+        };
+
         unsafe {
             let native_component = &mut self.native_component[layer];
 
@@ -454,7 +635,8 @@ impl crate::Backend for Backend {
                        egl::TRUE);
 
             match native_component.cached_egl_surface {
-                Some(ref cached_surface) if cached_surface.config_id == config_id => {}
+                Some(ref cached_surface) if cached_surface.config_id == config_id &&
+                    cached_surface.srgb == srgb => {}
                 _ => {
                     let attributes = [
                         egl::CONFIG_ID as i32,  config_id,
@@ -468,14 +650,28 @@ impl crate::Backend for Backend {
                                                  &mut num_configs),
                                egl::TRUE);
 
+                    // sRGB encoding is a property of the surface, not the configuration. This
+                    // fails if the driver doesn't support `EGL_KHR_gl_colorspace`.
+                    let colorspace = if srgb {
+                        EGL_GL_COLORSPACE_SRGB_KHR
+                    } else {
+                        EGL_GL_COLORSPACE_LINEAR_KHR
+                    };
+                    let attributes = [
+                        EGL_GL_COLORSPACE_KHR,  colorspace,
+                        egl::NONE as i32,       egl::NONE as i32,
+                    ];
                     let egl_surface = egl::CreateWindowSurface(self.egl_display,
                                                                config,
                                                                egl_window.ptr() as *mut _,
-                                                               ptr::null());
-                    assert!(egl_surface != egl::NO_SURFACE);
+                                                               attributes.as_ptr());
+                    if egl_surface == egl::NO_SURFACE {
+                        return Err(())
+                    }
                     native_component.cached_egl_surface = Some(CachedEGLSurface {
                         egl_surface,
                         config_id,
+                        srgb,
                     })
                 }
             }
@@ -534,19 +730,73 @@ impl crate::Backend for Backend {
                             container_component: &LayerMap<LayerContainerInfo>,
                             geometry_component: &LayerMap<LayerGeometryInfo>)
                             -> Result<(), ()> {
-        match self.window().unwrap().get_wayland_surface() {
-            Some(surface) => {
-                unsafe {
-                    self.host_layer(layer,
-                                    Proxy::from_c_ptr(surface as *mut wl_proxy),
-                                    tree_component,
-                                    container_component,
-                                    geometry_component);
-                }
-                Ok(())
-            }
-            None => Err(()),
+        let surface = self.window().unwrap().get_wayland_surface();
+        self.host_layer_in_wayland_surface(layer,
+                                           surface,
+                                           tree_component,
+                                           container_component,
+                                           geometry_component)
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn create_window(&mut self, window_builder: WindowBuilder, event_loop: &EventsLoop)
+                     -> Result<WindowId, ()> {
+        match window_builder.build(event_loop) {
+            Err(_) => Err(()),
+            Ok(window) => self.adopt_window(window).map_err(|_| ()),
+        }
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn adopt_window(&mut self, window: Window) -> Result<WindowId, Window> {
+        // FIXME(pcwalton): We should check that the window is on our display connection.
+        if window.get_wayland_surface().is_none() {
+            return Err(window)
         }
+
+        let window_id = window.id();
+        self.additional_windows.push(window);
+        Ok(window_id)
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn window_with_id(&self, window_id: WindowId) -> Option<&Window> {
+        self.window.iter().chain(self.additional_windows.iter()).find(|window| {
+            window.id() == window_id
+        })
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn host_layer_in_window_with_id(&mut self,
+                                    layer: LayerId,
+                                    window_id: WindowId,
+                                    tree_component: &LayerMap<LayerTreeInfo>,
+                                    container_component: &LayerMap<LayerContainerInfo>,
+                                    geometry_component: &LayerMap<LayerGeometryInfo>)
+                                    -> Result<(), ()> {
+        let surface = self.window_with_id(window_id).ok_or(())?.get_wayland_surface();
+        self.host_layer_in_wayland_surface(layer,
+                                           surface,
+                                           tree_component,
+                                           container_component,
+                                           geometry_component)
+    }
+
+    // Video
+
+    // TODO(pcwalton): Promote video layers to their own subsurfaces with `linux-dmabuf`
+    // buffers, so that the compositor can scan them out with hardware planes.
+    fn add_video_layer(&mut self, layer: LayerId) {
+        self.add_surface_layer(layer)
+    }
+
+    fn present_video_frame(&mut self,
+                           _: LayerId,
+                           _: &VideoFrame,
+                           _: &LayerMap<LayerTreeInfo>,
+                           _: &LayerMap<LayerGeometryInfo>)
+                           -> Result<(), ()> {
+        Err(())
     }
 
     // Screenshots
@@ -558,15 +808,99 @@ impl crate::Backend for Backend {
                                _: &LayerMap<LayerContainerInfo>,
                                _: &LayerMap<LayerGeometryInfo>,
                                _: &LayerMap<LayerSurfaceInfo>)
-                               -> Promise<RgbaImage> {
+                               -> Promise<RgbaImage, ScreenshotError> {
         // No reasonable way that I can see to do this on Wayland.
         let promise = Promise::new();
-        promise.reject();
+        promise.reject(ScreenshotError::Unsupported);
         promise
     }
+
+    // Event dispatch
+
+    fn pump_events(&mut self) -> Result<(), ()> {
+        // Transactions are settled in `end_transaction`, and screenshots are unsupported, so no
+        // event that we receive can settle a promise. Still, keep up with what has arrived.
+        drop(self.flush_and_dispatch(false));
+        Err(())
+    }
 }
 
 impl Backend {
+    // Sends our pending requests to the compositor and handles its events, optionally waiting for
+    // at least one to arrive. Once the connection is lost, this does nothing and fails.
+    fn flush_and_dispatch(&mut self, block: bool) -> Result<(), ()> {
+        if self.disconnected {
+            return Err(())
+        }
+
+        let result = self.display.flush().and_then(|()| {
+            if block {
+                self.event_queue.dispatch()
+            } else {
+                self.event_queue.dispatch_pending()
+            }
+        });
+        match result {
+            Ok(_) => Ok(()),
+            Err(error) => {
+                warn!("Lost the connection to the Wayland compositor: {}", error);
+                self.disconnected = true;
+                Err(())
+            }
+        }
+    }
+
+    // Positions a layer's subsurface relative to its parent, taking the parent's content offset
+    // into account if it's a scroll layer.
+    fn update_subsurface_position(&self,
+                                  layer: LayerId,
+                                  tree_component: &LayerMap<LayerTreeInfo>,
+                                  geometry_component: &LayerMap<LayerGeometryInfo>) {
+        let subsurface = match self.native_component[layer].subsurface {
+            None => return,
+            Some(ref subsurface) => subsurface,
+        };
+        let mut origin = match geometry_component.get(layer) {
+            None => return,
+            Some(geometry_info) => geometry_info.bounds.origin,
+        };
+
+        if let Some(tree_info) = tree_component.get(layer) {
+            if let LayerParent::Layer(parent) = tree_info.parent {
+                if let Some(content_offset) = self.scroll_component.get(parent) {
+                    origin -= *content_offset;
+                }
+            }
+        }
+
+        let origin = origin.round().to_i32();
+        subsurface.set_position(origin.x, origin.y);
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn host_layer_in_wayland_surface(&mut self,
+                                     layer: LayerId,
+                                     surface: Option<*mut c_void>,
+                                     tree_component: &LayerMap<LayerTreeInfo>,
+                                     container_component: &LayerMap<LayerContainerInfo>,
+                                     geometry_component: &LayerMap<LayerGeometryInfo>)
+                                     -> Result<(), ()> {
+        match surface {
+            Some(surface) => {
+                unsafe {
+                    crate::Backend::host_layer(self,
+                                               layer,
+                                               Proxy::from_c_ptr(surface as *mut wl_proxy),
+                                               tree_component,
+                                               container_component,
+                                               geometry_component);
+                }
+                Ok(())
+            }
+            None => Err(()),
+        }
+    }
+
     fn add_layer(&mut self, new_layer: LayerId) {
         let surface_enter_event_handler = Arc::new(Mutex::new(SurfaceEnterEventHandler {
             promise: Promise::new(),
@@ -627,6 +961,149 @@ impl Drop for Backend {
     }
 }
 
+// Returns the surface options that at least one window configuration supports.
+//
+// Each option is checked on its own, so a combination of supported options may still lack a
+// matching configuration.
+unsafe fn supported_surface_options(egl_display: EGLDisplay) -> SurfaceOptions {
+    let mut num_configs = 0;
+    if egl::GetConfigs(egl_display, ptr::null_mut(), 0, &mut num_configs) != egl::TRUE {
+        return SurfaceOptions::empty()
+    }
+    let mut configs = vec![ptr::null(); num_configs as usize];
+    if egl::GetConfigs(egl_display, configs.as_mut_ptr(), num_configs, &mut num_configs) !=
+            egl::TRUE {
+        return SurfaceOptions::empty()
+    }
+    configs.truncate(num_configs as usize);
+
+    // `EGL_COLOR_COMPONENT_TYPE_EXT` is only a valid attribute if the driver supports
+    // `EGL_EXT_pixel_format_float`.
+    let float_color = has_extension(egl_display, "EGL_EXT_pixel_format_float");
+    let config_attribute = |config, attribute: EGLint| {
+        let mut value = 0;
+        egl::GetConfigAttrib(egl_display, config, attribute, &mut value);
+        value
+    };
+    let configs: Vec<_> = configs.into_iter().filter(|&config| {
+        config_attribute(config, egl::SURFACE_TYPE as i32) & egl::WINDOW_BIT as i32 != 0 &&
+            config_attribute(config, egl::RENDERABLE_TYPE as i32) & egl::OPENGL_BIT as i32 != 0
+    }).collect();
+    let has_config = |predicate: &dyn Fn(EGLConfig) -> bool| {
+        configs.iter().any(|&config| predicate(config))
+    };
+    let has_color_sizes = |config, sizes: [EGLint; 4]| {
+        config_attribute(config, egl::RED_SIZE as i32) == sizes[0] &&
+            config_attribute(config, egl::GREEN_SIZE as i32) == sizes[1] &&
+            config_attribute(config, egl::BLUE_SIZE as i32) == sizes[2] &&
+            config_attribute(config, egl::ALPHA_SIZE as i32) == sizes[3]
+    };
+
+    let mut surface_options = SurfaceOptions::OPAQUE;
+    // sRGB encoding is chosen per surface, which needs `EGL_KHR_gl_colorspace`.
+    if has_extension(egl_display, "EGL_KHR_gl_colorspace") {
+        surface_options |= SurfaceOptions::SRGB;
+    }
+    if has_config(&|config| has_color_sizes(config, [10, 10, 10, 2])) {
+        surface_options |= SurfaceOptions::RGB10_A2;
+    }
+    if float_color && has_config(&|config| {
+        has_color_sizes(config, [16, 16, 16, 16]) &&
+            config_attribute(config, EGL_COLOR_COMPONENT_TYPE_EXT) ==
+            EGL_COLOR_COMPONENT_TYPE_FLOAT_EXT
+    }) {
+        surface_options |= SurfaceOptions::RGBA16F;
+    }
+    for &(option, depth_size) in &[(SurfaceOptions::DEPTH, 24),
+                                   (SurfaceOptions::DEPTH16, 16),
+                                   (SurfaceOptions::DEPTH32F, 32)] {
+        if has_config(&|config| config_attribute(config, egl::DEPTH_SIZE as i32) == depth_size) {
+            surface_options |= option;
+        }
+    }
+    if has_config(&|config| config_attribute(config, egl::STENCIL_SIZE as i32) == 8) {
+        surface_options |= SurfaceOptions::STENCIL;
+    }
+    for &(option, sample_count) in &[(SurfaceOptions::MSAA_2X, 2),
+                                     (SurfaceOptions::MSAA_4X, 4),
+                                     (SurfaceOptions::MSAA_8X, 8),
+                                     (SurfaceOptions::MSAA_16X, 16)] {
+        if has_config(&|config| {
+            config_attribute(config, egl::SAMPLE_BUFFERS as i32) == 1 &&
+                config_attribute(config, egl::SAMPLES as i32) == sample_count
+        }) {
+            surface_options |= option;
+        }
+    }
+    surface_options
+}
+
+// `GL_MAX_TEXTURE_SIZE` needs a current context, so make a throwaway one. It's unbound from any
+// window, using `EGL_KHR_surfaceless_context` if available and a 1×1 pbuffer otherwise.
+unsafe fn query_max_texture_size(egl_display: EGLDisplay) -> Option<u32> {
+    let surfaceless = has_extension(egl_display, "EGL_KHR_surfaceless_context");
+    let surface_type = if surfaceless { 0 } else { egl::PBUFFER_BIT as i32 };
+    let attributes = [
+        egl::SURFACE_TYPE as i32,       surface_type,
+        egl::RENDERABLE_TYPE as i32,    egl::OPENGL_BIT as i32,
+        egl::NONE as i32,               egl::NONE as i32,
+    ];
+    let (mut config, mut num_configs) = (ptr::null(), 0);
+    if egl::ChooseConfig(egl_display, attributes.as_ptr(), &mut config, 1, &mut num_configs) !=
+            egl::TRUE || num_configs == 0 {
+        return None
+    }
+
+    let attributes = [
+        egl::CONTEXT_CLIENT_VERSION as i32, 3,
+        egl::NONE as i32,                   egl::NONE as i32,
+    ];
+    let egl_context = egl::CreateContext(egl_display,
+                                         config,
+                                         egl::NO_CONTEXT,
+                                         attributes.as_ptr());
+    if egl_context == egl::NO_CONTEXT {
+        return None
+    }
+
+    let egl_surface = if surfaceless {
+        egl::NO_SURFACE
+    } else {
+        let attributes = [
+            egl::WIDTH as i32,  1,
+            egl::HEIGHT as i32, 1,
+            egl::NONE as i32,   egl::NONE as i32,
+        ];
+        egl::CreatePbufferSurface(egl_display, config, attributes.as_ptr())
+    };
+
+    let mut max_texture_size = None;
+    if (surfaceless || egl_surface != egl::NO_SURFACE) &&
+            egl::MakeCurrent(egl_display, egl_surface, egl_surface, egl_context) == egl::TRUE {
+        let mut size = 0;
+        gl::GetIntegerv(gl::MAX_TEXTURE_SIZE, &mut size);
+        if size > 0 {
+            max_texture_size = Some(size as u32);
+        }
+        egl::MakeCurrent(egl_display, egl::NO_SURFACE, egl::NO_SURFACE, egl::NO_CONTEXT);
+    }
+
+    if egl_surface != egl::NO_SURFACE {
+        egl::DestroySurface(egl_display, egl_surface);
+    }
+    egl::DestroyContext(egl_display, egl_context);
+    max_texture_size
+}
+
+unsafe fn has_extension(egl_display: EGLDisplay, name: &str) -> bool {
+    let extensions = egl::QueryString(egl_display, egl::EXTENSIONS as EGLint);
+    if extensions.is_null() {
+        return false
+    }
+    let extensions = CStr::from_ptr(extensions).to_string_lossy();
+    extensions.split_whitespace().any(|extension| extension == name)
+}
+
 pub struct WaylandConnection {
     pub display: Display,
     pub event_queue: EventQueue,
@@ -655,6 +1132,7 @@ struct HostSurface {
 struct CachedEGLSurface {
     egl_surface: EGLSurface,
     config_id: EGLint,
+    srgb: bool,
 }
 
 struct SurfaceEnterEventHandler {
@@ -0,0 +1,296 @@
+// planeshift/src/bin/planeshift-compositor/compositor.rs
+//
+// Copyright © 2018 The Pathfinder Project Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use euclid::{Point2D, Rect, Size2D};
+use image::RgbaImage;
+use planeshift::backend::Backend;
+use planeshift::backends::default;
+use planeshift::backends::remote::{self, HostId};
+use planeshift::server::{self, ClientId, Delegate, PeerCredentials, Permissions, Server};
+use planeshift::{Connection, LayerContext, LayerId, SurfaceOptions};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use std::time::Duration;
+use winit::{Event, EventsLoop, WindowBuilder, WindowEvent};
+
+// How long to wait for clients before checking for window events again.
+const DISPATCH_TIMEOUT: Duration = Duration::from_millis(16);
+
+const USAGE: &'static str = "\
+usage: planeshift-compositor [OPTIONS]
+
+Displays the layers of planeshift clients that connect over a Unix socket.
+
+options:
+    --socket PATH         Listen on PATH. Defaults to $PLANESHIFT_SOCKET, or to
+                          planeshift-compositor in $XDG_RUNTIME_DIR.
+    --allow-uid UID       Accept clients running as UID. May be repeated. By default,
+                          only clients running as the same user as the compositor are
+                          accepted.
+    --screenshot-uid UID  Allow clients running as UID to take screenshots. May be
+                          repeated. By default, no client may.
+    --view-only-uid UID   Accept clients running as UID, but don't show their layers.
+                          May be repeated.
+    --fullscreen          Cover the primary monitor, as a kiosk would.
+    --title TITLE         Set the title of the window.
+    --help                Print this message.
+";
+
+struct Options {
+    socket_path: PathBuf,
+    allowed_uids: Vec<u32>,
+    screenshot_uids: Vec<u32>,
+    view_only_uids: Vec<u32>,
+    fullscreen: bool,
+    title: String,
+}
+
+pub fn main() {
+    let options = match Options::parse() {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("planeshift-compositor: {}\n\n{}", message, USAGE);
+            process::exit(2);
+        }
+    };
+
+    let mut server = match Server::bind(&options.socket_path) {
+        Ok(server) => server,
+        Err(error) => {
+            eprintln!("planeshift-compositor: couldn't listen on {}: {}",
+                      options.socket_path.display(),
+                      error);
+            process::exit(1);
+        }
+    };
+
+    let mut event_loop = EventsLoop::new();
+    let mut window_builder = WindowBuilder::new().with_title(options.title.clone());
+    if options.fullscreen {
+        window_builder = window_builder.with_fullscreen(Some(event_loop.get_primary_monitor()));
+    }
+    let mut context = match LayerContext::new(Connection::Winit(window_builder, &event_loop)) {
+        Ok(context) => context,
+        Err(error) => {
+            eprintln!("planeshift-compositor: couldn't open a window: {:?}", error);
+            process::exit(1);
+        }
+    };
+
+    let mut shell = Shell::new(&mut context, options);
+    eprintln!("planeshift-compositor: listening on {}", shell.options.socket_path.display());
+
+    let mut running = true;
+    while running {
+        let mut resized = false;
+        event_loop.poll_events(|event| {
+            match event {
+                Event::WindowEvent { event: WindowEvent::CloseRequested, .. } => running = false,
+                Event::WindowEvent { event: WindowEvent::Resized(_), .. } |
+                Event::WindowEvent { event: WindowEvent::HiDpiFactorChanged(_), .. } => {
+                    resized = true
+                }
+                _ => {}
+            }
+        });
+
+        if resized {
+            shell.resize(&mut context);
+        }
+
+        if let Err(error) = server.dispatch(&mut context, &mut shell, Some(DISPATCH_TIMEOUT)) {
+            eprintln!("planeshift-compositor: {}", error);
+            break
+        }
+        context.update_flings();
+    }
+
+    drop(fs::remove_file(&shell.options.socket_path));
+}
+
+impl Options {
+    fn parse() -> Result<Options, String> {
+        let mut options = Options {
+            socket_path: default_socket_path(),
+            allowed_uids: vec![],
+            screenshot_uids: vec![],
+            view_only_uids: vec![],
+            fullscreen: false,
+            title: "planeshift compositor".to_owned(),
+        };
+
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match &*arg {
+                "--socket" => options.socket_path = PathBuf::from(value(&arg, args.next())?),
+                "--allow-uid" => options.allowed_uids.push(uid(&arg, args.next())?),
+                "--screenshot-uid" => options.screenshot_uids.push(uid(&arg, args.next())?),
+                "--view-only-uid" => options.view_only_uids.push(uid(&arg, args.next())?),
+                "--fullscreen" => options.fullscreen = true,
+                "--title" => options.title = value(&arg, args.next())?,
+                "--help" => {
+                    print!("{}", USAGE);
+                    process::exit(0);
+                }
+                _ => return Err(format!("unknown option `{}`", arg)),
+            }
+        }
+
+        if options.allowed_uids.is_empty() {
+            options.allowed_uids.push(unsafe { libc::getuid() });
+        }
+        Ok(options)
+    }
+
+    fn permissions(&self, uid: u32) -> Option<Permissions> {
+        let mut permissions = if self.allowed_uids.contains(&uid) {
+            Permissions::HOST_LAYERS
+        } else if self.view_only_uids.contains(&uid) {
+            Permissions::empty()
+        } else {
+            return None
+        };
+        if self.screenshot_uids.contains(&uid) {
+            permissions.insert(Permissions::SCREENSHOTS);
+        }
+        Some(permissions)
+    }
+}
+
+fn value(option: &str, value: Option<String>) -> Result<String, String> {
+    value.ok_or_else(|| format!("`{}` needs a value", option))
+}
+
+fn uid(option: &str, value: Option<String>) -> Result<u32, String> {
+    let uid = self::value(option, value)?;
+    uid.parse().map_err(|_| format!("`{}` isn't a user ID", uid))
+}
+
+fn default_socket_path() -> PathBuf {
+    if let Some(path) = env::var_os(remote::SOCKET_ENV_VAR) {
+        return PathBuf::from(path)
+    }
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(runtime_dir) => PathBuf::from(runtime_dir).join("planeshift-compositor"),
+        None => {
+            let uid = unsafe { libc::getuid() };
+            env::temp_dir().join(format!("planeshift-compositor-{}", uid))
+        }
+    }
+}
+
+// Gives each client a container layer of its own, filling the window, and hosts the client's
+// root layers inside it. Clients are stacked in the order that they connected.
+struct Shell {
+    options: Options,
+    root: LayerId,
+    client_containers: HashMap<ClientId, LayerId>,
+    gl_context: Option<<default::Backend as Backend>::GLContext>,
+}
+
+impl Shell {
+    fn new(context: &mut LayerContext, options: Options) -> Shell {
+        context.begin_transaction();
+        let root = context.add_container_layer();
+        context.host_layer_in_window(root).unwrap();
+        context.set_layer_bounds(root, &window_rect(context));
+        context.end_transaction();
+
+        Shell {
+            options,
+            root,
+            client_containers: HashMap::new(),
+            gl_context: None,
+        }
+    }
+
+    fn resize(&mut self, context: &mut LayerContext) {
+        let rect = window_rect(context);
+        context.begin_transaction();
+        context.set_layer_bounds(self.root, &rect);
+        for container in self.client_containers.values() {
+            context.set_layer_bounds(*container, &rect);
+        }
+        context.end_transaction();
+    }
+}
+
+impl Delegate<default::Backend> for Shell {
+    fn client_connected(&mut self,
+                        context: &mut LayerContext,
+                        client: ClientId,
+                        credentials: &PeerCredentials)
+                        -> Option<Permissions> {
+        let permissions = self.options.permissions(credentials.uid)?;
+
+        context.begin_transaction();
+        let container = context.add_container_layer();
+        context.set_layer_bounds(container, &window_rect(context));
+        context.append_child(self.root, container);
+        context.end_transaction();
+
+        self.client_containers.insert(client, container);
+        Some(permissions)
+    }
+
+    fn client_disconnected(&mut self, context: &mut LayerContext, client: ClientId) {
+        if let Some(container) = self.client_containers.remove(&client) {
+            context.begin_transaction();
+            context.remove_from_parent(container);
+            context.delete_layer(container);
+            context.end_transaction();
+        }
+    }
+
+    fn host_layer(&mut self,
+                  context: &mut LayerContext,
+                  client: ClientId,
+                  layer: LayerId,
+                  host: HostId)
+                  -> Result<(), ()> {
+        // There's only the one window.
+        if host != remote::MAIN_WINDOW_HOST {
+            return Err(())
+        }
+        let container = *self.client_containers.get(&client).ok_or(())?;
+        context.append_child(container, layer);
+        Ok(())
+    }
+
+    fn update_surface(&mut self,
+                      context: &mut LayerContext,
+                      layer: LayerId,
+                      pixels: &RgbaImage,
+                      changed_rect: &Rect<f32>)
+                      -> Result<(), ()> {
+        // FIXME(pcwalton): Opaque layers need a context created with `SurfaceOptions::OPAQUE` on
+        // some backends.
+        if self.gl_context.is_none() {
+            self.gl_context = Some(context.create_gl_context(SurfaceOptions::empty())?);
+        }
+        server::upload_surface_with_gl(context,
+                                       self.gl_context.as_mut().unwrap(),
+                                       layer,
+                                       pixels,
+                                       changed_rect)
+    }
+}
+
+fn window_rect(context: &LayerContext) -> Rect<f32> {
+    let window = context.window().unwrap();
+    let size = match window.get_inner_size() {
+        None => return Rect::zero(),
+        Some(size) => size.to_physical(window.get_hidpi_factor()),
+    };
+    Rect::new(Point2D::zero(), Size2D::new(size.width as f32, size.height as f32))
+}
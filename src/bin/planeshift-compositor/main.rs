@@ -0,0 +1,36 @@
+// planeshift/src/bin/planeshift-compositor/main.rs
+//
+// Copyright © 2018 The Pathfinder Project Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A lightweight compositor shell that displays the layers of other processes in one window.
+//!
+//! Clients connect with `planeshift::backends::remote`, usually by setting `PLANESHIFT_SOCKET`
+//! to the socket that this listens on. Run with `--help` for the options.
+
+extern crate euclid;
+extern crate image;
+extern crate planeshift;
+extern crate winit;
+
+#[cfg(unix)]
+extern crate libc;
+
+#[cfg(unix)]
+mod compositor;
+
+#[cfg(unix)]
+fn main() {
+    compositor::main()
+}
+
+#[cfg(not(unix))]
+fn main() {
+    eprintln!("planeshift-compositor: Unix domain sockets aren't supported on this platform");
+    std::process::exit(1);
+}
@@ -0,0 +1,300 @@
+// planeshift/src/effects.rs
+//
+// Copyright © 2018 The Pathfinder Project Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Helpers that backends share for layer effects: the area that effects paint, Gaussian
+//! kernels, and CPU implementations of blurs, shadows, blend modes, and masks for backends that
+//! composite on the CPU.
+//!
+//! Images are premultiplied RGBA, like layer surfaces.
+
+use euclid::{Point2D, Rect, Size2D};
+use image::{Rgba, RgbaImage};
+
+use crate::{BlendMode, DropShadow, LayerEffects};
+
+/// The largest distance, in pixels, that a blur spreads content.
+///
+/// This bounds the number of samples that a blur takes per pixel.
+pub const MAX_BLUR_EXTENT: u32 = 64;
+
+/// Returns how far, in whole pixels, a blur of the given radius spreads content: three standard
+/// deviations, rounded up.
+pub fn blur_extent(radius: f32) -> u32 {
+    if radius <= 0.0 {
+        return 0
+    }
+    ((sigma(radius) * 3.0).ceil() as u32).min(MAX_BLUR_EXTENT)
+}
+
+/// Returns how far beyond a layer's bounds its content must be padded to make room for the
+/// effects, before any shadow offset.
+///
+/// This also covers the backdrop that a backdrop blur reads, so that backends can use buffers of
+/// the same size for both.
+pub fn padding(effects: &LayerEffects) -> u32 {
+    let shadow_extent = effects.shadow.map_or(0, |shadow| blur_extent(shadow.radius));
+    let backdrop_extent = blur_extent(effects.backdrop_blur_radius);
+    blur_extent(effects.blur_radius).max(shadow_extent).max(backdrop_extent)
+}
+
+/// Returns the rect that a layer's content is rendered into before its effects are applied: its
+/// bounds, padded and rounded out to whole pixels.
+pub fn content_rect(effects: &LayerEffects, bounds: &Rect<f32>) -> Rect<f32> {
+    let padding = padding(effects) as f32;
+    bounds.inflate(padding, padding).round_out()
+}
+
+/// Returns the area that content within `rect` can paint once the effects are applied.
+pub fn painted_rect(effects: &LayerEffects, rect: &Rect<f32>) -> Rect<f32> {
+    let padding = padding(effects) as f32;
+    let padded_rect = rect.inflate(padding, padding);
+    match effects.shadow {
+        None => padded_rect,
+        Some(ref shadow) => padded_rect.union(&padded_rect.translate(&shadow.offset)),
+    }
+}
+
+/// Returns the weights of a normalized Gaussian kernel, from the center tap outward.
+///
+/// The kernel is symmetric, so tap `i` applies to the samples `i` pixels to either side.
+pub fn gaussian_kernel(radius: f32) -> Vec<f32> {
+    let extent = blur_extent(radius);
+    if extent == 0 {
+        return vec![1.0]
+    }
+
+    let sigma = sigma(radius);
+    let mut weights: Vec<f32> = (0..(extent + 1)).map(|offset| {
+        let offset = offset as f32;
+        (-offset * offset / (2.0 * sigma * sigma)).exp()
+    }).collect();
+
+    let total = weights[0] + 2.0 * weights[1..].iter().sum::<f32>();
+    for weight in &mut weights {
+        *weight /= total
+    }
+    weights
+}
+
+/// Blurs an image with a Gaussian of the given radius. Pixels outside the image are treated as
+/// transparent.
+pub fn blur(image: &RgbaImage, radius: f32) -> RgbaImage {
+    let kernel = gaussian_kernel(radius);
+    let (width, height) = image.dimensions();
+    let pixels: Vec<[f32; 4]> = image.pixels().map(|pixel| {
+        [pixel[0] as f32, pixel[1] as f32, pixel[2] as f32, pixel[3] as f32]
+    }).collect();
+
+    let pixels = blur_pass(&pixels, width, height, &kernel, 1, 0);
+    let pixels = blur_pass(&pixels, width, height, &kernel, 0, 1);
+
+    let mut result = RgbaImage::new(width, height);
+    for (dest, src) in result.pixels_mut().zip(pixels.iter()) {
+        *dest = Rgba {
+            data: [quantize(src[0]), quantize(src[1]), quantize(src[2]), quantize(src[3])],
+        };
+    }
+    result
+}
+
+/// Blurs the part of an image within `rect`, reading pixels from up to the blur's extent beyond
+/// it, as a backdrop blur does. Pixels outside the image are treated as transparent.
+pub fn blur_backdrop(image: &mut RgbaImage, rect: &Rect<i32>, radius: f32) {
+    let image_size = Size2D::new(image.width() as i32, image.height() as i32);
+    let image_rect = Rect::new(Point2D::zero(), image_size);
+    let rect = match rect.intersection(&image_rect) {
+        None => return,
+        Some(rect) => rect,
+    };
+    let extent = blur_extent(radius) as i32;
+    let source_rect = match rect.inflate(extent, extent).intersection(&image_rect) {
+        None => return,
+        Some(source_rect) => source_rect,
+    };
+
+    let (width, height) = (source_rect.size.width as u32, source_rect.size.height as u32);
+    let mut backdrop = RgbaImage::new(width, height);
+    let source_origin = source_rect.origin.to_u32();
+    for (x, y, pixel) in backdrop.enumerate_pixels_mut() {
+        *pixel = *image.get_pixel(source_origin.x + x, source_origin.y + y);
+    }
+    let backdrop = blur(&backdrop, radius);
+
+    for y in rect.min_y()..rect.max_y() {
+        for x in rect.min_x()..rect.max_x() {
+            let (src_x, src_y) = (x - source_rect.origin.x, y - source_rect.origin.y);
+            *image.get_pixel_mut(x as u32, y as u32) = *backdrop.get_pixel(src_x as u32,
+                                                                           src_y as u32);
+        }
+    }
+}
+
+/// Returns the shadow that an image casts: its alpha channel, blurred and filled with the color
+/// of the shadow.
+///
+/// The result is the same size as the image; the caller is responsible for offsetting it.
+pub fn shadow(image: &RgbaImage, shadow: &DropShadow) -> RgbaImage {
+    let kernel = gaussian_kernel(shadow.radius);
+    let (width, height) = image.dimensions();
+    let alphas: Vec<[f32; 4]> = image.pixels().map(|pixel| {
+        [0.0, 0.0, 0.0, pixel[3] as f32]
+    }).collect();
+
+    let alphas = blur_pass(&alphas, width, height, &kernel, 1, 0);
+    let alphas = blur_pass(&alphas, width, height, &kernel, 0, 1);
+
+    let color = shadow.color;
+    let opacity = shadow.opacity.max(0.0).min(1.0) * color[3] as f32 / 255.0;
+    let mut result = RgbaImage::new(width, height);
+    for (dest, src) in result.pixels_mut().zip(alphas.iter()) {
+        let alpha = src[3] / 255.0 * opacity;
+        *dest = Rgba {
+            data: [
+                quantize(color[0] as f32 * alpha),
+                quantize(color[1] as f32 * alpha),
+                quantize(color[2] as f32 * alpha),
+                quantize(255.0 * alpha),
+            ],
+        };
+    }
+    result
+}
+
+/// Multiplies every channel of an image by the alpha of the pixel at the same place in a mask
+/// the same size as it.
+pub fn mask(image: &mut RgbaImage, mask: &RgbaImage) {
+    for (dest, mask) in image.pixels_mut().zip(mask.pixels()) {
+        let alpha = mask[3] as f32 / 255.0;
+        for channel in 0..4 {
+            dest[channel] = quantize(dest[channel] as f32 * alpha);
+        }
+    }
+}
+
+/// Composites one premultiplied pixel over another with the given blend mode.
+pub fn blend(src: &Rgba<u8>, dest: &Rgba<u8>, mode: BlendMode) -> Rgba<u8> {
+    let (src_alpha, dest_alpha) = (src[3] as f32 / 255.0, dest[3] as f32 / 255.0);
+    let mut result = [0; 4];
+    for channel in 0..3 {
+        let (src_color, dest_color) = (src[channel] as f32 / 255.0, dest[channel] as f32 / 255.0);
+        let value = match mode {
+            BlendMode::Plus => src_color + dest_color,
+            _ => {
+                // Blend the unpremultiplied colors where the layer and its backdrop overlap.
+                let source = if src_alpha > 0.0 { src_color / src_alpha } else { 0.0 };
+                let backdrop = if dest_alpha > 0.0 { dest_color / dest_alpha } else { 0.0 };
+                src_color * (1.0 - dest_alpha) + dest_color * (1.0 - src_alpha) +
+                    src_alpha * dest_alpha * blend_channel(mode, backdrop, source)
+            }
+        };
+        result[channel] = quantize(value * 255.0);
+    }
+    let alpha = match mode {
+        BlendMode::Plus => src_alpha + dest_alpha,
+        _ => src_alpha + dest_alpha * (1.0 - src_alpha),
+    };
+    result[3] = quantize(alpha * 255.0);
+    Rgba { data: result }
+}
+
+/// Blends one unpremultiplied color channel of the layer, `source`, with that of its backdrop.
+pub fn blend_channel(mode: BlendMode, backdrop: f32, source: f32) -> f32 {
+    match mode {
+        BlendMode::Normal => source,
+        BlendMode::Multiply => backdrop * source,
+        BlendMode::Screen => backdrop + source - backdrop * source,
+        BlendMode::Overlay => blend_channel(BlendMode::HardLight, source, backdrop),
+        BlendMode::Darken => backdrop.min(source),
+        BlendMode::Lighten => backdrop.max(source),
+        BlendMode::ColorDodge => {
+            if backdrop <= 0.0 {
+                0.0
+            } else if source >= 1.0 {
+                1.0
+            } else {
+                (backdrop / (1.0 - source)).min(1.0)
+            }
+        }
+        BlendMode::ColorBurn => {
+            if backdrop >= 1.0 {
+                1.0
+            } else if source <= 0.0 {
+                0.0
+            } else {
+                1.0 - ((1.0 - backdrop) / source).min(1.0)
+            }
+        }
+        BlendMode::HardLight => {
+            if source <= 0.5 {
+                blend_channel(BlendMode::Multiply, backdrop, 2.0 * source)
+            } else {
+                blend_channel(BlendMode::Screen, backdrop, 2.0 * source - 1.0)
+            }
+        }
+        BlendMode::SoftLight => {
+            if source <= 0.5 {
+                backdrop - (1.0 - 2.0 * source) * backdrop * (1.0 - backdrop)
+            } else {
+                let d = if backdrop <= 0.25 {
+                    ((16.0 * backdrop - 12.0) * backdrop + 4.0) * backdrop
+                } else {
+                    backdrop.sqrt()
+                };
+                backdrop + (2.0 * source - 1.0) * (d - backdrop)
+            }
+        }
+        BlendMode::Difference => (backdrop - source).abs(),
+        BlendMode::Exclusion => backdrop + source - 2.0 * backdrop * source,
+        BlendMode::Plus => (backdrop + source).min(1.0),
+    }
+}
+
+// As in CSS, the standard deviation is half of the blur radius.
+fn sigma(radius: f32) -> f32 {
+    radius * 0.5
+}
+
+// Convolves the pixels with the kernel along one axis, given by `(step_x, step_y)`.
+fn blur_pass(pixels: &[[f32; 4]],
+             width: u32,
+             height: u32,
+             kernel: &[f32],
+             step_x: i32,
+             step_y: i32)
+             -> Vec<[f32; 4]> {
+    let (width, height) = (width as i32, height as i32);
+    let mut result = vec![[0.0; 4]; pixels.len()];
+    for y in 0..height {
+        for x in 0..width {
+            let mut sum = [0.0; 4];
+            for (tap, &weight) in kernel.iter().enumerate() {
+                let tap = tap as i32;
+                // The center tap only applies once.
+                let offsets = [tap, -tap];
+                for &offset in &offsets[..(if tap == 0 { 1 } else { 2 })] {
+                    let (src_x, src_y) = (x + offset * step_x, y + offset * step_y);
+                    if src_x < 0 || src_y < 0 || src_x >= width || src_y >= height {
+                        continue
+                    }
+                    let src = &pixels[(src_y * width + src_x) as usize];
+                    for channel in 0..4 {
+                        sum[channel] += src[channel] * weight;
+                    }
+                }
+            }
+            result[(y * width + x) as usize] = sum;
+        }
+    }
+    result
+}
+
+fn quantize(value: f32) -> u8 {
+    value.round().max(0.0).min(255.0) as u8
+}
@@ -0,0 +1,174 @@
+// planeshift/src/export.rs
+//
+// Copyright © 2018 The Pathfinder Project Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Shared-memory images that carry the contents of exported layers from one process to another.
+//!
+//! Backends that composite layers themselves export a layer by rendering its subtree into one
+//! of these after each transaction. The file begins with a 16-byte header: a magic number, then
+//! the width, the height, and a serial number, all little-endian `u32`s. The pixels follow,
+//! premultiplied RGBA, top row first.
+//!
+//! The serial number works like a sequence lock: it's odd while the writer is updating the file
+//! and even otherwise, so readers can detect frames that they read halfway through an update and
+//! try again later.
+
+use image::RgbaImage;
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tempfile::{Builder, NamedTempFile};
+
+use crate::ExportToken;
+
+const MAGIC: u32 = 0x4c58_5350;     // "PSXL"
+const HEADER_SIZE: usize = 16;
+
+const TOKEN_PREFIX: &'static [u8] = b"shm:";
+
+pub struct SharedImageWriter {
+    file: NamedTempFile,
+    serial: u32,
+}
+
+impl SharedImageWriter {
+    pub fn new() -> io::Result<SharedImageWriter> {
+        // The file is only readable by our own user.
+        let file = Builder::new().prefix("planeshift-export-").tempfile_in(shared_dir())?;
+        let mut writer = SharedImageWriter {
+            file,
+            serial: 0,
+        };
+        writer.write_header(0, 0)?;
+        Ok(writer)
+    }
+
+    pub fn token(&self) -> Result<ExportToken, ()> {
+        let path = self.file.path().to_str().ok_or(())?;
+        let mut bytes = TOKEN_PREFIX.to_vec();
+        bytes.extend_from_slice(path.as_bytes());
+        Ok(ExportToken::from_bytes(bytes))
+    }
+
+    pub fn write(&mut self, image: &RgbaImage) -> io::Result<()> {
+        self.serial = self.serial.wrapping_add(1);
+        self.write_header(image.width(), image.height())?;
+
+        let file = self.file.as_file_mut();
+        file.seek(SeekFrom::Start(HEADER_SIZE as u64))?;
+        file.write_all(&*image)?;
+        file.set_len((HEADER_SIZE + image.len()) as u64)?;
+
+        self.serial = self.serial.wrapping_add(1);
+        self.write_header(image.width(), image.height())
+    }
+
+    fn write_header(&mut self, width: u32, height: u32) -> io::Result<()> {
+        let mut header = [0; HEADER_SIZE];
+        for (index, value) in [MAGIC, width, height, self.serial].iter().enumerate() {
+            header[(index * 4)..(index * 4 + 4)].copy_from_slice(&value.to_le_bytes());
+        }
+
+        let file = self.file.as_file_mut();
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&header)?;
+        file.flush()
+    }
+}
+
+pub struct SharedImageReader {
+    file: File,
+    last_serial: Option<u32>,
+}
+
+impl SharedImageReader {
+    pub fn open(token: &ExportToken) -> Result<SharedImageReader, ()> {
+        let bytes = token.as_bytes();
+        if !bytes.starts_with(TOKEN_PREFIX) {
+            return Err(())
+        }
+        let path = Path::new(std::str::from_utf8(&bytes[TOKEN_PREFIX.len()..]).map_err(drop)?);
+
+        let mut reader = SharedImageReader {
+            file: OpenOptions::new().read(true).open(path).map_err(drop)?,
+            last_serial: None,
+        };
+        reader.read_header().map_err(drop)?;
+        Ok(reader)
+    }
+
+    /// Returns true if the exporter has finished writing a frame that `read_if_changed` hasn't
+    /// returned yet. This only reads the header.
+    pub fn has_changed(&mut self) -> bool {
+        match self.read_header() {
+            Ok((_, _, serial)) => serial % 2 == 0 && self.last_serial != Some(serial),
+            Err(_) => false,
+        }
+    }
+
+    /// Returns the image if the exporter has written a new frame since the last call.
+    ///
+    /// Returns `Ok(None)` if nothing changed, or if the exporter was in the middle of writing.
+    pub fn read_if_changed(&mut self) -> io::Result<Option<RgbaImage>> {
+        let (width, height, serial) = self.read_header()?;
+        if serial % 2 == 1 || self.last_serial == Some(serial) {
+            return Ok(None)
+        }
+
+        // Don't trust the header's dimensions until we know that the file is big enough to hold
+        // that many pixels.
+        let length = width as u64 * height as u64 * 4;
+        if self.file.metadata()?.len() < HEADER_SIZE as u64 + length {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated exported layer"))
+        }
+
+        let mut pixels = vec![0; length as usize];
+        self.file.read_exact(&mut pixels)?;
+
+        // If the serial number changed while we were reading, we may have a torn frame.
+        let (_, _, new_serial) = self.read_header()?;
+        if new_serial != serial {
+            return Ok(None)
+        }
+
+        self.last_serial = Some(serial);
+        Ok(RgbaImage::from_raw(width, height, pixels))
+    }
+
+    // Returns the width, height, and serial number, and leaves the file positioned at the start
+    // of the pixels.
+    fn read_header(&mut self) -> io::Result<(u32, u32, u32)> {
+        let mut header = [0; HEADER_SIZE];
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_exact(&mut header)?;
+
+        let mut fields = [0; 4];
+        for (index, field) in fields.iter_mut().enumerate() {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(&header[(index * 4)..(index * 4 + 4)]);
+            *field = u32::from_le_bytes(bytes);
+        }
+
+        if fields[0] != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not an exported layer"))
+        }
+        Ok((fields[1], fields[2], fields[3]))
+    }
+}
+
+// Prefer a RAM-backed file system, so that updating the image never touches the disk.
+fn shared_dir() -> PathBuf {
+    let shm = Path::new("/dev/shm");
+    if cfg!(target_os = "linux") && shm.is_dir() {
+        shm.to_owned()
+    } else {
+        env::temp_dir()
+    }
+}
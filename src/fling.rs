@@ -0,0 +1,207 @@
+// planeshift/src/fling.rs
+//
+// Copyright © 2018 The Pathfinder Project Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The physics of momentum scrolling.
+//!
+//! A fling coasts with exponentially decaying velocity while the content offset is in range.
+//! Past either edge of the content, a critically damped spring pulls it back instead, which
+//! produces the rubber band effect. Each axis is simulated independently.
+//!
+//! Backends that animate flings themselves keep them in a `FlingAnimator`, which runs them on a
+//! frame clock of its own.
+
+use euclid::{Size2D, Vector2D};
+use std::cmp;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{LayerId, Promise};
+
+// The rate, per second, at which a fling loses velocity while in range. At this rate, a fling
+// travels half a second's worth of its initial velocity before it stops.
+const FRICTION: f32 = 2.0;
+
+// The stiffness, per second squared, of the spring that pulls the content offset back in range.
+const SPRING_STIFFNESS: f32 = 200.0;
+
+// Slower than this many pixels per second, a fling is considered to have stopped.
+const MIN_VELOCITY: f32 = 10.0;
+
+// Closer than this many pixels to the edge, a spring is considered to have settled.
+const SETTLE_DISTANCE: f32 = 0.5;
+
+// The longest time step, in seconds, that the simulation takes at once. The spring is unstable
+// with long time steps.
+const MAX_TIME_STEP: f32 = 1.0 / 240.0;
+
+// The time between the frames of a `FlingAnimator`, which is about 60 frames per second.
+const FRAME_INTERVAL: Duration = Duration::from_micros(16_667);
+
+pub struct Fling {
+    offset: Vector2D<f32>,
+    velocity: Vector2D<f32>,
+    max_offset: Vector2D<f32>,
+}
+
+impl Fling {
+    /// Starts a fling at the given content offset and velocity, in pixels per second.
+    ///
+    /// The content offset rests between zero and `max_offset` on each axis.
+    pub fn new(offset: &Vector2D<f32>, velocity: &Vector2D<f32>, max_offset: &Vector2D<f32>)
+               -> Fling {
+        Fling { offset: *offset, velocity: *velocity, max_offset: *max_offset }
+    }
+
+    #[inline]
+    pub fn offset(&self) -> Vector2D<f32> {
+        self.offset
+    }
+
+    /// Returns true if the fling has come to rest, within range.
+    pub fn is_finished(&self) -> bool {
+        axis_is_finished(self.offset.x, self.velocity.x, self.max_offset.x) &&
+            axis_is_finished(self.offset.y, self.velocity.y, self.max_offset.y)
+    }
+
+    /// Runs the simulation forward by the given amount of time.
+    pub fn advance(&mut self, duration: Duration) {
+        let mut time_left = duration.as_secs() as f32 + duration.subsec_nanos() as f32 * 1e-9;
+        while time_left > 0.0 && !self.is_finished() {
+            let time_step = time_left.min(MAX_TIME_STEP);
+            advance_axis(&mut self.offset.x, &mut self.velocity.x, self.max_offset.x, time_step);
+            advance_axis(&mut self.offset.y, &mut self.velocity.y, self.max_offset.y, time_step);
+            time_left -= time_step;
+        }
+
+        // Snap axes that have settled, so that the final offset lies exactly in range.
+        settle_axis(&mut self.offset.x, &mut self.velocity.x, self.max_offset.x);
+        settle_axis(&mut self.offset.y, &mut self.velocity.y, self.max_offset.y);
+    }
+}
+
+/// The flings that a backend animates itself.
+///
+/// The backend advances the flings whenever it renders, and also once per frame of the
+/// animator's clock, so they keep moving even if no transactions end.
+pub struct FlingAnimator {
+    flings: Vec<LayerFling>,
+    next_frame: Instant,
+}
+
+struct LayerFling {
+    layer: LayerId,
+    fling: Fling,
+    last_update: Instant,
+    promise: Promise<Vector2D<f32>>,
+}
+
+impl FlingAnimator {
+    #[inline]
+    pub fn new() -> FlingAnimator {
+        FlingAnimator { flings: vec![], next_frame: Instant::now() }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.flings.is_empty()
+    }
+
+    /// Starts animating the given fling of a layer, replacing any fling already in progress on
+    /// it. The promise resolves to the final content offset once `settle` is called after the
+    /// fling comes to rest.
+    pub fn start(&mut self, layer: LayerId, fling: Fling, promise: &Promise<Vector2D<f32>>) {
+        self.stop(layer);
+        self.flings.push(LayerFling {
+            layer,
+            fling,
+            last_update: Instant::now(),
+            promise: promise.clone(),
+        });
+    }
+
+    /// Stops the fling of a layer, if there is one, without settling its promise.
+    pub fn stop(&mut self, layer: LayerId) {
+        self.flings.retain(|layer_fling| layer_fling.layer != layer);
+    }
+
+    /// Sleeps until the next frame of the animator's clock is due.
+    pub fn wait_for_frame(&mut self) {
+        let now = Instant::now();
+        if self.next_frame > now {
+            thread::sleep(self.next_frame - now);
+        }
+        // If we fell behind, skip the frames we missed instead of rushing to catch up.
+        self.next_frame = cmp::max(self.next_frame, now) + FRAME_INTERVAL;
+    }
+
+    /// Advances every fling to the current time and returns the new content offsets.
+    pub fn advance(&mut self) -> Vec<(LayerId, Vector2D<f32>)> {
+        let now = Instant::now();
+        self.flings.iter_mut().map(|layer_fling| {
+            layer_fling.fling.advance(now - layer_fling.last_update);
+            layer_fling.last_update = now;
+            (layer_fling.layer, layer_fling.fling.offset())
+        }).collect()
+    }
+
+    /// Resolves the promises of the flings that have come to rest, and forgets them.
+    ///
+    /// Call this once the final offsets have been rendered.
+    pub fn settle(&mut self) {
+        self.flings.retain(|layer_fling| {
+            if !layer_fling.fling.is_finished() {
+                return true
+            }
+            layer_fling.promise.resolve(layer_fling.fling.offset());
+            false
+        });
+    }
+}
+
+/// Returns the largest content offset that's in range for a scroll layer of the given size.
+pub fn max_offset(bounds_size: &Size2D<f32>, content_size: &Size2D<f32>) -> Vector2D<f32> {
+    Vector2D::new((content_size.width - bounds_size.width).max(0.0),
+                  (content_size.height - bounds_size.height).max(0.0))
+}
+
+// Returns how far past the nearest edge the offset is, with the sign of the direction that it
+// has to move to get back in range, or zero if it's in range.
+fn overscroll(offset: f32, max_offset: f32) -> f32 {
+    if offset < 0.0 {
+        offset
+    } else if offset > max_offset {
+        offset - max_offset
+    } else {
+        0.0
+    }
+}
+
+fn axis_is_finished(offset: f32, velocity: f32, max_offset: f32) -> bool {
+    velocity.abs() < MIN_VELOCITY && overscroll(offset, max_offset) == 0.0
+}
+
+fn advance_axis(offset: &mut f32, velocity: &mut f32, max_offset: f32, time_step: f32) {
+    let overscroll = overscroll(*offset, max_offset);
+    if overscroll == 0.0 {
+        *velocity *= (-FRICTION * time_step).exp();
+    } else {
+        let damping = 2.0 * SPRING_STIFFNESS.sqrt();
+        *velocity -= (SPRING_STIFFNESS * overscroll + damping * *velocity) * time_step;
+    }
+    *offset += *velocity * time_step;
+}
+
+fn settle_axis(offset: &mut f32, velocity: &mut f32, max_offset: f32) {
+    let overscroll = overscroll(*offset, max_offset);
+    if overscroll.abs() < SETTLE_DISTANCE && velocity.abs() < MIN_VELOCITY {
+        *offset -= overscroll;
+        *velocity = 0.0;
+    }
+}
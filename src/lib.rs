@@ -17,10 +17,15 @@ extern crate tempfile;
 extern crate bitflags;
 #[macro_use]
 extern crate lazy_static;
+#[macro_use]
+extern crate log;
 
 #[cfg(feature = "enable-winit")]
 extern crate winit;
 
+#[cfg(unix)]
+extern crate libc;
+
 #[cfg(target_os = "linux")]
 extern crate dbus;
 #[cfg(target_os = "linux")]
@@ -50,21 +55,37 @@ extern crate mozangle;
 #[cfg(target_family = "windows")]
 extern crate winapi;
 
-use euclid::Rect;
+use euclid::{Point2D, Rect, Size2D, Vector2D};
 use gl::types::GLuint;
-use image::RgbaImage;
+use image::{Rgba, RgbaImage};
+use std::collections::HashMap;
 use std::fmt::{self, Debug, Formatter};
+use std::future::Future;
 use std::mem;
 use std::ops::{Index, IndexMut};
+use std::pin::Pin;
 use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Instant;
 
 #[cfg(feature = "enable-winit")]
-use winit::{EventsLoop, Window, WindowBuilder};
+use winit::{EventsLoop, Window, WindowBuilder, WindowId};
 
 use crate::backend::Backend;
+use crate::fling::Fling;
 
 pub mod backend;
 pub mod backends;
+#[cfg(unix)]
+pub mod server;
+pub mod testing;
+
+mod effects;
+mod export;
+mod fling;
+#[cfg(unix)]
+mod protocol;
+mod video;
 
 #[cfg(target_os = "linux")]
 #[allow(non_camel_case_types)]
@@ -97,6 +118,11 @@ pub struct LayerContext<B = backends::default::Backend> where B: Backend {
     container_component: LayerMap<LayerContainerInfo>,
     geometry_component: LayerMap<LayerGeometryInfo>,
     surface_component: LayerMap<LayerSurfaceInfo>,
+    effects_component: LayerMap<LayerEffectsInfo>,
+    scroll_component: LayerMap<LayerScrollInfo>,
+
+    flings: Vec<FlingInfo>,
+    tiled_component: LayerMap<TiledLayerInfo<B>>,
 
     backend: B,
 }
@@ -122,21 +148,197 @@ pub enum Connection<'a, N> {
 
 bitflags! {
     /// Specifies the type of GPU surface or surfaces to be allocated for a surface layer.
-    pub struct SurfaceOptions: u8 {
+    pub struct SurfaceOptions: u16 {
         /// The layer is opaque.
         ///
         /// The OS may be able to optimize composition of opaque layers, for example by not
         /// composing any content underneath them
         const OPAQUE = 0x01;
 
-        /// The surface includes a 24-bit depth or Z-buffer.
+        /// The surface includes a depth or Z-buffer.
+        ///
+        /// By default, the depth buffer has 24 bits of precision. Use `DEPTH16` or `DEPTH32F` to
+        /// choose a different precision.
         const DEPTH = 0x02;
 
         /// The surface includes an 8-bit stencil buffer.
         const STENCIL = 0x04;
+
+        /// The color buffer stores sRGB-encoded values.
+        ///
+        /// This may not be combined with `RGB10_A2` or `RGBA16F`.
+        const SRGB = 0x08;
+
+        /// The color buffer has 10 bits per color channel and 2 bits of alpha, instead of the
+        /// default 8 bits per channel.
+        const RGB10_A2 = 0x10;
+
+        /// The color buffer has 16-bit floating point channels, instead of the default 8-bit
+        /// normalized channels.
+        const RGBA16F = 0x20;
+
+        /// The depth buffer has 16 bits of precision. Requires `DEPTH`.
+        const DEPTH16 = 0x40;
+
+        /// The depth buffer has 32-bit floating point precision. Requires `DEPTH`.
+        const DEPTH32F = 0x80;
+
+        /// The surface is rendered with 2x multisample antialiasing.
+        ///
+        /// At most one of the `MSAA_*` options may be specified. Without any of them, the
+        /// surface has one sample per pixel.
+        const MSAA_2X = 0x100;
+
+        /// The surface is rendered with 4x multisample antialiasing.
+        const MSAA_4X = 0x200;
+
+        /// The surface is rendered with 8x multisample antialiasing.
+        const MSAA_8X = 0x400;
+
+        /// The surface is rendered with 16x multisample antialiasing.
+        const MSAA_16X = 0x800;
     }
 }
 
+/// The layout of the planes of a YUV video frame.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum YUVFormat {
+    /// A full-resolution Y plane, followed by a half-resolution plane of interleaved U and V
+    /// samples.
+    NV12,
+    /// A full-resolution Y plane, followed by half-resolution U and V planes.
+    I420,
+}
+
+/// The matrix that converts the YUV values of a video frame to RGB.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ColorMatrix {
+    /// ITU-R BT.601, used by standard-definition video.
+    BT601,
+    /// ITU-R BT.709, used by high-definition video.
+    BT709,
+}
+
+/// The range of values that the samples of a video frame span.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ColorRange {
+    /// Y spans 16 to 235, and U and V span 16 to 240. Most video uses this range.
+    Limited,
+    /// Y, U, and V all span 0 to 255.
+    Full,
+}
+
+/// One plane of a video frame.
+#[derive(Clone, Copy, Debug)]
+pub enum VideoPlane<'a> {
+    /// Samples in memory, top row first, with `stride` bytes between the starts of rows.
+    Memory {
+        data: &'a [u8],
+        stride: usize,
+    },
+    /// An OpenGL texture belonging to the backend's context, top row first.
+    ///
+    /// Y, U, and V planes are single-channel textures (e.g. `GL_R8`), and the interleaved
+    /// chroma plane of an NV12 frame is a two-channel texture (e.g. `GL_RG8`).
+    Texture(GLuint),
+}
+
+/// A frame of video to display in a video layer.
+#[derive(Clone, Copy, Debug)]
+pub struct VideoFrame<'a> {
+    pub format: YUVFormat,
+    pub color_matrix: ColorMatrix,
+    pub color_range: ColorRange,
+    /// The width and height of the Y plane, in pixels.
+    ///
+    /// The chroma planes are half as wide and half as high, rounded up.
+    pub size: Size2D<u32>,
+    /// The planes of the frame, in order: Y and UV for NV12, or Y, U, and V for I420.
+    pub planes: &'a [VideoPlane<'a>],
+}
+
+/// A shadow that a layer casts behind itself, in the shape of its content.
+///
+/// The shadow follows the alpha channel of the layer and its descendants, so content with
+/// rounded corners or holes casts a shadow with the same shape.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct DropShadow {
+    /// How far the shadow is displaced from the layer, in device pixels.
+    pub offset: Vector2D<f32>,
+    /// The blur radius of the shadow, in device pixels.
+    ///
+    /// As in CSS, the standard deviation of the Gaussian blur is half of this.
+    pub radius: f32,
+    /// The color of the shadow. This is not premultiplied.
+    pub color: Rgba<u8>,
+    /// The opacity of the shadow, from 0 to 1, which multiplies the alpha of `color`.
+    pub opacity: f32,
+}
+
+/// Visual effects applied to a layer and its descendants as a group.
+///
+/// While a layer has any effects, its descendants are clipped to its bounds.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct LayerEffects {
+    /// A shadow drawn behind the layer.
+    pub shadow: Option<DropShadow>,
+    /// The radius, in device pixels, of a Gaussian blur applied to the layer's content, or 0 for
+    /// none.
+    ///
+    /// As with shadows, the standard deviation is half of the radius.
+    pub blur_radius: f32,
+    /// The radius, in device pixels, of a Gaussian blur applied to whatever is composited
+    /// beneath the layer, within its bounds, or 0 for none.
+    ///
+    /// The blurred backdrop replaces what was beneath the layer, and the layer's content is
+    /// blended over it, so translucent content looks like frosted glass. The backdrop includes
+    /// only the layers beneath this one that share its nearest ancestor with other effects, if
+    /// there is one.
+    pub backdrop_blur_radius: f32,
+    /// How the layer is combined with what's beneath it.
+    pub blend_mode: BlendMode,
+}
+
+/// How a layer's content is combined with what's beneath it.
+///
+/// These are the separable blend modes of the W3C Compositing and Blending specification, plus
+/// `Plus`, which adds the two together. Apart from `Normal`, blend modes apply to the layer and its
+/// descendants as a group.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BlendMode {
+    /// The layer is drawn over what's beneath it.
+    Normal,
+    Multiply,
+    Screen,
+    Overlay,
+    Darken,
+    Lighten,
+    ColorDodge,
+    ColorBurn,
+    HardLight,
+    SoftLight,
+    Difference,
+    Exclusion,
+    /// The colors are added, and saturate at white. This is sometimes called additive blending.
+    Plus,
+}
+
+/// A tile of a tiled layer that needs to be painted.
+#[derive(Clone, Copy, Debug)]
+pub struct Tile {
+    /// The tiled layer that the tile belongs to.
+    pub tiled_layer: LayerId,
+    /// The surface layer that displays the tile.
+    pub layer: LayerId,
+    /// The area of the tiled layer's content that the tile covers, relative to the tiled layer.
+    ///
+    /// This is also the tile's bounds, since tiles are children of the tiled layer.
+    pub rect: Rect<f32>,
+}
+
+/// Paints the tiles of a tiled layer. See `LayerContext::add_tiled_layer`.
+pub type TilePainter<B> = Box<dyn FnMut(&mut LayerContext<B>, &Tile)>;
+
 /// Information about the current binding between the OpenGL context and its associated layer.
 pub struct GLContextLayerBinding {
     /// The layer bound to the OpenGL context.
@@ -160,17 +362,85 @@ pub enum GLAPI {
     GL,
     /// OpenGL ES.
     GLES,
+    /// No OpenGL API at all. Surface layers must be drawn into on the CPU.
+    None,
+}
+
+/// Describes what the active backend supports, so that applications can adapt to it.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Capabilities {
+    /// Whether `screenshot_hosted_layer` can succeed.
+    pub screenshots: bool,
+    /// Whether `present_gl_context` only updates the changed rect that it's given, instead of
+    /// recompositing or swapping the entire surface.
+    pub damage_aware_present: bool,
+    /// Whether the platform compositor can transform layers natively, beyond translating them.
+    pub transforms: bool,
+    /// Whether the backend can animate layers itself, such as during a fling, without the
+    /// application redrawing them or ending transactions.
+    pub native_animations: bool,
+    /// The surface options that the backend understands.
+    ///
+    /// A set of options can only be used if it's a subset of these. Options that select
+    /// conflicting formats are rejected regardless.
+    pub surface_options: SurfaceOptions,
+    /// The largest width or height, in pixels, of a surface layer.
+    ///
+    /// This is `None` if the backend doesn't know. Some backends that use the platform's OpenGL
+    /// implementation only learn it the first time a layer is bound to an OpenGL context;
+    /// backends that draw on the CPU have no limit. Content larger than this can be displayed
+    /// with a tiled layer; see `LayerContext::add_tiled_layer`.
+    pub max_texture_size: Option<u32>,
+    /// Whether `export_layer` and `import_layer` can succeed.
+    pub layer_export: bool,
+    /// Whether video layers can display frames.
+    ///
+    /// Frames whose planes are OpenGL textures additionally need a backend with an OpenGL API.
+    pub video_layers: bool,
+    /// Whether layers display the shadows and blurs set with `set_layer_effects`.
+    pub layer_effects: bool,
+    /// Whether layers blur their backdrops, as `LayerEffects::backdrop_blur_radius` requests.
+    ///
+    /// This implies `layer_effects`.
+    pub backdrop_blur: bool,
+    /// Whether layers are composited with the blend modes in `LayerEffects::blend_mode`.
+    ///
+    /// This implies `layer_effects`.
+    pub blend_modes: bool,
+    /// Whether layers are masked by the layers set with `set_layer_mask`.
+    pub layer_masks: bool,
+    /// Whether scroll layers clip their children to their bounds.
+    ///
+    /// Scroll layers always shift their children by their content offset.
+    pub scroll_clipping: bool,
 }
 
+/// An opaque handle to a layer exported from one process, which another process can import.
+///
+/// Send the bytes to the importing process by any means, and rebuild the token there with
+/// `from_bytes`. A token is only meaningful to a backend of the same kind as the one that
+/// created it, and only while the exported layer remains exported.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct ExportToken(Vec<u8>);
+
 /// Represents the result of a pending operation.
 ///
 /// This is similar to a Rust future, but it always uses the native OS event loop for dispatch.
 /// Note that it is your responsibility to pump the OS event loop. (If using `winit`, this is the
 /// `EventLoop` object.)
 ///
-/// Use the `then` method to attach handlers.
+/// A promise either resolves to a value of type `T` or is rejected with an error of type `E`.
+/// Handlers attached with `then` and `or_else` are always called without any internal locks held,
+/// so they may freely attach further handlers to the same promise. Handlers attached after the
+/// promise has settled are called immediately.
+///
+/// Use the `then` method to attach handlers, or the `map` and `and_then` combinators to derive
+/// new promises. Promises also implement `std::future::Future`, so they can be `.await`ed from an
+/// asynchronous runtime; alternatively, `LayerContext::wait` blocks until a promise settles,
+/// pumping the backend's event loop as it does so.
 #[derive(Clone)]
-pub struct Promise<T>(Arc<Mutex<PromiseData<T>>>) where T: 'static + Clone + Send;
+pub struct Promise<T, E = ()>(Arc<Mutex<PromiseData<T, E>>>) where T: 'static + Clone + Send,
+                                                                   E: 'static + Clone + Send;
 
 // Components
 
@@ -195,26 +465,67 @@ pub struct LayerGeometryInfo {
 #[doc(hidden)]
 pub struct LayerSurfaceInfo {
     options: SurfaceOptions,
+    // Imported layers get their contents from another process, so they can't be drawn into.
+    imported: bool,
+    // Video layers get their contents from `present_video_frame`, so they can't be drawn into
+    // either.
+    video: bool,
+}
+
+#[doc(hidden)]
+pub struct LayerEffectsInfo {
+    effects: LayerEffects,
+    mask: Option<LayerId>,
+}
+
+#[doc(hidden)]
+pub struct LayerScrollInfo {
+    content_size: Size2D<f32>,
+    content_offset: Vector2D<f32>,
 }
 
 // Other data structures
 
+// The color format that a set of surface options selects.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum ColorFormat {
+    Rgba8,
+    Srgb8Alpha8,
+    Rgb10A2,
+    Rgba16F,
+}
+
+// The depth buffer format that a set of surface options selects.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum DepthFormat {
+    Depth16,
+    Depth24,
+    Depth32F,
+}
+
+/// Where a layer is attached: see `LayerContext::parent_of`.
 #[derive(PartialEq, Debug)]
 pub enum LayerParent {
+    /// The layer is a child of this container layer.
     Layer(LayerId),
+    /// The layer is the root of a tree hosted in a native surface or window.
     NativeHost,
+    /// The layer is the mask of this layer. See `LayerContext::set_layer_mask`.
+    Mask(LayerId),
 }
 
-struct PromiseData<T> where T: Clone + Send {
+struct PromiseData<T, E> where T: Clone + Send, E: Clone + Send {
     on_fulfilled: Vec<Box<dyn FnMut(T) + Send>>,
-    on_rejected: Vec<Box<dyn FnMut() + Send>>,
-    result: PromiseResult<T>,
+    on_rejected: Vec<Box<dyn FnMut(E) + Send>>,
+    wakers: Vec<Waker>,
+    result: PromiseResult<T, E>,
 }
 
-enum PromiseResult<T> where T: Clone + Send {
+#[derive(Clone)]
+enum PromiseResult<T, E> where T: Clone + Send, E: Clone + Send {
     Pending,
     Resolved(T),
-    Rejected,
+    Rejected(E),
 }
 
 // Public API for the context
@@ -227,8 +538,16 @@ impl<B> LayerContext<B> where B: Backend {
     /// This method allows you to specify a backend explicitly.
     pub fn with_backend_connection(connection: Connection<B::NativeConnection>)
                                    -> Result<LayerContext<B>, ConnectionError> {
-        Ok(LayerContext {
-            backend: Backend::new(connection)?,
+        Ok(LayerContext::with_backend(Backend::new(connection)?))
+    }
+
+    /// Creates a layer context around a backend that has already been connected.
+    ///
+    /// This is useful with backends that can be connected in several ways, such as
+    /// `dynamic::Backend`.
+    pub fn with_backend(backend: B) -> LayerContext<B> {
+        LayerContext {
+            backend,
 
             next_layer_id: LayerId(0),
             transaction: None,
@@ -237,7 +556,12 @@ impl<B> LayerContext<B> where B: Backend {
             container_component: LayerMap::new(),
             geometry_component: LayerMap::new(),
             surface_component: LayerMap::new(),
-        })
+            effects_component: LayerMap::new(),
+            scroll_component: LayerMap::new(),
+
+            flings: vec![],
+            tiled_component: LayerMap::new(),
+        }
     }
 
     // OpenGL context creation
@@ -246,6 +570,8 @@ impl<B> LayerContext<B> where B: Backend {
     ///
     /// The options must match those used to create any surface layers that this OpenGL context
     /// will bind to.
+    ///
+    /// Returns `Err` if the backend cannot provide the requested surface format.
     pub fn create_gl_context(&mut self, options: SurfaceOptions) -> Result<B::GLContext, ()> {
         self.backend.create_gl_context(options)
     }
@@ -261,6 +587,11 @@ impl<B> LayerContext<B> where B: Backend {
         self.backend.gl_api()
     }
 
+    /// Returns the features that the current backend supports.
+    pub fn capabilities(&self) -> Capabilities {
+        self.backend.capabilities()
+    }
+
     // Transactions
 
     /// Opens a new atomic transaction.
@@ -290,6 +621,12 @@ impl<B> LayerContext<B> where B: Backend {
     /// This method is *not* synchronous; it merely flushes the pending operations the server,
     /// ensuring that they will complete in finite time.
     pub fn end_transaction(&mut self) {
+        // Bring tiled layers up to date once the rest of the tree has reached its final state.
+        // Any transactions that the tile painters begin are nested inside this one.
+        if self.transaction.as_ref().map(|transaction| transaction.level) == Some(1) {
+            self.update_tiled_layers();
+        }
+
         {
             let transaction = self.transaction
                                   .as_mut()
@@ -354,12 +691,73 @@ impl<B> LayerContext<B> where B: Backend {
 
         self.surface_component.add(layer, LayerSurfaceInfo {
             options: SurfaceOptions::empty(),
+            imported: false,
+            video: false,
         });
 
         self.backend.add_surface_layer(layer);
         layer
     }
 
+    /// Creates a new scroll layer and returns its ID.
+    ///
+    /// Scroll layers are container layers that clip their children to their bounds and shift
+    /// them by a content offset, so scrolling doesn't change the bounds of any child. The bounds
+    /// of the children are relative to the top left corner of the content, and the content
+    /// offset is the point of the content that appears at the top left corner of the layer.
+    ///
+    /// Initially, the newly-created layer is off-screen, with neither position nor size, and its
+    /// content is empty and unscrolled.
+    pub fn add_scroll_layer(&mut self) -> LayerId {
+        debug_assert!(self.in_transaction());
+
+        let layer = self.next_layer_id;
+        self.next_layer_id.0 += 1;
+
+        self.container_component.add(layer, LayerContainerInfo {
+            first_child: None,
+            last_child: None,
+        });
+        self.scroll_component.add(layer, LayerScrollInfo {
+            content_size: Size2D::zero(),
+            content_offset: Vector2D::zero(),
+        });
+        self.backend.add_scroll_layer(layer);
+        layer
+    }
+
+    /// Creates a new tiled layer and returns its ID.
+    ///
+    /// Tiled layers display content that's too large for a single surface, such as a long
+    /// document. The content is split into tiles of the given size, in pixels. Only the tiles
+    /// that are visible, taking the content offsets and clipping of enclosing scroll layers into
+    /// account, are allocated; tiles that scroll more than a screenful out of view are deleted
+    /// again.
+    ///
+    /// Each tile is a surface layer. When a tile needs to be painted, `painter` is called with
+    /// it at the end of the transaction, while the transaction is still open. The painter should
+    /// set the tile's surface options if necessary, then bind it to an OpenGL context and
+    /// present it, as with any other surface layer.
+    ///
+    /// A tiled layer is a container layer whose children are its tiles. Don't add children to it
+    /// or remove them yourself.
+    ///
+    /// Initially, the newly-created layer is off-screen, with neither position nor size.
+    pub fn add_tiled_layer(&mut self, tile_size: &Size2D<u32>, painter: TilePainter<B>)
+                           -> LayerId {
+        debug_assert!(self.in_transaction());
+        debug_assert!(tile_size.width > 0 && tile_size.height > 0);
+
+        let layer = self.add_container_layer();
+        self.tiled_component.add(layer, TiledLayerInfo {
+            tile_size: *tile_size,
+            painter: Some(painter),
+            tiles: HashMap::new(),
+            size: Size2D::zero(),
+        });
+        layer
+    }
+
     /// Returns the parent of the given layer, if it is on-screen.
     pub fn parent_of(&self, layer: LayerId) -> Option<&LayerParent> {
         self.tree_component.get(layer).map(|info| &info.parent)
@@ -394,15 +792,15 @@ impl<B> LayerContext<B> where B: Backend {
             next_sibling: reference,
         });
 
+        match new_prev_sibling {
+            Some(prev_sibling) => self.tree_component[prev_sibling].next_sibling = Some(new_child),
+            None => self.container_component[parent].first_child = Some(new_child),
+        }
         match reference {
-            Some(reference) => self.tree_component[reference].next_sibling = Some(new_child),
+            Some(reference) => self.tree_component[reference].prev_sibling = Some(new_child),
             None => self.container_component[parent].last_child = Some(new_child),
         }
 
-        if self.tree_component[new_child].prev_sibling.is_none() {
-            self.container_component[parent].first_child = Some(new_child)
-        }
-
         self.backend.insert_before(parent,
                                    new_child,
                                    reference,
@@ -419,6 +817,10 @@ impl<B> LayerContext<B> where B: Backend {
         self.insert_before(parent, new_child, None)
     }
 
+    /// Makes the given layer the root of an on-screen layer tree, displayed in a native host.
+    ///
+    /// The layer must be off-screen. A context may host several independent layer trees at once,
+    /// each in a host of its own.
     #[inline]
     pub unsafe fn host_layer(&mut self, host: B::Host, layer: LayerId) {
         debug_assert!(self.in_transaction());
@@ -436,12 +838,20 @@ impl<B> LayerContext<B> where B: Backend {
                                 &self.geometry_component);
     }
 
+    /// Removes a layer from its parent, unhosts it if it's the root of a layer tree, or detaches
+    /// it from the layer it masks.
     pub fn remove_from_parent(&mut self, old_child: LayerId) {
         debug_assert!(self.in_transaction());
 
+        if let Some(&LayerParent::Mask(masked_layer)) = self.parent_of(old_child) {
+            self.set_layer_mask(masked_layer, None);
+            return
+        }
+
         let old_tree = self.tree_component.take(old_child);
         match old_tree.parent {
             LayerParent::NativeHost => self.backend.unhost_layer(old_child),
+            LayerParent::Mask(_) => unreachable!(),
 
             LayerParent::Layer(parent_layer) => {
                 self.backend.remove_from_superlayer(old_child,
@@ -478,14 +888,102 @@ impl<B> LayerContext<B> where B: Backend {
         // TODO(pcwalton): Use a free list to recycle IDs.
         debug_assert!(self.parent_of(layer).is_none());
 
+        // The mask outlives the layer, as an ordinary off-screen layer.
+        if self.layer_mask(layer).is_some() {
+            self.set_layer_mask(layer, None);
+        }
+
+        if let Some(fling_index) = self.flings.iter().position(|info| info.layer == layer) {
+            self.flings.remove(fling_index).promise.reject(());
+        }
+
+        if self.tiled_component.has(layer) {
+            self.discard_tiles(layer, |_| true);
+            self.tiled_component.remove(layer);
+        }
+
         self.tree_component.remove_if_present(layer);
         self.container_component.remove_if_present(layer);
         self.geometry_component.remove_if_present(layer);
         self.surface_component.remove_if_present(layer);
+        self.effects_component.remove_if_present(layer);
+        self.scroll_component.remove_if_present(layer);
 
         self.backend.delete_layer(layer);
     }
 
+    // Cross-process hosting
+
+    /// Exports the layer tree rooted at the given layer so that another process can display it,
+    /// and returns a token that the other process can pass to `import_layer`.
+    ///
+    /// The layer must be off-screen (i.e. not in the tree). Like a layer hosted in a window, it
+    /// becomes the root of a layer tree; remove it from its parent with `remove_from_parent` to
+    /// stop exporting it.
+    ///
+    /// Returns `Err` if the backend can't export layers. See `Capabilities::layer_export`.
+    pub fn export_layer(&mut self, layer: LayerId) -> Result<ExportToken, ()> {
+        debug_assert!(self.in_transaction());
+        debug_assert!(self.parent_of(layer).is_none());
+
+        self.tree_component.add(layer, LayerTreeInfo {
+            parent: LayerParent::NativeHost,
+            prev_sibling: None,
+            next_sibling: None,
+        });
+
+        let result = self.backend.export_layer(layer,
+                                               &self.tree_component,
+                                               &self.container_component,
+                                               &self.geometry_component);
+        if result.is_err() {
+            self.tree_component.remove(layer);
+        }
+        result
+    }
+
+    /// Creates a new layer that displays a layer tree exported from another process, and returns
+    /// its ID.
+    ///
+    /// The new layer is a leaf, like a surface layer, but it can't be bound to an OpenGL context;
+    /// its contents come from the exporting process. Initially, it's off-screen, with neither
+    /// position nor size.
+    ///
+    /// Returns `Err` if the token is invalid or was created by a different kind of backend.
+    pub fn import_layer(&mut self, token: &ExportToken) -> Result<LayerId, ()> {
+        debug_assert!(self.in_transaction());
+
+        let layer = self.next_layer_id;
+        self.backend.import_layer(layer, token)?;
+        self.next_layer_id.0 += 1;
+
+        self.surface_component.add(layer, LayerSurfaceInfo {
+            options: SurfaceOptions::empty(),
+            imported: true,
+            video: false,
+        });
+        Ok(layer)
+    }
+
+    /// Displays any new frames that exporting processes have rendered into imported layers, in a
+    /// transaction of its own.
+    ///
+    /// Imported layers otherwise only pick up new frames when a transaction ends, so call this
+    /// once per frame from the event loop. Returns true if any imported layer changed.
+    ///
+    /// This must not be called inside a transaction.
+    pub fn update_imported_layers(&mut self) -> bool {
+        debug_assert!(!self.in_transaction());
+
+        if !self.backend.imported_layers_changed() {
+            return false
+        }
+
+        self.begin_transaction();
+        self.end_transaction();
+        true
+    }
+
     // Geometry system
 
     /// Returns the boundaries of the layer relative to its parent.
@@ -534,176 +1032,991 @@ impl<B> LayerContext<B> where B: Backend {
         self.backend.set_layer_surface_options(layer, &self.surface_component);
     }
 
-    // Surface system
+    // Effects
 
-    pub fn bind_layer_to_gl_context(&mut self, layer: LayerId, context: &mut B::GLContext)
-                                    -> Result<GLContextLayerBinding, ()> {
+    /// Returns the visual effects applied to the layer.
+    pub fn layer_effects(&self, layer: LayerId) -> LayerEffects {
         debug_assert!(self.in_transaction());
-        debug_assert!(!self.container_component.has(layer));
 
-        self.backend.bind_layer_to_gl_context(layer,
-                                              context,
-                                              &self.geometry_component,
-                                              &self.surface_component)
+        match self.effects_component.get(layer) {
+            None => LayerEffects::default(),
+            Some(effects_info) => effects_info.effects,
+        }
     }
 
-    pub fn present_gl_context(&mut self, binding: GLContextLayerBinding, changed_rect: &Rect<f32>)
-                              -> Result<(), ()> {
+    /// Applies visual effects to the layer and its descendants.
+    ///
+    /// Effects may draw outside the bounds of the layer, so there is no need to pad surfaces to
+    /// make room for shadows. Backends that don't support effects ignore them; see
+    /// `Capabilities::layer_effects`.
+    pub fn set_layer_effects(&mut self, layer: LayerId, new_effects: &LayerEffects) {
         debug_assert!(self.in_transaction());
 
-        self.backend.present_gl_context(binding,
-                                        changed_rect,
-                                        &self.tree_component,
-                                        &self.geometry_component)
+        let old_effects = mem::replace(&mut self.effects_component.get_mut_default(layer).effects,
+                                       *new_effects);
+
+        self.backend.set_layer_effects(layer,
+                                       &old_effects,
+                                       &self.tree_component,
+                                       &self.geometry_component,
+                                       &self.effects_component);
     }
 
-    // Screenshots
+    /// Returns the layer that masks the given one, if any.
+    pub fn layer_mask(&self, layer: LayerId) -> Option<LayerId> {
+        self.effects_component.get(layer).and_then(|effects_info| effects_info.mask)
+    }
 
-    pub fn screenshot_hosted_layer(&mut self, layer: LayerId) -> Promise<RgbaImage> {
+    /// Masks the layer and its descendants with the alpha channel of another layer tree, like
+    /// `CALayer.mask`, or removes the mask if `mask` is `None`.
+    ///
+    /// The mask must be off-screen (i.e. not in the tree), and it leaves the tree of the layer it
+    /// masks: `parent_of` reports it as a `LayerParent::Mask`. Its bounds are relative to the
+    /// masked layer, which is transparent wherever the mask is. A layer that's no longer a mask
+    /// is off-screen again. Backends that don't support masks ignore them; see
+    /// `Capabilities::layer_masks`.
+    pub fn set_layer_mask(&mut self, layer: LayerId, mask: Option<LayerId>) {
         debug_assert!(self.in_transaction());
-        assert_eq!(self.tree_component[layer].parent, LayerParent::NativeHost);
 
-        let transaction_promise = self.transaction.as_ref().unwrap().promise.clone();
-        self.backend.screenshot_hosted_layer(layer,
-                                             &transaction_promise,
-                                             &self.tree_component,
-                                             &self.container_component,
-                                             &self.geometry_component,
-                                             &self.surface_component)
-    }
+        let old_mask = self.layer_mask(layer);
+        if old_mask == mask {
+            return
+        }
 
-    // `winit` integration
+        if let Some(old_mask) = old_mask {
+            self.tree_component.remove(old_mask);
+        }
+        if let Some(mask) = mask {
+            debug_assert!(mask != layer);
+            debug_assert!(self.parent_of(mask).is_none());
+            self.tree_component.add(mask, LayerTreeInfo {
+                parent: LayerParent::Mask(layer),
+                prev_sibling: None,
+                next_sibling: None,
+            });
+        }
+        self.effects_component.get_mut_default(layer).mask = mask;
 
-    #[cfg(feature = "enable-winit")]
-    pub fn window(&self) -> Option<&Window> {
-        self.backend.window()
+        self.backend.set_layer_mask(layer,
+                                    &self.tree_component,
+                                    &self.geometry_component,
+                                    &self.effects_component);
     }
 
-    #[cfg(feature = "enable-winit")]
-    pub fn host_layer_in_window(&mut self, layer: LayerId) -> Result<(), ()> {
-        debug_assert!(self.in_transaction());
+    // Scrolling
 
-        self.tree_component.add(layer, LayerTreeInfo {
-            parent: LayerParent::NativeHost,
-            prev_sibling: None,
-            next_sibling: None,
-        });
+    /// Returns the size of the content of a scroll layer.
+    pub fn layer_content_size(&self, layer: LayerId) -> Size2D<f32> {
+        debug_assert!(self.in_transaction());
 
-        self.backend.host_layer_in_window(layer,
-                                          &self.tree_component,
-                                          &self.container_component,
-                                          &self.geometry_component)
+        self.scroll_component[layer].content_size
     }
-}
 
-impl LayerContext<backends::default::Backend> {
-    #[inline]
-    pub fn new(connection: Connection<<backends::default::Backend as Backend>::NativeConnection>)
-               -> Result<LayerContext<backends::default::Backend>, ConnectionError> {
-        LayerContext::with_backend_connection(connection)
-    }
-}
+    /// Sets the size of the content of a scroll layer.
+    ///
+    /// The content offset normally ranges from zero to the content size less the size of the
+    /// layer's bounds. Neither the backend nor the context clamps it to that range, though, so
+    /// callers may scroll past the edges of the content to draw overscroll effects.
+    ///
+    /// The `layer` parameter must refer to a scroll layer.
+    pub fn set_layer_content_size(&mut self, layer: LayerId, new_size: &Size2D<f32>) {
+        debug_assert!(self.in_transaction());
 
-// Errors
+        self.scroll_component[layer].content_size = *new_size;
+        self.backend.set_layer_content_size(layer, &self.scroll_component);
+    }
 
-pub struct ConnectionError {
-    #[cfg(feature = "enable-winit")]
-    window_builder: Option<WindowBuilder>,
-}
+    /// Returns the point of the content of a scroll layer that appears at its top left corner.
+    pub fn layer_content_offset(&self, layer: LayerId) -> Vector2D<f32> {
+        debug_assert!(self.in_transaction());
 
-impl Debug for ConnectionError {
-    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
-        "ConnectionError".fmt(formatter)
+        self.scroll_component[layer].content_offset
     }
-}
 
-impl ConnectionError {
-    #[inline]
-    pub fn new() -> ConnectionError {
-        ConnectionError {
-            #[cfg(feature = "enable-winit")]
-            window_builder: None,
-        }
+    /// Scrolls a scroll layer so that the given point of its content appears at its top left
+    /// corner.
+    ///
+    /// This moves the children of the layer without changing their bounds, which backends can
+    /// do without redrawing them. It also stops any fling in progress on the layer.
+    ///
+    /// The `layer` parameter must refer to a scroll layer.
+    pub fn set_layer_content_offset(&mut self, layer: LayerId, new_offset: &Vector2D<f32>) {
+        debug_assert!(self.in_transaction());
+
+        self.stop_fling(layer);
+        self.update_layer_content_offset(layer, new_offset);
     }
-}
 
-// Promise infrastructure
+    /// Sets a scroll layer in motion at the given velocity, in pixels per second, and lets it
+    /// coast to a stop.
+    ///
+    /// The layer decelerates, and if it scrolls past the edges of its content, a spring pulls it
+    /// back in range. The app doesn't need to redraw anything while the layer moves. Backends
+    /// with `native_animations` animate the content offset themselves, on their own frame clock,
+    /// so the layer keeps moving while the app waits on the promise with `wait` or is busy.
+    /// Otherwise, the context animates it, and the app must call `update_flings` every frame
+    /// while flings are in progress; that works with every backend. A velocity of zero just
+    /// springs the content back in range, which is useful when the user lets go of overscrolled
+    /// content.
+    ///
+    /// Returns a promise that resolves to the final content offset when the layer comes to rest,
+    /// or to the offset it was at if the fling is stopped by `set_layer_content_offset` or by
+    /// another fling first. The promise is rejected if the layer is deleted.
+    ///
+    /// The `layer` parameter must refer to a scroll layer.
+    pub fn start_fling(&mut self, layer: LayerId, velocity: &Vector2D<f32>)
+                       -> Promise<Vector2D<f32>> {
+        debug_assert!(self.in_transaction());
 
-impl<T> Promise<T> where T: 'static + Clone + Send {
-    fn new() -> Promise<T> {
-        Promise(Arc::new(Mutex::new(PromiseData {
-            on_fulfilled: vec![],
-            on_rejected: vec![],
-            result: PromiseResult::Pending,
-        })))
+        self.stop_fling(layer);
+
+        let promise = Promise::new();
+        let backend_promise = Promise::new();
+        let backend_promise = match self.backend.start_fling(layer,
+                                                             velocity,
+                                                             &backend_promise,
+                                                             &self.geometry_component,
+                                                             &self.scroll_component) {
+            Ok(()) => {
+                backend_promise.forward_to(&promise);
+                Some(backend_promise)
+            }
+            Err(()) => None,
+        };
+
+        let bounds_size = match self.geometry_component.get(layer) {
+            None => Size2D::zero(),
+            Some(geometry_info) => geometry_info.bounds.size,
+        };
+        let scroll_info = &self.scroll_component[layer];
+        let max_offset = fling::max_offset(&bounds_size, &scroll_info.content_size);
+        self.flings.push(FlingInfo {
+            layer,
+            fling: Fling::new(&scroll_info.content_offset, velocity, &max_offset),
+            last_update: Instant::now(),
+            promise: promise.clone(),
+            backend_promise,
+        });
+        promise
     }
 
-    fn all(promises: Vec<Promise<T>>) -> Promise<Vec<T>> {
-        let result_promise = Promise::new();
-        let all = Arc::new(Mutex::new(All {
-            result_promise: result_promise.clone(),
-            promises: promises,
-            results: vec![],
-        }));
-        wait(all);
-        return result_promise;
+    /// Advances the flings started with `start_fling` to the current time, in a transaction of
+    /// their own.
+    ///
+    /// Call this once per frame from the event loop. Returns true if any flings are still in
+    /// progress afterward.
+    ///
+    /// This must not be called inside a transaction.
+    pub fn update_flings(&mut self) -> bool {
+        debug_assert!(!self.in_transaction());
+
+        if self.flings.is_empty() {
+            return false
+        }
 
-        fn wait<T>(all: Arc<Mutex<All<T>>>) where T: 'static + Clone + Send {
-            let next_promise;
+        // Flings that the backend animates are simulated here as well, so that
+        // `layer_content_offset` stays close to what's on screen, but only the backend moves the
+        // layer. Ending the transaction gives it the chance to.
+        let now = Instant::now();
+        self.begin_transaction();
+        for fling_index in 0..self.flings.len() {
+            let (layer, new_offset, animated_by_backend);
             {
-                let mut all = all.lock().unwrap();
-                if all.results.len() == all.promises.len() {
-                    let results = mem::replace(&mut all.results, vec![]);
-                    all.result_promise.resolve(results);
-                    return;
-                }
-                next_promise = all.promises[all.results.len()].clone();
+                let fling_info = &mut self.flings[fling_index];
+                fling_info.fling.advance(now - fling_info.last_update);
+                fling_info.last_update = now;
+                layer = fling_info.layer;
+                new_offset = fling_info.fling.offset();
+                animated_by_backend = fling_info.backend_promise.is_some();
+            }
+            if animated_by_backend {
+                self.scroll_component[layer].content_offset = new_offset;
+            } else {
+                self.update_layer_content_offset(layer, &new_offset);
             }
-
-            next_promise.then(Box::new(move |result| {
-                all.lock().unwrap().results.push(result);
-                wait(all.clone());
-            }));
         }
+        self.end_transaction();
 
-        struct All<T> where T: 'static + Clone + Send {
-            result_promise: Promise<Vec<T>>,
-            promises: Vec<Promise<T>>,
-            results: Vec<T>,
-        }
+        self.settle_flings();
+        !self.flings.is_empty()
     }
 
-    pub fn then(&self, mut on_fulfilled: Box<FnMut(T) + Send>) {
-        let mut this = self.0.lock().unwrap();
-        match this.result {
-            PromiseResult::Rejected => {}
-            PromiseResult::Resolved(ref result) => on_fulfilled((*result).clone()),
-            PromiseResult::Pending => this.on_fulfilled.push(on_fulfilled),
+    // Settles the promises of finished flings and forgets them.
+    //
+    // The flings we animate are finished only once their final offsets have been submitted. The
+    // backend settles the promises of its own flings, which are finished once it has.
+    fn settle_flings(&mut self) {
+        let mut fling_index = 0;
+        while fling_index < self.flings.len() {
+            let result = match self.flings[fling_index].backend_promise {
+                None if self.flings[fling_index].fling.is_finished() => {
+                    Some(Ok(self.flings[fling_index].fling.offset()))
+                }
+                None => None,
+                Some(ref backend_promise) => backend_promise.result(),
+            };
+            match result {
+                None => fling_index += 1,
+                Some(result) => {
+                    let fling_info = self.flings.remove(fling_index);
+                    if let Ok(final_offset) = result {
+                        self.scroll_component[fling_info.layer].content_offset = final_offset;
+                        fling_info.promise.resolve(final_offset);
+                    }
+                }
+            }
         }
     }
 
-    pub fn or_else(&self, mut on_rejected: Box<FnMut() + Send>) {
-        let mut this = self.0.lock().unwrap();
-        match this.result {
-            PromiseResult::Rejected => on_rejected(),
-            PromiseResult::Resolved(_) => {}
-            PromiseResult::Pending => this.on_rejected.push(on_rejected),
+    fn stop_fling(&mut self, layer: LayerId) {
+        if let Some(fling_index) = self.flings.iter().position(|info| info.layer == layer) {
+            let fling_info = self.flings.remove(fling_index);
+            fling_info.promise.resolve(self.scroll_component[layer].content_offset);
         }
     }
 
-    fn resolve(&self, result: T) {
-        let mut this = self.0.lock().unwrap();
-        this.result = PromiseResult::Resolved(result.clone());
-        for mut on_fulfilled in this.on_fulfilled.drain(..) {
+    fn update_layer_content_offset(&mut self, layer: LayerId, new_offset: &Vector2D<f32>) {
+        self.scroll_component[layer].content_offset = *new_offset;
+        self.backend.set_layer_content_offset(layer,
+                                              &self.tree_component,
+                                              &self.container_component,
+                                              &self.geometry_component,
+                                              &self.scroll_component);
+    }
+
+    // Tiling
+
+    /// Returns the size of the tiles of a tiled layer.
+    pub fn layer_tile_size(&self, layer: LayerId) -> Size2D<u32> {
+        debug_assert!(self.in_transaction());
+
+        self.tiled_component[layer].tile_size
+    }
+
+    /// Marks the content of a tiled layer in the given rect, relative to the layer, as changed.
+    ///
+    /// At the end of the transaction, the affected tiles that are visible are painted again,
+    /// and the rest are deleted, to be painted when they come into view.
+    ///
+    /// The `layer` parameter must refer to a tiled layer.
+    pub fn invalidate_tiles(&mut self, layer: LayerId, rect: &Rect<f32>) {
+        debug_assert!(self.in_transaction());
+
+        for tile_info in self.tiled_component[layer].tiles.values_mut() {
+            if tile_info.rect.intersects(rect) {
+                tile_info.dirty = true;
+            }
+        }
+    }
+
+    // Creates and paints the tiles of every tiled layer that have come into view, and deletes
+    // the ones that have gone far out of view.
+    fn update_tiled_layers(&mut self) {
+        for layer in self.tiled_component.layers() {
+            self.update_tiles(layer);
+        }
+    }
+
+    fn update_tiles(&mut self, layer: LayerId) {
+        // Resizing the layer resizes the tiles along its edges, so start over.
+        let size = match self.geometry_component.get(layer) {
+            None => Size2D::zero(),
+            Some(geometry_info) => geometry_info.bounds.size,
+        };
+        if size != self.tiled_component[layer].size {
+            self.discard_tiles(layer, |_| true);
+            self.tiled_component[layer].size = size;
+        }
+
+        let visible_rect = match self.visible_rect(layer) {
+            None => {
+                self.discard_tiles(layer, |_| true);
+                return
+            }
+            Some(visible_rect) => visible_rect,
+        };
+
+        // Keep tiles within a screenful of the visible area, so that scrolling back and forth a
+        // little doesn't paint the same tiles over and over.
+        let keep_rect = visible_rect.inflate(visible_rect.size.width, visible_rect.size.height);
+        self.discard_tiles(layer, |tile_info| {
+            !tile_info.rect.intersects(&keep_rect) ||
+                (tile_info.dirty && !tile_info.rect.intersects(&visible_rect))
+        });
+
+        // Create the visible tiles that are missing.
+        let tile_size = self.tiled_component[layer].tile_size.to_f32();
+        let layer_rect = Rect::new(Point2D::zero(), size);
+        let first_column = (visible_rect.origin.x / tile_size.width).floor() as u32;
+        let first_row = (visible_rect.origin.y / tile_size.height).floor() as u32;
+        let last_column = (visible_rect.max_x() / tile_size.width).ceil() as u32;
+        let last_row = (visible_rect.max_y() / tile_size.height).ceil() as u32;
+        for row in first_row..last_row {
+            for column in first_column..last_column {
+                if self.tiled_component[layer].tiles.contains_key(&(column, row)) {
+                    continue
+                }
+
+                let origin = Point2D::new(column as f32 * tile_size.width,
+                                          row as f32 * tile_size.height);
+                let rect = match Rect::new(origin, tile_size).intersection(&layer_rect) {
+                    None => continue,
+                    Some(rect) => rect,
+                };
+
+                let tile = self.add_surface_layer();
+                self.set_layer_bounds(tile, &rect);
+                self.append_child(layer, tile);
+                self.tiled_component[layer].tiles.insert((column, row), TileInfo {
+                    layer: tile,
+                    rect,
+                    dirty: true,
+                });
+            }
+        }
+
+        // Paint the visible tiles that need it. The painter is taken out of the layer while it
+        // runs so that it can borrow the context.
+        let mut tiles = vec![];
+        for tile_info in self.tiled_component[layer].tiles.values_mut() {
+            if tile_info.dirty && tile_info.rect.intersects(&visible_rect) {
+                tile_info.dirty = false;
+                tiles.push(Tile {
+                    tiled_layer: layer,
+                    layer: tile_info.layer,
+                    rect: tile_info.rect,
+                });
+            }
+        }
+        if tiles.is_empty() {
+            return
+        }
+        let mut painter = self.tiled_component[layer].painter.take().unwrap();
+        for tile in &tiles {
+            painter(self, tile);
+        }
+        self.tiled_component[layer].painter = Some(painter);
+    }
+
+    // Deletes the tiles of a tiled layer for which the given function returns true.
+    fn discard_tiles<F>(&mut self, layer: LayerId, mut discard: F)
+                        where F: FnMut(&TileInfo) -> bool {
+        let mut doomed_tiles = vec![];
+        self.tiled_component[layer].tiles.retain(|_, tile_info| {
+            if discard(tile_info) {
+                doomed_tiles.push(tile_info.layer);
+                false
+            } else {
+                true
+            }
+        });
+
+        for tile in doomed_tiles {
+            self.remove_from_parent(tile);
+            self.delete_layer(tile);
+        }
+    }
+
+    // Returns the part of a layer, relative to the layer itself, that isn't clipped away by the
+    // scroll layers that enclose it or by the bounds of the root of its tree, or `None` if the
+    // layer isn't displayed.
+    fn visible_rect(&self, layer: LayerId) -> Option<Rect<f32>> {
+        let size = self.geometry_component.get(layer)?.bounds.size;
+        let mut visible_rect = Rect::new(Point2D::zero(), size);
+
+        // The offset from the layer's coordinate space to that of `current_layer`.
+        let mut offset = Vector2D::zero();
+        let mut current_layer = layer;
+        loop {
+            let bounds = self.geometry_component.get(current_layer)?.bounds;
+            match self.tree_component.get(current_layer)?.parent {
+                LayerParent::NativeHost => {
+                    return visible_rect.intersection(&Rect::new(Point2D::zero() - offset,
+                                                                bounds.size))
+                }
+                LayerParent::Mask(_) => return None,
+                LayerParent::Layer(parent) => {
+                    offset += bounds.origin.to_vector();
+                    if let Some(scroll_info) = self.scroll_component.get(parent) {
+                        offset -= scroll_info.content_offset;
+                        let parent_size = self.geometry_component.get(parent)?.bounds.size;
+                        let parent_rect = Rect::new(Point2D::zero() - offset, parent_size);
+                        visible_rect = visible_rect.intersection(&parent_rect)?;
+                    }
+                    current_layer = parent;
+                }
+            }
+        }
+    }
+
+    // Surface system
+
+    /// Binds a surface layer to an OpenGL context so that it can be rendered to.
+    ///
+    /// Returns `Err` if the layer's surface could not be allocated, for example because the
+    /// backend does not support its surface format, or if the layer is a video layer or was
+    /// imported from another process.
+    pub fn bind_layer_to_gl_context(&mut self, layer: LayerId, context: &mut B::GLContext)
+                                    -> Result<GLContextLayerBinding, ()> {
+        debug_assert!(self.in_transaction());
+        debug_assert!(!self.container_component.has(layer));
+
+        let surface_info = &self.surface_component[layer];
+        if surface_info.imported || surface_info.video {
+            return Err(())
+        }
+
+        self.backend.bind_layer_to_gl_context(layer,
+                                              context,
+                                              &self.geometry_component,
+                                              &self.surface_component)
+    }
+
+    pub fn present_gl_context(&mut self, binding: GLContextLayerBinding, changed_rect: &Rect<f32>)
+                              -> Result<(), ()> {
+        debug_assert!(self.in_transaction());
+
+        self.backend.present_gl_context(binding,
+                                        changed_rect,
+                                        &self.tree_component,
+                                        &self.geometry_component)
+    }
+
+    // Video system
+
+    /// Creates a new video layer and returns its ID.
+    ///
+    /// Video layers display frames of YUV video, passed to `present_video_frame`, stretched to
+    /// fill their bounds. Like surface layers, they must be leaves of the layer tree, but they
+    /// can't be bound to OpenGL contexts. Depending on the backend, frames are converted to RGB
+    /// by the compositor or displayed on a hardware overlay.
+    ///
+    /// Initially, the newly-created layer is off-screen, with neither position nor size.
+    pub fn add_video_layer(&mut self) -> LayerId {
+        debug_assert!(self.in_transaction());
+
+        let layer = self.next_layer_id;
+        self.next_layer_id.0 += 1;
+
+        // Video is always opaque.
+        self.surface_component.add(layer, LayerSurfaceInfo {
+            options: SurfaceOptions::OPAQUE,
+            imported: false,
+            video: true,
+        });
+
+        self.backend.add_video_layer(layer);
+        layer
+    }
+
+    /// Displays a new frame in a video layer.
+    ///
+    /// Planes in memory are copied during this call, so they may be reused as soon as it returns.
+    /// Planes that are OpenGL textures must stay alive, unmodified, until the next frame is
+    /// presented or the layer is deleted.
+    ///
+    /// Returns `Err` if the layer isn't a video layer, if the frame is malformed (for example, if
+    /// it has the wrong number of planes, or a plane is too small for the frame size), or if the
+    /// backend can't display it. See `Capabilities::video_layers`.
+    pub fn present_video_frame(&mut self, layer: LayerId, frame: &VideoFrame) -> Result<(), ()> {
+        debug_assert!(self.in_transaction());
+
+        match self.surface_component.get(layer) {
+            Some(surface_info) if surface_info.video => {}
+            _ => return Err(()),
+        }
+        video::validate(frame)?;
+        self.backend.present_video_frame(layer,
+                                         frame,
+                                         &self.tree_component,
+                                         &self.geometry_component)
+    }
+
+    // Screenshots
+
+    pub fn screenshot_hosted_layer(&mut self, layer: LayerId)
+                                   -> Promise<RgbaImage, ScreenshotError> {
+        debug_assert!(self.in_transaction());
+        assert_eq!(self.tree_component[layer].parent, LayerParent::NativeHost);
+
+        let transaction_promise = self.transaction.as_ref().unwrap().promise.clone();
+        self.backend.screenshot_hosted_layer(layer,
+                                             &transaction_promise,
+                                             &self.tree_component,
+                                             &self.container_component,
+                                             &self.geometry_component,
+                                             &self.surface_component)
+    }
+
+    // Event dispatch
+
+    /// Blocks until the given promise settles, pumping the backend's event loop as necessary.
+    ///
+    /// While waiting, backends with `native_animations` keep the animations that they run, such
+    /// as flings, moving on their own frame clock.
+    ///
+    /// Returns the value that the promise resolved to, or the error that it was rejected with.
+    /// If nothing that the backend is waiting for can settle the promise, returns
+    /// `WaitError::Stalled` instead of blocking forever. This happens, for example, with backends
+    /// that settle all of their promises in `end_transaction`.
+    ///
+    /// This must not be called inside a transaction, because the transaction's promise cannot
+    /// resolve until `end_transaction` is called.
+    pub fn wait<T, E>(&mut self, promise: &Promise<T, E>) -> Result<T, WaitError<E>>
+                      where T: 'static + Clone + Send, E: 'static + Clone + Send {
+        debug_assert!(!self.in_transaction());
+
+        loop {
+            match promise.result() {
+                Some(Ok(value)) => return Ok(value),
+                Some(Err(error)) => return Err(WaitError::Rejected(error)),
+                None => {}
+            }
+            let pumped = self.backend.pump_events().is_ok() ||
+                self.backend.run_animation_frame(&self.tree_component,
+                                                 &self.container_component,
+                                                 &self.geometry_component,
+                                                 &self.surface_component).is_ok();
+            self.settle_flings();
+            if !pumped && promise.result().is_none() {
+                return Err(WaitError::Stalled)
+            }
+        }
+    }
+
+    // `winit` integration
+
+    #[cfg(feature = "enable-winit")]
+    pub fn window(&self) -> Option<&Window> {
+        self.backend.window()
+    }
+
+    #[cfg(feature = "enable-winit")]
+    pub fn host_layer_in_window(&mut self, layer: LayerId) -> Result<(), ()> {
+        debug_assert!(self.in_transaction());
+
+        self.tree_component.add(layer, LayerTreeInfo {
+            parent: LayerParent::NativeHost,
+            prev_sibling: None,
+            next_sibling: None,
+        });
+
+        let result = self.backend.host_layer_in_window(layer,
+                                                       &self.tree_component,
+                                                       &self.container_component,
+                                                       &self.geometry_component);
+        if result.is_err() {
+            self.tree_component.remove(layer);
+        }
+        result
+    }
+
+    /// Creates an additional window that layer trees can be hosted in, and returns its ID.
+    ///
+    /// The window is owned by this context and lives as long as it does.
+    #[cfg(feature = "enable-winit")]
+    pub fn create_window(&mut self, window_builder: WindowBuilder, event_loop: &EventsLoop)
+                         -> Result<WindowId, ()> {
+        self.backend.create_window(window_builder, event_loop)
+    }
+
+    /// Takes ownership of an existing window so that layer trees can be hosted in it, and returns
+    /// its ID.
+    ///
+    /// If the backend can't host layers in the window, the window is returned unchanged. The
+    /// OpenGL backend never can, because it has no way to attach a context to a window that it
+    /// didn't create; use `create_window` with it instead.
+    #[cfg(feature = "enable-winit")]
+    pub fn adopt_window(&mut self, window: Window) -> Result<WindowId, Window> {
+        self.backend.adopt_window(window)
+    }
+
+    /// Returns the window with the given ID, if it is owned by this context.
+    ///
+    /// This includes both the window that the context was created with and any windows created or
+    /// adopted since.
+    #[cfg(feature = "enable-winit")]
+    pub fn window_with_id(&self, window_id: WindowId) -> Option<&Window> {
+        self.backend.window_with_id(window_id)
+    }
+
+    /// Makes the given layer the root of the layer tree displayed in the window with the given ID.
+    ///
+    /// To move a layer tree from one window to another, remove its root from its parent with
+    /// `remove_from_parent` and host it again in the new window. The layers themselves are
+    /// preserved.
+    ///
+    /// Returns `Err` if the backend can't host the layer there, in which case the layer is left
+    /// unattached.
+    #[cfg(feature = "enable-winit")]
+    pub fn host_layer_in_window_with_id(&mut self, layer: LayerId, window_id: WindowId)
+                                        -> Result<(), ()> {
+        debug_assert!(self.in_transaction());
+
+        self.tree_component.add(layer, LayerTreeInfo {
+            parent: LayerParent::NativeHost,
+            prev_sibling: None,
+            next_sibling: None,
+        });
+
+        let result = self.backend.host_layer_in_window_with_id(layer,
+                                                               window_id,
+                                                               &self.tree_component,
+                                                               &self.container_component,
+                                                               &self.geometry_component);
+        if result.is_err() {
+            self.tree_component.remove(layer);
+        }
+        result
+    }
+}
+
+impl LayerContext<backends::default::Backend> {
+    #[inline]
+    pub fn new(connection: Connection<<backends::default::Backend as Backend>::NativeConnection>)
+               -> Result<LayerContext<backends::default::Backend>, ConnectionError> {
+        LayerContext::with_backend_connection(connection)
+    }
+}
+
+// Errors
+
+pub struct ConnectionError {
+    reason: Option<&'static str>,
+    #[cfg(feature = "enable-winit")]
+    window_builder: Option<WindowBuilder>,
+}
+
+impl Debug for ConnectionError {
+    fn fmt(&self, formatter: &mut Formatter) -> Result<(), fmt::Error> {
+        match self.reason {
+            None => "ConnectionError".fmt(formatter),
+            Some(reason) => write!(formatter, "ConnectionError({})", reason),
+        }
+    }
+}
+
+impl ConnectionError {
+    #[inline]
+    pub fn new() -> ConnectionError {
+        ConnectionError {
+            reason: None,
+            #[cfg(feature = "enable-winit")]
+            window_builder: None,
+        }
+    }
+
+    /// Creates an error with a human-readable explanation of what went wrong.
+    #[inline]
+    pub fn with_reason(reason: &'static str) -> ConnectionError {
+        ConnectionError {
+            reason: Some(reason),
+            ..ConnectionError::new()
+        }
+    }
+
+    /// Returns a human-readable explanation of what went wrong, if the backend supplied one.
+    #[inline]
+    pub fn reason(&self) -> Option<&'static str> {
+        self.reason
+    }
+}
+
+impl LayerEffects {
+    /// Returns true if these effects don't change how the layer looks.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.shadow.is_none() && self.blur_radius <= 0.0 && self.backdrop_blur_radius <= 0.0 &&
+            self.blend_mode == BlendMode::Normal
+    }
+}
+
+impl Default for BlendMode {
+    #[inline]
+    fn default() -> BlendMode {
+        BlendMode::Normal
+    }
+}
+
+impl ExportToken {
+    /// Rebuilds a token from the bytes that `as_bytes` returned in the exporting process.
+    #[inline]
+    pub fn from_bytes(bytes: Vec<u8>) -> ExportToken {
+        ExportToken(bytes)
+    }
+
+    /// Returns the bytes to send to the importing process.
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// The reason that a screenshot could not be taken.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ScreenshotError {
+    /// The backend has no way to capture the contents of hosted layers.
+    Unsupported,
+    /// The transaction that the screenshot was waiting on failed to complete.
+    TransactionFailed,
+    /// The rendered pixels could not be read back.
+    ReadbackFailed,
+}
+
+/// The reason that `LayerContext::wait` returned without a value.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum WaitError<E> {
+    /// The promise was rejected with this error.
+    Rejected(E),
+    /// Nothing that the backend is waiting for can settle the promise, so it would never settle.
+    Stalled,
+}
+
+// Promise infrastructure
+
+impl<T, E> Promise<T, E> where T: 'static + Clone + Send, E: 'static + Clone + Send {
+    /// Creates a promise that hasn't settled yet.
+    ///
+    /// Backends return promises like this from their methods and settle them later with
+    /// `resolve` or `reject`.
+    pub fn new() -> Promise<T, E> {
+        Promise(Arc::new(Mutex::new(PromiseData {
+            on_fulfilled: vec![],
+            on_rejected: vec![],
+            wakers: vec![],
+            result: PromiseResult::Pending,
+        })))
+    }
+
+    /// Returns a promise that resolves once all of the given promises have resolved.
+    ///
+    /// The results are supplied in the same order as the promises. If any of the promises is
+    /// rejected, the returned promise is rejected with the same error.
+    pub fn all(promises: Vec<Promise<T, E>>) -> Promise<Vec<T>, E> {
+        let result_promise = Promise::new();
+        if promises.is_empty() {
+            result_promise.resolve(vec![]);
+            return result_promise
+        }
+
+        // Watch every promise at once, so that a rejection is noticed even while earlier promises
+        // are still pending.
+        let results = Arc::new(Mutex::new(All {
+            results: promises.iter().map(|_| None).collect(),
+            pending_count: promises.len(),
+        }));
+        for (index, promise) in promises.iter().enumerate() {
+            let (fulfilled_promise, rejected_promise) = (result_promise.clone(),
+                                                         result_promise.clone());
+            let results = results.clone();
+            promise.then(Box::new(move |result| {
+                let results = {
+                    let mut all = results.lock().unwrap();
+                    all.results[index] = Some(result);
+                    all.pending_count -= 1;
+                    if all.pending_count > 0 {
+                        return
+                    }
+                    mem::replace(&mut all.results, vec![])
+                };
+                fulfilled_promise.resolve(results.into_iter().map(Option::unwrap).collect())
+            }));
+            promise.or_else(Box::new(move |error| rejected_promise.reject(error)));
+        }
+        return result_promise;
+
+        struct All<T> {
+            results: Vec<Option<T>>,
+            pending_count: usize,
+        }
+    }
+
+    /// Returns a promise that settles as soon as the first of the given promises settles, with
+    /// the same result.
+    pub fn race(promises: Vec<Promise<T, E>>) -> Promise<T, E> {
+        let result_promise = Promise::new();
+        for promise in &promises {
+            promise.forward_to(&result_promise);
+        }
+        result_promise
+    }
+
+    /// Attaches a handler that is called with the value of this promise once it resolves.
+    ///
+    /// If the promise has already resolved, the handler is called immediately.
+    pub fn then(&self, mut on_fulfilled: Box<FnMut(T) + Send>) {
+        let result = {
+            let mut this = self.0.lock().unwrap();
+            match this.result {
+                PromiseResult::Pending => {
+                    this.on_fulfilled.push(on_fulfilled);
+                    return
+                }
+                ref result => (*result).clone(),
+            }
+        };
+
+        if let PromiseResult::Resolved(result) = result {
+            on_fulfilled(result)
+        }
+    }
+
+    /// Attaches a handler that is called with the error of this promise once it is rejected.
+    ///
+    /// If the promise has already been rejected, the handler is called immediately.
+    pub fn or_else(&self, mut on_rejected: Box<FnMut(E) + Send>) {
+        let result = {
+            let mut this = self.0.lock().unwrap();
+            match this.result {
+                PromiseResult::Pending => {
+                    this.on_rejected.push(on_rejected);
+                    return
+                }
+                ref result => (*result).clone(),
+            }
+        };
+
+        if let PromiseResult::Rejected(error) = result {
+            on_rejected(error)
+        }
+    }
+
+    /// Returns a promise that resolves to the result of applying `f` to the value of this
+    /// promise.
+    ///
+    /// If this promise is rejected, the returned promise is rejected with the same error.
+    pub fn map<U, F>(&self, f: F) -> Promise<U, E> where U: 'static + Clone + Send,
+                                                         F: 'static + FnOnce(T) -> U + Send {
+        let result_promise = Promise::new();
+        let (fulfilled_promise, rejected_promise) = (result_promise.clone(),
+                                                     result_promise.clone());
+        let mut f = Some(f);
+        self.then(Box::new(move |result| {
+            if let Some(f) = f.take() {
+                fulfilled_promise.resolve(f(result))
+            }
+        }));
+        self.or_else(Box::new(move |error| rejected_promise.reject(error)));
+        result_promise
+    }
+
+    /// Returns a promise that settles with the promise that `f` returns when applied to the value
+    /// of this promise.
+    ///
+    /// If this promise is rejected, `f` is not called, and the returned promise is rejected with
+    /// the same error.
+    pub fn and_then<U, F>(&self, f: F) -> Promise<U, E>
+                          where U: 'static + Clone + Send,
+                                F: 'static + FnOnce(T) -> Promise<U, E> + Send {
+        let result_promise = Promise::new();
+        let (fulfilled_promise, rejected_promise) = (result_promise.clone(),
+                                                     result_promise.clone());
+        let mut f = Some(f);
+        self.then(Box::new(move |result| {
+            if let Some(f) = f.take() {
+                f(result).forward_to(&fulfilled_promise)
+            }
+        }));
+        self.or_else(Box::new(move |error| rejected_promise.reject(error)));
+        result_promise
+    }
+
+    /// Resolves this promise with a value, calling the handlers attached with `then`.
+    ///
+    /// This does nothing if the promise has already settled.
+    pub fn resolve(&self, result: T) {
+        let (on_fulfilled, wakers) = {
+            let mut this = self.0.lock().unwrap();
+            if !this.result.is_pending() {
+                return
+            }
+
+            this.result = PromiseResult::Resolved(result.clone());
+            this.on_rejected.clear();
+            (mem::replace(&mut this.on_fulfilled, vec![]), mem::replace(&mut this.wakers, vec![]))
+        };
+
+        // Run the callbacks with the lock released, so that they can touch this promise.
+        for mut on_fulfilled in on_fulfilled {
             on_fulfilled(result.clone())
         }
+        for waker in wakers {
+            waker.wake()
+        }
+    }
+
+    /// Rejects this promise with an error, calling the handlers attached with `or_else`.
+    ///
+    /// This does nothing if the promise has already settled.
+    pub fn reject(&self, error: E) {
+        let (on_rejected, wakers) = {
+            let mut this = self.0.lock().unwrap();
+            if !this.result.is_pending() {
+                return
+            }
+
+            this.result = PromiseResult::Rejected(error.clone());
+            this.on_fulfilled.clear();
+            (mem::replace(&mut this.on_rejected, vec![]), mem::replace(&mut this.wakers, vec![]))
+        };
+
+        // Run the callbacks with the lock released, so that they can touch this promise.
+        for mut on_rejected in on_rejected {
+            on_rejected(error.clone())
+        }
+        for waker in wakers {
+            waker.wake()
+        }
     }
 
-    fn reject(&self) {
+    // Settles `other` in the same way as this promise once this promise settles.
+    fn forward_to(&self, other: &Promise<T, E>) {
+        let (fulfilled_promise, rejected_promise) = (other.clone(), other.clone());
+        self.then(Box::new(move |result| fulfilled_promise.resolve(result)));
+        self.or_else(Box::new(move |error| rejected_promise.reject(error)));
+    }
+
+    // Returns the result of this promise, or `None` if it hasn't settled yet.
+    fn result(&self) -> Option<Result<T, E>> {
+        match self.0.lock().unwrap().result {
+            PromiseResult::Pending => None,
+            PromiseResult::Resolved(ref result) => Some(Ok((*result).clone())),
+            PromiseResult::Rejected(ref error) => Some(Err((*error).clone())),
+        }
+    }
+}
+
+impl<T, E> Future for Promise<T, E> where T: 'static + Clone + Send, E: 'static + Clone + Send {
+    type Output = Result<T, E>;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Result<T, E>> {
         let mut this = self.0.lock().unwrap();
-        this.result = PromiseResult::Rejected;
-        for mut on_rejected in this.on_rejected.drain(..) {
-            on_rejected()
+        match this.result {
+            PromiseResult::Resolved(ref result) => Poll::Ready(Ok((*result).clone())),
+            PromiseResult::Rejected(ref error) => Poll::Ready(Err((*error).clone())),
+            PromiseResult::Pending => {
+                if !this.wakers.iter().any(|waker| waker.will_wake(context.waker())) {
+                    this.wakers.push(context.waker().clone());
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T, E> Default for Promise<T, E> where T: 'static + Clone + Send, E: 'static + Clone + Send {
+    #[inline]
+    fn default() -> Promise<T, E> {
+        Promise::new()
+    }
+}
+
+impl<T, E> PromiseResult<T, E> where T: Clone + Send, E: Clone + Send {
+    #[inline]
+    fn is_pending(&self) -> bool {
+        match *self {
+            PromiseResult::Pending => true,
+            PromiseResult::Resolved(_) | PromiseResult::Rejected(_) => false,
         }
     }
 }
@@ -713,6 +2026,32 @@ struct TransactionInfo {
     promise: Promise<()>,
 }
 
+// The tiles of a tiled layer.
+struct TiledLayerInfo<B> where B: Backend {
+    tile_size: Size2D<u32>,
+    painter: Option<TilePainter<B>>,
+    // Tiles by column and row.
+    tiles: HashMap<(u32, u32), TileInfo>,
+    // The size of the layer that the tiles were laid out for.
+    size: Size2D<f32>,
+}
+
+struct TileInfo {
+    layer: LayerId,
+    rect: Rect<f32>,
+    dirty: bool,
+}
+
+// A fling that `update_flings` animates, or keeps track of if the backend animates it.
+struct FlingInfo {
+    layer: LayerId,
+    fling: Fling,
+    last_update: Instant,
+    promise: Promise<Vector2D<f32>>,
+    // The promise that the backend settles, if it animates the fling.
+    backend_promise: Option<Promise<Vector2D<f32>>>,
+}
+
 // Entity-component system infrastructure
 
 impl<T> LayerMap<T> {
@@ -763,6 +2102,13 @@ impl<T> LayerMap<T> {
             self.0[layer_id.0 as usize].as_mut()
         }
     }
+
+    // Returns the IDs of all the layers that have this component.
+    fn layers(&self) -> Vec<LayerId> {
+        self.0.iter().enumerate().filter_map(|(index, element)| {
+            element.as_ref().map(|_| LayerId(index as u32))
+        }).collect()
+    }
 }
 
 impl<T> LayerMap<T> where T: Default {
@@ -807,6 +2153,67 @@ impl<'a, N> Connection<'a, N> {
 
 // Specific component infrastructure
 
+impl SurfaceOptions {
+    // Returns the color format that these options select, or `Err` if they select more than one.
+    fn color_format(&self) -> Result<ColorFormat, ()> {
+        let color_options = *self & (SurfaceOptions::SRGB |
+                                     SurfaceOptions::RGB10_A2 |
+                                     SurfaceOptions::RGBA16F);
+        if color_options.is_empty() {
+            Ok(ColorFormat::Rgba8)
+        } else if color_options == SurfaceOptions::SRGB {
+            Ok(ColorFormat::Srgb8Alpha8)
+        } else if color_options == SurfaceOptions::RGB10_A2 {
+            Ok(ColorFormat::Rgb10A2)
+        } else if color_options == SurfaceOptions::RGBA16F {
+            Ok(ColorFormat::Rgba16F)
+        } else {
+            Err(())
+        }
+    }
+
+    // Returns the depth format that these options select, if any, or `Err` if the depth options
+    // are inconsistent.
+    fn depth_format(&self) -> Result<Option<DepthFormat>, ()> {
+        let precision_options = *self & (SurfaceOptions::DEPTH16 | SurfaceOptions::DEPTH32F);
+        if !self.contains(SurfaceOptions::DEPTH) {
+            return if precision_options.is_empty() { Ok(None) } else { Err(()) }
+        }
+
+        if precision_options.is_empty() {
+            Ok(Some(DepthFormat::Depth24))
+        } else if precision_options == SurfaceOptions::DEPTH16 {
+            Ok(Some(DepthFormat::Depth16))
+        } else if precision_options == SurfaceOptions::DEPTH32F {
+            Ok(Some(DepthFormat::Depth32F))
+        } else {
+            Err(())
+        }
+    }
+
+    // Returns the number of samples per pixel that these options select, or `Err` if they select
+    // more than one sample count.
+    fn sample_count(&self) -> Result<u32, ()> {
+        let sample_options = *self & (SurfaceOptions::MSAA_2X |
+                                      SurfaceOptions::MSAA_4X |
+                                      SurfaceOptions::MSAA_8X |
+                                      SurfaceOptions::MSAA_16X);
+        if sample_options.is_empty() {
+            Ok(1)
+        } else if sample_options == SurfaceOptions::MSAA_2X {
+            Ok(2)
+        } else if sample_options == SurfaceOptions::MSAA_4X {
+            Ok(4)
+        } else if sample_options == SurfaceOptions::MSAA_8X {
+            Ok(8)
+        } else if sample_options == SurfaceOptions::MSAA_16X {
+            Ok(16)
+        } else {
+            Err(())
+        }
+    }
+}
+
 impl Default for LayerGeometryInfo {
     fn default() -> LayerGeometryInfo {
         LayerGeometryInfo {
@@ -814,3 +2221,570 @@ impl Default for LayerGeometryInfo {
         }
     }
 }
+
+impl Default for LayerEffectsInfo {
+    fn default() -> LayerEffectsInfo {
+        LayerEffectsInfo {
+            effects: LayerEffects::default(),
+            mask: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use euclid::{Point2D, Rect, Size2D, Vector2D};
+    use std::cell::RefCell;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::rc::Rc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll, Wake, Waker};
+    use std::thread;
+    use std::time::Duration;
+    use crate::backends::recording::{self, Event, EventLog};
+    use super::{ColorMatrix, ColorRange, Connection, LayerContext, LayerId, LayerParent, Promise};
+    use super::{VideoFrame, VideoPlane, YUVFormat};
+
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn poll<T, E>(promise: &mut Promise<T, E>, waker: &Waker) -> Poll<Result<T, E>>
+                  where T: 'static + Clone + Send, E: 'static + Clone + Send {
+        Pin::new(promise).poll(&mut Context::from_waker(waker))
+    }
+
+    #[test]
+    fn map_resolves_with_mapped_value() {
+        let promise: Promise<u32, ()> = Promise::new();
+        let mapped = promise.map(|value| value * 2);
+        assert_eq!(mapped.result(), None);
+        promise.resolve(21);
+        assert_eq!(mapped.result(), Some(Ok(42)));
+    }
+
+    #[test]
+    fn map_propagates_rejection_without_calling_function() {
+        let promise: Promise<u32, &'static str> = Promise::new();
+        let mapped = promise.map(|_| -> u32 { panic!("mapped a rejected promise") });
+        promise.reject("error");
+        assert_eq!(mapped.result(), Some(Err("error")));
+    }
+
+    #[test]
+    fn and_then_settles_with_returned_promise() {
+        let promise: Promise<u32, &'static str> = Promise::new();
+        let inner = Promise::new();
+        let inner_x = inner.clone();
+        let chained = promise.and_then(move |value| inner_x.map(move |other: u32| value + other));
+        promise.resolve(1);
+        assert_eq!(chained.result(), None);
+        inner.resolve(2);
+        assert_eq!(chained.result(), Some(Ok(3)));
+
+        let promise: Promise<u32, &'static str> = Promise::new();
+        let inner: Promise<u32, &'static str> = Promise::new();
+        let inner_x = inner.clone();
+        let chained = promise.and_then(move |_| inner_x);
+        promise.resolve(1);
+        inner.reject("inner");
+        assert_eq!(chained.result(), Some(Err("inner")));
+    }
+
+    #[test]
+    fn and_then_propagates_rejection_without_calling_function() {
+        let promise: Promise<u32, &'static str> = Promise::new();
+        let chained = promise.and_then(|_| -> Promise<u32, &'static str> {
+            panic!("chained onto a rejected promise")
+        });
+        promise.reject("outer");
+        assert_eq!(chained.result(), Some(Err("outer")));
+    }
+
+    #[test]
+    fn all_resolves_in_order_once_every_promise_resolves() {
+        let promises: Vec<Promise<u32, ()>> = (0..3).map(|_| Promise::new()).collect();
+        let all = Promise::all(promises.clone());
+        promises[2].resolve(2);
+        promises[0].resolve(0);
+        assert_eq!(all.result(), None);
+        promises[1].resolve(1);
+        assert_eq!(all.result(), Some(Ok(vec![0, 1, 2])));
+
+        let none: Promise<Vec<u32>, ()> = Promise::all(vec![]);
+        assert_eq!(none.result(), Some(Ok(vec![])));
+    }
+
+    #[test]
+    fn all_rejects_with_first_rejection() {
+        let promises: Vec<Promise<u32, &'static str>> = (0..3).map(|_| Promise::new()).collect();
+        let all = Promise::all(promises.clone());
+        promises[0].resolve(0);
+        promises[2].reject("third");
+        assert_eq!(all.result(), Some(Err("third")));
+        promises[1].reject("second");
+        assert_eq!(all.result(), Some(Err("third")));
+    }
+
+    #[test]
+    fn race_settles_with_first_promise_to_settle() {
+        let promises: Vec<Promise<u32, &'static str>> = (0..2).map(|_| Promise::new()).collect();
+        let race = Promise::race(promises.clone());
+        assert_eq!(race.result(), None);
+        promises[1].resolve(1);
+        promises[0].reject("late");
+        assert_eq!(race.result(), Some(Ok(1)));
+
+        let promises: Vec<Promise<u32, &'static str>> = (0..2).map(|_| Promise::new()).collect();
+        let race = Promise::race(promises.clone());
+        promises[0].reject("early");
+        promises[1].resolve(1);
+        assert_eq!(race.result(), Some(Err("early")));
+    }
+
+    #[test]
+    fn promises_settle_only_once() {
+        let promise: Promise<u32, &'static str> = Promise::new();
+        let results = Arc::new(Mutex::new(vec![]));
+        let (fulfilled_results, rejected_results) = (results.clone(), results.clone());
+        promise.then(Box::new(move |value| fulfilled_results.lock().unwrap().push(Ok(value))));
+        promise.or_else(Box::new(move |error| rejected_results.lock().unwrap().push(Err(error))));
+        promise.resolve(1);
+        promise.resolve(2);
+        promise.reject("error");
+        assert_eq!(*results.lock().unwrap(), vec![Ok(1)]);
+
+        // Handlers attached after settling run immediately.
+        let late_results = results.clone();
+        promise.then(Box::new(move |value| late_results.lock().unwrap().push(Ok(value))));
+        assert_eq!(*results.lock().unwrap(), vec![Ok(1), Ok(1)]);
+    }
+
+    #[test]
+    fn future_wakes_once_when_resolved() {
+        let mut promise: Promise<u32, ()> = Promise::new();
+        let counter = Arc::new(CountingWaker(AtomicUsize::new(0)));
+        let waker = Waker::from(counter.clone());
+
+        // Polling repeatedly with the same waker registers it only once.
+        assert_eq!(poll(&mut promise, &waker), Poll::Pending);
+        assert_eq!(poll(&mut promise, &waker.clone()), Poll::Pending);
+        assert_eq!(promise.0.lock().unwrap().wakers.len(), 1);
+
+        promise.resolve(1);
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+        assert_eq!(poll(&mut promise, &waker), Poll::Ready(Ok(1)));
+    }
+
+    #[test]
+    fn future_wakes_every_distinct_waker_when_rejected() {
+        let mut promise: Promise<u32, &'static str> = Promise::new();
+        let counters: Vec<_> = (0..2).map(|_| {
+            Arc::new(CountingWaker(AtomicUsize::new(0)))
+        }).collect();
+        for counter in &counters {
+            assert_eq!(poll(&mut promise, &Waker::from(counter.clone())), Poll::Pending);
+        }
+
+        promise.reject("error");
+        for counter in &counters {
+            assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+        }
+        let waker = Waker::from(counters[0].clone());
+        assert_eq!(poll(&mut promise, &waker), Poll::Ready(Err("error")));
+    }
+
+    // Layer context tests, against the recording backend
+
+    fn context() -> (LayerContext<recording::Backend>, EventLog) {
+        let log = EventLog::new();
+        let context = LayerContext::with_backend_connection(Connection::Native(log.clone()));
+        (context.unwrap(), log)
+    }
+
+    fn rect(x: f32, y: f32, width: f32, height: f32) -> Rect<f32> {
+        Rect::new(Point2D::new(x, y), Size2D::new(width, height))
+    }
+
+    #[test]
+    fn export_and_import() {
+        let (mut context, log) = context();
+        context.begin_transaction();
+        let root = context.add_container_layer();
+        let token = context.export_layer(root).unwrap();
+        let imported = context.import_layer(&token).unwrap();
+        context.end_transaction();
+
+        assert_eq!(log.take(), vec![
+            Event::BeginTransaction,
+            Event::AddContainerLayer(root),
+            Event::ExportLayer(root),
+            Event::ImportLayer(imported, token),
+            Event::EndTransaction,
+        ]);
+        assert_eq!(context.parent_of(root), Some(&LayerParent::NativeHost));
+
+        // Nothing is ever exported to the recording backend, so there's nothing to update.
+        assert!(!context.update_imported_layers());
+        assert_eq!(log.take(), vec![Event::ImportedLayersChanged]);
+    }
+
+    #[test]
+    fn roll_back_failed_export_and_import() {
+        let (mut context, log) = context();
+        context.begin_transaction();
+        let root = context.add_container_layer();
+        let token = context.export_layer(root).unwrap();
+        let other_root = context.add_container_layer();
+
+        // The failed export mustn't leave the layer in the tree, so it can be exported again.
+        log.set_failing(true);
+        assert!(context.export_layer(other_root).is_err());
+        assert_eq!(context.parent_of(other_root), None);
+        log.set_failing(false);
+        context.export_layer(other_root).unwrap();
+
+        // The failed import mustn't use up a layer ID.
+        log.set_failing(true);
+        assert!(context.import_layer(&token).is_err());
+        log.set_failing(false);
+        let imported = context.import_layer(&token).unwrap();
+        context.end_transaction();
+
+        assert_eq!(log.take(), vec![
+            Event::BeginTransaction,
+            Event::AddContainerLayer(root),
+            Event::ExportLayer(root),
+            Event::AddContainerLayer(other_root),
+            Event::ExportLayer(other_root),
+            Event::ExportLayer(other_root),
+            Event::ImportLayer(imported, token.clone()),
+            Event::ImportLayer(imported, token),
+            Event::EndTransaction,
+        ]);
+    }
+
+    #[test]
+    fn reject_drawing_into_video_layers_and_video_into_other_layers() {
+        let (mut context, log) = context();
+        let (luma, chroma) = ([16; 4], [128; 2]);
+        let frame = VideoFrame {
+            format: YUVFormat::NV12,
+            color_matrix: ColorMatrix::BT709,
+            color_range: ColorRange::Limited,
+            size: Size2D::new(2, 2),
+            planes: &[
+                VideoPlane::Memory { data: &luma, stride: 2 },
+                VideoPlane::Memory { data: &chroma, stride: 2 },
+            ],
+        };
+        context.begin_transaction();
+        let video = context.add_video_layer();
+        let surface = context.add_surface_layer();
+        let container = context.add_container_layer();
+        log.take();
+
+        // Binding a video layer would replace its frames with whatever the context draws.
+        assert!(context.bind_layer_to_gl_context(video, &mut ()).is_err());
+
+        // Video frames only go to video layers.
+        assert!(context.present_video_frame(surface, &frame).is_err());
+        assert!(context.present_video_frame(container, &frame).is_err());
+        assert!(context.present_video_frame(video, &frame).is_ok());
+        context.end_transaction();
+
+        assert_eq!(log.take(), vec![
+            Event::PresentVideoFrame(video, YUVFormat::NV12, Size2D::new(2, 2)),
+            Event::EndTransaction,
+        ]);
+    }
+
+    #[test]
+    fn reparent_masks() {
+        let (mut context, log) = context();
+        context.begin_transaction();
+        let root = context.add_container_layer();
+        let masked = context.add_container_layer();
+        let mask = context.add_surface_layer();
+        unsafe {
+            context.host_layer((), root);
+        }
+        context.append_child(root, masked);
+        log.take();
+
+        // Setting a mask takes it out of the tree.
+        context.set_layer_mask(masked, Some(mask));
+        assert_eq!(context.parent_of(mask), Some(&LayerParent::Mask(masked)));
+        assert_eq!(context.layer_mask(masked), Some(mask));
+
+        // Removing a mask from its parent detaches it from the layer that it masks.
+        context.remove_from_parent(mask);
+        assert_eq!(context.parent_of(mask), None);
+        assert_eq!(context.layer_mask(masked), None);
+
+        // Then it can go back in the tree as an ordinary layer, or mask another layer.
+        context.append_child(root, mask);
+        context.remove_from_parent(mask);
+        context.set_layer_mask(root, Some(mask));
+
+        // Deleting the masked layer leaves the mask off-screen.
+        context.remove_from_parent(root);
+        context.delete_layer(root);
+        assert_eq!(context.parent_of(mask), None);
+        context.end_transaction();
+
+        assert_eq!(log.take(), vec![
+            Event::SetLayerMask(masked, Some(mask)),
+            Event::SetLayerMask(masked, None),
+            Event::InsertBefore(root, mask, None),
+            Event::RemoveFromSuperlayer(mask, root),
+            Event::SetLayerMask(root, Some(mask)),
+            Event::UnhostLayer(root),
+            Event::SetLayerMask(root, None),
+            Event::DeleteLayer(root),
+            Event::EndTransaction,
+        ]);
+    }
+
+
+    #[test]
+    fn scroll() {
+        let (mut context, log) = context();
+        context.begin_transaction();
+        let layer = context.add_scroll_layer();
+        context.set_layer_bounds(layer, &rect(0.0, 0.0, 32.0, 32.0));
+        context.set_layer_content_size(layer, &Size2D::new(32.0, 64.0));
+        context.set_layer_content_offset(layer, &Vector2D::new(0.0, 16.0));
+        assert_eq!(context.layer_content_offset(layer), Vector2D::new(0.0, 16.0));
+        context.end_transaction();
+
+        assert_eq!(log.take(), vec![
+            Event::BeginTransaction,
+            Event::AddScrollLayer(layer),
+            Event::SetLayerBounds(layer, Rect::zero(), rect(0.0, 0.0, 32.0, 32.0)),
+            Event::SetLayerContentSize(layer, Size2D::new(32.0, 64.0)),
+            Event::SetLayerContentOffset(layer, Vector2D::new(0.0, 16.0)),
+            Event::EndTransaction,
+        ]);
+    }
+
+    #[test]
+    fn spring_back_after_fling() {
+        let (mut context, log) = context();
+        context.begin_transaction();
+        let layer = context.add_scroll_layer();
+        context.set_layer_bounds(layer, &rect(0.0, 0.0, 32.0, 32.0));
+        context.set_layer_content_size(layer, &Size2D::new(32.0, 64.0));
+        context.set_layer_content_offset(layer, &Vector2D::new(0.0, -16.0));
+        log.take();
+        let fling = context.start_fling(layer, &Vector2D::zero());
+        context.end_transaction();
+        assert_eq!(log.take(), vec![
+            Event::StartFling(layer, Vector2D::zero()),
+            Event::EndTransaction,
+        ]);
+
+        // The recording backend refuses to animate flings, so the context moves the layer one
+        // step at a time, in a transaction per step.
+        while context.update_flings() {
+            thread::sleep(Duration::from_millis(5));
+        }
+        let events = log.take();
+        for step in events.chunks(3) {
+            match *step {
+                [Event::BeginTransaction,
+                 Event::SetLayerContentOffset(step_layer, _),
+                 Event::EndTransaction] if step_layer == layer => {}
+                _ => panic!("unexpected fling step: {:?}", step),
+            }
+        }
+        let final_event = Event::SetLayerContentOffset(layer, Vector2D::zero());
+        assert_eq!(events[events.len() - 2], final_event);
+        assert_eq!(fling.result(), Some(Ok(Vector2D::zero())));
+    }
+
+    #[test]
+    fn fling_to_end_of_content() {
+        let (mut context, log) = context();
+        context.begin_transaction();
+        let layer = context.add_scroll_layer();
+        context.set_layer_bounds(layer, &rect(0.0, 0.0, 32.0, 32.0));
+        context.set_layer_content_size(layer, &Size2D::new(32.0, 64.0));
+        let fling = context.start_fling(layer, &Vector2D::new(0.0, 2000.0));
+        context.end_transaction();
+
+        // A fast fling overshoots the end of the content and springs back to rest exactly there.
+        let mut max_offset: f32 = 0.0;
+        while context.update_flings() {
+            context.begin_transaction();
+            max_offset = max_offset.max(context.layer_content_offset(layer).y);
+            context.end_transaction();
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(max_offset > 32.0);
+        assert_eq!(fling.result(), Some(Ok(Vector2D::new(0.0, 32.0))));
+        let events = log.take();
+        let final_event = Event::SetLayerContentOffset(layer, Vector2D::new(0.0, 32.0));
+        assert_eq!(events[events.len() - 2], final_event);
+    }
+
+    #[test]
+    fn stop_fling() {
+        let (mut context, log) = context();
+        context.begin_transaction();
+        let layer = context.add_scroll_layer();
+        context.set_layer_bounds(layer, &rect(0.0, 0.0, 32.0, 32.0));
+        context.set_layer_content_size(layer, &Size2D::new(32.0, 64.0));
+        let first_fling = context.start_fling(layer, &Vector2D::new(0.0, 2000.0));
+        let second_fling = context.start_fling(layer, &Vector2D::new(0.0, 2000.0));
+        context.set_layer_content_offset(layer, &Vector2D::new(0.0, 8.0));
+        context.end_transaction();
+
+        // Each fling is stopped by the next change to the content offset, where it started.
+        assert_eq!(first_fling.result(), Some(Ok(Vector2D::zero())));
+        assert_eq!(second_fling.result(), Some(Ok(Vector2D::zero())));
+        assert!(!context.update_flings());
+        assert_eq!(log.take()[4..], [
+            Event::StartFling(layer, Vector2D::new(0.0, 2000.0)),
+            Event::StartFling(layer, Vector2D::new(0.0, 2000.0)),
+            Event::SetLayerContentOffset(layer, Vector2D::new(0.0, 8.0)),
+            Event::EndTransaction,
+        ]);
+
+        // Deleting a layer rejects its fling.
+        context.begin_transaction();
+        let fling = context.start_fling(layer, &Vector2D::new(0.0, 2000.0));
+        context.delete_layer(layer);
+        context.end_transaction();
+        assert_eq!(fling.result(), Some(Err(())));
+        assert!(!context.update_flings());
+    }
+
+    #[test]
+    fn create_visible_tiles() {
+        let (mut context, log) = context();
+        let painted = Rc::new(RefCell::new(vec![]));
+        let painted_by_painter = painted.clone();
+        context.begin_transaction();
+        let root = context.add_scroll_layer();
+        context.set_layer_bounds(root, &rect(0.0, 0.0, 32.0, 32.0));
+        context.set_layer_content_size(root, &Size2D::new(32.0, 64.0));
+        unsafe {
+            context.host_layer((), root);
+        }
+        let tiled_layer = context.add_tiled_layer(&Size2D::new(16, 16), Box::new(move |_, tile| {
+            painted_by_painter.borrow_mut().push(tile.rect);
+        }));
+        context.set_layer_bounds(tiled_layer, &rect(0.0, 0.0, 32.0, 64.0));
+        context.append_child(root, tiled_layer);
+        log.take();
+        context.end_transaction();
+
+        // Only the top half of the tiled layer is visible, so only its four tiles are created.
+        let tile_rects = [
+            rect(0.0, 0.0, 16.0, 16.0),
+            rect(16.0, 0.0, 16.0, 16.0),
+            rect(0.0, 16.0, 16.0, 16.0),
+            rect(16.0, 16.0, 16.0, 16.0),
+        ];
+        let mut expected_events = vec![];
+        for (tile_index, tile_rect) in tile_rects.iter().enumerate() {
+            let tile = LayerId(tiled_layer.0 + 1 + tile_index as u32);
+            expected_events.push(Event::AddSurfaceLayer(tile));
+            expected_events.push(Event::SetLayerBounds(tile, Rect::zero(), *tile_rect));
+            expected_events.push(Event::InsertBefore(tiled_layer, tile, None));
+        }
+        expected_events.push(Event::EndTransaction);
+        assert_eq!(log.take(), expected_events);
+
+        assert_eq!(sorted_rects(&painted.borrow()), tile_rects);
+    }
+    #[test]
+    fn repaint_tiles_after_scrolling() {
+        let (mut context, log) = context();
+        let painted = Rc::new(RefCell::new(vec![]));
+        let painted_by_painter = painted.clone();
+        context.begin_transaction();
+        let root = context.add_scroll_layer();
+        context.set_layer_bounds(root, &rect(0.0, 0.0, 32.0, 32.0));
+        context.set_layer_content_size(root, &Size2D::new(32.0, 4096.0));
+        unsafe {
+            context.host_layer((), root);
+        }
+        let tiled_layer = context.add_tiled_layer(&Size2D::new(16, 16), Box::new(move |_, tile| {
+            painted_by_painter.borrow_mut().push(tile.rect);
+        }));
+        context.set_layer_bounds(tiled_layer, &rect(0.0, 0.0, 32.0, 4096.0));
+        context.append_child(root, tiled_layer);
+        context.end_transaction();
+        let first_tiles = added_surface_layers(&log.take());
+        assert_eq!(first_tiles.len(), 4);
+        painted.borrow_mut().clear();
+
+        // Scrolling far away paints the tiles that come into view and deletes the old ones.
+        context.begin_transaction();
+        context.set_layer_content_offset(root, &Vector2D::new(0.0, 2056.0));
+        context.end_transaction();
+        let events = log.take();
+        assert_eq!(added_surface_layers(&events).len(), 6);
+        let mut deleted_tiles: Vec<LayerId> = events.iter().filter_map(|event| {
+            match *event {
+                Event::DeleteLayer(layer) => Some(layer),
+                _ => None,
+            }
+        }).collect();
+        deleted_tiles.sort();
+        assert_eq!(deleted_tiles, first_tiles);
+        assert_eq!(sorted_rects(&painted.borrow()), [
+            rect(0.0, 2048.0, 16.0, 16.0),
+            rect(16.0, 2048.0, 16.0, 16.0),
+            rect(0.0, 2064.0, 16.0, 16.0),
+            rect(16.0, 2064.0, 16.0, 16.0),
+            rect(0.0, 2080.0, 16.0, 16.0),
+            rect(16.0, 2080.0, 16.0, 16.0),
+        ]);
+        painted.borrow_mut().clear();
+
+        // Scrolling a little doesn't paint anything that's already been painted.
+        context.begin_transaction();
+        context.set_layer_content_offset(root, &Vector2D::new(0.0, 2050.0));
+        context.end_transaction();
+        assert!(painted.borrow().is_empty());
+
+        // Invalidated tiles are painted again, in place.
+        context.begin_transaction();
+        context.invalidate_tiles(tiled_layer, &rect(0.0, 2048.0, 32.0, 1.0));
+        context.end_transaction();
+        assert_eq!(added_surface_layers(&log.take()).len(), 0);
+        assert_eq!(sorted_rects(&painted.borrow()), [
+            rect(0.0, 2048.0, 16.0, 16.0),
+            rect(16.0, 2048.0, 16.0, 16.0),
+        ]);
+    }
+
+    fn added_surface_layers(events: &[Event]) -> Vec<LayerId> {
+        let mut layers: Vec<LayerId> = events.iter().filter_map(|event| {
+            match *event {
+                Event::AddSurfaceLayer(layer) => Some(layer),
+                _ => None,
+            }
+        }).collect();
+        layers.sort();
+        layers
+    }
+
+    // Sorts rects from top to bottom, then from left to right.
+    fn sorted_rects(rects: &[Rect<f32>]) -> Vec<Rect<f32>> {
+        let mut rects = rects.to_vec();
+        rects.sort_by(|a, b| {
+            (a.origin.y, a.origin.x).partial_cmp(&(b.origin.y, b.origin.x)).unwrap()
+        });
+        rects
+    }
+}
@@ -0,0 +1,1095 @@
+// planeshift/src/protocol.rs
+//
+// Copyright © 2018 The Pathfinder Project Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// The wire protocol spoken between the remote backend and the compositor server.
+//
+// Each message is a little-endian `u32` byte length followed by that many bytes of payload. The
+// first byte of the payload identifies the message. Surface buffers are passed as file
+// descriptors alongside the messages that introduce them.
+
+use euclid::{Point2D, Rect, Size2D, Vector2D};
+use image::{Rgba, RgbaImage};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::raw::c_void;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::ptr;
+use std::time::Duration;
+
+use crate::server::PeerCredentials;
+use crate::{BlendMode, Capabilities, DropShadow, LayerEffects, LayerId, ScreenshotError};
+use crate::SurfaceOptions;
+
+// Don't let a peer that has gone away kill us with `SIGPIPE`.
+// FIXME(pcwalton): On macOS, we should set `SO_NOSIGPIPE` on the socket instead.
+#[cfg(target_os = "linux")]
+const SEND_FLAGS: i32 = libc::MSG_NOSIGNAL;
+#[cfg(not(target_os = "linux"))]
+const SEND_FLAGS: i32 = 0;
+
+// Don't let file descriptors that we receive leak into processes that we spawn.
+// FIXME(pcwalton): On macOS, we should set `FD_CLOEXEC` on each one instead.
+#[cfg(target_os = "linux")]
+const RECEIVE_FLAGS: i32 = libc::MSG_CMSG_CLOEXEC;
+#[cfg(not(target_os = "linux"))]
+const RECEIVE_FLAGS: i32 = 0;
+
+const RECEIVE_BUFFER_SIZE: usize = 65536;
+
+// The longest request payload that the server accepts. Requests are small, so this is generous.
+pub const MAX_REQUEST_SIZE: usize = 65536;
+
+// The longest reply payload that the client accepts: enough for a screenshot of an 8K display.
+pub const MAX_REPLY_SIZE: usize = 256 * 1024 * 1024;
+
+// The most file descriptors that the server accepts with one request. Only `AttachBuffer`
+// requests carry one.
+pub const MAX_REQUEST_FDS: usize = 1;
+
+// Enough room for a control message carrying a handful of file descriptors, suitably aligned.
+const CONTROL_BUFFER_WORDS: usize = 16;
+
+// Requests, sent from the client to the server
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Request {
+    BeginTransaction,
+    EndTransaction,
+    AddContainerLayer(LayerId),
+    AddSurfaceLayer(LayerId),
+    DeleteLayer(LayerId),
+    // The parent, the new child, and the reference layer.
+    InsertBefore(LayerId, LayerId, Option<LayerId>),
+    RemoveFromParent(LayerId),
+    HostLayer(LayerId, u32),
+    SetLayerBounds(LayerId, Rect<f32>),
+    SetLayerSurfaceOptions(LayerId, SurfaceOptions),
+    // Accompanied by the file descriptor of the new buffer.
+    AttachBuffer(LayerId, Size2D<u32>),
+    // The layer and the rect that changed.
+    PresentBuffer(LayerId, Rect<f32>),
+    ScreenshotHostedLayer(LayerId),
+    SetLayerEffects(LayerId, LayerEffects),
+    // The masked layer and its mask.
+    SetLayerMask(LayerId, Option<LayerId>),
+    AddScrollLayer(LayerId),
+    SetLayerContentSize(LayerId, Size2D<f32>),
+    SetLayerContentOffset(LayerId, Vector2D<f32>),
+    // An ID for the fling, the layer, and the velocity.
+    StartFling(u32, LayerId, Vector2D<f32>),
+}
+
+// Replies, sent from the server to the client in the same order as the requests they answer
+
+#[derive(Debug)]
+pub enum Reply {
+    TransactionCommitted,
+    Screenshot(Result<RgbaImage, ScreenshotError>),
+    // Sent once, before anything else, when the server accepts a client.
+    Capabilities(Capabilities),
+    // Sent whenever a fling ends, regardless of order, with the ID that the client gave it and
+    // the final content offset.
+    FlingEnded(u32, Result<Vector2D<f32>, ()>),
+}
+
+impl Request {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new();
+        match *self {
+            Request::BeginTransaction => encoder.u8(0),
+            Request::EndTransaction => encoder.u8(1),
+            Request::AddContainerLayer(layer) => {
+                encoder.u8(2);
+                encoder.layer(layer);
+            }
+            Request::AddSurfaceLayer(layer) => {
+                encoder.u8(3);
+                encoder.layer(layer);
+            }
+            Request::DeleteLayer(layer) => {
+                encoder.u8(4);
+                encoder.layer(layer);
+            }
+            Request::InsertBefore(parent, new_child, reference) => {
+                encoder.u8(5);
+                encoder.layer(parent);
+                encoder.layer(new_child);
+                match reference {
+                    None => encoder.u8(0),
+                    Some(reference) => {
+                        encoder.u8(1);
+                        encoder.layer(reference);
+                    }
+                }
+            }
+            Request::RemoveFromParent(layer) => {
+                encoder.u8(6);
+                encoder.layer(layer);
+            }
+            Request::HostLayer(layer, host) => {
+                encoder.u8(7);
+                encoder.layer(layer);
+                encoder.u32(host);
+            }
+            Request::SetLayerBounds(layer, ref bounds) => {
+                encoder.u8(8);
+                encoder.layer(layer);
+                encoder.rect(bounds);
+            }
+            Request::SetLayerSurfaceOptions(layer, options) => {
+                encoder.u8(9);
+                encoder.layer(layer);
+                encoder.u32(options.bits() as u32);
+            }
+            Request::AttachBuffer(layer, ref size) => {
+                encoder.u8(10);
+                encoder.layer(layer);
+                encoder.u32(size.width);
+                encoder.u32(size.height);
+            }
+            Request::PresentBuffer(layer, ref changed_rect) => {
+                encoder.u8(11);
+                encoder.layer(layer);
+                encoder.rect(changed_rect);
+            }
+            Request::ScreenshotHostedLayer(layer) => {
+                encoder.u8(12);
+                encoder.layer(layer);
+            }
+            Request::SetLayerEffects(layer, ref effects) => {
+                encoder.u8(13);
+                encoder.layer(layer);
+                match effects.shadow {
+                    None => encoder.u8(0),
+                    Some(ref shadow) => {
+                        encoder.u8(1);
+                        encoder.f32(shadow.offset.x);
+                        encoder.f32(shadow.offset.y);
+                        encoder.f32(shadow.radius);
+                        encoder.bytes(&shadow.color.data);
+                        encoder.f32(shadow.opacity);
+                    }
+                }
+                encoder.f32(effects.blur_radius);
+                encoder.f32(effects.backdrop_blur_radius);
+                encoder.u8(match effects.blend_mode {
+                    BlendMode::Normal => 0,
+                    BlendMode::Multiply => 1,
+                    BlendMode::Screen => 2,
+                    BlendMode::Overlay => 3,
+                    BlendMode::Darken => 4,
+                    BlendMode::Lighten => 5,
+                    BlendMode::ColorDodge => 6,
+                    BlendMode::ColorBurn => 7,
+                    BlendMode::HardLight => 8,
+                    BlendMode::SoftLight => 9,
+                    BlendMode::Difference => 10,
+                    BlendMode::Exclusion => 11,
+                    BlendMode::Plus => 12,
+                });
+            }
+            Request::SetLayerMask(layer, mask) => {
+                encoder.u8(14);
+                encoder.layer(layer);
+                match mask {
+                    None => encoder.u8(0),
+                    Some(mask) => {
+                        encoder.u8(1);
+                        encoder.layer(mask);
+                    }
+                }
+            }
+            Request::AddScrollLayer(layer) => {
+                encoder.u8(15);
+                encoder.layer(layer);
+            }
+            Request::SetLayerContentSize(layer, ref size) => {
+                encoder.u8(16);
+                encoder.layer(layer);
+                encoder.f32(size.width);
+                encoder.f32(size.height);
+            }
+            Request::SetLayerContentOffset(layer, ref offset) => {
+                encoder.u8(17);
+                encoder.layer(layer);
+                encoder.f32(offset.x);
+                encoder.f32(offset.y);
+            }
+            Request::StartFling(id, layer, ref velocity) => {
+                encoder.u8(18);
+                encoder.u32(id);
+                encoder.layer(layer);
+                encoder.f32(velocity.x);
+                encoder.f32(velocity.y);
+            }
+        }
+        encoder.finish()
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Request, ()> {
+        let mut decoder = Decoder::new(payload);
+        let request = match decoder.u8()? {
+            0 => Request::BeginTransaction,
+            1 => Request::EndTransaction,
+            2 => Request::AddContainerLayer(decoder.layer()?),
+            3 => Request::AddSurfaceLayer(decoder.layer()?),
+            4 => Request::DeleteLayer(decoder.layer()?),
+            5 => {
+                let (parent, new_child) = (decoder.layer()?, decoder.layer()?);
+                let reference = match decoder.u8()? {
+                    0 => None,
+                    1 => Some(decoder.layer()?),
+                    _ => return Err(()),
+                };
+                Request::InsertBefore(parent, new_child, reference)
+            }
+            6 => Request::RemoveFromParent(decoder.layer()?),
+            7 => Request::HostLayer(decoder.layer()?, decoder.u32()?),
+            8 => Request::SetLayerBounds(decoder.layer()?, decoder.rect()?),
+            9 => {
+                let layer = decoder.layer()?;
+                let bits = decoder.u32()?;
+                if bits > u16::max_value() as u32 {
+                    return Err(())
+                }
+                let options = SurfaceOptions::from_bits(bits as u16).ok_or(())?;
+                Request::SetLayerSurfaceOptions(layer, options)
+            }
+            10 => {
+                let layer = decoder.layer()?;
+                let size = Size2D::new(decoder.u32()?, decoder.u32()?);
+                Request::AttachBuffer(layer, size)
+            }
+            11 => Request::PresentBuffer(decoder.layer()?, decoder.rect()?),
+            12 => Request::ScreenshotHostedLayer(decoder.layer()?),
+            13 => {
+                let layer = decoder.layer()?;
+                let shadow = match decoder.u8()? {
+                    0 => None,
+                    1 => {
+                        let offset = Vector2D::new(decoder.f32()?, decoder.f32()?);
+                        let radius = decoder.f32()?;
+                        let mut color = Rgba { data: [0; 4] };
+                        color.data.copy_from_slice(decoder.bytes(4)?);
+                        let opacity = decoder.f32()?;
+                        Some(DropShadow { offset, radius, color, opacity })
+                    }
+                    _ => return Err(()),
+                };
+                let (blur_radius, backdrop_blur_radius) = (decoder.f32()?, decoder.f32()?);
+                let blend_mode = match decoder.u8()? {
+                    0 => BlendMode::Normal,
+                    1 => BlendMode::Multiply,
+                    2 => BlendMode::Screen,
+                    3 => BlendMode::Overlay,
+                    4 => BlendMode::Darken,
+                    5 => BlendMode::Lighten,
+                    6 => BlendMode::ColorDodge,
+                    7 => BlendMode::ColorBurn,
+                    8 => BlendMode::HardLight,
+                    9 => BlendMode::SoftLight,
+                    10 => BlendMode::Difference,
+                    11 => BlendMode::Exclusion,
+                    12 => BlendMode::Plus,
+                    _ => return Err(()),
+                };
+                Request::SetLayerEffects(layer, LayerEffects {
+                    shadow,
+                    blur_radius,
+                    backdrop_blur_radius,
+                    blend_mode,
+                })
+            }
+            14 => {
+                let layer = decoder.layer()?;
+                let mask = match decoder.u8()? {
+                    0 => None,
+                    1 => Some(decoder.layer()?),
+                    _ => return Err(()),
+                };
+                Request::SetLayerMask(layer, mask)
+            }
+            15 => Request::AddScrollLayer(decoder.layer()?),
+            16 => {
+                let layer = decoder.layer()?;
+                let size = Size2D::new(decoder.f32()?, decoder.f32()?);
+                Request::SetLayerContentSize(layer, size)
+            }
+            17 => {
+                let layer = decoder.layer()?;
+                let offset = Vector2D::new(decoder.f32()?, decoder.f32()?);
+                Request::SetLayerContentOffset(layer, offset)
+            }
+            18 => {
+                let (id, layer) = (decoder.u32()?, decoder.layer()?);
+                let velocity = Vector2D::new(decoder.f32()?, decoder.f32()?);
+                Request::StartFling(id, layer, velocity)
+            }
+            _ => return Err(()),
+        };
+        decoder.finish()?;
+        Ok(request)
+    }
+}
+
+impl Reply {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoder = Encoder::new();
+        match *self {
+            Reply::TransactionCommitted => encoder.u8(0),
+            Reply::Screenshot(Ok(ref image)) => {
+                encoder.u8(1);
+                encoder.u32(image.width());
+                encoder.u32(image.height());
+                encoder.bytes(&**image);
+            }
+            Reply::Screenshot(Err(error)) => {
+                encoder.u8(2);
+                encoder.u8(match error {
+                    ScreenshotError::Unsupported => 0,
+                    ScreenshotError::TransactionFailed => 1,
+                    ScreenshotError::ReadbackFailed => 2,
+                });
+            }
+            Reply::Capabilities(ref capabilities) => {
+                encoder.u8(3);
+                encoder.bool(capabilities.screenshots);
+                encoder.bool(capabilities.damage_aware_present);
+                encoder.bool(capabilities.transforms);
+                encoder.bool(capabilities.native_animations);
+                encoder.u32(capabilities.surface_options.bits() as u32);
+                match capabilities.max_texture_size {
+                    None => encoder.u8(0),
+                    Some(max_texture_size) => {
+                        encoder.u8(1);
+                        encoder.u32(max_texture_size);
+                    }
+                }
+                encoder.bool(capabilities.layer_export);
+                encoder.bool(capabilities.video_layers);
+                encoder.bool(capabilities.layer_effects);
+                encoder.bool(capabilities.backdrop_blur);
+                encoder.bool(capabilities.blend_modes);
+                encoder.bool(capabilities.layer_masks);
+                encoder.bool(capabilities.scroll_clipping);
+            }
+            Reply::FlingEnded(id, ref result) => {
+                encoder.u8(4);
+                encoder.u32(id);
+                match *result {
+                    Ok(ref offset) => {
+                        encoder.u8(0);
+                        encoder.f32(offset.x);
+                        encoder.f32(offset.y);
+                    }
+                    Err(()) => encoder.u8(1),
+                }
+            }
+        }
+        encoder.finish()
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Reply, ()> {
+        let mut decoder = Decoder::new(payload);
+        let reply = match decoder.u8()? {
+            0 => Reply::TransactionCommitted,
+            1 => {
+                let (width, height) = (decoder.u32()?, decoder.u32()?);
+                let length = (width as usize).checked_mul(height as usize)
+                                             .and_then(|area| area.checked_mul(4))
+                                             .ok_or(())?;
+                let pixels = decoder.bytes(length)?.to_vec();
+                Reply::Screenshot(Ok(RgbaImage::from_raw(width, height, pixels).ok_or(())?))
+            }
+            2 => {
+                let error = match decoder.u8()? {
+                    0 => ScreenshotError::Unsupported,
+                    1 => ScreenshotError::TransactionFailed,
+                    2 => ScreenshotError::ReadbackFailed,
+                    _ => return Err(()),
+                };
+                Reply::Screenshot(Err(error))
+            }
+            3 => {
+                let (screenshots, damage_aware_present) = (decoder.bool()?, decoder.bool()?);
+                let (transforms, native_animations) = (decoder.bool()?, decoder.bool()?);
+                let bits = decoder.u32()?;
+                if bits > u16::max_value() as u32 {
+                    return Err(())
+                }
+                let surface_options = SurfaceOptions::from_bits(bits as u16).ok_or(())?;
+                let max_texture_size = match decoder.u8()? {
+                    0 => None,
+                    1 => Some(decoder.u32()?),
+                    _ => return Err(()),
+                };
+                Reply::Capabilities(Capabilities {
+                    screenshots,
+                    damage_aware_present,
+                    transforms,
+                    native_animations,
+                    surface_options,
+                    max_texture_size,
+                    layer_export: decoder.bool()?,
+                    video_layers: decoder.bool()?,
+                    layer_effects: decoder.bool()?,
+                    backdrop_blur: decoder.bool()?,
+                    blend_modes: decoder.bool()?,
+                    layer_masks: decoder.bool()?,
+                    scroll_clipping: decoder.bool()?,
+                })
+            }
+            4 => {
+                let id = decoder.u32()?;
+                let result = match decoder.u8()? {
+                    0 => Ok(Vector2D::new(decoder.f32()?, decoder.f32()?)),
+                    1 => Err(()),
+                    _ => return Err(()),
+                };
+                Reply::FlingEnded(id, result)
+            }
+            _ => return Err(()),
+        };
+        decoder.finish()?;
+        Ok(reply)
+    }
+}
+
+// Encoding and decoding
+
+struct Encoder {
+    data: Vec<u8>,
+}
+
+impl Encoder {
+    fn new() -> Encoder {
+        // Leave room for the length, which is filled in by `finish()`.
+        Encoder { data: vec![0; 4] }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let length = (self.data.len() - 4) as u32;
+        self.data[0..4].copy_from_slice(&length.to_le_bytes());
+        self.data
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.data.push(value)
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes())
+    }
+
+    fn f32(&mut self, value: f32) {
+        self.u32(value.to_bits())
+    }
+
+    fn bool(&mut self, value: bool) {
+        self.u8(value as u8)
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes)
+    }
+
+    fn layer(&mut self, layer: LayerId) {
+        self.u32(layer.0)
+    }
+
+    fn rect(&mut self, rect: &Rect<f32>) {
+        self.f32(rect.origin.x);
+        self.f32(rect.origin.y);
+        self.f32(rect.size.width);
+        self.f32(rect.size.height);
+    }
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn new(data: &'a [u8]) -> Decoder<'a> {
+        Decoder { data }
+    }
+
+    fn finish(self) -> Result<(), ()> {
+        if self.data.is_empty() { Ok(()) } else { Err(()) }
+    }
+
+    fn bytes(&mut self, length: usize) -> Result<&'a [u8], ()> {
+        if self.data.len() < length {
+            return Err(())
+        }
+        let (bytes, rest) = self.data.split_at(length);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, ()> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, ()> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn f32(&mut self) -> Result<f32, ()> {
+        Ok(f32::from_bits(self.u32()?))
+    }
+
+    fn bool(&mut self) -> Result<bool, ()> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(()),
+        }
+    }
+
+    fn layer(&mut self) -> Result<LayerId, ()> {
+        Ok(LayerId(self.u32()?))
+    }
+
+    fn rect(&mut self) -> Result<Rect<f32>, ()> {
+        let origin = Point2D::new(self.f32()?, self.f32()?);
+        let size = Size2D::new(self.f32()?, self.f32()?);
+        Ok(Rect::new(origin, size))
+    }
+}
+
+// Socket I/O
+
+/// Writes an encoded message to the socket, optionally passing a file descriptor along with it.
+///
+/// This blocks until the whole message is written, unless the socket is nonblocking, in which
+/// case it fails partway through; use a `Sender` with nonblocking sockets.
+pub fn send(stream: &UnixStream, message: &[u8], mut fd: Option<RawFd>) -> io::Result<()> {
+    let mut offset = 0;
+    while offset < message.len() {
+        offset += send_some(stream, &message[offset..], fd)?;
+
+        // The file descriptor goes along with the first byte only.
+        fd = None;
+    }
+    Ok(())
+}
+
+// Writes as much of the data as the socket will take in one go, and returns how much that was.
+fn send_some(stream: &UnixStream, data: &[u8], fd: Option<RawFd>) -> io::Result<usize> {
+    loop {
+        unsafe {
+            let mut iov = libc::iovec {
+                iov_base: data.as_ptr() as *mut c_void,
+                iov_len: data.len(),
+            };
+            let mut control = [0u64; CONTROL_BUFFER_WORDS];
+            let mut header: libc::msghdr = mem::zeroed();
+            header.msg_iov = &mut iov;
+            header.msg_iovlen = 1;
+
+            if let Some(fd) = fd {
+                let fd_size = mem::size_of::<RawFd>() as u32;
+                header.msg_control = control.as_mut_ptr() as *mut c_void;
+                header.msg_controllen = libc::CMSG_SPACE(fd_size) as _;
+                let control_message = libc::CMSG_FIRSTHDR(&header);
+                (*control_message).cmsg_level = libc::SOL_SOCKET;
+                (*control_message).cmsg_type = libc::SCM_RIGHTS;
+                (*control_message).cmsg_len = libc::CMSG_LEN(fd_size) as _;
+                ptr::write_unaligned(libc::CMSG_DATA(control_message) as *mut RawFd, fd);
+            }
+
+            let sent = libc::sendmsg(stream.as_raw_fd(), &header, SEND_FLAGS);
+            if sent < 0 {
+                let error = io::Error::last_os_error();
+                if error.kind() == io::ErrorKind::Interrupted {
+                    continue
+                }
+                return Err(error)
+            }
+            return Ok(sent as usize)
+        }
+    }
+}
+
+/// Waits up to `timeout` for any of the given sockets to have data to read, or, for those paired
+/// with `true`, room to write, and returns which ones do.
+///
+/// A timeout of `None` waits forever.
+pub fn wait_until_ready(fds: &[(RawFd, bool)], timeout: Option<Duration>)
+                        -> io::Result<Vec<bool>> {
+    let mut poll_fds: Vec<_> = fds.iter().map(|&(fd, writable)| {
+        let events = if writable { libc::POLLIN | libc::POLLOUT } else { libc::POLLIN };
+        libc::pollfd { fd, events, revents: 0 }
+    }).collect();
+    let timeout = match timeout {
+        None => -1,
+        Some(timeout) => {
+            let millis = timeout.as_secs() * 1000 + timeout.subsec_millis() as u64;
+            millis.min(i32::max_value() as u64) as i32
+        }
+    };
+
+    loop {
+        let result = unsafe {
+            libc::poll(poll_fds.as_mut_ptr(), poll_fds.len() as libc::nfds_t, timeout)
+        };
+        if result >= 0 {
+            break
+        }
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(error)
+        }
+    }
+
+    // Report hangups and errors as readable, so that the caller notices them when it reads.
+    Ok(poll_fds.iter().map(|poll_fd| poll_fd.revents != 0).collect())
+}
+
+/// Returns the identity of the process on the other end of the socket.
+#[cfg(target_os = "linux")]
+pub fn peer_credentials(stream: &UnixStream) -> io::Result<PeerCredentials> {
+    unsafe {
+        let mut credentials: libc::ucred = mem::zeroed();
+        let mut length = mem::size_of::<libc::ucred>() as libc::socklen_t;
+        if libc::getsockopt(stream.as_raw_fd(),
+                            libc::SOL_SOCKET,
+                            libc::SO_PEERCRED,
+                            &mut credentials as *mut libc::ucred as *mut c_void,
+                            &mut length) < 0 {
+            return Err(io::Error::last_os_error())
+        }
+        Ok(PeerCredentials {
+            uid: credentials.uid,
+            gid: credentials.gid,
+            pid: Some(credentials.pid as u32),
+        })
+    }
+}
+
+/// Returns the identity of the process on the other end of the socket.
+#[cfg(not(target_os = "linux"))]
+pub fn peer_credentials(stream: &UnixStream) -> io::Result<PeerCredentials> {
+    let (mut uid, mut gid) = (0, 0);
+    unsafe {
+        if libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) < 0 {
+            return Err(io::Error::last_os_error())
+        }
+    }
+    Ok(PeerCredentials { uid, gid, pid: None })
+}
+
+/// Queues messages for a nonblocking socket and writes them as the socket has room.
+pub struct Sender {
+    buffer: VecDeque<u8>,
+}
+
+impl Sender {
+    pub fn new() -> Sender {
+        Sender { buffer: VecDeque::new() }
+    }
+
+    /// Returns the number of bytes waiting to be written.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    /// Adds an encoded message to the end of the queue.
+    pub fn queue(&mut self, message: &[u8]) {
+        self.buffer.extend(message.iter().cloned())
+    }
+
+    /// Writes as much of the queue as the socket will take without blocking.
+    pub fn flush(&mut self, stream: &UnixStream) -> io::Result<()> {
+        while !self.buffer.is_empty() {
+            let sent = {
+                let (data, _) = self.buffer.as_slices();
+                match send_some(stream, data, None) {
+                    Ok(sent) => sent,
+                    Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                    Err(error) => return Err(error),
+                }
+            };
+            self.buffer.drain(0..sent);
+        }
+        Ok(())
+    }
+}
+
+/// Accumulates bytes and file descriptors read from a socket and splits them into messages.
+///
+/// The kernel hands over file descriptors along with the bytes that were sent with them, and it
+/// ends the read there, so each file descriptor belongs to the message that the last byte read
+/// with it is in.
+pub struct Receiver {
+    buffer: Vec<u8>,
+    // How many bytes came before the start of `buffer` on the socket.
+    buffer_position: u64,
+    // File descriptors that haven't been returned with a message yet, each with the position on
+    // the socket of the message byte that it belongs to.
+    fds: VecDeque<(u64, File)>,
+    max_message_size: usize,
+    max_fds: usize,
+}
+
+impl Receiver {
+    /// Creates a receiver that rejects messages with payloads longer than `max_message_size`,
+    /// and with more than `max_fds` file descriptors.
+    pub fn new(max_message_size: usize, max_fds: usize) -> Receiver {
+        Receiver {
+            buffer: vec![],
+            buffer_position: 0,
+            fds: VecDeque::new(),
+            max_message_size,
+            max_fds,
+        }
+    }
+
+    /// Reads whatever is available from the socket, blocking if nothing is and the socket is
+    /// blocking.
+    ///
+    /// Returns the number of bytes read; zero means that the peer hung up. Fails if more file
+    /// descriptors arrived than fit in one message, which leaves the receiver unusable; to keep
+    /// from failing when a peer sends several messages with file descriptors, take every complete
+    /// message with `next_message` before calling this again.
+    pub fn receive(&mut self, stream: &UnixStream) -> io::Result<usize> {
+        let mut data = vec![0; RECEIVE_BUFFER_SIZE];
+        let mut control = [0u64; CONTROL_BUFFER_WORDS];
+        loop {
+            unsafe {
+                let mut iov = libc::iovec {
+                    iov_base: data.as_mut_ptr() as *mut c_void,
+                    iov_len: data.len(),
+                };
+                let mut header: libc::msghdr = mem::zeroed();
+                header.msg_iov = &mut iov;
+                header.msg_iovlen = 1;
+                header.msg_control = control.as_mut_ptr() as *mut c_void;
+                header.msg_controllen = mem::size_of_val(&control) as _;
+
+                let received = libc::recvmsg(stream.as_raw_fd(), &mut header, RECEIVE_FLAGS);
+                if received < 0 {
+                    let error = io::Error::last_os_error();
+                    if error.kind() == io::ErrorKind::Interrupted {
+                        continue
+                    }
+                    return Err(error)
+                }
+
+                // Take ownership of every file descriptor first, so that they're all closed if
+                // we fail.
+                let mut fds = vec![];
+                let mut control_message = libc::CMSG_FIRSTHDR(&header);
+                while !control_message.is_null() {
+                    if (*control_message).cmsg_level == libc::SOL_SOCKET &&
+                            (*control_message).cmsg_type == libc::SCM_RIGHTS {
+                        let data_length = (*control_message).cmsg_len as usize -
+                            libc::CMSG_LEN(0) as usize;
+                        let fd_data = libc::CMSG_DATA(control_message) as *const RawFd;
+                        for index in 0..(data_length / mem::size_of::<RawFd>()) {
+                            let fd = ptr::read_unaligned(fd_data.add(index));
+                            fds.push(File::from_raw_fd(fd));
+                        }
+                    }
+                    control_message = libc::CMSG_NXTHDR(&header, control_message);
+                }
+
+                // If the control buffer overflowed, the kernel closed the file descriptors that
+                // didn't fit.
+                if header.msg_flags & libc::MSG_CTRUNC != 0 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              "too many file descriptors"))
+                }
+                let received = received as usize;
+                if !fds.is_empty() {
+                    if received == 0 || self.fds.len() + fds.len() > self.max_fds {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                  "too many file descriptors"))
+                    }
+                    let position = self.buffer_position + (self.buffer.len() + received - 1) as u64;
+                    self.fds.extend(fds.into_iter().map(|fd| (position, fd)));
+                }
+
+                self.buffer.extend_from_slice(&data[0..received]);
+                return Ok(received)
+            }
+        }
+    }
+
+    /// Removes and returns the payload of the next complete message, if there is one, along with
+    /// the file descriptors that were sent with it.
+    ///
+    /// Fails if the next message is too long, which leaves the receiver unusable.
+    pub fn next_message(&mut self) -> io::Result<Option<(Vec<u8>, Vec<File>)>> {
+        if self.buffer.len() < 4 {
+            return Ok(None)
+        }
+        let mut length = [0; 4];
+        length.copy_from_slice(&self.buffer[0..4]);
+        let length = u32::from_le_bytes(length) as usize;
+        if length > self.max_message_size {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "message too long"))
+        }
+        let end = 4 + length;
+        if self.buffer.len() < end {
+            return Ok(None)
+        }
+        let payload = self.buffer[4..end].to_vec();
+        self.buffer.drain(0..end);
+        self.buffer_position += end as u64;
+
+        let mut fds = vec![];
+        while self.fds.front().map_or(false, |&(position, _)| position < self.buffer_position) {
+            fds.push(self.fds.pop_front().unwrap().1);
+        }
+        Ok(Some((payload, fds)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use euclid::{Point2D, Rect, Size2D, Vector2D};
+    use image::{Rgba, RgbaImage};
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+
+    use crate::{BlendMode, Capabilities, DropShadow, LayerEffects, LayerId, ScreenshotError};
+    use crate::SurfaceOptions;
+    use super::{Receiver, Reply, Request, Sender};
+
+    fn requests() -> Vec<Request> {
+        let (layer, other) = (LayerId(1), LayerId(0x01020304));
+        let rect = Rect::new(Point2D::new(1.5, -2.0), Size2D::new(30.0, 40.25));
+        let effects = LayerEffects {
+            shadow: Some(DropShadow {
+                offset: Vector2D::new(2.0, 3.0),
+                radius: 4.0,
+                color: Rgba([1, 2, 3, 4]),
+                opacity: 0.5,
+            }),
+            blur_radius: 6.0,
+            backdrop_blur_radius: 7.0,
+            blend_mode: BlendMode::Plus,
+        };
+        vec![
+            Request::BeginTransaction,
+            Request::EndTransaction,
+            Request::AddContainerLayer(layer),
+            Request::AddSurfaceLayer(layer),
+            Request::DeleteLayer(layer),
+            Request::InsertBefore(layer, other, None),
+            Request::InsertBefore(layer, other, Some(LayerId(2))),
+            Request::RemoveFromParent(layer),
+            Request::HostLayer(layer, 7),
+            Request::SetLayerBounds(layer, rect),
+            Request::SetLayerSurfaceOptions(layer, SurfaceOptions::OPAQUE | SurfaceOptions::DEPTH),
+            Request::AttachBuffer(layer, Size2D::new(30, 41)),
+            Request::PresentBuffer(layer, rect),
+            Request::ScreenshotHostedLayer(layer),
+            Request::SetLayerEffects(layer, LayerEffects::default()),
+            Request::SetLayerEffects(layer, effects),
+            Request::SetLayerMask(layer, None),
+            Request::SetLayerMask(layer, Some(other)),
+            Request::AddScrollLayer(layer),
+            Request::SetLayerContentSize(layer, Size2D::new(100.0, 200.0)),
+            Request::SetLayerContentOffset(layer, Vector2D::new(-5.0, 10.5)),
+            Request::StartFling(3, layer, Vector2D::new(0.0, -2000.0)),
+        ]
+    }
+
+    fn replies() -> Vec<Reply> {
+        let image = RgbaImage::from_fn(3, 2, |x, y| Rgba([x as u8, y as u8, 0xff, 0x80]));
+        vec![
+            Reply::TransactionCommitted,
+            Reply::Screenshot(Ok(image)),
+            Reply::Screenshot(Ok(RgbaImage::new(0, 0))),
+            Reply::Screenshot(Err(ScreenshotError::Unsupported)),
+            Reply::Screenshot(Err(ScreenshotError::TransactionFailed)),
+            Reply::Screenshot(Err(ScreenshotError::ReadbackFailed)),
+            Reply::Capabilities(Capabilities {
+                screenshots: true,
+                damage_aware_present: false,
+                transforms: true,
+                native_animations: false,
+                surface_options: SurfaceOptions::OPAQUE | SurfaceOptions::STENCIL,
+                max_texture_size: Some(4096),
+                layer_export: false,
+                video_layers: true,
+                layer_effects: true,
+                backdrop_blur: false,
+                blend_modes: true,
+                layer_masks: false,
+                scroll_clipping: true,
+            }),
+            Reply::Capabilities(Capabilities {
+                screenshots: false,
+                damage_aware_present: true,
+                transforms: false,
+                native_animations: true,
+                surface_options: SurfaceOptions::empty(),
+                max_texture_size: None,
+                layer_export: true,
+                video_layers: false,
+                layer_effects: false,
+                backdrop_blur: true,
+                blend_modes: false,
+                layer_masks: true,
+                scroll_clipping: false,
+            }),
+            Reply::FlingEnded(3, Ok(Vector2D::new(0.0, 32.0))),
+            Reply::FlingEnded(4, Err(())),
+        ]
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        for request in requests() {
+            let message = request.encode();
+            assert_eq!(Request::decode(&message[4..]), Ok(request));
+        }
+    }
+
+    // Images can't be compared directly, so this compares the encodings instead.
+    #[test]
+    fn replies_round_trip() {
+        for reply in replies() {
+            let message = reply.encode();
+            let decoded = Reply::decode(&message[4..]).unwrap();
+            assert_eq!(decoded.encode(), message, "{:?}", reply);
+        }
+    }
+
+    #[test]
+    fn messages_are_length_prefixed() {
+        for message in requests().iter().map(Request::encode)
+                                 .chain(replies().iter().map(Reply::encode)) {
+            let mut length = [0; 4];
+            length.copy_from_slice(&message[0..4]);
+            assert_eq!(u32::from_le_bytes(length) as usize, message.len() - 4);
+        }
+    }
+
+    #[test]
+    fn truncated_payloads_are_rejected() {
+        for request in requests() {
+            let message = request.encode();
+            for end in 4..message.len() {
+                assert!(Request::decode(&message[4..end]).is_err(), "{:?} to {}", request, end);
+            }
+        }
+        for reply in replies() {
+            let message = reply.encode();
+            for end in 4..message.len() {
+                assert!(Reply::decode(&message[4..end]).is_err(), "{:?} to {}", reply, end);
+            }
+        }
+    }
+
+    #[test]
+    fn trailing_bytes_are_rejected() {
+        for request in requests() {
+            let mut message = request.encode();
+            message.push(0);
+            assert!(Request::decode(&message[4..]).is_err(), "{:?}", request);
+        }
+        for reply in replies() {
+            let mut message = reply.encode();
+            message.push(0);
+            assert!(Reply::decode(&message[4..]).is_err(), "{:?}", reply);
+        }
+    }
+
+    #[test]
+    fn unknown_opcodes_are_rejected() {
+        assert!(Request::decode(&[0xff]).is_err());
+        assert!(Reply::decode(&[0xff]).is_err());
+        assert!(Request::decode(&[]).is_err());
+    }
+
+    #[test]
+    fn sender_and_receiver_split_messages() {
+        let (client, server) = UnixStream::pair().unwrap();
+        client.set_nonblocking(true).unwrap();
+        let mut sender = Sender::new();
+        for request in requests() {
+            sender.queue(&request.encode());
+        }
+        sender.flush(&client).unwrap();
+        assert_eq!(sender.len(), 0);
+
+        let mut receiver = Receiver::new(super::MAX_REQUEST_SIZE, super::MAX_REQUEST_FDS);
+        let mut received = vec![];
+        while received.len() < requests().len() {
+            match receiver.next_message().unwrap() {
+                Some((payload, fds)) => {
+                    assert!(fds.is_empty());
+                    received.push(Request::decode(&payload).unwrap())
+                }
+                None => assert!(receiver.receive(&server).unwrap() > 0),
+            }
+        }
+        assert_eq!(received, requests());
+    }
+
+    #[test]
+    fn receiver_rejects_overlong_messages() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let message = Request::SetLayerBounds(LayerId(0), Rect::zero()).encode();
+        super::send(&client, &message, None).unwrap();
+
+        let mut receiver = Receiver::new(message.len() - 5, 0);
+        assert!(receiver.receive(&server).unwrap() > 0);
+        assert!(receiver.next_message().is_err());
+    }
+
+    #[test]
+    fn receiver_returns_fds_with_their_messages() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let file = tempfile::tempfile().unwrap();
+        let (layer, size) = (LayerId(1), Size2D::new(2, 2));
+        super::send(&client, &Request::AddSurfaceLayer(layer).encode(), None).unwrap();
+        super::send(&client, &Request::AttachBuffer(layer, size).encode(), Some(file.as_raw_fd()))
+            .unwrap();
+        super::send(&client, &Request::AddSurfaceLayer(layer).encode(), None).unwrap();
+
+        let mut receiver = Receiver::new(super::MAX_REQUEST_SIZE, super::MAX_REQUEST_FDS);
+        let mut fd_counts = vec![];
+        while fd_counts.len() < 3 {
+            match receiver.next_message().unwrap() {
+                Some((_, fds)) => fd_counts.push(fds.len()),
+                None => assert!(receiver.receive(&server).unwrap() > 0),
+            }
+        }
+        assert_eq!(fd_counts, [0, 1, 0]);
+    }
+
+    #[test]
+    fn receiver_rejects_too_many_fds() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let file = tempfile::tempfile().unwrap();
+        let message = Request::AddSurfaceLayer(LayerId(1)).encode();
+        super::send(&client, &message, Some(file.as_raw_fd())).unwrap();
+
+        let mut receiver = Receiver::new(super::MAX_REQUEST_SIZE, 0);
+        assert!(receiver.receive(&server).is_err());
+    }
+}
@@ -0,0 +1,1074 @@
+// planeshift/src/server.rs
+//
+// Copyright © 2018 The Pathfinder Project Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Displays layer trees built by other processes.
+//!
+//! A `Server` listens on a Unix socket for clients using `backends::remote`, and replays their
+//! layer operations into a `LayerContext` of the server's choosing. A `Delegate` decides where
+//! the clients' root layers are hosted and how their pixels get into the server's surface layers.
+//!
+//! Each client is granted a set of `Permissions` by the delegate when it connects, based on the
+//! credentials of the process on the other end of the socket. When a client disconnects or
+//! misbehaves, all of its layers are removed and deleted; the rest of the server's layer tree is
+//! unaffected.
+
+use euclid::{Point2D, Rect, Size2D, Vector2D};
+use gl::types::{GLint, GLuint};
+use image::RgbaImage;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, File};
+use std::io;
+use std::mem;
+use std::os::raw::c_void;
+use std::os::unix::fs::{FileExt, FileTypeExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::backend::Backend;
+use crate::backends::remote::HostId;
+use crate::protocol::{self, Receiver, Reply, Request, Sender};
+use crate::{Capabilities, LayerContext, LayerId, LayerParent, Promise, ScreenshotError};
+use crate::SurfaceOptions;
+
+// The most requests that a client may send in one transaction. Transactions are buffered until
+// they end, so this keeps a client from making us buffer without limit.
+const MAX_TRANSACTION_LENGTH: usize = 65536;
+
+// The most replies that may be waiting to be sent to a client. Replies are only held back while a
+// client isn't reading them, so a client that exceeds this is disconnected.
+const MAX_PENDING_REPLIES: usize = 65536;
+
+// Replies stay in the pending queue, rather than being encoded, while this many bytes are already
+// waiting to be written to a client.
+const MAX_UNSENT_REPLY_BYTES: usize = 1024 * 1024;
+
+// The widest or tallest buffer that a client may attach to a surface layer.
+const MAX_BUFFER_DIMENSION: u32 = 8192;
+
+bitflags! {
+    /// The operations that a client is allowed to perform, besides building layer trees.
+    pub struct Permissions: u8 {
+        /// The client may host its layers, which makes them visible.
+        const HOST_LAYERS = 0x01;
+        /// The client may take screenshots of its hosted layers.
+        const SCREENSHOTS = 0x02;
+    }
+}
+
+/// Identifies a client of a server for as long as the server runs.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct ClientId(pub u32);
+
+/// The identity of the process on the other end of a client's socket.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PeerCredentials {
+    pub uid: u32,
+    pub gid: u32,
+    /// The process ID, if the platform reports it.
+    pub pid: Option<u32>,
+}
+
+/// Decides how the layers of remote clients are presented.
+pub trait Delegate<B> where B: Backend {
+    /// Called when a client connects. Returns the permissions to grant it, or `None` to refuse
+    /// the connection.
+    ///
+    /// The default implementation grants every permission to every client, relying on the
+    /// socket's file permissions to keep out other users.
+    fn client_connected(&mut self,
+                        _: &mut LayerContext<B>,
+                        _: ClientId,
+                        _: &PeerCredentials)
+                        -> Option<Permissions> {
+        Some(Permissions::all())
+    }
+
+    /// Called after a client has disconnected and all of its layers have been deleted.
+    ///
+    /// This is called outside of any transaction.
+    fn client_disconnected(&mut self, _: &mut LayerContext<B>, _: ClientId) {}
+
+    /// Hosts a client's root layer in the place identified by `host`.
+    ///
+    /// `backends::remote::MAIN_WINDOW_HOST` is the host that clients use when they call
+    /// `host_layer_in_window()`. This is only called for clients with the `HOST_LAYERS`
+    /// permission.
+    fn host_layer(&mut self,
+                  context: &mut LayerContext<B>,
+                  client: ClientId,
+                  layer: LayerId,
+                  host: HostId)
+                  -> Result<(), ()>;
+
+    /// Replaces the contents of a surface layer with pixels that a client drew.
+    ///
+    /// The pixels are premultiplied RGBA, top row first, and have the size of the layer's bounds
+    /// rounded out to whole pixels. `upload_surface_with_gl()` implements this for backends that
+    /// support OpenGL; for the software backend, copy the pixels into `GLContext::pixels_mut()`.
+    fn update_surface(&mut self,
+                      context: &mut LayerContext<B>,
+                      layer: LayerId,
+                      pixels: &RgbaImage,
+                      changed_rect: &Rect<f32>)
+                      -> Result<(), ()>;
+}
+
+/// Accepts connections from remote backends and replays their layer trees.
+pub struct Server {
+    listener: UnixListener,
+    clients: Vec<Client>,
+    next_client_id: u32,
+}
+
+impl Server {
+    /// Listens for clients on a new socket at the given path.
+    ///
+    /// Fails with `AddrInUse` if another server is listening on a socket at that path. A socket
+    /// that nobody is listening on is left over from a server that exited, so it's replaced.
+    pub fn bind<P>(path: P) -> io::Result<Server> where P: AsRef<Path> {
+        let path = path.as_ref();
+        match UnixStream::connect(path) {
+            Ok(_) => {
+                return Err(io::Error::new(io::ErrorKind::AddrInUse,
+                                          "another server is listening on the socket"))
+            }
+            Err(ref error) if error.kind() == io::ErrorKind::ConnectionRefused => {
+                if fs::symlink_metadata(path)?.file_type().is_socket() {
+                    fs::remove_file(path)?;
+                }
+            }
+            Err(_) => {}
+        }
+        Server::from_listener(UnixListener::bind(path)?)
+    }
+
+    /// Listens for clients on an existing socket.
+    pub fn from_listener(listener: UnixListener) -> io::Result<Server> {
+        listener.set_nonblocking(true)?;
+        Ok(Server {
+            listener,
+            clients: vec![],
+            next_client_id: 0,
+        })
+    }
+
+    /// Returns the number of clients currently connected.
+    #[inline]
+    pub fn client_count(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// Waits up to `timeout` for clients to connect or send requests, and handles everything
+    /// that arrived.
+    ///
+    /// Call this from the server's event loop. Replies to screenshot requests are sent once the
+    /// backend produces the screenshots, so the backend's events must be pumped as well. Clients'
+    /// flings run in our layer context, so call `LayerContext::update_flings()` every frame too.
+    /// A timeout of `None` waits until something happens.
+    pub fn dispatch<B, D>(&mut self,
+                          context: &mut LayerContext<B>,
+                          delegate: &mut D,
+                          timeout: Option<Duration>)
+                          -> io::Result<()>
+                          where B: Backend, D: Delegate<B> {
+        // Wait for room to write to clients whose replies are backed up, too.
+        let mut fds = vec![(self.listener.as_raw_fd(), false)];
+        fds.extend(self.clients.iter().map(|client| {
+            (client.stream.as_raw_fd(), !client.sender.is_empty())
+        }));
+        let ready = protocol::wait_until_ready(&fds, timeout)?;
+
+        for (client_index, _) in ready[1..].iter().enumerate().filter(|&(_, &ready)| ready) {
+            let (clients_before, clients_after) = self.clients.split_at_mut(client_index);
+            let (client, clients_after) = clients_after.split_first_mut().unwrap();
+            let others = OtherClients(clients_before, clients_after);
+            if let Err(reason) = client.receive_and_handle(context, delegate, &others) {
+                info!("Disconnecting client {}: {}", client.id, reason);
+                client.dead = true;
+            }
+        }
+
+        for client in &mut self.clients {
+            if !client.dead {
+                if let Err(error) = client.flush_replies() {
+                    info!("Disconnecting client {}: {}", client.id, error);
+                    client.dead = true;
+                }
+            }
+        }
+
+        for client in &mut self.clients {
+            if client.dead {
+                client.destroy_layers(context);
+                delegate.client_disconnected(context, ClientId(client.id));
+            }
+        }
+        self.clients.retain(|client| !client.dead);
+
+        if ready[0] {
+            self.accept_clients(context, delegate)?;
+        }
+        Ok(())
+    }
+
+    fn accept_clients<B, D>(&mut self, context: &mut LayerContext<B>, delegate: &mut D)
+                            -> io::Result<()>
+                            where B: Backend, D: Delegate<B> {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => self.add_client(context, delegate, stream)?,
+                Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    fn add_client<B, D>(&mut self,
+                        context: &mut LayerContext<B>,
+                        delegate: &mut D,
+                        stream: UnixStream)
+                        -> io::Result<()>
+                        where B: Backend, D: Delegate<B> {
+        // Replies are written without blocking, so that a client that stops reading them can't
+        // hold up everyone else.
+        stream.set_nonblocking(true)?;
+
+        let id = self.next_client_id;
+        self.next_client_id += 1;
+
+        let credentials = match protocol::peer_credentials(&stream) {
+            Ok(credentials) => credentials,
+            Err(error) => {
+                warn!("Refusing client {}: couldn't get its credentials: {}", id, error);
+                return Ok(())
+            }
+        };
+        let permissions = match delegate.client_connected(context, ClientId(id), &credentials) {
+            Some(permissions) => permissions,
+            None => {
+                info!("Refusing client {} (uid {})", id, credentials.uid);
+                return Ok(())
+            }
+        };
+        info!("Client {} (uid {}) connected", id, credentials.uid);
+
+        // The client's backend reports these as its own.
+        let capabilities = client_capabilities(context, permissions);
+        let mut pending_replies = VecDeque::new();
+        pending_replies.push_back(PendingReply::Ready(Reply::Capabilities(capabilities)));
+
+        self.clients.push(Client {
+            id,
+            permissions,
+            stream,
+            receiver: Receiver::new(protocol::MAX_REQUEST_SIZE, protocol::MAX_REQUEST_FDS),
+            sender: Sender::new(),
+            dead: false,
+            layers: HashMap::new(),
+            records: HashMap::new(),
+            transaction_level: 0,
+            transaction: vec![],
+            transaction_buffers: VecDeque::new(),
+            pending_replies,
+            flings: vec![],
+        });
+        Ok(())
+    }
+}
+
+/// Copies pixels into a surface layer using OpenGL and presents them.
+///
+/// This is a building block for delegates of backends that support OpenGL. The OpenGL context
+/// must be current, and the `gl` crate's symbols must be loaded.
+pub fn upload_surface_with_gl<B>(context: &mut LayerContext<B>,
+                                 gl_context: &mut B::GLContext,
+                                 layer: LayerId,
+                                 pixels: &RgbaImage,
+                                 changed_rect: &Rect<f32>)
+                                 -> Result<(), ()>
+                                 where B: Backend {
+    let (width, height) = (pixels.width() as GLint, pixels.height() as GLint);
+    let binding = context.bind_layer_to_gl_context(layer, gl_context)?;
+    unsafe {
+        let (mut texture, mut read_framebuffer): (GLuint, GLuint) = (0, 0);
+        gl::GenTextures(1, &mut texture);
+        gl::BindTexture(gl::TEXTURE_2D, texture);
+        gl::TexImage2D(gl::TEXTURE_2D,
+                       0,
+                       gl::RGBA8 as GLint,
+                       width,
+                       height,
+                       0,
+                       gl::RGBA,
+                       gl::UNSIGNED_BYTE,
+                       pixels.as_ptr() as *const c_void);
+
+        gl::GenFramebuffers(1, &mut read_framebuffer);
+        gl::BindFramebuffer(gl::READ_FRAMEBUFFER, read_framebuffer);
+        gl::FramebufferTexture2D(gl::READ_FRAMEBUFFER,
+                                 gl::COLOR_ATTACHMENT0,
+                                 gl::TEXTURE_2D,
+                                 texture,
+                                 0);
+
+        // The pixels are stored top row first, but OpenGL puts the first row at the bottom, so
+        // flip them vertically.
+        gl::BindFramebuffer(gl::DRAW_FRAMEBUFFER, binding.framebuffer);
+        gl::BlitFramebuffer(0, 0, width, height,
+                            0, height, width, 0,
+                            gl::COLOR_BUFFER_BIT,
+                            gl::NEAREST);
+
+        gl::BindFramebuffer(gl::FRAMEBUFFER, binding.framebuffer);
+        gl::DeleteFramebuffers(1, &read_framebuffer);
+        gl::DeleteTextures(1, &texture);
+    }
+    context.present_gl_context(binding, changed_rect)
+}
+
+struct Client {
+    id: u32,
+    permissions: Permissions,
+    stream: UnixStream,
+    receiver: Receiver,
+    sender: Sender,
+    dead: bool,
+
+    // Maps the client's layer IDs to ours.
+    layers: HashMap<LayerId, LayerId>,
+    // Information about each of the client's layers, keyed by our layer ID.
+    records: HashMap<LayerId, LayerRecord>,
+    // How many transactions the client has open.
+    transaction_level: u32,
+    // The requests of the open transaction, which are replayed into our layer context when the
+    // client ends its outermost transaction.
+    transaction: Vec<Request>,
+    // The buffers that arrived with the `AttachBuffer` requests of the open transaction.
+    transaction_buffers: VecDeque<File>,
+    pending_replies: VecDeque<PendingReply>,
+    // The flings in progress, with the IDs that the client gave them.
+    flings: Vec<(u32, Promise<Vector2D<f32>>)>,
+}
+
+// The clients besides the one whose requests are being handled.
+struct OtherClients<'a>(&'a [Client], &'a [Client]);
+
+impl<'a> OtherClients<'a> {
+    fn records(&self) -> impl Iterator<Item = (&'a LayerId, &'a LayerRecord)> {
+        self.0.iter().chain(self.1.iter()).flat_map(|client| client.records.iter())
+    }
+}
+
+struct LayerRecord {
+    kind: LayerKind,
+    child_count: u32,
+    buffer: Option<ClientBuffer>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum LayerKind {
+    Container,
+    Surface,
+    Scroll,
+}
+
+// A buffer that a client draws a surface layer's pixels into.
+struct ClientBuffer {
+    file: File,
+    size: Size2D<u32>,
+}
+
+enum PendingReply {
+    Ready(Reply),
+    Screenshot(Promise<RgbaImage, ScreenshotError>),
+}
+
+impl Client {
+    fn receive_and_handle<B, D>(&mut self,
+                                context: &mut LayerContext<B>,
+                                delegate: &mut D,
+                                others: &OtherClients)
+                                -> Result<(), String>
+                                where B: Backend, D: Delegate<B> {
+        match self.receiver.receive(&self.stream) {
+            Ok(0) => return Err("hung up".to_owned()),
+            Ok(_) => {}
+            Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            Err(error) => return Err(error.to_string()),
+        }
+
+        loop {
+            let (payload, mut fds) = match self.receiver.next_message() {
+                Ok(Some(message)) => message,
+                Ok(None) => return Ok(()),
+                Err(error) => return Err(error.to_string()),
+            };
+            let request = Request::decode(&payload).map_err(|()| "malformed request".to_owned())?;
+
+            // Only `AttachBuffer` requests carry a file descriptor, and they carry exactly one.
+            let fd_count = match request {
+                Request::AttachBuffer(..) => 1,
+                _ => 0,
+            };
+            if fds.len() != fd_count {
+                return Err("wrong number of file descriptors".to_owned())
+            }
+            self.queue_request(context, delegate, others, request, fds.pop())
+                .map_err(|()| "invalid request".to_owned())?;
+        }
+    }
+
+    // Each client's transactions are buffered here and replayed into our layer context in one go
+    // when they end, so a client that leaves a transaction open doesn't hold up anyone else's
+    // changes, and our layer context is never left in a transaction between dispatches.
+    fn queue_request<B, D>(&mut self,
+                           context: &mut LayerContext<B>,
+                           delegate: &mut D,
+                           others: &OtherClients,
+                           request: Request,
+                           fd: Option<File>)
+                           -> Result<(), ()>
+                           where B: Backend, D: Delegate<B> {
+        // Everything besides opening a transaction must happen inside one.
+        match request {
+            Request::BeginTransaction => self.transaction_level += 1,
+            _ if self.transaction_level == 0 => return Err(()),
+            Request::EndTransaction => self.transaction_level -= 1,
+            Request::AttachBuffer(..) => self.transaction_buffers.push_back(fd.ok_or(())?),
+            _ => {}
+        }
+
+        if self.transaction.len() == MAX_TRANSACTION_LENGTH {
+            return Err(())
+        }
+        self.transaction.push(request);
+
+        if self.transaction_level == 0 {
+            self.commit_transaction(context, delegate, others)?;
+        }
+        Ok(())
+    }
+
+    fn commit_transaction<B, D>(&mut self,
+                                context: &mut LayerContext<B>,
+                                delegate: &mut D,
+                                others: &OtherClients)
+                                -> Result<(), ()>
+                                where B: Backend, D: Delegate<B> {
+        let requests = mem::replace(&mut self.transaction, vec![]);
+        context.begin_transaction();
+        let result = requests.into_iter().map(|request| {
+            self.handle_request(context, delegate, others, request)
+        }).collect();
+        context.end_transaction();
+        self.transaction_buffers.clear();
+        result
+    }
+
+    // Returns `Err` if the client broke the rules of the protocol, in which case it's dropped.
+    // Requests that are valid but fail are logged instead.
+    //
+    // This is called inside a transaction on our layer context.
+    fn handle_request<B, D>(&mut self,
+                            context: &mut LayerContext<B>,
+                            delegate: &mut D,
+                            others: &OtherClients,
+                            request: Request)
+                            -> Result<(), ()>
+                            where B: Backend, D: Delegate<B> {
+        match request {
+            Request::BeginTransaction => {}
+            Request::EndTransaction => {
+                self.pending_replies.push_back(PendingReply::Ready(Reply::TransactionCommitted));
+            }
+            Request::AddContainerLayer(client_layer) => {
+                if self.layers.contains_key(&client_layer) {
+                    return Err(())
+                }
+                let layer = context.add_container_layer();
+                self.add_layer(client_layer, layer, LayerKind::Container);
+            }
+            Request::AddSurfaceLayer(client_layer) => {
+                if self.layers.contains_key(&client_layer) {
+                    return Err(())
+                }
+                let layer = context.add_surface_layer();
+                self.add_layer(client_layer, layer, LayerKind::Surface);
+            }
+            Request::AddScrollLayer(client_layer) => {
+                if self.layers.contains_key(&client_layer) {
+                    return Err(())
+                }
+                let layer = context.add_scroll_layer();
+                self.add_layer(client_layer, layer, LayerKind::Scroll);
+            }
+            Request::DeleteLayer(client_layer) => {
+                let layer = self.layer(client_layer)?;
+                if context.parent_of(layer).is_some() || self.records[&layer].child_count > 0 {
+                    return Err(())
+                }
+                context.delete_layer(layer);
+                self.layers.remove(&client_layer);
+                self.records.remove(&layer);
+            }
+            Request::InsertBefore(client_parent, client_new_child, client_reference) => {
+                let (parent, new_child) = (self.layer(client_parent)?,
+                                           self.layer(client_new_child)?);
+                if self.records[&parent].kind == LayerKind::Surface ||
+                        context.parent_of(new_child).is_some() ||
+                        self.is_ancestor(context, new_child, parent) {
+                    return Err(())
+                }
+                let reference = match client_reference {
+                    None => None,
+                    Some(client_reference) => {
+                        let reference = self.layer(client_reference)?;
+                        if context.parent_of(reference) != Some(&LayerParent::Layer(parent)) {
+                            return Err(())
+                        }
+                        Some(reference)
+                    }
+                };
+                context.insert_before(parent, new_child, reference);
+                self.records.get_mut(&parent).unwrap().child_count += 1;
+            }
+            Request::RemoveFromParent(client_layer) => {
+                let layer = self.layer(client_layer)?;
+                let parent = match context.parent_of(layer) {
+                    None => return Err(()),
+                    Some(&LayerParent::NativeHost) | Some(&LayerParent::Mask(_)) => None,
+                    Some(&LayerParent::Layer(parent)) => Some(parent),
+                };
+                context.remove_from_parent(layer);
+
+                // If the parent isn't one of the client's layers, then it's wherever the delegate
+                // hosted the layer, and this unhosts it.
+                if let Some(record) = parent.and_then(|parent| self.records.get_mut(&parent)) {
+                    record.child_count -= 1;
+                }
+            }
+            Request::HostLayer(client_layer, host) => {
+                let layer = self.layer(client_layer)?;
+                if context.parent_of(layer).is_some() {
+                    return Err(())
+                }
+                if !self.permissions.contains(Permissions::HOST_LAYERS) {
+                    warn!("Client {}: not allowed to host layers", self.id);
+                    return Ok(())
+                }
+                if delegate.host_layer(context, ClientId(self.id), layer, HostId(host)).is_err() {
+                    warn!("Client {}: failed to host a layer in host {}", self.id, host);
+                }
+            }
+            Request::SetLayerBounds(client_layer, bounds) => {
+                let layer = self.layer(client_layer)?;
+                context.set_layer_bounds(layer, &bounds);
+            }
+            Request::SetLayerSurfaceOptions(client_layer, options) => {
+                let layer = self.surface_layer(client_layer)?;
+                context.set_layer_surface_options(layer, options);
+            }
+            Request::AttachBuffer(client_layer, size) => {
+                let layer = self.surface_layer(client_layer)?;
+                let file = self.transaction_buffers.pop_front().ok_or(())?;
+
+                // Buffers have the size of the layer, and we read whole buffers into memory, so
+                // keep them to a sensible size.
+                if size != context.layer_bounds(layer).round_out().size.to_u32() ||
+                        size.width > MAX_BUFFER_DIMENSION ||
+                        size.height > MAX_BUFFER_DIMENSION {
+                    return Err(())
+                }
+                self.records.get_mut(&layer).unwrap().buffer = Some(ClientBuffer { file, size });
+            }
+            Request::PresentBuffer(client_layer, changed_rect) => {
+                let layer = self.surface_layer(client_layer)?;
+                let pixels = {
+                    let buffer = self.records[&layer].buffer.as_ref().ok_or(())?;
+                    read_buffer(buffer)
+                };
+                let pixels = match pixels {
+                    Ok(pixels) => pixels,
+                    Err(error) => {
+                        warn!("Client {}: failed to read a surface buffer: {}", self.id, error);
+                        return Ok(())
+                    }
+                };
+                if delegate.update_surface(context, layer, &pixels, &changed_rect).is_err() {
+                    warn!("Client {}: failed to update a surface layer", self.id);
+                }
+            }
+            Request::ScreenshotHostedLayer(client_layer) => {
+                let layer = self.layer(client_layer)?;
+                if !self.permissions.contains(Permissions::SCREENSHOTS) {
+                    let reply = Reply::Screenshot(Err(ScreenshotError::Unsupported));
+                    self.pending_replies.push_back(PendingReply::Ready(reply));
+                    return Ok(())
+                }
+                // The delegate may have put the layer anywhere in our tree, so capture the root
+                // that it ended up in and crop that down to the layer.
+                let (root, rect) = match self.screenshot_rect(context, others, layer) {
+                    Some(root_and_rect) => root_and_rect,
+                    None => {
+                        let reply = Reply::Screenshot(Err(ScreenshotError::Unsupported));
+                        self.pending_replies.push_back(PendingReply::Ready(reply));
+                        return Ok(())
+                    }
+                };
+                let promise = context.screenshot_hosted_layer(root)
+                                     .map(move |image| crop_image(&image, &rect));
+                self.pending_replies.push_back(PendingReply::Screenshot(promise));
+            }
+            Request::SetLayerEffects(client_layer, effects) => {
+                let layer = self.layer(client_layer)?;
+                context.set_layer_effects(layer, &effects);
+            }
+            Request::SetLayerMask(client_layer, client_mask) => {
+                let layer = self.layer(client_layer)?;
+                let mask = match client_mask {
+                    None => None,
+                    Some(client_mask) => {
+                        let mask = self.layer(client_mask)?;
+                        if context.layer_mask(layer) != Some(mask) &&
+                                (context.parent_of(mask).is_some() ||
+                                 self.is_ancestor(context, mask, layer)) {
+                            return Err(())
+                        }
+                        Some(mask)
+                    }
+                };
+                context.set_layer_mask(layer, mask);
+            }
+            Request::SetLayerContentSize(client_layer, size) => {
+                let layer = self.scroll_layer(client_layer)?;
+                context.set_layer_content_size(layer, &size);
+            }
+            Request::SetLayerContentOffset(client_layer, offset) => {
+                let layer = self.scroll_layer(client_layer)?;
+                context.set_layer_content_offset(layer, &offset);
+            }
+            Request::StartFling(id, client_layer, velocity) => {
+                let layer = self.scroll_layer(client_layer)?;
+                // A fling that never slows down would never end.
+                if !velocity.x.is_finite() || !velocity.y.is_finite() {
+                    return Err(())
+                }
+                let promise = context.start_fling(layer, &velocity);
+                self.flings.push((id, promise));
+            }
+        }
+        Ok(())
+    }
+
+    // Sends replies in order, stopping at the first one that isn't ready yet, as far as the socket
+    // has room for them. The rest are sent on later dispatches.
+    fn flush_replies(&mut self) -> io::Result<()> {
+        self.queue_replies();
+        self.sender.flush(&self.stream)?;
+        if self.pending_replies.len() > MAX_PENDING_REPLIES {
+            return Err(io::Error::new(io::ErrorKind::Other, "not reading its replies"))
+        }
+        Ok(())
+    }
+
+    fn queue_replies(&mut self) {
+        // Flings end whenever they end, so they don't wait their turn.
+        let mut fling_index = 0;
+        while fling_index < self.flings.len() {
+            match self.flings[fling_index].1.result() {
+                None => fling_index += 1,
+                Some(result) => {
+                    let (id, _) = self.flings.remove(fling_index);
+                    self.sender.queue(&Reply::FlingEnded(id, result).encode());
+                }
+            }
+        }
+
+        while self.sender.len() < MAX_UNSENT_REPLY_BYTES {
+            let reply = match self.pending_replies.front() {
+                None => return,
+                Some(&PendingReply::Ready(_)) => {
+                    match self.pending_replies.pop_front() {
+                        Some(PendingReply::Ready(reply)) => reply,
+                        _ => unreachable!(),
+                    }
+                }
+                Some(&PendingReply::Screenshot(ref promise)) => {
+                    match promise.result() {
+                        None => return,
+                        Some(result) => {
+                            self.pending_replies.pop_front();
+                            Reply::Screenshot(result)
+                        }
+                    }
+                }
+            };
+            self.sender.queue(&reply.encode());
+        }
+    }
+
+    // Removes all of this client's layers from our layer context.
+    fn destroy_layers<B>(&mut self, context: &mut LayerContext<B>) where B: Backend {
+        // Any transaction that the client left open is never applied.
+        self.transaction.clear();
+        self.transaction_buffers.clear();
+        self.transaction_level = 0;
+        self.flings.clear();
+
+        context.begin_transaction();
+        for &layer in self.records.keys() {
+            if context.parent_of(layer).is_some() {
+                context.remove_from_parent(layer);
+            }
+        }
+        for &layer in self.records.keys() {
+            context.delete_layer(layer);
+        }
+        context.end_transaction();
+
+        self.layers.clear();
+        self.records.clear();
+    }
+
+    // Returns the hosted root that a screenshot of the layer has to capture, and the rect of the
+    // layer within it, or `None` if the layer can't be captured.
+    //
+    // A client may only capture its own pixels, so this refuses while a surface belonging to
+    // another client overlaps the layer.
+    fn screenshot_rect<B>(&self, context: &LayerContext<B>, others: &OtherClients, layer: LayerId)
+                          -> Option<(LayerId, Rect<f32>)>
+                          where B: Backend {
+        let scroll_layers: HashSet<LayerId> =
+            self.records.iter().chain(others.records()).filter_map(|(&layer, record)| {
+                if record.kind == LayerKind::Scroll { Some(layer) } else { None }
+            }).collect();
+        let is_scroll_layer = |layer| scroll_layers.contains(&layer);
+
+        let (root, rect) = rect_in_hosted_root(context, layer, &is_scroll_layer)?;
+        for (&other_layer, record) in others.records() {
+            if record.kind != LayerKind::Surface {
+                continue
+            }
+            if let Some((other_root, other_rect)) = rect_in_hosted_root(context,
+                                                                        other_layer,
+                                                                        &is_scroll_layer) {
+                if other_root == root && other_rect.intersects(&rect) {
+                    warn!("Client {}: refusing a screenshot that another client overlaps",
+                          self.id);
+                    return None
+                }
+            }
+        }
+        Some((root, rect))
+    }
+
+    fn add_layer(&mut self, client_layer: LayerId, layer: LayerId, kind: LayerKind) {
+        self.layers.insert(client_layer, layer);
+        self.records.insert(layer, LayerRecord { kind, child_count: 0, buffer: None });
+    }
+
+    fn layer(&self, client_layer: LayerId) -> Result<LayerId, ()> {
+        self.layers.get(&client_layer).cloned().ok_or(())
+    }
+
+    fn surface_layer(&self, client_layer: LayerId) -> Result<LayerId, ()> {
+        let layer = self.layer(client_layer)?;
+        match self.records[&layer].kind {
+            LayerKind::Surface => Ok(layer),
+            LayerKind::Container | LayerKind::Scroll => Err(()),
+        }
+    }
+
+    fn scroll_layer(&self, client_layer: LayerId) -> Result<LayerId, ()> {
+        let layer = self.layer(client_layer)?;
+        match self.records[&layer].kind {
+            LayerKind::Scroll => Ok(layer),
+            LayerKind::Container | LayerKind::Surface => Err(()),
+        }
+    }
+
+    // Returns true if `ancestor` is `layer` or one of its ancestors.
+    fn is_ancestor<B>(&self, context: &LayerContext<B>, ancestor: LayerId, mut layer: LayerId)
+                      -> bool
+                      where B: Backend {
+        loop {
+            if layer == ancestor {
+                return true
+            }
+            match context.parent_of(layer) {
+                Some(&LayerParent::Layer(parent)) | Some(&LayerParent::Mask(parent)) => {
+                    layer = parent
+                }
+                Some(&LayerParent::NativeHost) | None => return false,
+            }
+        }
+    }
+}
+
+// Returns what a client can do with our layer context, given its permissions.
+//
+// Clients draw surfaces, including video frames, on the CPU, and the protocol has no way to
+// transform, animate, or export layers. Everything else that they send is applied to our layers,
+// so it works if our backend supports it.
+fn client_capabilities<B>(context: &LayerContext<B>, permissions: Permissions) -> Capabilities
+                          where B: Backend {
+    let capabilities = context.capabilities();
+    let max_texture_size = match capabilities.max_texture_size {
+        None => MAX_BUFFER_DIMENSION,
+        Some(max_texture_size) => max_texture_size.min(MAX_BUFFER_DIMENSION),
+    };
+    Capabilities {
+        screenshots: capabilities.screenshots &&
+            permissions.contains(Permissions::HOST_LAYERS | Permissions::SCREENSHOTS),
+        damage_aware_present: false,
+        transforms: false,
+        native_animations: false,
+        surface_options: SurfaceOptions::OPAQUE,
+        max_texture_size: Some(max_texture_size),
+        layer_export: false,
+        video_layers: true,
+        layer_effects: capabilities.layer_effects,
+        backdrop_blur: capabilities.backdrop_blur,
+        blend_modes: capabilities.blend_modes,
+        layer_masks: capabilities.layer_masks,
+        scroll_clipping: capabilities.scroll_clipping,
+    }
+}
+
+fn read_buffer(buffer: &ClientBuffer) -> io::Result<RgbaImage> {
+    let length = buffer.size.width as usize * buffer.size.height as usize * 4;
+
+    // Don't allocate for pixels that aren't there.
+    if buffer.file.metadata()?.len() < length as u64 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "buffer too short"))
+    }
+
+    let mut pixels = vec![0; length];
+    buffer.file.read_exact_at(&mut pixels, 0)?;
+    RgbaImage::from_raw(buffer.size.width, buffer.size.height, pixels).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "buffer size mismatch")
+    })
+}
+
+// Returns the hosted root of the tree that the layer is in, and the rect of the layer relative to
+// it, or `None` if the layer isn't in a hosted tree. `is_scroll_layer` picks out the ancestors
+// whose content offsets shift their descendants.
+fn rect_in_hosted_root<B, F>(context: &LayerContext<B>, layer: LayerId, is_scroll_layer: &F)
+                             -> Option<(LayerId, Rect<f32>)>
+                             where B: Backend, F: Fn(LayerId) -> bool {
+    let mut rect = Rect::new(Point2D::zero(), context.layer_bounds(layer).size);
+    let mut current = layer;
+    loop {
+        match context.parent_of(current) {
+            None => return None,
+            Some(&LayerParent::NativeHost) => return Some((current, rect)),
+            Some(&LayerParent::Layer(parent)) => {
+                rect.origin = rect.origin + context.layer_bounds(current).origin.to_vector();
+                if is_scroll_layer(parent) {
+                    rect.origin = rect.origin - context.layer_content_offset(parent);
+                }
+                current = parent;
+            }
+            Some(&LayerParent::Mask(parent)) => {
+                rect.origin = rect.origin + context.layer_bounds(current).origin.to_vector();
+                current = parent;
+            }
+        }
+    }
+}
+
+fn crop_image(image: &RgbaImage, rect: &Rect<f32>) -> RgbaImage {
+    let image_rect = Rect::new(Point2D::zero(),
+                               Size2D::new(image.width() as f32, image.height() as f32));
+    let rect = rect.round_out().intersection(&image_rect).unwrap_or(Rect::zero());
+    let (left, top) = (rect.origin.x as u32, rect.origin.y as u32);
+    RgbaImage::from_fn(rect.size.width as u32, rect.size.height as u32, |x, y| {
+        *image.get_pixel(left + x, top + y)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use euclid::{Point2D, Rect, Size2D, Vector2D};
+    use image::{Rgba, RgbaImage};
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+    use std::process;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crate::backends::{remote, software};
+    use crate::protocol::{self, Request};
+    use crate::{Connection, LayerContext, LayerId, SurfaceOptions};
+    use super::{ClientId, Delegate, Server, MAX_BUFFER_DIMENSION};
+
+    const SURFACE_SIZE: u32 = 4;
+
+    // Hosts client layers directly and remembers the pixels that they present.
+    struct TestDelegate {
+        gl_context: software::GLContext,
+        presented: Vec<RgbaImage>,
+        disconnected_count: u32,
+    }
+
+    impl Delegate<software::Backend> for TestDelegate {
+        fn client_disconnected(&mut self, _: &mut LayerContext<software::Backend>, _: ClientId) {
+            self.disconnected_count += 1;
+        }
+
+        fn host_layer(&mut self,
+                      context: &mut LayerContext<software::Backend>,
+                      _: ClientId,
+                      layer: LayerId,
+                      host: remote::HostId)
+                      -> Result<(), ()> {
+            assert_eq!(host, remote::MAIN_WINDOW_HOST);
+            unsafe {
+                context.host_layer(Size2D::new(SURFACE_SIZE, SURFACE_SIZE), layer);
+            }
+            Ok(())
+        }
+
+        fn update_surface(&mut self,
+                          context: &mut LayerContext<software::Backend>,
+                          layer: LayerId,
+                          pixels: &RgbaImage,
+                          changed_rect: &Rect<f32>)
+                          -> Result<(), ()> {
+            self.presented.push(pixels.clone());
+            let binding = context.bind_layer_to_gl_context(layer, &mut self.gl_context)?;
+            *self.gl_context.pixels_mut().ok_or(())? = pixels.clone();
+            context.present_gl_context(binding, changed_rect)
+        }
+    }
+
+    // Serves one client on the given socket until it disconnects, and returns the images that
+    // it presented and the number of times that it disconnected.
+    fn serve(name: &str, stream: UnixStream) -> (Vec<RgbaImage>, u32) {
+        let socket_path = env::temp_dir().join(format!("planeshift-server-{}-{}",
+                                                       process::id(),
+                                                       name));
+        let mut server = Server::bind(&socket_path).unwrap();
+        let size = Size2D::new(SURFACE_SIZE, SURFACE_SIZE);
+        let mut context: LayerContext<software::Backend> =
+            LayerContext::with_backend_connection(Connection::Native(size)).unwrap();
+        let gl_context = context.create_gl_context(SurfaceOptions::empty()).unwrap();
+        let mut delegate = TestDelegate { gl_context, presented: vec![], disconnected_count: 0 };
+
+        server.add_client(&mut context, &mut delegate, stream).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(30);
+        while server.client_count() > 0 {
+            assert!(Instant::now() < deadline, "the client never disconnected");
+            server.dispatch(&mut context, &mut delegate, Some(Duration::from_millis(10))).unwrap();
+            context.update_flings();
+        }
+        drop(std::fs::remove_file(&socket_path));
+        (delegate.presented, delegate.disconnected_count)
+    }
+
+    #[test]
+    fn remote_client_presents_and_disconnects() {
+        let (client_stream, server_stream) = UnixStream::pair().unwrap();
+        let server_thread = thread::spawn(move || serve("presents", server_stream));
+
+        let mut context: LayerContext<remote::Backend> =
+            LayerContext::with_backend_connection(Connection::Native(client_stream)).unwrap();
+        let capabilities = context.capabilities();
+        assert!(capabilities.screenshots);
+        assert_eq!(capabilities.max_texture_size, Some(MAX_BUFFER_DIMENSION));
+
+        let mut gl_context = context.create_gl_context(SurfaceOptions::empty()).unwrap();
+        let color = Rgba([0x20, 0x40, 0x60, 0xff]);
+        let bounds = Rect::new(Point2D::zero(), Size2D::new(SURFACE_SIZE, SURFACE_SIZE).to_f32());
+
+        context.begin_transaction();
+        let layer = context.add_surface_layer();
+        context.set_layer_bounds(layer, &bounds);
+        unsafe {
+            context.host_layer(remote::MAIN_WINDOW_HOST, layer);
+        }
+        let binding = context.bind_layer_to_gl_context(layer, &mut gl_context).unwrap();
+        for pixel in gl_context.pixels_mut().unwrap().pixels_mut() {
+            *pixel = color;
+        }
+        context.present_gl_context(binding, &bounds).unwrap();
+        let screenshot = context.screenshot_hosted_layer(layer);
+        context.end_transaction();
+
+        let screenshot = context.wait(&screenshot).unwrap();
+        assert_eq!(screenshot.dimensions(), (SURFACE_SIZE, SURFACE_SIZE));
+        assert!(screenshot.pixels().all(|&pixel| pixel == color));
+
+        drop(context);
+        let (presented, disconnected_count) = server_thread.join().unwrap();
+        assert_eq!(presented.len(), 1);
+        assert!(presented[0].pixels().all(|&pixel| pixel == color));
+        assert_eq!(disconnected_count, 1);
+    }
+
+    #[test]
+    fn remote_flings_run_in_the_server() {
+        let (client_stream, server_stream) = UnixStream::pair().unwrap();
+        let server_thread = thread::spawn(move || serve("flings", server_stream));
+
+        let mut context: LayerContext<remote::Backend> =
+            LayerContext::with_backend_connection(Connection::Native(client_stream)).unwrap();
+        context.begin_transaction();
+        let scroll = context.add_scroll_layer();
+        context.set_layer_bounds(scroll, &Rect::new(Point2D::zero(), Size2D::new(32.0, 32.0)));
+        context.set_layer_content_size(scroll, &Size2D::new(32.0, 64.0));
+        let fling = context.start_fling(scroll, &Vector2D::new(0.0, 2000.0));
+        context.end_transaction();
+
+        // We never update the fling ourselves; the server tells us when it's over.
+        assert_eq!(context.wait(&fling), Ok(Vector2D::new(0.0, 32.0)));
+
+        drop(context);
+        assert_eq!(server_thread.join().unwrap().1, 1);
+    }
+
+    #[test]
+    fn disconnect_clients_that_send_stray_fds() {
+        let (client_stream, server_stream) = UnixStream::pair().unwrap();
+        let server_thread = thread::spawn(move || serve("stray-fds", server_stream));
+
+        // Only `AttachBuffer` requests may carry a file descriptor.
+        let file = tempfile::tempfile().unwrap();
+        let message = Request::BeginTransaction.encode();
+        protocol::send(&client_stream, &message, Some(file.as_raw_fd())).unwrap();
+
+        assert_eq!(server_thread.join().unwrap().1, 1);
+    }
+
+    #[test]
+    fn bind_replaces_only_stale_sockets() {
+        let socket_path = env::temp_dir().join(format!("planeshift-server-{}-bind",
+                                                       process::id()));
+        let server = Server::bind(&socket_path).unwrap();
+        let error = Server::bind(&socket_path).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::AddrInUse);
+
+        // Once the server is gone, its socket is stale.
+        drop(server);
+        drop(Server::bind(&socket_path).unwrap());
+
+        // Files other than sockets are left alone.
+        fs::remove_file(&socket_path).unwrap();
+        fs::write(&socket_path, b"not a socket").unwrap();
+        assert!(Server::bind(&socket_path).is_err());
+        assert_eq!(fs::read(&socket_path).unwrap(), b"not a socket");
+        fs::remove_file(&socket_path).unwrap();
+    }
+}
@@ -0,0 +1,1023 @@
+// planeshift/src/testing.rs
+//
+// Copyright © 2018 The Pathfinder Project Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A conformance suite for backends.
+//!
+//! The suite builds layer trees out of solid-colored surface layers, manipulates them through a
+//! `LayerContext`, and compares screenshots of the result against images of what a correct
+//! backend displays. To run it against a backend, implement `Harness` for that backend and call
+//! `run_suite()`. Harnesses for the software and headless backends are provided.
+//!
+//! This module also has helpers for comparing screenshots against reference images stored as
+//! PNGs. Set the `PLANESHIFT_BLESS` environment variable to `1` to update the references instead.
+
+use euclid::{Point2D, Rect, Size2D, Vector2D};
+use gl;
+use image::{self, ImageError, Rgba, RgbaImage};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::backend::Backend;
+use crate::backends::software;
+use crate::effects;
+use crate::{BlendMode, Capabilities, ColorMatrix, ColorRange, Connection, DropShadow, LayerContext};
+use crate::{LayerEffects, LayerId, ScreenshotError, SurfaceOptions, VideoFrame, VideoPlane};
+use crate::{WaitError, YUVFormat};
+
+#[cfg(target_os = "linux")]
+use crate::backends::gl::GLInterface;
+#[cfg(target_os = "linux")]
+use crate::backends::headless::{self, HeadlessInterface};
+
+/// The width and height, in device pixels, of the target that harnesses host root layers in.
+pub const TARGET_SIZE: u32 = 64;
+
+/// The environment variable that, when set to `1`, makes `check_reference_image()` overwrite
+/// reference images with screenshots instead of comparing against them.
+pub const BLESS_ENV_VAR: &'static str = "PLANESHIFT_BLESS";
+
+// GPU backends may round differently when blending, so allow for small differences.
+const SUITE_TOLERANCE: Tolerance = Tolerance {
+    per_channel: 2,
+    max_differing_pixels: 0,
+};
+
+const RED: Rgba<u8> = Rgba { data: [255, 0, 0, 255] };
+const GREEN: Rgba<u8> = Rgba { data: [0, 255, 0, 255] };
+const BLUE: Rgba<u8> = Rgba { data: [0, 0, 255, 255] };
+const TRANSLUCENT_WHITE: Rgba<u8> = Rgba { data: [128, 128, 128, 128] };
+const WHITE: Rgba<u8> = Rgba { data: [255, 255, 255, 255] };
+// Full-range BT.601 Y = 128, U = 128, V = 200.
+const PINK: Rgba<u8> = Rgba { data: [229, 77, 128, 255] };
+
+/// Adapts a backend to the conformance suite.
+pub trait Harness {
+    type Backend: Backend;
+
+    /// Creates a new layer context for a test case.
+    fn create_context(&mut self) -> Result<LayerContext<Self::Backend>, ()>;
+
+    /// Hosts `root` in a target `TARGET_SIZE` pixels square.
+    ///
+    /// This is called inside a transaction.
+    fn host_root(&mut self, context: &mut LayerContext<Self::Backend>, root: LayerId)
+                 -> Result<(), ()>;
+
+    /// Fills a surface layer with a solid, premultiplied color and presents it.
+    ///
+    /// This is called inside a transaction, after the layer's bounds and surface options have
+    /// been set.
+    fn fill_layer(&mut self,
+                  context: &mut LayerContext<Self::Backend>,
+                  layer: LayerId,
+                  color: Rgba<u8>)
+                  -> Result<(), ()>;
+}
+
+/// A step of a conformance test case that failed.
+#[derive(Debug)]
+pub struct CaseFailure {
+    /// The name of the test case.
+    pub case: &'static str,
+    /// A description of the step that failed.
+    pub step: &'static str,
+    pub reason: FailureReason,
+}
+
+/// Why a step of a conformance test case failed.
+#[derive(Debug)]
+pub enum FailureReason {
+    /// The harness failed to create a context, host the root layer, or fill a layer.
+    Harness,
+    /// The backend failed to take a screenshot.
+    Screenshot(ScreenshotError),
+    /// The backend had nothing to wait for that could complete the screenshot.
+    Stalled,
+    /// The backend didn't notice that an exporting process had rendered a new frame.
+    Unnoticed,
+    /// A fling that the backend animates didn't come to rest where it should have, if at all.
+    Fling(Result<Vector2D<f32>, WaitError<()>>),
+    /// The screenshot didn't match what the backend should have displayed.
+    Mismatch {
+        expected: RgbaImage,
+        actual: RgbaImage,
+    },
+}
+
+/// Runs every test case in the conformance suite and returns the failures, if any.
+///
+/// Each test case stops at its first failure, but the remaining test cases still run. Every case
+/// checks screenshots, so if the backend can't take them, the cases are all skipped.
+pub fn run_suite<H>(harness: &mut H) -> Vec<CaseFailure> where H: Harness {
+    // Each case is also skipped unless the backend has the capability that it needs.
+    let cases: [CaseEntry<H>; 15] = [
+        ("insertion", |_| true, test_insertion),
+        ("reordering", |_| true, test_reordering),
+        ("removal", |_| true, test_removal),
+        ("bounds changes", |_| true, test_bounds_changes),
+        ("nesting", |_| true, test_nesting),
+        ("surface options", |_| true, test_surface_options),
+        ("presenting", |_| true, test_presenting),
+        ("export", |capabilities| capabilities.layer_export, test_export),
+        ("video", |capabilities| capabilities.video_layers, test_video),
+        ("effects", |capabilities| capabilities.layer_effects, test_effects),
+        ("backdrop blur", |capabilities| capabilities.backdrop_blur, test_backdrop_blur),
+        ("blend modes", |capabilities| capabilities.blend_modes, test_blend_modes),
+        ("layer masks", |capabilities| capabilities.layer_masks, test_layer_masks),
+        ("scroll layers", |capabilities| capabilities.scroll_clipping, test_scroll_layers),
+        ("flings", |capabilities| {
+            capabilities.scroll_clipping && capabilities.native_animations
+        }, test_flings),
+    ];
+
+    let mut failures = vec![];
+    for &(name, is_supported, test) in cases.iter() {
+        let result = Case::new(name, harness).and_then(|mut case| {
+            let capabilities = case.context.capabilities();
+            if capabilities.screenshots && is_supported(&capabilities) {
+                test(&mut case)
+            } else {
+                Ok(())
+            }
+        });
+        if let Err(failure) = result {
+            failures.push(failure);
+        }
+    }
+    failures
+}
+
+// Test cases
+
+fn test_insertion<H>(case: &mut Case<H>) -> CaseResult where H: Harness {
+    case.begin();
+    let root = case.root;
+    case.add_surface(root, None, rect(0, 0, 32, 32), SurfaceOptions::empty(), RED)?;
+    let blue = case.add_surface(root, None, rect(16, 16, 32, 32), SurfaceOptions::empty(), BLUE)?;
+    case.check("append children", &[(rect(0, 0, 32, 32), RED), (rect(16, 16, 32, 32), BLUE)])?;
+
+    case.begin();
+    case.add_surface(root, Some(blue), rect(8, 8, 16, 16), SurfaceOptions::empty(), GREEN)?;
+    case.check("insert a child before another", &[
+        (rect(0, 0, 32, 32), RED),
+        (rect(8, 8, 16, 16), GREEN),
+        (rect(16, 16, 32, 32), BLUE),
+    ])
+}
+
+fn test_reordering<H>(case: &mut Case<H>) -> CaseResult where H: Harness {
+    case.begin();
+    let root = case.root;
+    let red = case.add_surface(root, None, rect(0, 0, 32, 32), SurfaceOptions::empty(), RED)?;
+    let blue = case.add_surface(root, None, rect(16, 16, 32, 32), SurfaceOptions::empty(), BLUE)?;
+    case.check("initial order", &[(rect(0, 0, 32, 32), RED), (rect(16, 16, 32, 32), BLUE)])?;
+
+    case.begin();
+    case.context.remove_from_parent(blue);
+    case.context.insert_before(root, blue, Some(red));
+    case.check("move a child to the front", &[
+        (rect(16, 16, 32, 32), BLUE),
+        (rect(0, 0, 32, 32), RED),
+    ])
+}
+
+fn test_removal<H>(case: &mut Case<H>) -> CaseResult where H: Harness {
+    case.begin();
+    let root = case.root;
+    let red = case.add_surface(root, None, rect(0, 0, 32, 32), SurfaceOptions::empty(), RED)?;
+    case.add_surface(root, None, rect(16, 16, 32, 32), SurfaceOptions::empty(), BLUE)?;
+    case.check("initial tree", &[(rect(0, 0, 32, 32), RED), (rect(16, 16, 32, 32), BLUE)])?;
+
+    case.begin();
+    case.context.remove_from_parent(red);
+    case.check("remove a child", &[(rect(16, 16, 32, 32), BLUE)])?;
+
+    case.begin();
+    case.context.delete_layer(red);
+    case.check("delete a removed child", &[(rect(16, 16, 32, 32), BLUE)])
+}
+
+fn test_bounds_changes<H>(case: &mut Case<H>) -> CaseResult where H: Harness {
+    case.begin();
+    let root = case.root;
+    let red = case.add_surface(root, None, rect(0, 0, 16, 16), SurfaceOptions::empty(), RED)?;
+    case.check("initial bounds", &[(rect(0, 0, 16, 16), RED)])?;
+
+    case.begin();
+    case.context.set_layer_bounds(red, &rect(24, 8, 16, 16).to_f32());
+    case.check("move a layer", &[(rect(24, 8, 16, 16), RED)])?;
+
+    // Resizing may reallocate the surface, so its contents have to be redrawn.
+    case.begin();
+    case.context.set_layer_bounds(red, &rect(8, 24, 32, 16).to_f32());
+    case.fill("redraw a resized layer", red, RED)?;
+    case.check("resize a layer", &[(rect(8, 24, 32, 16), RED)])
+}
+
+fn test_nesting<H>(case: &mut Case<H>) -> CaseResult where H: Harness {
+    case.begin();
+    let root = case.root;
+    let container = case.context.add_container_layer();
+    case.context.set_layer_bounds(container, &rect(8, 8, 48, 48).to_f32());
+    case.context.append_child(root, container);
+    case.add_surface(container, None, rect(8, 8, 16, 16), SurfaceOptions::empty(), GREEN)?;
+    case.check("offset a child by its container", &[(rect(16, 16, 16, 16), GREEN)])?;
+
+    case.begin();
+    case.context.set_layer_bounds(container, &rect(24, 0, 48, 48).to_f32());
+    case.check("move a container", &[(rect(32, 8, 16, 16), GREEN)])
+}
+
+fn test_surface_options<H>(case: &mut Case<H>) -> CaseResult where H: Harness {
+    case.begin();
+    let root = case.root;
+    case.add_surface(root, None, rect(0, 0, 64, 64), SurfaceOptions::OPAQUE, BLUE)?;
+    let overlay = case.add_surface(root,
+                                   None,
+                                   rect(16, 16, 32, 32),
+                                   SurfaceOptions::empty(),
+                                   TRANSLUCENT_WHITE)?;
+    case.check("blend a translucent layer", &[
+        (rect(0, 0, 64, 64), BLUE),
+        (rect(16, 16, 32, 32), TRANSLUCENT_WHITE),
+    ])?;
+
+    // Changing surface options may reallocate the surface, so its contents have to be redrawn.
+    case.begin();
+    case.context.set_layer_surface_options(overlay, SurfaceOptions::OPAQUE);
+    case.fill("redraw a layer made opaque", overlay, GREEN)?;
+    case.check("make a layer opaque", &[
+        (rect(0, 0, 64, 64), BLUE),
+        (rect(16, 16, 32, 32), GREEN),
+    ])
+}
+
+fn test_presenting<H>(case: &mut Case<H>) -> CaseResult where H: Harness {
+    case.begin();
+    let root = case.root;
+    let layer = case.add_surface(root, None, rect(0, 0, 32, 32), SurfaceOptions::empty(), RED)?;
+    case.check("initial contents", &[(rect(0, 0, 32, 32), RED)])?;
+
+    case.begin();
+    case.fill("redraw a layer", layer, BLUE)?;
+    case.check("present new contents", &[(rect(0, 0, 32, 32), BLUE)])
+}
+
+// Exports a layer tree from a second context and imports it into the case's context, as another
+// process would. Skipped if the backend can't export layers.
+fn test_export<H>(case: &mut Case<H>) -> CaseResult where H: Harness {
+    let harness_failure = |step| case_failure("export", step, FailureReason::Harness);
+    let mut exporter = case.harness.create_context().map_err(|()| {
+        harness_failure("create an exporting context")
+    })?;
+
+    // The origin of the exported root doesn't affect what importers display.
+    exporter.begin_transaction();
+    let exported = exporter.add_container_layer();
+    exporter.set_layer_bounds(exported, &rect(100, 100, 32, 32).to_f32());
+    let token = exporter.export_layer(exported);
+    let child = exporter.add_surface_layer();
+    exporter.set_layer_bounds(child, &rect(8, 8, 16, 16).to_f32());
+    exporter.append_child(exported, child);
+    let filled = case.harness.fill_layer(&mut exporter, child, RED);
+    exporter.end_transaction();
+    let token = token.map_err(|()| harness_failure("export a layer"))?;
+    filled.map_err(|()| harness_failure("fill an exported layer"))?;
+
+    case.begin();
+    let root = case.root;
+    let imported = case.context.import_layer(&token);
+    let imported = match imported {
+        Ok(imported) => imported,
+        Err(()) => {
+            case.context.end_transaction();
+            return Err(harness_failure("import a layer"))
+        }
+    };
+    case.context.set_layer_bounds(imported, &rect(16, 16, 32, 32).to_f32());
+    case.context.append_child(root, imported);
+    case.check("display an imported layer", &[(rect(24, 24, 16, 16), RED)])?;
+
+    exporter.begin_transaction();
+    let filled = case.harness.fill_layer(&mut exporter, child, BLUE);
+    exporter.end_transaction();
+    filled.map_err(|()| harness_failure("redraw an exported layer"))?;
+
+    // The new frame should show up without the importer changing anything.
+    if !case.context.update_imported_layers() {
+        return Err(case_failure("export", "notice a new frame", FailureReason::Unnoticed))
+    }
+    case.begin();
+    case.check("update an imported layer", &[(rect(24, 24, 16, 16), BLUE)])
+}
+
+// Presents solid-colored frames in both formats to a video layer. Skipped if the backend can't
+// display video.
+fn test_video<H>(case: &mut Case<H>) -> CaseResult where H: Harness {
+    let (luma, chroma) = ([128; 64], [128; 16]);
+    let (v, uv, white) = ([200; 16], [128; 32], [235; 64]);
+
+    case.begin();
+    let root = case.root;
+    let layer = case.context.add_video_layer();
+    case.context.set_layer_bounds(layer, &rect(8, 8, 16, 16).to_f32());
+    case.context.append_child(root, layer);
+    case.present_frame("present an I420 frame", layer, &VideoFrame {
+        format: YUVFormat::I420,
+        color_matrix: ColorMatrix::BT601,
+        color_range: ColorRange::Full,
+        size: Size2D::new(8, 8),
+        planes: &[
+            VideoPlane::Memory { data: &luma, stride: 8 },
+            VideoPlane::Memory { data: &chroma, stride: 4 },
+            VideoPlane::Memory { data: &v, stride: 4 },
+        ],
+    })?;
+    case.check("display an I420 frame", &[(rect(8, 8, 16, 16), PINK)])?;
+
+    // Limited-range video is white at Y = 235.
+    case.begin();
+    case.present_frame("present an NV12 frame", layer, &VideoFrame {
+        format: YUVFormat::NV12,
+        color_matrix: ColorMatrix::BT709,
+        color_range: ColorRange::Limited,
+        size: Size2D::new(8, 8),
+        planes: &[
+            VideoPlane::Memory { data: &white, stride: 8 },
+            VideoPlane::Memory { data: &uv, stride: 8 },
+        ],
+    })?;
+    case.check("display an NV12 frame", &[(rect(8, 8, 16, 16), WHITE)])
+}
+
+// Casts a shadow with no blur, so that the expected pixels are exact, and then removes it.
+// Skipped if the backend can't draw effects.
+fn test_effects<H>(case: &mut Case<H>) -> CaseResult where H: Harness {
+    case.begin();
+    let root = case.root;
+    let layer = case.add_surface(root, None, rect(8, 8, 16, 16), SurfaceOptions::empty(), RED)?;
+    case.context.set_layer_effects(layer, &LayerEffects {
+        shadow: Some(DropShadow {
+            offset: Vector2D::new(8.0, 8.0),
+            radius: 0.0,
+            color: BLUE,
+            opacity: 1.0,
+        }),
+        ..LayerEffects::default()
+    });
+    case.check("cast a shadow", &[(rect(16, 16, 16, 16), BLUE), (rect(8, 8, 16, 16), RED)])?;
+
+    case.begin();
+    case.context.set_layer_effects(layer, &LayerEffects::default());
+    case.check("remove a shadow", &[(rect(8, 8, 16, 16), RED)])
+}
+
+// Frosts a translucent layer over the edge between two colors, with an opaque layer above it
+// whose color must not bleed into the blur. Skipped if the backend can't blur backdrops.
+fn test_backdrop_blur<H>(case: &mut Case<H>) -> CaseResult where H: Harness {
+    case.begin();
+    let root = case.root;
+    case.add_surface(root, None, rect(0, 0, 64, 64), SurfaceOptions::OPAQUE, BLUE)?;
+    case.add_surface(root, None, rect(24, 0, 40, 64), SurfaceOptions::OPAQUE, RED)?;
+    let glass = case.add_surface(root,
+                                 None,
+                                 rect(8, 8, 32, 32),
+                                 SurfaceOptions::empty(),
+                                 TRANSLUCENT_WHITE)?;
+    case.context.set_layer_effects(glass, &LayerEffects {
+        backdrop_blur_radius: 4.0,
+        ..LayerEffects::default()
+    });
+    case.add_surface(root, None, rect(36, 36, 16, 16), SurfaceOptions::OPAQUE, GREEN)?;
+
+    let mut expected = paint(&[(rect(0, 0, 64, 64), BLUE), (rect(24, 0, 40, 64), RED)]);
+    effects::blur_backdrop(&mut expected, &rect(8, 8, 32, 32), 4.0);
+    paint_over(&mut expected, &[
+        (rect(8, 8, 32, 32), TRANSLUCENT_WHITE),
+        (rect(36, 36, 16, 16), GREEN),
+    ]);
+    case.check_image("blur a backdrop", expected)
+}
+
+// Blends layers over an opaque and a translucent backdrop, with modes that blending can express
+// and modes that it can't. Skipped if the backend can't blend layers.
+fn test_blend_modes<H>(case: &mut Case<H>) -> CaseResult where H: Harness {
+    let backdrop = [(rect(0, 0, 64, 32), GREEN), (rect(0, 32, 64, 32), TRANSLUCENT_WHITE)];
+    let layers = [
+        (rect(4, 16, 16, 32), PINK, BlendMode::Multiply),
+        (rect(24, 16, 16, 32), TRANSLUCENT_WHITE, BlendMode::Screen),
+        (rect(44, 16, 16, 32), PINK, BlendMode::Difference),
+    ];
+
+    case.begin();
+    let root = case.root;
+    for &(rect, color) in &backdrop {
+        let options = match color[3] {
+            255 => SurfaceOptions::OPAQUE,
+            _ => SurfaceOptions::empty(),
+        };
+        case.add_surface(root, None, rect, options, color)?;
+    }
+    for &(rect, color, blend_mode) in &layers {
+        let layer = case.add_surface(root, None, rect, SurfaceOptions::empty(), color)?;
+        case.context.set_layer_effects(layer, &LayerEffects {
+            blend_mode,
+            ..LayerEffects::default()
+        });
+    }
+
+    let mut expected = paint(&backdrop);
+    for &(rect, color, blend_mode) in &layers {
+        for (x, y, pixel) in expected.enumerate_pixels_mut() {
+            if rect.contains(&Point2D::new(x as i32, y as i32)) {
+                *pixel = effects::blend(&color, pixel, blend_mode);
+            }
+        }
+    }
+    case.check_image("blend layers", expected)
+}
+
+// Masks a layer with a translucent layer that covers part of it, drawing the mask after it's
+// attached, and then removes the mask. Skipped if the backend can't mask layers.
+fn test_layer_masks<H>(case: &mut Case<H>) -> CaseResult where H: Harness {
+    case.begin();
+    let root = case.root;
+    let layer = case.add_surface(root, None, rect(8, 8, 32, 32), SurfaceOptions::empty(), RED)?;
+    let mask = case.context.add_surface_layer();
+    case.context.set_layer_bounds(mask, &rect(8, 8, 16, 16).to_f32());
+    case.context.set_layer_mask(layer, Some(mask));
+    case.fill("fill a mask", mask, TRANSLUCENT_WHITE)?;
+    let translucent_red = Rgba { data: [128, 0, 0, 128] };
+    case.check("mask a layer", &[(rect(16, 16, 16, 16), translucent_red)])?;
+
+    case.begin();
+    case.context.set_layer_mask(layer, None);
+    case.check("remove a mask", &[(rect(8, 8, 32, 32), RED)])
+}
+
+fn test_scroll_layers<H>(case: &mut Case<H>) -> CaseResult where H: Harness {
+    case.begin();
+    let root = case.root;
+    let scroll = case.context.add_scroll_layer();
+    case.context.set_layer_bounds(scroll, &rect(8, 8, 32, 32).to_f32());
+    case.context.set_layer_content_size(scroll, &Size2D::new(64.0, 64.0));
+    case.context.append_child(root, scroll);
+    case.add_surface(scroll, None, rect(0, 0, 64, 64), SurfaceOptions::empty(), RED)?;
+    case.add_surface(scroll, None, rect(32, 32, 32, 32), SurfaceOptions::empty(), BLUE)?;
+    case.check("clip scrolled content", &[(rect(8, 8, 32, 32), RED)])?;
+
+    case.begin();
+    case.context.set_layer_content_offset(scroll, &Vector2D::new(32.0, 32.0));
+    case.check("scroll content", &[(rect(8, 8, 32, 32), BLUE)])
+}
+
+fn test_flings<H>(case: &mut Case<H>) -> CaseResult where H: Harness {
+    case.begin();
+    let root = case.root;
+    let scroll = case.context.add_scroll_layer();
+    case.context.set_layer_bounds(scroll, &rect(8, 8, 32, 32).to_f32());
+    case.context.set_layer_content_size(scroll, &Size2D::new(32.0, 64.0));
+    case.context.append_child(root, scroll);
+    case.add_surface(scroll, None, rect(0, 0, 32, 32), SurfaceOptions::empty(), RED)?;
+    case.add_surface(scroll, None, rect(0, 32, 32, 32), SurfaceOptions::empty(), BLUE)?;
+    case.check("before the fling", &[(rect(8, 8, 32, 32), RED)])?;
+
+    // Nothing ends a transaction while the fling is in progress, so the backend has to animate
+    // it by itself.
+    case.begin();
+    let fling = case.context.start_fling(scroll, &Vector2D::new(0.0, 2000.0));
+    case.context.end_transaction();
+    match case.context.wait(&fling) {
+        Ok(offset) if offset == Vector2D::new(0.0, 32.0) => {}
+        result => return Err(case_failure(case.name, "fling", FailureReason::Fling(result))),
+    }
+
+    case.begin();
+    case.check("after the fling", &[(rect(8, 8, 32, 32), BLUE)])
+}
+
+// Test case infrastructure
+
+type CaseResult = Result<(), CaseFailure>;
+
+// A test case: its name, whether the backend can run it, and the function that runs it.
+type CaseEntry<H> = (&'static str, fn(&Capabilities) -> bool, fn(&mut Case<H>) -> CaseResult);
+
+struct Case<'a, H> where H: Harness {
+    name: &'static str,
+    harness: &'a mut H,
+    context: LayerContext<H::Backend>,
+    root: LayerId,
+}
+
+impl<'a, H> Case<'a, H> where H: Harness {
+    fn new(name: &'static str, harness: &'a mut H) -> Result<Case<'a, H>, CaseFailure> {
+        let mut context = match harness.create_context() {
+            Ok(context) => context,
+            Err(()) => return Err(case_failure(name, "create a context", FailureReason::Harness)),
+        };
+
+        context.begin_transaction();
+        let root = context.add_container_layer();
+        let target_size = TARGET_SIZE as i32;
+        context.set_layer_bounds(root, &rect(0, 0, target_size, target_size).to_f32());
+        let result = harness.host_root(&mut context, root);
+        context.end_transaction();
+        if result.is_err() {
+            return Err(case_failure(name, "host the root layer", FailureReason::Harness))
+        }
+
+        Ok(Case { name, harness, context, root })
+    }
+
+    fn begin(&mut self) {
+        self.context.begin_transaction();
+    }
+
+    fn add_surface(&mut self,
+                   parent: LayerId,
+                   reference: Option<LayerId>,
+                   bounds: Rect<i32>,
+                   options: SurfaceOptions,
+                   color: Rgba<u8>)
+                   -> Result<LayerId, CaseFailure> {
+        let layer = self.context.add_surface_layer();
+        self.context.set_layer_surface_options(layer, options);
+        self.context.set_layer_bounds(layer, &bounds.to_f32());
+        self.context.insert_before(parent, layer, reference);
+        self.fill("fill a new layer", layer, color)?;
+        Ok(layer)
+    }
+
+    fn fill(&mut self, step: &'static str, layer: LayerId, color: Rgba<u8>)
+            -> Result<(), CaseFailure> {
+        match self.harness.fill_layer(&mut self.context, layer, color) {
+            Ok(()) => Ok(()),
+            Err(()) => Err(case_failure(self.name, step, FailureReason::Harness)),
+        }
+    }
+
+    fn present_frame(&mut self, step: &'static str, layer: LayerId, frame: &VideoFrame)
+                     -> Result<(), CaseFailure> {
+        match self.context.present_video_frame(layer, frame) {
+            Ok(()) => Ok(()),
+            Err(()) => Err(case_failure(self.name, step, FailureReason::Harness)),
+        }
+    }
+
+    // Ends the current transaction and checks that the root layer displays the given rects,
+    // painted in order.
+    fn check(&mut self, step: &'static str, expected: &[(Rect<i32>, Rgba<u8>)])
+             -> Result<(), CaseFailure> {
+        self.check_image(step, paint(expected))
+    }
+
+    // Ends the current transaction and checks that the root layer displays the given image.
+    fn check_image(&mut self, step: &'static str, expected: RgbaImage)
+                   -> Result<(), CaseFailure> {
+        let promise = self.context.screenshot_hosted_layer(self.root);
+        self.context.end_transaction();
+        let actual = match self.context.wait(&promise) {
+            Ok(actual) => actual,
+            Err(WaitError::Rejected(err)) => {
+                return Err(case_failure(self.name, step, FailureReason::Screenshot(err)))
+            }
+            Err(WaitError::Stalled) => {
+                return Err(case_failure(self.name, step, FailureReason::Stalled))
+            }
+        };
+
+        if compare_images(&expected, &actual, &SUITE_TOLERANCE).is_ok() {
+            Ok(())
+        } else {
+            Err(case_failure(self.name, step, FailureReason::Mismatch { expected, actual }))
+        }
+    }
+}
+
+fn case_failure(case: &'static str, step: &'static str, reason: FailureReason) -> CaseFailure {
+    CaseFailure { case, step, reason }
+}
+
+fn rect(x: i32, y: i32, width: i32, height: i32) -> Rect<i32> {
+    Rect::new(Point2D::new(x, y), Size2D::new(width, height))
+}
+
+// Paints premultiplied solid rects over a transparent background, in order.
+fn paint(rects: &[(Rect<i32>, Rgba<u8>)]) -> RgbaImage {
+    let mut image = RgbaImage::new(TARGET_SIZE, TARGET_SIZE);
+    paint_over(&mut image, rects);
+    image
+}
+
+// Paints premultiplied solid rects over an image, in order.
+fn paint_over(image: &mut RgbaImage, rects: &[(Rect<i32>, Rgba<u8>)]) {
+    for &(rect, color) in rects {
+        for (x, y, pixel) in image.enumerate_pixels_mut() {
+            if rect.contains(&Point2D::new(x as i32, y as i32)) {
+                let inv_alpha = 255 - color[3] as u32;
+                for channel in 0..4 {
+                    let value = color[channel] as u32 + (pixel[channel] as u32 * inv_alpha + 127) /
+                        255;
+                    pixel[channel] = value.min(255) as u8;
+                }
+            }
+        }
+    }
+}
+
+// Image comparison
+
+/// How much two images may differ and still be considered to match.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Tolerance {
+    /// The largest difference in any one channel for which two pixels are considered the same.
+    pub per_channel: u8,
+    /// The largest number of pixels that may differ for two images to be considered the same.
+    pub max_differing_pixels: u32,
+}
+
+impl Default for Tolerance {
+    /// Returns a tolerance that requires images to match exactly.
+    fn default() -> Tolerance {
+        Tolerance { per_channel: 0, max_differing_pixels: 0 }
+    }
+}
+
+/// Describes how two images that don't match differ.
+#[derive(Debug)]
+pub struct ImageMismatch {
+    /// The number of pixels that differ by more than the per-channel tolerance.
+    ///
+    /// If the images have different sizes, pixels that fall outside either image count as
+    /// differing.
+    pub differing_pixels: u32,
+    /// The largest difference in any one channel of any pixel.
+    pub max_channel_difference: u8,
+    /// An image that highlights the differing pixels in red on a faded copy of the expected
+    /// image.
+    pub diff: RgbaImage,
+}
+
+/// The result of a failed `check_reference_image()`.
+#[derive(Debug)]
+pub enum ReferenceImageError {
+    /// The reference image could not be loaded.
+    Load(ImageError),
+    /// The screenshot didn't match the reference. The diff image, and the screenshot itself, were
+    /// written to the given paths.
+    Mismatch {
+        mismatch: ImageMismatch,
+        diff_path: PathBuf,
+        actual_path: PathBuf,
+    },
+    /// A new reference image, or a diff image, could not be written.
+    Write(io::Error),
+}
+
+/// Compares two images, allowing for the given tolerance.
+pub fn compare_images(expected: &RgbaImage, actual: &RgbaImage, tolerance: &Tolerance)
+                      -> Result<(), ImageMismatch> {
+    let (width, height) = (expected.width().max(actual.width()),
+                           expected.height().max(actual.height()));
+    let mut diff = RgbaImage::new(width, height);
+    let (mut differing_pixels, mut max_channel_difference) = (0, 0);
+
+    for (x, y, diff_pixel) in diff.enumerate_pixels_mut() {
+        let in_expected = x < expected.width() && y < expected.height();
+        let in_actual = x < actual.width() && y < actual.height();
+        if !in_expected || !in_actual {
+            differing_pixels += 1;
+            max_channel_difference = 255;
+            *diff_pixel = DIFF_COLOR;
+            continue
+        }
+
+        let (expected_pixel, actual_pixel) = (expected.get_pixel(x, y), actual.get_pixel(x, y));
+        let channel_difference = (0..4).map(|channel| {
+            (expected_pixel[channel] as i32 - actual_pixel[channel] as i32).abs() as u8
+        }).max().unwrap();
+        max_channel_difference = max_channel_difference.max(channel_difference);
+
+        if channel_difference > tolerance.per_channel {
+            differing_pixels += 1;
+            *diff_pixel = DIFF_COLOR;
+        } else {
+            // Fade the matching pixels so that the differences stand out.
+            let luminance = (expected_pixel[0] as u32 * 3 +
+                             expected_pixel[1] as u32 * 6 +
+                             expected_pixel[2] as u32) / 10;
+            let faded = (255 - (255 - luminance) / 4) as u8;
+            *diff_pixel = Rgba { data: [faded, faded, faded, 255] };
+        }
+    }
+
+    if differing_pixels <= tolerance.max_differing_pixels {
+        Ok(())
+    } else {
+        Err(ImageMismatch { differing_pixels, max_channel_difference, diff })
+    }
+}
+
+/// Compares a screenshot against a reference PNG, allowing for the given tolerance.
+///
+/// On mismatch, writes the diff image to `NAME.diff.png` and the screenshot to `NAME.actual.png`
+/// next to the reference `NAME.png`.
+///
+/// If the `PLANESHIFT_BLESS` environment variable is `1`, the screenshot is written to the
+/// reference path instead, creating any missing directories, and no comparison is done.
+pub fn check_reference_image<P>(actual: &RgbaImage, reference_path: P, tolerance: &Tolerance)
+                                -> Result<(), ReferenceImageError>
+                                where P: AsRef<Path> {
+    check_or_bless_reference_image(actual, reference_path.as_ref(), tolerance, bless_mode())
+}
+
+// Does the work of `check_reference_image()`, with bless mode passed in so that tests don't have
+// to set the environment variable.
+fn check_or_bless_reference_image(actual: &RgbaImage,
+                                  reference_path: &Path,
+                                  tolerance: &Tolerance,
+                                  bless: bool)
+                                  -> Result<(), ReferenceImageError> {
+    if bless {
+        if let Some(parent) = reference_path.parent() {
+            fs::create_dir_all(parent).map_err(ReferenceImageError::Write)?;
+        }
+        return actual.save(reference_path).map_err(ReferenceImageError::Write)
+    }
+
+    let expected = image::open(reference_path).map_err(ReferenceImageError::Load)?.to_rgba();
+    let mismatch = match compare_images(&expected, actual, tolerance) {
+        Ok(()) => return Ok(()),
+        Err(mismatch) => mismatch,
+    };
+
+    let diff_path = reference_path.with_extension("diff.png");
+    let actual_path = reference_path.with_extension("actual.png");
+    mismatch.diff.save(&diff_path).map_err(ReferenceImageError::Write)?;
+    actual.save(&actual_path).map_err(ReferenceImageError::Write)?;
+    Err(ReferenceImageError::Mismatch { mismatch, diff_path, actual_path })
+}
+
+/// Returns true if reference images should be updated rather than compared against.
+pub fn bless_mode() -> bool {
+    match env::var(BLESS_ENV_VAR) {
+        Ok(ref value) => value == "1",
+        Err(_) => false,
+    }
+}
+
+const DIFF_COLOR: Rgba<u8> = Rgba { data: [255, 0, 0, 255] };
+
+// Harnesses
+
+/// Fills a surface layer with a solid color using OpenGL and presents it.
+///
+/// This is a building block for harnesses of backends that support OpenGL. The OpenGL context
+/// must be current, and the `gl` crate's symbols must be loaded.
+pub fn fill_layer_with_gl<B>(context: &mut LayerContext<B>,
+                             gl_context: &mut B::GLContext,
+                             layer: LayerId,
+                             color: Rgba<u8>)
+                             -> Result<(), ()>
+                             where B: Backend {
+    let size = context.layer_bounds(layer).round_out().size;
+    let binding = context.bind_layer_to_gl_context(layer, gl_context)?;
+    unsafe {
+        gl::BindFramebuffer(gl::FRAMEBUFFER, binding.framebuffer);
+        gl::Viewport(0, 0, size.width as i32, size.height as i32);
+        gl::ClearColor(color[0] as f32 / 255.0,
+                       color[1] as f32 / 255.0,
+                       color[2] as f32 / 255.0,
+                       color[3] as f32 / 255.0);
+        gl::Clear(gl::COLOR_BUFFER_BIT);
+    }
+    context.present_gl_context(binding, &Rect::new(Point2D::zero(), size))
+}
+
+/// Runs the conformance suite against the software backend.
+pub struct SoftwareHarness;
+
+impl Harness for SoftwareHarness {
+    type Backend = software::Backend;
+
+    fn create_context(&mut self) -> Result<LayerContext<software::Backend>, ()> {
+        let target_size = Size2D::new(TARGET_SIZE, TARGET_SIZE);
+        LayerContext::with_backend_connection(Connection::Native(target_size)).map_err(drop)
+    }
+
+    fn host_root(&mut self, context: &mut LayerContext<software::Backend>, root: LayerId)
+                 -> Result<(), ()> {
+        unsafe {
+            context.host_layer(Size2D::new(TARGET_SIZE, TARGET_SIZE), root);
+        }
+        Ok(())
+    }
+
+    fn fill_layer(&mut self,
+                  context: &mut LayerContext<software::Backend>,
+                  layer: LayerId,
+                  color: Rgba<u8>)
+                  -> Result<(), ()> {
+        let size = context.layer_bounds(layer).round_out().size;
+        let mut gl_context = context.create_gl_context(SurfaceOptions::empty())?;
+        let binding = context.bind_layer_to_gl_context(layer, &mut gl_context)?;
+        for pixel in gl_context.pixels_mut().ok_or(())?.pixels_mut() {
+            *pixel = color;
+        }
+        context.present_gl_context(binding, &Rect::new(Point2D::zero(), size))
+    }
+}
+
+/// Runs the conformance suite against the headless EGL backend.
+#[cfg(target_os = "linux")]
+pub struct HeadlessHarness {
+    // The target for the root layer of the test case in progress.
+    host: Option<HeadlessInterface>,
+}
+
+#[cfg(target_os = "linux")]
+impl HeadlessHarness {
+    #[inline]
+    pub fn new() -> HeadlessHarness {
+        HeadlessHarness { host: None }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Harness for HeadlessHarness {
+    type Backend = headless::Backend;
+
+    fn create_context(&mut self) -> Result<LayerContext<headless::Backend>, ()> {
+        let target_size = Size2D::new(TARGET_SIZE, TARGET_SIZE);
+        let connection = HeadlessInterface::new(&target_size)?;
+        self.host = Some(connection.create_shared(&target_size)?);
+        let connection: Box<dyn GLInterface> = Box::new(connection);
+        LayerContext::with_backend_connection(Connection::Native(connection)).map_err(drop)
+    }
+
+    fn host_root(&mut self, context: &mut LayerContext<headless::Backend>, root: LayerId)
+                 -> Result<(), ()> {
+        let host = self.host.take().ok_or(())?;
+        unsafe {
+            context.host_layer(Box::new(host), root);
+        }
+        Ok(())
+    }
+
+    fn fill_layer(&mut self,
+                  context: &mut LayerContext<headless::Backend>,
+                  layer: LayerId,
+                  color: Rgba<u8>)
+                  -> Result<(), ()> {
+        let mut gl_context = context.create_gl_context(SurfaceOptions::empty())?;
+        fill_layer_with_gl(context, &mut gl_context, layer, color)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+    use std::fs;
+    use std::path::Path;
+    use tempfile;
+    use super::{DIFF_COLOR, ReferenceImageError, Tolerance};
+    use super::{check_or_bless_reference_image, compare_images};
+
+    const GRAY: Rgba<u8> = Rgba { data: [128, 128, 128, 255] };
+
+    // Returns a gray image with the given number of pixels in the top row brightened by `delta`.
+    fn brightened(changed_pixels: u32, delta: u8) -> RgbaImage {
+        let mut image = RgbaImage::from_pixel(4, 4, GRAY);
+        for x in 0..changed_pixels {
+            image.put_pixel(x, 0, Rgba { data: [128 + delta, 128, 128, 255] });
+        }
+        image
+    }
+
+    fn load(path: &Path) -> RgbaImage {
+        image::open(path).unwrap().to_rgba()
+    }
+
+    fn assert_same_image(a: &RgbaImage, b: &RgbaImage) {
+        assert_eq!(a.dimensions(), b.dimensions());
+        assert_eq!(&**a, &**b);
+    }
+
+    fn tolerance(per_channel: u8, max_differing_pixels: u32) -> Tolerance {
+        Tolerance { per_channel, max_differing_pixels }
+    }
+
+    #[test]
+    fn identical_images_match_exactly() {
+        let image = RgbaImage::from_pixel(4, 4, GRAY);
+        assert!(compare_images(&image, &image.clone(), &Tolerance::default()).is_ok());
+    }
+
+    #[test]
+    fn per_channel_tolerance_is_inclusive() {
+        let expected = brightened(0, 0);
+        assert!(compare_images(&expected, &brightened(4, 2), &tolerance(2, 0)).is_ok());
+
+        let mismatch = compare_images(&expected, &brightened(4, 3), &tolerance(2, 0)).unwrap_err();
+        assert_eq!(mismatch.differing_pixels, 4);
+        assert_eq!(mismatch.max_channel_difference, 3);
+    }
+
+    #[test]
+    fn max_differing_pixels_is_inclusive() {
+        let expected = brightened(0, 0);
+        assert!(compare_images(&expected, &brightened(2, 10), &tolerance(0, 2)).is_ok());
+
+        let mismatch = compare_images(&expected, &brightened(3, 10), &tolerance(0, 2)).unwrap_err();
+        assert_eq!(mismatch.differing_pixels, 3);
+        assert_eq!(mismatch.max_channel_difference, 10);
+    }
+
+    #[test]
+    fn diff_highlights_differing_pixels() {
+        let mismatch = compare_images(&brightened(0, 0), &brightened(1, 10), &tolerance(0, 0))
+            .unwrap_err();
+        assert_eq!(mismatch.diff.dimensions(), (4, 4));
+        assert_eq!(*mismatch.diff.get_pixel(0, 0), DIFF_COLOR);
+        for (x, y, pixel) in mismatch.diff.enumerate_pixels() {
+            if (x, y) != (0, 0) {
+                assert_ne!(*pixel, DIFF_COLOR);
+            }
+        }
+    }
+
+    #[test]
+    fn pixels_outside_either_image_differ() {
+        let expected = RgbaImage::from_pixel(4, 4, GRAY);
+        let actual = RgbaImage::from_pixel(4, 2, GRAY);
+        let mismatch = compare_images(&expected, &actual, &tolerance(255, 0)).unwrap_err();
+        assert_eq!(mismatch.differing_pixels, 8);
+        assert_eq!(mismatch.max_channel_difference, 255);
+        assert_eq!(*mismatch.diff.get_pixel(0, 3), DIFF_COLOR);
+        assert!(compare_images(&expected, &actual, &tolerance(0, 8)).is_ok());
+    }
+
+    #[test]
+    fn bless_then_check_reference_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let reference_path = dir.path().join("references").join("gray.png");
+        let image = brightened(0, 0);
+
+        // Blessing creates the directory and the reference, without comparing anything.
+        check_or_bless_reference_image(&image, &reference_path, &tolerance(0, 0), true).unwrap();
+        assert_same_image(&load(&reference_path), &image);
+        check_or_bless_reference_image(&image, &reference_path, &tolerance(0, 0), false).unwrap();
+
+        // Blessing again overwrites the reference, even if it doesn't match.
+        let changed = brightened(4, 10);
+        check_or_bless_reference_image(&changed, &reference_path, &tolerance(0, 0), true).unwrap();
+        assert_same_image(&load(&reference_path), &changed);
+    }
+
+    #[test]
+    fn mismatched_reference_image_writes_diff_and_actual() {
+        let dir = tempfile::tempdir().unwrap();
+        let reference_path = dir.path().join("gray.png");
+        brightened(0, 0).save(&reference_path).unwrap();
+
+        let actual = brightened(1, 10);
+        let result =
+            check_or_bless_reference_image(&actual, &reference_path, &tolerance(0, 0), false);
+        let (mismatch, diff_path, actual_path) = match result {
+            Err(ReferenceImageError::Mismatch { mismatch, diff_path, actual_path }) => {
+                (mismatch, diff_path, actual_path)
+            }
+            result => panic!("expected a mismatch, got {:?}", result),
+        };
+        assert_eq!(mismatch.differing_pixels, 1);
+        assert_eq!(diff_path, dir.path().join("gray.diff.png"));
+        assert_eq!(actual_path, dir.path().join("gray.actual.png"));
+        assert_same_image(&load(&diff_path), &mismatch.diff);
+        assert_same_image(&load(&actual_path), &actual);
+
+        // The reference itself is left alone.
+        assert_same_image(&load(&reference_path), &brightened(0, 0));
+    }
+
+    #[test]
+    fn missing_reference_image_fails_to_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let reference_path = dir.path().join("missing.png");
+        let image = brightened(0, 0);
+        match check_or_bless_reference_image(&image, &reference_path, &tolerance(0, 0), false) {
+            Err(ReferenceImageError::Load(_)) => {}
+            result => panic!("expected a load error, got {:?}", result),
+        }
+        assert!(fs::read_dir(dir.path()).unwrap().next().is_none());
+    }
+}
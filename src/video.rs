@@ -0,0 +1,162 @@
+// planeshift/src/video.rs
+//
+// Copyright © 2018 The Pathfinder Project Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Helpers that backends share for video layers: plane geometry, YUV to RGB conversion, and a
+//! CPU fallback for backends that can't convert frames on the GPU.
+
+use euclid::Size2D;
+use image::{Rgba, RgbaImage};
+
+use crate::{ColorMatrix, ColorRange, VideoFrame, VideoPlane, YUVFormat};
+
+/// Converts normalized YUV samples to RGB: `rgb = matrix * (yuv - offset)`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Conversion {
+    /// Row-major.
+    pub matrix: [[f32; 3]; 3],
+    pub offset: [f32; 3],
+}
+
+impl Conversion {
+    pub fn new(color_matrix: ColorMatrix, color_range: ColorRange) -> Conversion {
+        // The luma coefficients of red and blue.
+        let (kr, kb) = match color_matrix {
+            ColorMatrix::BT601 => (0.299, 0.114),
+            ColorMatrix::BT709 => (0.2126, 0.0722),
+        };
+        let kg = 1.0 - kr - kb;
+
+        // Limited range has 219 steps of luma, starting at 16, and 224 steps of chroma.
+        let (y_scale, c_scale, y_offset) = match color_range {
+            ColorRange::Limited => (255.0 / 219.0, 255.0 / 224.0, 16.0 / 255.0),
+            ColorRange::Full => (1.0, 1.0, 0.0),
+        };
+
+        Conversion {
+            matrix: [
+                [y_scale, 0.0, c_scale * 2.0 * (1.0 - kr)],
+                [
+                    y_scale,
+                    -c_scale * 2.0 * kb * (1.0 - kb) / kg,
+                    -c_scale * 2.0 * kr * (1.0 - kr) / kg,
+                ],
+                [y_scale, c_scale * 2.0 * (1.0 - kb), 0.0],
+            ],
+            offset: [y_offset, 128.0 / 255.0, 128.0 / 255.0],
+        }
+    }
+
+    /// Returns the matrix in column-major order, as `glUniformMatrix3fv()` expects.
+    pub fn column_major_matrix(&self) -> [f32; 9] {
+        let mut result = [0.0; 9];
+        for column in 0..3 {
+            for row in 0..3 {
+                result[column * 3 + row] = self.matrix[row][column];
+            }
+        }
+        result
+    }
+
+    fn apply(&self, yuv: [f32; 3]) -> [u8; 3] {
+        let yuv = [yuv[0] - self.offset[0], yuv[1] - self.offset[1], yuv[2] - self.offset[2]];
+        let mut rgb = [0; 3];
+        for (channel, row) in rgb.iter_mut().zip(self.matrix.iter()) {
+            let value = row[0] * yuv[0] + row[1] * yuv[1] + row[2] * yuv[2];
+            *channel = (value.max(0.0).min(1.0) * 255.0).round() as u8;
+        }
+        rgb
+    }
+}
+
+pub fn plane_count(format: YUVFormat) -> usize {
+    match format {
+        YUVFormat::NV12 => 2,
+        YUVFormat::I420 => 3,
+    }
+}
+
+/// Returns the width and height, in samples, and the number of bytes per sample of the given
+/// plane of a frame of the given size.
+pub fn plane_geometry(format: YUVFormat, size: &Size2D<u32>, plane_index: usize)
+                      -> (Size2D<u32>, usize) {
+    if plane_index == 0 {
+        return (*size, 1)
+    }
+
+    let chroma_size = Size2D::new((size.width + 1) / 2, (size.height + 1) / 2);
+    match format {
+        YUVFormat::NV12 => (chroma_size, 2),
+        YUVFormat::I420 => (chroma_size, 1),
+    }
+}
+
+/// Checks that the frame has the right number of planes, and that its planes in memory are big
+/// enough to hold it.
+pub fn validate(frame: &VideoFrame) -> Result<(), ()> {
+    if frame.planes.len() != plane_count(frame.format) ||
+            frame.size.width == 0 || frame.size.height == 0 {
+        return Err(())
+    }
+
+    for (plane_index, plane) in frame.planes.iter().enumerate() {
+        let (data, stride) = match *plane {
+            VideoPlane::Memory { data, stride } => (data, stride),
+            VideoPlane::Texture(_) => continue,
+        };
+        let (size, bytes_per_sample) = plane_geometry(frame.format, &frame.size, plane_index);
+        let row_length = size.width as usize * bytes_per_sample;
+        if stride < row_length || stride % bytes_per_sample != 0 ||
+                data.len() < stride * (size.height as usize - 1) + row_length {
+            return Err(())
+        }
+    }
+
+    Ok(())
+}
+
+/// Converts a frame whose planes are all in memory to opaque RGBA, stretching it to fill the
+/// image with nearest-neighbor sampling.
+///
+/// Returns `Err` if any of the planes are OpenGL textures.
+pub fn convert_to_rgba(frame: &VideoFrame, image: &mut RgbaImage) -> Result<(), ()> {
+    let mut planes = [(&[][..], 0); 3];
+    for (plane_index, plane) in frame.planes.iter().enumerate() {
+        match *plane {
+            VideoPlane::Memory { data, stride } => planes[plane_index] = (data, stride),
+            VideoPlane::Texture(_) => return Err(()),
+        }
+    }
+
+    let conversion = Conversion::new(frame.color_matrix, frame.color_range);
+    let (image_width, image_height) = image.dimensions();
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        let src_x = (x as u64 * frame.size.width as u64 / image_width as u64) as usize;
+        let src_y = (y as u64 * frame.size.height as u64 / image_height as u64) as usize;
+        let (chroma_x, chroma_y) = (src_x / 2, src_y / 2);
+
+        let luma = sample(planes[0], src_x, src_y);
+        let (u, v) = match frame.format {
+            YUVFormat::NV12 => (sample(planes[1], chroma_x * 2, chroma_y),
+                                sample(planes[1], chroma_x * 2 + 1, chroma_y)),
+            YUVFormat::I420 => (sample(planes[1], chroma_x, chroma_y),
+                                sample(planes[2], chroma_x, chroma_y)),
+        };
+
+        let rgb = conversion.apply([luma, u, v]);
+        *pixel = Rgba { data: [rgb[0], rgb[1], rgb[2], 255] };
+    }
+
+    Ok(())
+}
+
+// Returns the byte at the given column and row of a plane, normalized to [0, 1].
+fn sample((data, stride): (&[u8], usize), column: usize, row: usize) -> f32 {
+    data[row * stride + column] as f32 / 255.0
+}
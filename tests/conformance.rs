@@ -0,0 +1,180 @@
+// planeshift/tests/conformance.rs
+//
+// Copyright © 2018 The Pathfinder Project Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+extern crate euclid;
+extern crate image;
+extern crate planeshift;
+#[cfg(feature = "enable-winit")]
+extern crate winit;
+
+use euclid::Rect;
+use image::Rgba;
+use planeshift::backend::Backend;
+use planeshift::testing::{self, Harness, SoftwareHarness};
+use planeshift::{Connection, ConnectionError, GLAPI, GLContextLayerBinding, LayerContainerInfo};
+use planeshift::{LayerContext, LayerGeometryInfo, LayerId, LayerMap, LayerSurfaceInfo};
+use planeshift::{LayerTreeInfo, Promise, SurfaceOptions};
+
+#[cfg(feature = "enable-winit")]
+use winit::Window;
+
+#[cfg(target_os = "linux")]
+use planeshift::testing::HeadlessHarness;
+
+#[test]
+fn software_backend_conforms() {
+    let failures = testing::run_suite(&mut SoftwareHarness);
+    assert!(failures.is_empty(), "{:#?}", failures);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn headless_backend_conforms() {
+    let failures = testing::run_suite(&mut HeadlessHarness::new());
+    assert!(failures.is_empty(), "{:#?}", failures);
+}
+
+// A backend outside the crate needs only the methods without default implementations. This one
+// can't take screenshots, so the suite skips every case.
+#[test]
+fn minimal_backend_skips_every_case() {
+    let failures = testing::run_suite(&mut MinimalHarness);
+    assert!(failures.is_empty(), "{:#?}", failures);
+}
+
+struct MinimalBackend;
+
+impl Backend for MinimalBackend {
+    type NativeConnection = ();
+    type GLContext = ();
+    type NativeGLContext = ();
+    type Host = ();
+
+    fn new(_: Connection<()>) -> Result<MinimalBackend, ConnectionError> {
+        Ok(MinimalBackend)
+    }
+
+    fn create_gl_context(&mut self, _: SurfaceOptions) -> Result<(), ()> {
+        Err(())
+    }
+
+    unsafe fn wrap_gl_context(&mut self, _: ()) -> Result<(), ()> {
+        Err(())
+    }
+
+    fn gl_api(&self) -> GLAPI {
+        GLAPI::None
+    }
+
+    fn begin_transaction(&self) {}
+
+    fn end_transaction(&mut self,
+                       promise: &Promise<()>,
+                       _: &LayerMap<LayerTreeInfo>,
+                       _: &LayerMap<LayerContainerInfo>,
+                       _: &LayerMap<LayerGeometryInfo>,
+                       _: &LayerMap<LayerSurfaceInfo>) {
+        promise.resolve(());
+    }
+
+    fn add_container_layer(&mut self, _: LayerId) {}
+
+    fn add_surface_layer(&mut self, _: LayerId) {}
+
+    fn delete_layer(&mut self, _: LayerId) {}
+
+    fn insert_before(&mut self,
+                     _: LayerId,
+                     _: LayerId,
+                     _: Option<LayerId>,
+                     _: &LayerMap<LayerTreeInfo>,
+                     _: &LayerMap<LayerContainerInfo>,
+                     _: &LayerMap<LayerGeometryInfo>) {}
+
+    fn remove_from_superlayer(&mut self,
+                              _: LayerId,
+                              _: LayerId,
+                              _: &LayerMap<LayerTreeInfo>,
+                              _: &LayerMap<LayerGeometryInfo>) {}
+
+    unsafe fn host_layer(&mut self,
+                         _: LayerId,
+                         _: (),
+                         _: &LayerMap<LayerTreeInfo>,
+                         _: &LayerMap<LayerContainerInfo>,
+                         _: &LayerMap<LayerGeometryInfo>) {}
+
+    fn unhost_layer(&mut self, _: LayerId) {}
+
+    fn set_layer_bounds(&mut self,
+                        _: LayerId,
+                        _: &Rect<f32>,
+                        _: &LayerMap<LayerTreeInfo>,
+                        _: &LayerMap<LayerContainerInfo>,
+                        _: &LayerMap<LayerGeometryInfo>) {}
+
+    fn set_layer_surface_options(&mut self, _: LayerId, _: &LayerMap<LayerSurfaceInfo>) {}
+
+    fn bind_layer_to_gl_context(&mut self,
+                                _: LayerId,
+                                _: &mut (),
+                                _: &LayerMap<LayerGeometryInfo>,
+                                _: &LayerMap<LayerSurfaceInfo>)
+                                -> Result<GLContextLayerBinding, ()> {
+        Err(())
+    }
+
+    fn present_gl_context(&mut self,
+                          _: GLContextLayerBinding,
+                          _: &Rect<f32>,
+                          _: &LayerMap<LayerTreeInfo>,
+                          _: &LayerMap<LayerGeometryInfo>)
+                          -> Result<(), ()> {
+        Err(())
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn window(&self) -> Option<&Window> {
+        None
+    }
+
+    #[cfg(feature = "enable-winit")]
+    fn host_layer_in_window(&mut self,
+                            _: LayerId,
+                            _: &LayerMap<LayerTreeInfo>,
+                            _: &LayerMap<LayerContainerInfo>,
+                            _: &LayerMap<LayerGeometryInfo>)
+                            -> Result<(), ()> {
+        Err(())
+    }
+}
+
+struct MinimalHarness;
+
+impl Harness for MinimalHarness {
+    type Backend = MinimalBackend;
+
+    fn create_context(&mut self) -> Result<LayerContext<MinimalBackend>, ()> {
+        LayerContext::with_backend_connection(Connection::Native(())).map_err(drop)
+    }
+
+    fn host_root(&mut self, context: &mut LayerContext<MinimalBackend>, root: LayerId)
+                 -> Result<(), ()> {
+        unsafe {
+            context.host_layer((), root);
+        }
+        Ok(())
+    }
+
+    fn fill_layer(&mut self, _: &mut LayerContext<MinimalBackend>, _: LayerId, _: Rgba<u8>)
+                  -> Result<(), ()> {
+        Err(())
+    }
+}
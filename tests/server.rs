@@ -0,0 +1,156 @@
+// planeshift/tests/server.rs
+//
+// Copyright © 2018 The Pathfinder Project Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Runs the compositor server against clients in other processes.
+//!
+//! The clients are this test binary, run again with `CLIENT_ENV_VAR` set to the server's socket.
+
+#![cfg(unix)]
+
+extern crate euclid;
+extern crate image;
+extern crate planeshift;
+
+use euclid::{Point2D, Rect, Size2D};
+use image::RgbaImage;
+use planeshift::backends::{remote, software};
+use planeshift::server::{ClientId, Delegate, PeerCredentials, Permissions, Server};
+use planeshift::{Connection, LayerContext, LayerId};
+use std::env;
+use std::os::unix::net::UnixStream;
+use std::process::{self, Command};
+use std::time::{Duration, Instant};
+
+const CLIENT_ENV_VAR: &'static str = "PLANESHIFT_TEST_CLIENT_SOCKET";
+
+const SERVER_SIZE: u32 = 64;
+
+// Hosts every client root in one container that fills the server's surface.
+struct TestShell {
+    root: LayerId,
+    connected_count: u32,
+    disconnected_count: u32,
+}
+
+impl Delegate<software::Backend> for TestShell {
+    fn client_connected(&mut self,
+                        _: &mut LayerContext<software::Backend>,
+                        _: ClientId,
+                        _: &PeerCredentials)
+                        -> Option<Permissions> {
+        self.connected_count += 1;
+        Some(Permissions::all())
+    }
+
+    fn client_disconnected(&mut self, _: &mut LayerContext<software::Backend>, _: ClientId) {
+        self.disconnected_count += 1;
+    }
+
+    fn host_layer(&mut self,
+                  context: &mut LayerContext<software::Backend>,
+                  _: ClientId,
+                  layer: LayerId,
+                  _: remote::HostId)
+                  -> Result<(), ()> {
+        context.append_child(self.root, layer);
+        Ok(())
+    }
+
+    fn update_surface(&mut self,
+                      _: &mut LayerContext<software::Backend>,
+                      _: LayerId,
+                      _: &RgbaImage,
+                      _: &Rect<f32>)
+                      -> Result<(), ()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn server_survives_client_unhosting_and_deleting_its_root() {
+    let socket_path = env::temp_dir().join(format!("planeshift-test-server-{}", process::id()));
+    let mut server = Server::bind(&socket_path).unwrap();
+
+    let size = Size2D::new(SERVER_SIZE, SERVER_SIZE);
+    let mut context: LayerContext<software::Backend> =
+        LayerContext::with_backend_connection(Connection::Native(size)).unwrap();
+    context.begin_transaction();
+    let root = context.add_container_layer();
+    context.set_layer_bounds(root, &Rect::new(Point2D::zero(), size.to_f32()));
+    unsafe {
+        context.host_layer(size, root);
+    }
+    context.end_transaction();
+    let mut shell = TestShell { root, connected_count: 0, disconnected_count: 0 };
+
+    let mut client = Command::new(env::current_exe().unwrap())
+        .args(&["--exact", "client_process", "--nocapture"])
+        .env(CLIENT_ENV_VAR, &socket_path)
+        .spawn()
+        .unwrap();
+
+    // Serve until the client has come and gone.
+    let deadline = Instant::now() + Duration::from_secs(30);
+    let status = loop {
+        assert!(Instant::now() < deadline, "the client never finished");
+        server.dispatch(&mut context, &mut shell, Some(Duration::from_millis(10))).unwrap();
+        if let Some(status) = client.try_wait().unwrap() {
+            if shell.connected_count > 0 && server.client_count() == 0 {
+                break status
+            }
+        }
+    };
+
+    drop(std::fs::remove_file(&socket_path));
+    assert!(status.success());
+    assert_eq!((shell.connected_count, shell.disconnected_count), (1, 1));
+}
+
+/// The client half of the tests above. This does nothing unless `CLIENT_ENV_VAR` is set.
+#[test]
+fn client_process() {
+    let socket_path = match env::var_os(CLIENT_ENV_VAR) {
+        None => return,
+        Some(socket_path) => socket_path,
+    };
+    let stream = UnixStream::connect(socket_path).unwrap();
+    let mut context: LayerContext<remote::Backend> =
+        LayerContext::with_backend_connection(Connection::Native(stream)).unwrap();
+    let bounds = Rect::new(Point2D::zero(), Size2D::new(32.0, 32.0));
+
+    // Host a layer and take a screenshot, to make sure that the server got it.
+    context.begin_transaction();
+    let layer = context.add_container_layer();
+    context.set_layer_bounds(layer, &bounds);
+    unsafe {
+        context.host_layer(remote::MAIN_WINDOW_HOST, layer);
+    }
+    let screenshot = context.screenshot_hosted_layer(layer);
+    context.end_transaction();
+    assert_eq!(context.wait(&screenshot).unwrap().dimensions(), (32, 32));
+
+    // Unhost and delete it. The server hosted it inside a layer of its own, which mustn't trip it
+    // up.
+    context.begin_transaction();
+    context.remove_from_parent(layer);
+    context.delete_layer(layer);
+    context.end_transaction();
+
+    // The server should still be there to answer.
+    context.begin_transaction();
+    let layer = context.add_container_layer();
+    context.set_layer_bounds(layer, &bounds);
+    unsafe {
+        context.host_layer(remote::MAIN_WINDOW_HOST, layer);
+    }
+    let screenshot = context.screenshot_hosted_layer(layer);
+    context.end_transaction();
+    assert_eq!(context.wait(&screenshot).unwrap().dimensions(), (32, 32));
+}